@@ -5,7 +5,10 @@ const CHUNK_SIZE: usize = 1920;
 const FREQUENCY: usize = 440;
 
 fn main() -> anyhow::Result<()> {
-    let mut encoder = kaudio::ogg_opus::Encoder::new(SAMPLE_RATE)?;
+    let mut cfg = kaudio::opus::EncoderConfig::new(SAMPLE_RATE);
+    cfg.application = kaudio::opus::Application::Audio;
+    cfg.bitrate = Some(32_000);
+    let mut encoder = kaudio::ogg_opus::Encoder::new_with_config(cfg)?;
     let freq_in_samples = SAMPLE_RATE / FREQUENCY;
     let mut file = std::fs::File::create("out.ogg")?;
     file.write_all(encoder.header_data())?;
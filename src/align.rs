@@ -0,0 +1,111 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Sample accurate alignment of two signals, e.g. an original and its decoded version or a
+// microphone capture and the reference played on the speakers. The cross-correlation is
+// computed with FFTs so that large search windows remain cheap.
+
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    /// Delay of the second signal compared to the first one in samples, `b[i + lag]` matches
+    /// `a[i]`. Negative values mean that `b` is ahead of `a`.
+    pub lag: i64,
+    /// Cross-correlation at `lag` normalized by the energy of both signals, close to 1 when `b`
+    /// is a delayed copy of `a`.
+    pub confidence: f32,
+}
+
+/// Finds the lag in `[-max_lag, max_lag]` that maximizes the cross-correlation of `a` and `b`.
+pub fn align(a: &[f32], b: &[f32], max_lag: usize) -> Result<Alignment> {
+    use realfft::num_complex::Complex;
+
+    if a.is_empty() || b.is_empty() {
+        crate::bail!("cannot align empty signals")
+    }
+    let n = (a.len() + b.len()).next_power_of_two();
+    let mut planner = realfft::RealFftPlanner::<f64>::new();
+    let fft = planner.plan_fft_forward(n);
+    let ifft = planner.plan_fft_inverse(n);
+    let spectrum = |pcm: &[f32]| -> Result<Vec<Complex<f64>>> {
+        let mut input = fft.make_input_vec();
+        for (i, v) in input.iter_mut().zip(pcm.iter()) {
+            *i = *v as f64
+        }
+        let mut spec = fft.make_output_vec();
+        fft.process(&mut input, &mut spec).map_err(crate::Error::wrap)?;
+        Ok(spec)
+    };
+    let spec_a = spectrum(a)?;
+    let spec_b = spectrum(b)?;
+    let mut cross: Vec<Complex<f64>> =
+        spec_a.iter().zip(spec_b.iter()).map(|(a, b)| a.conj() * b).collect();
+    // The imaginary parts of the dc and nyquist bins have to be zero for the inverse transform.
+    cross[0].im = 0.;
+    if let Some(last) = cross.last_mut() {
+        last.im = 0.
+    }
+    let mut xcorr = ifft.make_output_vec();
+    ifft.process(&mut cross, &mut xcorr).map_err(crate::Error::wrap)?;
+
+    // xcorr[k] = sum_i a[i] b[i + k], negative lags wrap around.
+    let max_pos = usize::min(max_lag, b.len() - 1) as i64;
+    let max_neg = usize::min(max_lag, a.len() - 1) as i64;
+    let mut best = (0i64, f64::NEG_INFINITY);
+    for lag in -max_neg..=max_pos {
+        let idx = if lag >= 0 { lag as usize } else { n - (-lag) as usize };
+        if xcorr[idx] > best.1 {
+            best = (lag, xcorr[idx])
+        }
+    }
+    let energy = |pcm: &[f32]| pcm.iter().map(|&v| v as f64 * v as f64).sum::<f64>();
+    let norm = (energy(a) * energy(b)).sqrt() * n as f64;
+    let confidence = if norm > 0. { (best.1 / norm) as f32 } else { 0. };
+    Ok(Alignment { lag: best.0, confidence })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delayed_and_ahead() {
+        let a = noise(4_000, 1);
+        let mut delayed = vec![0.; 123];
+        delayed.extend_from_slice(&a);
+        let alignment = align(&a, &delayed, 500).unwrap();
+        assert_eq!(alignment.lag, 123);
+        assert!(alignment.confidence > 0.99, "{alignment:?}");
+        let alignment = align(&a, &a[50..], 500).unwrap();
+        assert_eq!(alignment.lag, -50);
+        assert!(alignment.confidence > 0.95, "{alignment:?}");
+        // The lag is searched within `max_lag` only.
+        let alignment = align(&a, &delayed, 100).unwrap();
+        assert!(alignment.lag.abs() <= 100);
+        assert!(alignment.confidence < 0.2, "{alignment:?}");
+    }
+
+    #[test]
+    fn unrelated_and_invalid() {
+        let alignment = align(&noise(4_000, 1), &noise(4_000, 2), 500).unwrap();
+        assert!(alignment.confidence < 0.2, "{alignment:?}");
+        let alignment = align(&noise(1_000, 1), &[0.; 1_000], 100).unwrap();
+        assert_eq!(alignment.confidence, 0.);
+        assert!(align(&[], &[1.], 10).is_err());
+        assert!(align(&[1.], &[], 10).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Session archives: a single ogg file multiplexing an opus stream with a stream of timestamped
+// json events, e.g. turns, tool calls or markers. Each event packet is on its own page whose
+// granule position is the time of the event at 48kHz, as for the audio, so that the events stay
+// aligned with the audio. Players ignore the events stream and play the audio.
+
+use crate::ogg_pager::{PacketEnd, PageWriter};
+use crate::Result;
+
+// Identification header of the events stream, followed by a version byte.
+const EVENTS_MAGIC: &[u8; 8] = b"KAEvents";
+const EVENTS_VERSION: u8 = 1;
+
+/// An event and its position on the audio timeline, in samples since the start of the audio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub position: u64,
+    pub data: serde_json::Value,
+}
+
+pub struct ArchiveWriter {
+    audio: PageWriter,
+    events: PageWriter,
+    // The pages of both streams in the order in which they were written.
+    data: Vec<u8>,
+    encoder: crate::opus::Encoder,
+    sample_rate: usize,
+    last_event: u64,
+}
+
+impl ArchiveWriter {
+    /// The audio is encoded according to `cfg`, the events stream uses the serial following
+    /// `cfg.serial`.
+    pub fn new(cfg: &crate::opus::EncoderConfig) -> Result<Self> {
+        let encoder = crate::opus::Encoder::new(cfg)?;
+        let mut audio = PageWriter::new(cfg.serial);
+        let mut events = PageWriter::new(cfg.serial.wrapping_add(1));
+        // The first page of each logical stream has to come before any other page.
+        let mut head = Vec::new();
+        crate::ogg_opus::write_opus_header(&mut head, encoder.channel_mapping())?;
+        audio.write_packet(&head, 0, PacketEnd::EndPage);
+        let mut data = audio.take_data();
+        let mut events_head = EVENTS_MAGIC.to_vec();
+        events_head.push(EVENTS_VERSION);
+        events.write_packet(&events_head, 0, PacketEnd::EndPage);
+        data.extend_from_slice(&events.take_data());
+        let mut tags = Vec::new();
+        let vendor = cfg.vendor.as_deref().unwrap_or(crate::ogg_opus::DEFAULT_VENDOR);
+        crate::ogg_opus::write_opus_tags(&mut tags, vendor, &cfg.comments)?;
+        audio.write_packet(&tags, 0, PacketEnd::EndPage);
+        data.extend_from_slice(&audio.take_data());
+        Ok(Self { audio, events, data, encoder, sample_rate: cfg.sample_rate, last_event: 0 })
+    }
+
+    /// Number of audio samples per channel pushed so far, this is the position of an event
+    /// happening right after the pushed audio.
+    pub fn position(&self) -> u64 {
+        let encoded = self.encoder.granule_position() * self.sample_rate as u64 / 48_000;
+        encoded + self.encoder.buffered_samples() as u64
+    }
+
+    /// Appends interleaved pcm to the audio stream.
+    pub fn push_audio(&mut self, pcm: &[f32]) -> Result<()> {
+        let packets = self.encoder.encode(pcm)?;
+        self.write_audio(packets)
+    }
+
+    fn write_audio(&mut self, packets: Vec<crate::opus::Packet>) -> Result<()> {
+        for packet in packets {
+            self.audio.write_packet(&packet.data, packet.granule_position, PacketEnd::EndPage);
+        }
+        self.data.extend_from_slice(&self.audio.take_data());
+        Ok(())
+    }
+
+    /// Appends an event at `position` samples on the audio timeline, events have to be pushed
+    /// in order.
+    pub fn push_event(&mut self, position: u64, data: &serde_json::Value) -> Result<()> {
+        if position < self.last_event {
+            crate::bail!("event at {position} pushed after an event at {}", self.last_event)
+        }
+        self.last_event = position;
+        let granule_position = position * 48_000 / self.sample_rate as u64;
+        let data = serde_json::to_vec(data).map_err(crate::Error::wrap)?;
+        self.events.write_packet(&data, granule_position, PacketEnd::EndPage);
+        self.data.extend_from_slice(&self.events.take_data());
+        Ok(())
+    }
+
+    /// Returns the pages written so far, so that the archive can be written incrementally.
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    /// Flushes the audio, ends both streams and returns the remaining data.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        // The events stream ends first so that the end of the audio stream, which gives the
+        // duration of the audio, comes last.
+        self.events.end_stream();
+        self.data.extend_from_slice(&self.events.take_data());
+        let packets = self.encoder.flush()?;
+        self.audio.end_stream_with(packets.iter().map(|p| (&p.data[..], p.granule_position)));
+        self.data.extend_from_slice(&self.audio.take_data());
+        Ok(self.take_data())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub sample_rate: usize,
+    pub channels: usize,
+    /// The decoded audio, interleaved when there is more than one channel.
+    pub pcm: Vec<f32>,
+    pub tags: crate::ogg_opus::OpusTags,
+    /// The events in order, their positions index the frames of `pcm`.
+    pub events: Vec<Event>,
+}
+
+/// Decodes a session archive at `sample_rate`. Logical streams other than the first opus and
+/// events streams are ignored.
+pub fn read(data: &[u8], sample_rate: usize) -> Result<Archive> {
+    let mut reader = ogg::reading::PacketReader::new(std::io::Cursor::new(data));
+    let mut decoder = crate::opus::Decoder::new(sample_rate, false)?;
+    let mut audio_serial = None;
+    let mut events_serial = None;
+    let mut head = None;
+    let mut tags = None;
+    let mut pcm = vec![];
+    let mut last_granule_position = 0;
+    // Event positions as granule positions, mapped to output samples once the pre-skip is known.
+    let mut events = vec![];
+    while let Some(packet) = reader.read_packet()? {
+        let serial = packet.stream_serial();
+        if packet.first_in_stream() {
+            if audio_serial.is_none() && packet.data.starts_with(b"OpusHead") {
+                head = Some(crate::ogg_opus::apply_head(&mut decoder, &packet.data, true)?);
+                audio_serial = Some(serial);
+            } else if events_serial.is_none() && packet.data.starts_with(EVENTS_MAGIC) {
+                if packet.data.get(EVENTS_MAGIC.len()) != Some(&EVENTS_VERSION) {
+                    crate::bail!("unsupported session archive events version")
+                }
+                events_serial = Some(serial);
+            }
+            continue;
+        }
+        if Some(serial) == audio_serial {
+            if tags.is_none() {
+                tags = Some(crate::ogg_opus::OpusTags::from_slice(&packet.data)?);
+                continue;
+            }
+            if !packet.data.is_empty() {
+                decoder.decode(&packet.data, &mut pcm)?;
+            }
+            if packet.last_in_page() {
+                last_granule_position = packet.absgp_page();
+            }
+        } else if Some(serial) == events_serial && !packet.data.is_empty() {
+            let data = serde_json::from_slice(&packet.data).map_err(crate::Error::wrap)?;
+            events.push(Event { position: packet.absgp_page(), data })
+        }
+    }
+    let (Some(head), Some(tags)) = (head, tags) else {
+        crate::bail!("no opus stream found in the session archive")
+    };
+    if events_serial.is_none() {
+        crate::bail!("no events stream found in the session archive")
+    }
+    let channels = decoder.channels();
+    let pre_skip = head.pre_skip as u64;
+    let to_samples = |granule_position: u64| {
+        granule_position.saturating_sub(pre_skip) * sample_rate as u64 / 48_000
+    };
+    // The last packet is padded, its granule position marks the end of the actual audio.
+    pcm.truncate(to_samples(last_granule_position) as usize * channels);
+    events.iter_mut().for_each(|event| event.position = to_samples(event.position));
+    Ok(Archive { sample_rate, channels, pcm, tags, events })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One second of silence at 24kHz with a burst at 0.5s, with an event at the burst.
+    fn write(take_data: bool) -> Vec<u8> {
+        let mut cfg = crate::opus::EncoderConfig::new(24_000);
+        cfg.comments.push(("TITLE".to_string(), "session".to_string()));
+        let mut writer = ArchiveWriter::new(&cfg).unwrap();
+        let mut pcm = vec![0f32; 24_000];
+        for (i, v) in pcm[12_000..12_480].iter_mut().enumerate() {
+            *v = 0.5 * (i as f32 * 0.3).sin()
+        }
+        let mut data = vec![];
+        writer.push_event(0, &serde_json::json!({"turn": "user"})).unwrap();
+        writer.push_audio(&pcm[..12_100]).unwrap();
+        assert_eq!(writer.position(), 12_100);
+        if take_data {
+            data.extend(writer.take_data())
+        }
+        writer.push_event(12_000, &serde_json::json!({"burst": true})).unwrap();
+        assert!(writer.push_event(11_000, &serde_json::json!(null)).is_err());
+        writer.push_audio(&pcm[12_100..]).unwrap();
+        writer.push_event(24_000, &serde_json::json!({"turn": "end"})).unwrap();
+        data.extend(writer.finish().unwrap());
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = write(true);
+        assert_eq!(data, write(false));
+        let archive = read(&data, 24_000).unwrap();
+        assert_eq!((archive.sample_rate, archive.channels), (24_000, 1));
+        assert_eq!(archive.tags.get("TITLE"), Some("session"));
+        // The pre-skip is dropped from both the audio and the event positions.
+        let pre_skip = 3_840 / 2;
+        assert_eq!(archive.pcm.len(), 24_000 - pre_skip);
+        let positions: Vec<u64> = archive.events.iter().map(|e| e.position).collect();
+        assert_eq!(positions, [0, 12_000 - pre_skip as u64, 24_000 - pre_skip as u64]);
+        assert_eq!(archive.events[1].data, serde_json::json!({"burst": true}));
+        // The burst event stays aligned with the burst, up to the encoder lookahead.
+        let burst = archive.pcm.iter().position(|v| v.abs() > 0.05).unwrap() as u64;
+        assert!(burst >= positions[1] && burst < positions[1] + 240, "{burst}");
+    }
+
+    #[test]
+    fn read_invalid() {
+        let pcm = vec![0f32; 24_000];
+        let opus = crate::ogg_opus::Encoder::new(24_000).unwrap().encode_file(&pcm).unwrap();
+        assert!(read(&opus, 24_000).is_err());
+        let mut data = write(false);
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        // The events version is the last byte of the first page of the events stream.
+        data[ranges[1].end - 1] = EVENTS_VERSION + 1;
+        let page = &mut data[ranges[1].clone()];
+        let hdr = crate::parse::PageHeader::read(page);
+        let (serial, sequence) = (hdr.bitstream_serial, hdr.page_sequence);
+        crate::parse::rewrite_page(page, hdr.header_type, hdr.granule_position, serial, sequence);
+        assert!(read(&data, 24_000).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Base64 output of the ogg/opus encoder, for embedding short clips in JSON APIs or HTML, and the
+// decoding of base64 payloads.
+
+use crate::Result;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 encoding with padding.
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    encode_into(data, &mut out);
+    out
+}
+
+/// Decodes standard base64, the padding is required.
+pub fn decode(data: &str) -> Result<Vec<u8>> {
+    if !data.len().is_multiple_of(4) {
+        crate::bail!("base64 length {} is not a multiple of 4", data.len())
+    }
+    let mut out = Vec::with_capacity(data.len() / 4 * 3);
+    let bytes = data.as_bytes();
+    for (index, chunk) in bytes.chunks(4).enumerate() {
+        let last = (index + 1) * 4 == bytes.len();
+        let padding = if last { chunk.iter().rev().take_while(|&&c| c == b'=').count() } else { 0 };
+        if padding > 2 {
+            crate::bail!("invalid base64 padding")
+        }
+        let mut v = 0u32;
+        for &c in chunk[..4 - padding].iter() {
+            let Some(d) = ALPHABET.iter().position(|&a| a == c) else {
+                crate::bail!("invalid base64 character {:?}", c as char)
+            };
+            v = v << 6 | d as u32;
+        }
+        v <<= 6 * padding;
+        out.extend_from_slice(&v.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+fn encode_into(data: &[u8], out: &mut String) {
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let v = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(v >> (18 - 6 * i) & 63) as usize] as char)
+            } else {
+                out.push('=')
+            }
+        }
+    }
+}
+
+/// Wraps an ogg/opus encoder and returns its output as base64 chunks. The chunks only contain
+/// complete 3 bytes groups so that they can be concatenated, the end of the stream and the
+/// remaining bytes are emitted by `finalize`.
+pub struct Base64Encoder {
+    encoder: crate::ogg_opus::Encoder,
+    remainder: Vec<u8>,
+    // The full base64 output when building a data URI.
+    all: Option<String>,
+}
+
+impl Base64Encoder {
+    pub fn new(encoder: crate::ogg_opus::Encoder) -> Self {
+        let remainder = encoder.header_data().to_vec();
+        Self { encoder, remainder, all: None }
+    }
+
+    /// Keeps the whole output around so that `finalize_data_uri` can be used.
+    pub fn new_for_data_uri(encoder: crate::ogg_opus::Encoder) -> Self {
+        let mut s = Self::new(encoder);
+        s.all = Some(String::new());
+        s
+    }
+
+    pub fn encode_page(&mut self, pcm: &[f32]) -> Result<String> {
+        let data = self.encoder.encode_page(pcm)?;
+        self.remainder.extend_from_slice(&data);
+        let len = self.remainder.len() / 3 * 3;
+        let chunk = encode(&self.remainder[..len]);
+        self.remainder.drain(..len);
+        if let Some(all) = self.all.as_mut() {
+            all.push_str(&chunk)
+        }
+        Ok(chunk)
+    }
+
+    /// Finishes the ogg stream, see [`crate::ogg_opus::Encoder::finish`], and returns the last
+    /// base64 chunk including the padding.
+    pub fn finalize(mut self) -> Result<String> {
+        let data = self.encoder.finish()?;
+        self.remainder.extend_from_slice(&data);
+        Ok(encode(&self.remainder))
+    }
+
+    /// Returns the complete output as a `data:audio/ogg;base64,...` URI. This requires the
+    /// encoder to have been created with `new_for_data_uri`.
+    pub fn finalize_data_uri(mut self) -> Result<String> {
+        let Some(mut all) = self.all.take() else {
+            crate::bail!("data URI output requires Base64Encoder::new_for_data_uri")
+        };
+        all.push_str(&self.finalize()?);
+        Ok(format!("data:audio/ogg;base64,{all}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for len in 0..64usize {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            let encoded = encode(&data);
+            assert_eq!(encoded.len(), len.div_ceil(3) * 4);
+            assert_eq!(decode(&encoded).unwrap(), data);
+        }
+        assert_eq!(encode(b"kaudio"), "a2F1ZGlv");
+        assert_eq!(encode(b"opus"), "b3B1cw==");
+        assert_eq!(decode("b2dn").unwrap(), b"ogg");
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert!(decode("abc").is_err());
+        assert!(decode("ab=c").is_err());
+        assert!(decode("a===").is_err());
+        assert!(decode("ab!d").is_err());
+    }
+
+    fn pcm() -> Vec<f32> {
+        // Not a multiple of the frame size, so that `finish` has a partial frame to flush.
+        (0..30_000).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect()
+    }
+
+    fn reference() -> Vec<u8> {
+        crate::ogg_opus::Encoder::new(48_000).unwrap().encode_file(&pcm()).unwrap()
+    }
+
+    #[test]
+    fn finalize() {
+        let mut encoder = Base64Encoder::new(crate::ogg_opus::Encoder::new(48_000).unwrap());
+        let mut out = String::new();
+        for pcm in pcm().chunks(4_800) {
+            let chunk = encoder.encode_page(pcm).unwrap();
+            assert_eq!(chunk.len() % 4, 0);
+            assert!(!chunk.contains('='));
+            out.push_str(&chunk);
+        }
+        out.push_str(&encoder.finalize().unwrap());
+        let data = decode(&out).unwrap();
+        assert_eq!(data, reference());
+        assert_eq!(crate::validate::validate(&data), vec![]);
+    }
+
+    #[test]
+    fn finalize_data_uri() {
+        let encoder = crate::ogg_opus::Encoder::new(48_000).unwrap();
+        assert!(Base64Encoder::new(encoder).finalize_data_uri().is_err());
+        let encoder = crate::ogg_opus::Encoder::new(48_000).unwrap();
+        let mut encoder = Base64Encoder::new_for_data_uri(encoder);
+        encoder.encode_page(&pcm()).unwrap();
+        let uri = encoder.finalize_data_uri().unwrap();
+        let payload = uri.strip_prefix("data:audio/ogg;base64,").unwrap();
+        assert_eq!(decode(payload).unwrap(), reference());
+    }
+}
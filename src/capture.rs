@@ -0,0 +1,144 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Debug captures of opus packet streams as newline-delimited JSON, one packet per line with its
+// base64 data, sequence number, granule position and arrival time. Captures are meant to be
+// attached to bug reports about streaming issues: replaying one into a decoder reproduces the
+// packet losses and reorderings that were observed.
+
+use crate::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CapturedPacket {
+    pub seq: u64,
+    /// Position of the end of the packet at 48kHz, unknown for packets received in the raw
+    /// packet mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub granule_position: Option<u64>,
+    /// Time at which the packet was captured in microseconds since the start of the capture.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arrival_us: Option<u64>,
+    /// Base64 encoded opus packet.
+    pub data: String,
+}
+
+impl CapturedPacket {
+    pub fn new(packet: &crate::opus::Packet, arrival: Option<std::time::Duration>) -> Self {
+        Self {
+            seq: packet.seq,
+            granule_position: Some(packet.granule_position),
+            arrival_us: arrival.map(|a| a.as_micros() as u64),
+            data: crate::base64::encode(&packet.data),
+        }
+    }
+
+    /// Captures a packet framed with `opus::Packet::to_framed`.
+    pub fn from_framed(framed: &[u8], arrival: Option<std::time::Duration>) -> Result<Self> {
+        let (seq, data) = crate::opus::Packet::parse_framed(framed)?;
+        Ok(Self {
+            seq,
+            granule_position: None,
+            arrival_us: arrival.map(|a| a.as_micros() as u64),
+            data: crate::base64::encode(data),
+        })
+    }
+
+    pub fn arrival(&self) -> Option<std::time::Duration> {
+        self.arrival_us.map(std::time::Duration::from_micros)
+    }
+
+    /// The opus packet, the granule position is 0 when unknown.
+    pub fn to_packet(&self) -> Result<crate::opus::Packet> {
+        Ok(crate::opus::Packet {
+            data: crate::base64::decode(&self.data)?,
+            granule_position: self.granule_position.unwrap_or(0),
+            seq: self.seq,
+        })
+    }
+}
+
+/// Writes the packets in the order they are pushed, which should be the order in which they were
+/// received. Arrival times are measured from the creation of the writer.
+pub struct CaptureWriter<W: std::io::Write> {
+    w: W,
+    start: std::time::Instant,
+    line: Vec<u8>,
+}
+
+impl<W: std::io::Write> CaptureWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self { w, start: std::time::Instant::now(), line: vec![] }
+    }
+
+    pub fn push(&mut self, packet: &crate::opus::Packet) -> Result<()> {
+        let arrival = self.start.elapsed();
+        self.write(&CapturedPacket::new(packet, Some(arrival)))
+    }
+
+    pub fn push_framed(&mut self, framed: &[u8]) -> Result<()> {
+        let arrival = self.start.elapsed();
+        self.write(&CapturedPacket::from_framed(framed, Some(arrival))?)
+    }
+
+    /// Writes `packet` as is, e.g. to merge or edit captures.
+    pub fn write(&mut self, packet: &CapturedPacket) -> Result<()> {
+        self.line.clear();
+        serde_json::to_writer(&mut self.line, packet).map_err(crate::Error::wrap)?;
+        self.line.push(b'\n');
+        self.w.write_all(&self.line)?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Reads the packets of a capture, empty lines are skipped.
+pub struct CaptureReader<R: std::io::BufRead> {
+    lines: std::io::Lines<R>,
+    line_index: usize,
+}
+
+impl<R: std::io::BufRead> CaptureReader<R> {
+    pub fn new(r: R) -> Self {
+        Self { lines: r.lines(), line_index: 0 }
+    }
+}
+
+impl<R: std::io::BufRead> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedPacket>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            self.line_index += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let packet = serde_json::from_str(&line).map_err(|err| {
+                crate::Error::wrap(err).context(format!("capture line {}", self.line_index))
+            });
+            return Some(packet);
+        }
+    }
+}
+
+/// Decodes the packets of a capture in order with `decoder`, the sequence numbers are used to
+/// detect the lost and late packets as `opus::Decoder::decode_framed` does for a live stream.
+/// Returns the decoded pcm.
+pub fn replay<I: IntoIterator<Item = Result<CapturedPacket>>>(
+    packets: I,
+    decoder: &mut crate::opus::Decoder,
+) -> Result<Vec<f32>> {
+    let mut pcm = vec![];
+    for packet in packets {
+        let packet = packet?.to_packet()?;
+        decoder.decode_framed(&packet.to_framed(), &mut pcm)?;
+    }
+    Ok(pcm)
+}
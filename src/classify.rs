@@ -0,0 +1,294 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Lightweight streaming speech/music/noise classifier. A few spectral and temporal features are
+// computed over fixed size chunks and fed to a linear softmax model. The default weights have
+// been tuned by hand and are meant for routing decisions (e.g. only sending speech to ASR), not
+// for fine grained tagging.
+
+use crate::Result;
+
+pub const N_FEATURES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AudioClass {
+    Speech,
+    Music,
+    Noise,
+}
+
+impl AudioClass {
+    pub const ALL: [Self; 3] = [Self::Speech, Self::Music, Self::Noise];
+
+    /// The opus application mode best suited to this kind of content.
+    pub fn opus_application(&self) -> opus2::Application {
+        match self {
+            Self::Speech | Self::Noise => opus2::Application::Voip,
+            Self::Music => opus2::Application::Audio,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    /// Mean spectral flatness over the chunk, close to 1 for white noise and to 0 for tones.
+    pub flatness: f32,
+    /// Standard deviation of the frame energies in dB, divided by 10.
+    pub modulation: f32,
+    /// Ratio of frames with an energy below half the mean energy of the chunk.
+    pub low_energy_ratio: f32,
+    /// Mean spectral flux between consecutive frames, on normalized spectra.
+    pub flux: f32,
+}
+
+impl Features {
+    fn to_array(self) -> [f32; N_FEATURES] {
+        [self.flatness, self.modulation, self.low_energy_ratio, self.flux]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassifierConfig {
+    pub sample_rate: usize,
+    /// Duration of the chunks, one label is produced per chunk.
+    pub chunk_ms: usize,
+    /// For each class in `AudioClass::ALL` order, the weights applied to the features followed
+    /// by the bias.
+    pub weights: [[f32; N_FEATURES + 1]; 3],
+}
+
+impl ClassifierConfig {
+    pub fn new(sample_rate: usize) -> Self {
+        let weights = [
+            [-2.0, 3.0, 4.0, 2.0, -2.5],  // speech
+            [-4.0, 0.5, -2.0, -1.0, 1.0], // music
+            [6.0, -3.0, -2.0, 0.0, -1.0], // noise
+        ];
+        Self { sample_rate, chunk_ms: 1000, weights }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Classification {
+    /// Position of the chunk in the stream, in samples.
+    pub start_sample: u64,
+    pub end_sample: u64,
+    pub class: AudioClass,
+    /// Probabilities in `AudioClass::ALL` order.
+    pub probabilities: [f32; 3],
+    pub features: Features,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FrameStats {
+    flatness: f32,
+    energy: f32,
+    flux: f32,
+}
+
+pub struct Classifier {
+    cfg: ClassifierConfig,
+    fft: std::sync::Arc<dyn realfft::RealToComplex<f32>>,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    spec: Vec<realfft::num_complex::Complex<f32>>,
+    prev_spec: Vec<f32>,
+    buffer: Vec<f32>,
+    frames: Vec<FrameStats>,
+    frames_per_chunk: usize,
+    position: u64,
+}
+
+impl Classifier {
+    pub fn new(cfg: ClassifierConfig) -> Result<Self> {
+        // Frames of ~20ms.
+        let frame_size = (cfg.sample_rate / 50).next_power_of_two();
+        let frames_per_chunk = cfg.sample_rate * cfg.chunk_ms / 1000 / frame_size;
+        if frames_per_chunk < 2 {
+            crate::bail!("classifier chunks of {}ms are too short", cfg.chunk_ms)
+        }
+        let mut planner = realfft::RealFftPlanner::<f32>::new();
+        let fft = planner.plan_fft_forward(frame_size);
+        let window = (0..frame_size)
+            .map(|i| 0.5 - 0.5 * (2. * std::f32::consts::PI * i as f32 / frame_size as f32).cos())
+            .collect();
+        let input = fft.make_input_vec();
+        let spec = fft.make_output_vec();
+        let prev_spec = vec![0f32; spec.len()];
+        Ok(Self {
+            cfg,
+            fft,
+            window,
+            input,
+            spec,
+            prev_spec,
+            buffer: Vec::with_capacity(frame_size),
+            frames: Vec::with_capacity(frames_per_chunk),
+            frames_per_chunk,
+            position: 0,
+        })
+    }
+
+    fn frame_size(&self) -> usize {
+        self.window.len()
+    }
+
+    fn process_frame(&mut self) -> Result<FrameStats> {
+        for ((i, b), w) in self.input.iter_mut().zip(self.buffer.iter()).zip(self.window.iter()) {
+            *i = b * w
+        }
+        self.fft.process(&mut self.input, &mut self.spec).map_err(crate::Error::wrap)?;
+        let power: Vec<f32> = self.spec.iter().map(|c| c.norm_sqr() + 1e-12).collect();
+        let n = power.len() as f32;
+        let energy = power.iter().sum::<f32>();
+        let arith_mean = energy / n;
+        let geo_mean = (power.iter().map(|p| p.ln()).sum::<f32>() / n).exp();
+        let flatness = geo_mean / arith_mean;
+        let mut flux = 0f32;
+        for (prev, p) in self.prev_spec.iter_mut().zip(power.iter()) {
+            let p = p / energy;
+            flux += (p.sqrt() - prev.sqrt()).powi(2);
+            *prev = p;
+        }
+        Ok(FrameStats { flatness, energy, flux: flux.sqrt() })
+    }
+
+    fn classify_chunk(&mut self) -> Classification {
+        let n = self.frames.len() as f32;
+        let flatness = self.frames.iter().map(|f| f.flatness).sum::<f32>() / n;
+        let flux = self.frames.iter().map(|f| f.flux).sum::<f32>() / n;
+        let mean_energy = self.frames.iter().map(|f| f.energy).sum::<f32>() / n;
+        let low_energy =
+            self.frames.iter().filter(|f| f.energy < 0.5 * mean_energy).count() as f32 / n;
+        // Frames more than 60dB below the loudest one are clamped so that digital silence does
+        // not dominate the modulation.
+        let max_energy = self.frames.iter().fold(0f32, |a, f| a.max(f.energy));
+        let floor = max_energy * 1e-6;
+        let db: Vec<f32> = self.frames.iter().map(|f| 10. * f.energy.max(floor).log10()).collect();
+        let mean_db = db.iter().sum::<f32>() / n;
+        let var_db = db.iter().map(|d| (d - mean_db).powi(2)).sum::<f32>() / n;
+        let features = Features {
+            flatness,
+            modulation: var_db.sqrt() / 10.,
+            low_energy_ratio: low_energy,
+            flux,
+        };
+        let x = features.to_array();
+        let logits = self.cfg.weights.map(|w| {
+            w[N_FEATURES] + w[..N_FEATURES].iter().zip(x.iter()).map(|(w, x)| w * x).sum::<f32>()
+        });
+        let max = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let exps = logits.map(|l| (l - max).exp());
+        let sum = exps.iter().sum::<f32>();
+        let probabilities = exps.map(|e| e / sum);
+        let best = (0..3).fold(0, |b, i| if probabilities[i] > probabilities[b] { i } else { b });
+        let len = (self.frames.len() * self.frame_size()) as u64;
+        let c = Classification {
+            start_sample: self.position,
+            end_sample: self.position + len,
+            class: AudioClass::ALL[best],
+            probabilities,
+            features,
+        };
+        self.position += len;
+        self.frames.clear();
+        c
+    }
+
+    /// Processes `pcm` and returns the labels of the chunks completed by these samples.
+    pub fn push(&mut self, pcm: &[f32]) -> Result<Vec<Classification>> {
+        let mut labels = vec![];
+        let frame_size = self.frame_size();
+        let mut pcm = pcm;
+        while !pcm.is_empty() {
+            let n = usize::min(frame_size - self.buffer.len(), pcm.len());
+            self.buffer.extend_from_slice(&pcm[..n]);
+            pcm = &pcm[n..];
+            if self.buffer.len() < frame_size {
+                break;
+            }
+            let frame = self.process_frame()?;
+            self.buffer.clear();
+            self.frames.push(frame);
+            if self.frames.len() == self.frames_per_chunk {
+                labels.push(self.classify_chunk())
+            }
+        }
+        Ok(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect()
+    }
+
+    fn classify(pcm: &[f32]) -> Vec<Classification> {
+        let mut classifier = Classifier::new(ClassifierConfig::new(16_000)).unwrap();
+        let mut labels = vec![];
+        for pcm in pcm.chunks(1_000) {
+            labels.extend(classifier.push(pcm).unwrap())
+        }
+        labels
+    }
+
+    #[test]
+    fn classes() {
+        let sr = 16_000.;
+        let tau = 2. * std::f32::consts::PI;
+        let noise = classify(&noise(3 * 16_000, 1));
+        // A sustained chord.
+        let music: Vec<f32> = (0..3 * 16_000)
+            .map(|i| {
+                let t = i as f32 / sr;
+                [262., 330., 392.].iter().map(|f| 0.2 * (tau * f * t).sin()).sum::<f32>()
+            })
+            .collect();
+        let music = classify(&music);
+        // Harmonic syllables of ~150ms separated by pauses.
+        let speech: Vec<f32> = (0..3 * 16_000)
+            .map(|i| {
+                let t = i as f32 / sr;
+                let envelope = (tau * 3. * t).sin().max(0.);
+                let f0 = 120. + 30. * (tau * 2. * t).sin();
+                (1..8).map(|h| (tau * f0 * h as f32 * t).sin() / h as f32).sum::<f32>()
+                    * envelope
+                    * 0.3
+            })
+            .collect();
+        let speech = classify(&speech);
+        for (labels, class) in
+            [(noise, AudioClass::Noise), (music, AudioClass::Music), (speech, AudioClass::Speech)]
+        {
+            // Chunks of 31 frames of 512 samples.
+            assert_eq!(labels.len(), 3);
+            for (i, label) in labels.iter().enumerate() {
+                assert_eq!(label.class, class, "{label:?}");
+                assert_eq!(label.start_sample, i as u64 * 15_872);
+                assert_eq!(label.end_sample, (i as u64 + 1) * 15_872);
+                assert!((label.probabilities.iter().sum::<f32>() - 1.).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn config() {
+        let mut cfg = ClassifierConfig::new(16_000);
+        cfg.chunk_ms = 40;
+        assert!(Classifier::new(cfg).is_err());
+        assert_eq!(AudioClass::Music.opus_application(), opus2::Application::Audio);
+        assert_eq!(AudioClass::Speech.opus_application(), opus2::Application::Voip);
+    }
+}
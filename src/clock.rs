@@ -0,0 +1,182 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Mapping between the three time bases of a stream: granule positions (48kHz), sample counts at
+// the output rate, and wall-clock arrival times. Discontinuities in the granule positions, e.g. a
+// restarted encoder or a spliced stream, rebase the clock so that the output sample count stays
+// continuous.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct StreamClock {
+    sample_rate: usize,
+    max_jump: u64,
+    // Granule position matching `anchor_samples` output samples.
+    anchor_granule: u64,
+    anchor_samples: u64,
+    // Granule position of the end of a packet and its arrival time.
+    time_anchor: Option<(u64, Instant)>,
+    last_granule: Option<u64>,
+    discontinuities: u64,
+}
+
+impl StreamClock {
+    /// Creates a clock for an output at `sample_rate`. Jumps in the granule positions of more
+    /// than one second are considered as discontinuities, see `set_max_jump`.
+    pub fn new(sample_rate: usize) -> Self {
+        Self {
+            sample_rate,
+            max_jump: 48_000,
+            anchor_granule: 0,
+            anchor_samples: 0,
+            time_anchor: None,
+            last_granule: None,
+            discontinuities: 0,
+        }
+    }
+
+    /// Largest gap or overlap between consecutive packets that is not treated as a
+    /// discontinuity. Smaller gaps are lost packets and count in the output sample positions.
+    pub fn set_max_jump(&mut self, max_jump: Duration) {
+        self.max_jump = max_jump.as_micros() as u64 * 48 / 1000
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Number of times the clock has been rebased because of a discontinuity.
+    pub fn discontinuities(&self) -> u64 {
+        self.discontinuities
+    }
+
+    /// Granule position of the end of the last observed packet.
+    pub fn last_granule_position(&self) -> Option<u64> {
+        self.last_granule
+    }
+
+    /// Records a packet of `duration` samples at 48kHz ending at `granule_position` and received
+    /// at `arrival`. Returns true when the packet does not follow the previous one, in which case
+    /// the clock has been rebased: the packet starts right after the previous one in the output
+    /// samples and arrival times are measured from this packet.
+    pub fn observe(&mut self, granule_position: u64, duration: u64, arrival: Instant) -> bool {
+        let start = granule_position.saturating_sub(duration);
+        let discontinuity = match self.last_granule {
+            None => {
+                self.rebase(start, 0);
+                self.time_anchor = Some((granule_position, arrival));
+                false
+            }
+            Some(last) if start.abs_diff(last) > self.max_jump => {
+                let samples = self.granule_to_samples(last);
+                self.rebase(start, samples);
+                self.time_anchor = Some((granule_position, arrival));
+                self.discontinuities += 1;
+                true
+            }
+            Some(_) => false,
+        };
+        self.last_granule = Some(granule_position);
+        discontinuity
+    }
+
+    /// Maps `granule_position` to `samples` output samples, the following positions are mapped
+    /// relative to this one.
+    pub fn rebase(&mut self, granule_position: u64, samples: u64) {
+        self.anchor_granule = granule_position;
+        self.anchor_samples = samples;
+    }
+
+    /// Output sample count at `granule_position`, positions before the last rebase are clamped.
+    pub fn granule_to_samples(&self, granule_position: u64) -> u64 {
+        let delta = granule_position.saturating_sub(self.anchor_granule);
+        self.anchor_samples + (delta as u128 * self.sample_rate as u128 / 48_000) as u64
+    }
+
+    /// Granule position at `samples` output samples, counts before the last rebase are clamped.
+    pub fn samples_to_granule(&self, samples: u64) -> u64 {
+        let delta = samples.saturating_sub(self.anchor_samples);
+        self.anchor_granule + (delta as u128 * 48_000 / self.sample_rate as u128) as u64
+    }
+
+    /// Time at which a packet ending at `granule_position` is expected to arrive if the stream
+    /// is received in real time. `None` until a packet has been observed.
+    pub fn granule_to_time(&self, granule_position: u64) -> Option<Instant> {
+        let (anchor_granule, anchor_time) = self.time_anchor?;
+        if granule_position >= anchor_granule {
+            anchor_time.checked_add(granule_duration(granule_position - anchor_granule))
+        } else {
+            anchor_time.checked_sub(granule_duration(anchor_granule - granule_position))
+        }
+    }
+
+    /// Granule position expected to be received at `time` if the stream is received in real
+    /// time. `None` until a packet has been observed.
+    pub fn time_to_granule(&self, time: Instant) -> Option<u64> {
+        let (anchor_granule, anchor_time) = self.time_anchor?;
+        let granule_position = if time >= anchor_time {
+            let delta = (time - anchor_time).as_micros() as u64 * 48 / 1000;
+            anchor_granule.saturating_add(delta)
+        } else {
+            let delta = (anchor_time - time).as_micros() as u64 * 48 / 1000;
+            anchor_granule.saturating_sub(delta)
+        };
+        Some(granule_position)
+    }
+
+    /// How late a packet ending at `granule_position` arrived compared to the real time
+    /// schedule, in seconds, negative values for packets arriving early. This is the input of
+    /// jitter estimates.
+    pub fn lateness(&self, granule_position: u64, arrival: Instant) -> Option<f64> {
+        let expected = self.granule_to_time(granule_position)?;
+        let lateness = if arrival >= expected {
+            (arrival - expected).as_secs_f64()
+        } else {
+            -(expected - arrival).as_secs_f64()
+        };
+        Some(lateness)
+    }
+}
+
+fn granule_duration(granules: u64) -> Duration {
+    let micros = u128::from(granules) * 1000 / 48;
+    Duration::from_micros(u64::try_from(micros).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discontinuities() {
+        let start = Instant::now();
+        let mut clock = StreamClock::new(24_000);
+        assert!(!clock.observe(960, 960, start));
+        assert!(!clock.observe(1920, 960, start));
+        // A lost packet is not a discontinuity and counts in the output samples.
+        assert!(!clock.observe(3840, 960, start));
+        assert_eq!(clock.granule_to_samples(3840), 1920);
+        // A jump of more than a second, the packet follows the previous one in the output.
+        assert!(clock.observe(500_000, 960, start));
+        assert_eq!(clock.discontinuities(), 1);
+        assert_eq!(clock.granule_to_samples(500_000), 2400);
+        assert_eq!(clock.samples_to_granule(2400), 500_000);
+    }
+
+    #[test]
+    fn lateness() {
+        let start = Instant::now();
+        let mut clock = StreamClock::new(48_000);
+        assert_eq!(clock.lateness(960, start), None);
+        clock.observe(960, 960, start);
+        let lateness = clock.lateness(48_960, start + Duration::from_millis(1100)).unwrap();
+        assert!((lateness - 0.1).abs() < 1e-6);
+        let time = clock.granule_to_time(960 + 4_800).unwrap();
+        assert_eq!(time - start, Duration::from_millis(100));
+        assert_eq!(clock.time_to_granule(time), Some(960 + 4_800));
+        // Granule positions of corrupt pages do not overflow.
+        assert!(clock.lateness(u64::MAX, start).is_none_or(|lateness| lateness < 0.));
+    }
+}
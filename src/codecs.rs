@@ -0,0 +1,67 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Registry of the container formats and codecs used when decoding audio files. This starts with
+// the formats built into symphonia, other crates can register their own symphonia format readers
+// and decoders so that `pcm_decode`, `pcm_decode_channels` and the transcoding functions handle
+// more formats without changes to this crate.
+
+use crate::Result;
+use symphonia::core::codecs::{CodecParameters, CodecRegistry, Decoder};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::{Hint, Probe, ProbeResult, QueryDescriptor};
+
+struct Registry {
+    probe: Probe,
+    codecs: CodecRegistry,
+}
+
+fn registry() -> &'static std::sync::RwLock<Registry> {
+    static REGISTRY: std::sync::OnceLock<std::sync::RwLock<Registry>> = std::sync::OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut probe = Probe::default();
+        symphonia::default::register_enabled_formats(&mut probe);
+        let mut codecs = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut codecs);
+        std::sync::RwLock::new(Registry { probe, codecs })
+    })
+}
+
+/// Registers the container formats read by `F`. The format detection tries the registered
+/// formats in order, so the built-in formats take precedence when their markers also match.
+pub fn register_format<F: QueryDescriptor>() -> Result<()> {
+    match registry().write() {
+        Ok(mut registry) => registry.probe.register_all::<F>(),
+        Err(_) => crate::bail!("codec registry lock poisoned"),
+    }
+    Ok(())
+}
+
+/// Registers the codecs decoded by `D`, this replaces the previous decoder for these codecs,
+/// including the built-in ones.
+pub fn register_decoder<D: Decoder>() -> Result<()> {
+    match registry().write() {
+        Ok(mut registry) => registry.codecs.register_all::<D>(),
+        Err(_) => crate::bail!("codec registry lock poisoned"),
+    }
+    Ok(())
+}
+
+// Detects the container format of `mss` among the registered formats.
+pub(crate) fn probe(mss: MediaSourceStream) -> Result<ProbeResult> {
+    let fmt_opts = Default::default();
+    let meta_opts = Default::default();
+    match registry().read() {
+        Ok(registry) => Ok(registry.probe.format(&Hint::new(), mss, &fmt_opts, &meta_opts)?),
+        Err(_) => crate::bail!("codec registry lock poisoned"),
+    }
+}
+
+// Creates a decoder for `params` using the registered codecs.
+pub(crate) fn make_decoder(params: &CodecParameters) -> Result<Box<dyn Decoder>> {
+    match registry().read() {
+        Ok(registry) => Ok(registry.codecs.make(params, &Default::default())?),
+        Err(_) => crate::bail!("codec registry lock poisoned"),
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Post-processing of TTS outputs: the generated segments are trimmed, loudness matched and
+// joined with short crossfades before being encoded as a single ogg/opus stream.
+
+use crate::Result;
+
+#[derive(Debug, Clone)]
+pub struct ConcatOptions {
+    pub encoder: crate::opus::EncoderConfig,
+    /// Leading and trailing frames with a rms below this level are trimmed, in dBFS. Use
+    /// `f32::NEG_INFINITY` to disable trimming.
+    pub silence_threshold_db: f32,
+    /// Each segment is scaled so that its rms matches this level, in dBFS. Peaks are limited to
+    /// full scale.
+    pub target_rms_db: Option<f32>,
+    pub crossfade_ms: usize,
+}
+
+impl ConcatOptions {
+    pub fn new(sample_rate: usize) -> Self {
+        Self {
+            encoder: crate::opus::EncoderConfig::new(sample_rate),
+            silence_threshold_db: -50.,
+            target_rms_db: Some(-20.),
+            crossfade_ms: 20,
+        }
+    }
+}
+
+fn rms(pcm: &[f32]) -> f32 {
+    if pcm.is_empty() {
+        return 0.;
+    }
+    (pcm.iter().map(|v| v * v).sum::<f32>() / pcm.len() as f32).sqrt()
+}
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+// Returns the part of `pcm` between the first and last 10ms frames above the threshold, `pcm`
+// has `channels` interleaved channels.
+fn trim(pcm: &[f32], sample_rate: usize, channels: usize, threshold_db: f32) -> &[f32] {
+    let frame = usize::max(sample_rate / 100, 1) * channels;
+    let threshold = db_to_amplitude(threshold_db);
+    let active = |chunk: &[f32]| rms(chunk) >= threshold;
+    let n_frames = pcm.len().div_ceil(frame);
+    let frame_at = |i: usize| &pcm[i * frame..usize::min((i + 1) * frame, pcm.len())];
+    let first = (0..n_frames).find(|&i| active(frame_at(i)));
+    let last = (0..n_frames).rev().find(|&i| active(frame_at(i)));
+    match (first, last) {
+        (Some(first), Some(last)) => &pcm[first * frame..usize::min((last + 1) * frame, pcm.len())],
+        _ => &[],
+    }
+}
+
+/// Applies the trimming, loudness matching and crossfades of `concat_utterances` without
+/// encoding the result.
+pub fn concat_pcm(segments: &[&[f32]], options: &ConcatOptions) -> Vec<f32> {
+    let sample_rate = options.encoder.sample_rate;
+    let channels = input_channels(&options.encoder);
+    let crossfade = sample_rate * options.crossfade_ms / 1000 * channels;
+    let mut out: Vec<f32> = Vec::with_capacity(segments.iter().map(|s| s.len()).sum::<usize>());
+    for segment in segments.iter() {
+        let segment = trim(segment, sample_rate, channels, options.silence_threshold_db);
+        if segment.is_empty() {
+            continue;
+        }
+        let gain = match options.target_rms_db {
+            None => 1.,
+            Some(target_db) => {
+                let gain = db_to_amplitude(target_db) / rms(segment);
+                let peak = segment.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+                if peak * gain > 1. {
+                    1. / peak
+                } else {
+                    gain
+                }
+            }
+        };
+        let overlap = usize::min(crossfade, usize::min(out.len(), segment.len()));
+        let start = out.len() - overlap;
+        for (i, &v) in segment[..overlap].iter().enumerate() {
+            let alpha = (i / channels + 1) as f32 / (overlap / channels + 1) as f32;
+            out[start + i] = out[start + i] * (1. - alpha) + v * gain * alpha;
+        }
+        out.extend(segment[overlap..].iter().map(|v| v * gain));
+    }
+    out
+}
+
+fn input_channels(cfg: &crate::opus::EncoderConfig) -> usize {
+    let channels = cfg.channel_map.as_ref().map_or(cfg.channels, |m| m.input_channels);
+    usize::max(channels, 1)
+}
+
+/// Joins TTS segments into a single ogg/opus stream, see `ConcatOptions` for the processing
+/// applied to each segment. The segments must use the encoder sample rate and be interleaved
+/// with the encoder input channels. The stream is complete, ending with an end of stream page.
+pub fn concat_utterances(segments: &[&[f32]], options: &ConcatOptions) -> Result<Vec<u8>> {
+    let pcm = concat_pcm(segments, options);
+    let encoder = crate::ogg_opus::Encoder::new_with_config(options.encoder.clone())?;
+    encoder.encode_file(&pcm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(len: usize, channels: usize) -> Vec<f32> {
+        (0..len * channels).map(|i| 0.3 * ((i / channels) as f32 * 0.05).sin()).collect()
+    }
+
+    #[test]
+    fn stream_is_finished() {
+        for channels in [1, 2] {
+            let mut options = ConcatOptions::new(24_000);
+            options.encoder.channels = channels;
+            options.encoder.paging = crate::ogg_opus::Paging::file();
+            let a = tone(10_000, channels);
+            let b = tone(7_000, channels);
+            let pcm = concat_pcm(&[&a, &b], &options);
+            assert_eq!(pcm.len() % channels, 0);
+            let data = concat_utterances(&[&a, &b], &options).unwrap();
+
+            let mut pages = crate::ogg_pager::PageReader::new();
+            pages.set_checksum_policy(crate::ogg_pager::ChecksumPolicy::Reject);
+            pages.append_bytes(&data);
+            let pages: Vec<_> = std::iter::from_fn(|| pages.next().unwrap()).collect();
+            assert!(pages.last().unwrap().is_eos());
+            assert_eq!(pages.iter().filter(|p| p.is_eos()).count(), 1);
+
+            let info = crate::ogg_opus::inspect(&data).unwrap();
+            assert_eq!(info.head.channel_count as usize, channels);
+            let frames = pcm.len() / channels;
+            // The last packet is padded to a full frame of 20ms, granules are at 48kHz.
+            let encoded = info.last_granule_position as usize / 2;
+            assert!(encoded >= frames && encoded < frames + 480, "{encoded} {frames}");
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// One call conversions between audio file formats for tooling: the input is decoded in memory,
+// its channels are remapped and it is resampled before being written in the output format.
+// Ogg/opus streams are decoded with the decoders of this crate, wav and flac with symphonia.
+
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Wav,
+    OggOpus,
+    /// Only supported as an input, there is no flac encoder.
+    Flac,
+    /// Interleaved samples without any header.
+    RawPcm(RawPcm),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawPcm {
+    pub sample_rate: usize,
+    pub channels: usize,
+    pub sample_format: RawSampleFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawSampleFormat {
+    F32Le,
+    S16Le,
+}
+
+impl RawSampleFormat {
+    // The conversions of the `pcm` module are used in both directions.
+    fn sample_format(&self) -> crate::pcm::SampleFormat {
+        match self {
+            Self::F32Le => crate::pcm::SampleFormat::F32,
+            Self::S16Le => crate::pcm::SampleFormat::I16,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Sample rate of the output, the input rate is kept when not set. Raw pcm outputs use the
+    /// rate of their format.
+    pub sample_rate: Option<usize>,
+    /// Applied to the decoded channels before resampling.
+    pub channel_map: Option<crate::ChannelMap>,
+    /// Target bitrate of ogg/opus outputs.
+    pub bitrate: Option<u32>,
+}
+
+impl ConvertOptions {
+    pub fn new() -> Self {
+        Self { sample_rate: None, channel_map: None, bitrate: None }
+    }
+}
+
+/// Detects the format of an audio file from its first bytes, raw pcm cannot be detected.
+pub fn sniff(data: &[u8]) -> Option<Format> {
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE") {
+        Some(Format::Wav)
+    } else if data.starts_with(b"fLaC") {
+        Some(Format::Flac)
+    } else if data.starts_with(b"OggS") && data.windows(8).take(512).any(|w| w == b"OpusHead") {
+        Some(Format::OggOpus)
+    } else {
+        None
+    }
+}
+
+/// Reads the whole of `src` and writes it to `dst` in `dst_format`. The input format is
+/// detected from its content when `src_hint` is not set, raw pcm inputs require it.
+pub fn convert<R: std::io::Read, W: std::io::Write>(
+    mut src: R,
+    src_hint: Option<Format>,
+    dst: &mut W,
+    dst_format: Format,
+    options: &ConvertOptions,
+) -> Result<()> {
+    let mut data = vec![];
+    src.read_to_end(&mut data)?;
+    let src_format = match src_hint.or_else(|| sniff(&data)) {
+        Some(format) => format,
+        None => crate::bail!("unable to detect the input format, a hint is required"),
+    };
+    let (mut planar, mut sample_rate) = decode(data, src_format)?;
+    if let Some(map) = options.channel_map.as_ref() {
+        if map.input_channels != planar.len() {
+            crate::bail!(
+                "channel map expects {} channels, got {}",
+                map.input_channels,
+                planar.len()
+            )
+        }
+        planar = map.sources.iter().map(|&src| planar[src].clone()).collect();
+    }
+    let target_rate = match dst_format {
+        Format::RawPcm(raw) => raw.sample_rate,
+        _ => options.sample_rate.unwrap_or(sample_rate),
+    };
+    if target_rate != sample_rate {
+        planar = planar
+            .iter()
+            .map(|pcm| crate::resample::resample(pcm, sample_rate, target_rate))
+            .collect::<Result<Vec<_>>>()?;
+        sample_rate = target_rate;
+    }
+    let channels = planar.len();
+    let pcm = crate::interleave(&planar);
+    match dst_format {
+        Format::Wav => {
+            let spec = crate::wav::WavSpec::new(sample_rate as u32, channels as u16);
+            crate::wav::write(dst, &pcm, &spec)?
+        }
+        Format::OggOpus => {
+            let mut cfg = crate::opus::EncoderConfig::new(sample_rate);
+            cfg.channels = channels;
+            cfg.bitrate = options.bitrate;
+            cfg.resample = true;
+            let mut encoder = crate::ogg_opus::Encoder::new_with_config(cfg)?;
+            dst.write_all(encoder.header_data())?;
+            dst.write_all(&encoder.encode_page(&pcm)?)?;
+            dst.write_all(&encoder.finish()?)?;
+        }
+        Format::Flac => crate::bail!("flac encoding is not supported"),
+        Format::RawPcm(raw) => {
+            if raw.channels != channels {
+                crate::bail!("raw pcm output expects {} channels, got {channels}", raw.channels)
+            }
+            let mut quantizer = crate::pcm::Quantizer::new(crate::pcm::Dither::None);
+            let bytes = quantizer.to_bytes(&pcm, raw.sample_format.sample_format());
+            dst.write_all(&bytes)?
+        }
+    }
+    Ok(())
+}
+
+// Returns the decoded channels and their sample rate.
+fn decode(data: Vec<u8>, format: Format) -> Result<(Vec<Vec<f32>>, usize)> {
+    match format {
+        Format::OggOpus => {
+            let mut cfg = crate::ogg_opus::DecoderConfig::new(48_000, 0);
+            cfg.flush_policy = crate::ogg_opus::FlushPolicy::Explicit;
+            let mut decoder = crate::ogg_opus::Decoder::new_with_config(cfg)?;
+            decoder.decode(&data)?;
+            let channels = decoder.channels();
+            Ok((crate::deinterleave(decoder.flush(), channels), 48_000))
+        }
+        Format::RawPcm(raw) => {
+            if raw.channels == 0 {
+                crate::bail!("raw pcm input with no channel")
+            }
+            let sample_format = raw.sample_format.sample_format();
+            // A truncated last sample is dropped.
+            let len =
+                data.len() / sample_format.bytes_per_sample() * sample_format.bytes_per_sample();
+            let pcm = crate::pcm::from_bytes(&data[..len], sample_format)?;
+            Ok((crate::deinterleave(&pcm, raw.channels), raw.sample_rate))
+        }
+        Format::Wav | Format::Flac => {
+            let mut planar: Vec<Vec<f32>> = vec![];
+            let mut sample_rate = 0;
+            let src = Box::new(std::io::Cursor::new(data));
+            crate::decode_source(src, &[], None, |pcm, sr| {
+                sample_rate = sr as usize;
+                planar.resize(pcm.len(), vec![]);
+                for (all, pcm) in planar.iter_mut().zip(pcm.iter()) {
+                    all.extend_from_slice(pcm)
+                }
+                Ok(())
+            })?;
+            Ok((planar, sample_rate))
+        }
+    }
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s16(sample_rate: usize, channels: usize) -> Format {
+        Format::RawPcm(RawPcm { sample_rate, channels, sample_format: RawSampleFormat::S16Le })
+    }
+
+    #[test]
+    fn raw_s16_round_trip() -> Result<()> {
+        let samples: Vec<i16> = vec![i16::MIN, -16384, -1, 0, 1, 12345, i16::MAX];
+        let data: Vec<u8> = samples.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let options = ConvertOptions::new();
+        let mut raw = vec![];
+        convert(data.as_slice(), Some(s16(16000, 1)), &mut raw, s16(16000, 1), &options)?;
+        assert_eq!(raw, data);
+        let mut wav = vec![];
+        convert(data.as_slice(), Some(s16(16000, 1)), &mut wav, Format::Wav, &options)?;
+        let mut raw = vec![];
+        convert(wav.as_slice(), None, &mut raw, s16(16000, 1), &options)?;
+        assert_eq!(raw, data);
+        Ok(())
+    }
+
+    #[test]
+    fn raw_s16_clamps() -> Result<()> {
+        let pcm = [-1.5f32, -1., 0.5, 1., 1.5];
+        let data: Vec<u8> = pcm.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let f32_le = Format::RawPcm(RawPcm {
+            sample_rate: 16000,
+            channels: 1,
+            sample_format: RawSampleFormat::F32Le,
+        });
+        let mut raw = vec![];
+        convert(data.as_slice(), Some(f32_le), &mut raw, s16(16000, 1), &ConvertOptions::new())?;
+        let samples: Vec<i16> =
+            raw.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(samples, [i16::MIN, i16::MIN, 16384, i16::MAX, i16::MAX]);
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// CRC-32 as used for the ogg page checksums: polynomial 0x04c11db7, no reflection, a zero
+// initial value and no final xor. The checksum field has to be zeroed before computing the crc
+// of a page. This runs for every page read or written so it uses precomputed tables.
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+/// Computes the ogg CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    update(0, data)
+}
+
+/// Extends `crc`, the CRC-32 of some previous data, with `data`. This allows computing the
+/// checksum of a page that is not contiguous in memory.
+pub fn update(crc: u32, data: &[u8]) -> u32 {
+    // Slicing-by-8: eight bytes are processed per iteration using one table per byte position.
+    let mut crc = crc;
+    let mut chunks = data.chunks_exact(8);
+    for c in chunks.by_ref() {
+        let hi = crc ^ u32::from_be_bytes([c[0], c[1], c[2], c[3]]);
+        crc = TABLES[7][(hi >> 24) as usize]
+            ^ TABLES[6][(hi >> 16) as usize & 0xff]
+            ^ TABLES[5][(hi >> 8) as usize & 0xff]
+            ^ TABLES[4][hi as usize & 0xff]
+            ^ TABLES[3][c[4] as usize]
+            ^ TABLES[2][c[5] as usize]
+            ^ TABLES[1][c[6] as usize]
+            ^ TABLES[0][c[7] as usize];
+    }
+    for &b in chunks.remainder() {
+        crc = (crc << 8) ^ TABLES[0][((crc >> 24) ^ b as u32) as usize];
+    }
+    crc
+}
+
+const POLYNOMIAL: u32 = 0x04c1_1db7;
+
+// TABLES[0] is the usual byte-wise table, TABLES[k][b] is the crc of byte b followed by k zeros.
+static TABLES: [[u32; 256]; 8] = tables();
+
+const fn tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ POLYNOMIAL } else { crc << 1 };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev << 8) ^ tables[0][(prev >> 24) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Bit at a time reference implementation.
+    fn crc32_bitwise(data: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for &b in data {
+            crc ^= (b as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ POLYNOMIAL } else { crc << 1 };
+            }
+        }
+        crc
+    }
+
+    #[test]
+    fn check_value() {
+        // CRC-32/POSIX without its final xor.
+        assert_eq!(crc32(b"123456789"), 0x765e_7680 ^ 0xffff_ffff);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn unaligned_lengths() {
+        let data: Vec<u8> =
+            (0..200u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        for start in 0..9 {
+            for end in start..data.len() {
+                let data = &data[start..end];
+                let expected = crc32_bitwise(data);
+                assert_eq!(crc32(data), expected, "{start} {end}");
+                let mid = data.len() / 3;
+                assert_eq!(update(crc32(&data[..mid]), &data[mid..]), expected);
+            }
+        }
+    }
+
+    // A page written by the ogg crate, the checksum is computed with the field zeroed.
+    #[cfg(feature = "std")]
+    #[test]
+    fn ogg_crate_pages() {
+        for len in [0, 1, 7, 8, 9, 254, 255, 256, 1000, 4097] {
+            let mut pw = ogg::PacketWriter::new(Vec::new());
+            let packet = (0..len).map(|i| (i * 7 + len) as u8).collect::<Vec<u8>>();
+            pw.write_packet(packet, 3, ogg::PacketWriteEndInfo::EndStream, 960).unwrap();
+            let mut page = pw.into_inner();
+            let expected = u32::from_le_bytes([page[22], page[23], page[24], page[25]]);
+            page[22..26].fill(0);
+            assert_eq!(crc32(&page), expected, "{len}");
+            assert_eq!(update(crc32(&page[..13]), &page[13..]), expected, "{len}");
+        }
+    }
+}
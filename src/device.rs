@@ -0,0 +1,190 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Interfaces to audio devices. kaudio does not drive audio devices itself, backends such as
+// cpal, JACK, PipeWire or ASIO implement these traits in their own crates and the helpers built
+// on top of them work with any backend. The backends report errors with their own types, they
+// are wrapped in kaudio errors by the helpers.
+
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_rate: usize,
+    pub channels: usize,
+}
+
+/// An input device producing interleaved pcm.
+pub trait CaptureSource: Send {
+    type Error: std::fmt::Display + Send + Sync + 'static;
+
+    fn format(&self) -> StreamFormat;
+
+    /// Fills `pcm` with captured samples, blocking until some are available. Returns the
+    /// number of samples written, always a whole number of frames.
+    fn read(&mut self, pcm: &mut [f32]) -> std::result::Result<usize, Self::Error>;
+}
+
+/// An output device consuming interleaved pcm.
+pub trait PlaybackSink: Send {
+    type Error: std::fmt::Display + Send + Sync + 'static;
+
+    fn format(&self) -> StreamFormat;
+
+    /// Queues `pcm` for playback, blocking until there is room for some of it. Returns the
+    /// number of samples accepted, always a whole number of frames.
+    fn write(&mut self, pcm: &[f32]) -> std::result::Result<usize, Self::Error>;
+
+    /// Number of samples per channel queued and not played yet, this is the latency added by
+    /// the sink to the samples written now.
+    fn queued(&self) -> usize;
+}
+
+// Extra time given to the devices on top of the pcm duration before `play_and_record` fails.
+const DEADLINE_MARGIN: std::time::Duration = std::time::Duration::from_secs(2);
+// Longest sleep when the devices make no progress.
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Plays mono `pcm` on all the channels of `sink` and returns the first channel captured by
+/// `source` meanwhile, as many samples as were played. Both devices have to run at the same
+/// rate and should have been started together, e.g. for `loopback::measure_loopback`.
+/// Devices that return without reading or writing anything are polled with an increasing
+/// back-off, an error is returned if the pcm is not played and recorded within its duration
+/// plus two seconds.
+pub fn play_and_record<S: CaptureSource, P: PlaybackSink>(
+    source: &mut S,
+    sink: &mut P,
+    pcm: &[f32],
+) -> Result<Vec<f32>> {
+    let (input, output) = (source.format(), sink.format());
+    if input.sample_rate != output.sample_rate {
+        crate::bail!("capture at {}Hz, playback at {}Hz", input.sample_rate, output.sample_rate)
+    }
+    if input.channels == 0 || output.channels == 0 {
+        crate::bail!("devices without any channel")
+    }
+    let played: Vec<f32> =
+        pcm.iter().flat_map(|&v| std::iter::repeat_n(v, output.channels)).collect();
+    let mut written = 0;
+    let mut captured = Vec::with_capacity(pcm.len());
+    let mut buf = vec![0f32; input.channels * 1024];
+    let duration = std::time::Duration::from_secs_f64(pcm.len() as f64 / input.sample_rate as f64);
+    let deadline = std::time::Instant::now() + duration + DEADLINE_MARGIN;
+    let mut backoff = std::time::Duration::ZERO;
+    while written < played.len() || captured.len() < pcm.len() {
+        let mut progress = 0;
+        if written < played.len() {
+            let n = sink.write(&played[written..]).map_err(crate::Error::wrap)?;
+            written += n;
+            progress += n;
+        }
+        let n = source.read(&mut buf).map_err(crate::Error::wrap)?;
+        captured.extend(buf[..n].iter().step_by(input.channels));
+        progress += n;
+        if progress > 0 {
+            backoff = std::time::Duration::ZERO;
+            continue;
+        }
+        if std::time::Instant::now() >= deadline {
+            crate::bail!(
+                "devices stalled, played {} and captured {} of {} samples",
+                written / output.channels,
+                captured.len(),
+                pcm.len()
+            )
+        }
+        backoff = (backoff * 2).clamp(std::time::Duration::from_micros(100), MAX_BACKOFF);
+        std::thread::sleep(backoff);
+    }
+    captured.truncate(pcm.len());
+    Ok(captured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Queue = std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<f32>>>;
+
+    const FORMAT: StreamFormat = StreamFormat { sample_rate: 8_000, channels: 2 };
+
+    // Captures what the sink played, nothing when `stalled` is set.
+    struct Source {
+        queue: Queue,
+        stalled: bool,
+        reads: usize,
+    }
+
+    impl CaptureSource for Source {
+        type Error = String;
+
+        fn format(&self) -> StreamFormat {
+            FORMAT
+        }
+
+        fn read(&mut self, pcm: &mut [f32]) -> std::result::Result<usize, String> {
+            self.reads += 1;
+            if self.stalled {
+                return Ok(0);
+            }
+            let mut queue = self.queue.lock().unwrap();
+            let len = usize::min(pcm.len(), queue.len());
+            for v in pcm[..len].iter_mut() {
+                *v = queue.pop_front().unwrap_or(0.)
+            }
+            Ok(len)
+        }
+    }
+
+    struct Sink(Queue);
+
+    impl PlaybackSink for Sink {
+        type Error = String;
+
+        fn format(&self) -> StreamFormat {
+            FORMAT
+        }
+
+        fn write(&mut self, pcm: &[f32]) -> std::result::Result<usize, String> {
+            let len = usize::min(pcm.len(), 256);
+            self.0.lock().unwrap().extend(&pcm[..len]);
+            Ok(len)
+        }
+
+        fn queued(&self) -> usize {
+            0
+        }
+    }
+
+    fn run(stalled: bool) -> (Result<Vec<f32>>, usize) {
+        let queue = Queue::default();
+        let mut source = Source { queue: queue.clone(), stalled, reads: 0 };
+        let mut sink = Sink(queue);
+        let pcm: Vec<f32> = (0..800).map(|i| i as f32 / 800.).collect();
+        let res = play_and_record(&mut source, &mut sink, &pcm);
+        if let Ok(captured) = res.as_ref() {
+            assert_eq!(captured, &pcm);
+        }
+        (res, source.reads)
+    }
+
+    #[test]
+    fn loop_back() {
+        let (res, _) = run(false);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn stalled_source() {
+        // The call returns an error after the deadline rather than spinning forever.
+        let start = std::time::Instant::now();
+        let (res, reads) = run(true);
+        let elapsed = start.elapsed();
+        assert!(res.is_err());
+        assert!(elapsed >= DEADLINE_MARGIN, "{elapsed:?}");
+        assert!(elapsed < DEADLINE_MARGIN * 2, "{elapsed:?}");
+        // The devices are polled about once per MAX_BACKOFF once the sink is full.
+        assert!(reads < 1_000, "{reads}");
+    }
+}
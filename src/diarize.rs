@@ -0,0 +1,204 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Energy based diarization for the duplex case, where the user and the agent each have their own
+// channel. Each channel is considered active when its frame energy is above a threshold and not
+// explained by the other channel bleeding into it, a hangover smooths short pauses.
+
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeakerLabel {
+    User,
+    Agent,
+    /// Both speakers are active at the same time.
+    Overlap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SpeakerSegment {
+    pub label: SpeakerLabel,
+    /// Position of the segment in samples since the start of the stream.
+    pub start_sample: u64,
+    pub end_sample: u64,
+}
+
+impl SpeakerSegment {
+    pub fn start(&self, sample_rate: usize) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.start_sample as f64 / sample_rate as f64)
+    }
+
+    pub fn end(&self, sample_rate: usize) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.end_sample as f64 / sample_rate as f64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiarizerConfig {
+    pub sample_rate: usize,
+    pub frame_ms: usize,
+    /// Frames below this level are considered silent, in dBFS.
+    pub threshold_db: f32,
+    /// A channel at least this many dB below the other one is considered to only contain
+    /// crosstalk from the other speaker.
+    pub crosstalk_margin_db: f32,
+    /// Duration for which a channel stays active after its last active frame.
+    pub hangover_ms: usize,
+}
+
+impl DiarizerConfig {
+    pub fn new(sample_rate: usize) -> Self {
+        Self {
+            sample_rate,
+            frame_ms: 20,
+            threshold_db: -45.,
+            crosstalk_margin_db: 15.,
+            hangover_ms: 200,
+        }
+    }
+}
+
+pub struct Diarizer {
+    cfg: DiarizerConfig,
+    frame_size: usize,
+    hangover_frames: usize,
+    user: VecDeque<f32>,
+    agent: VecDeque<f32>,
+    // Number of frames left before each channel becomes inactive.
+    user_hangover: usize,
+    agent_hangover: usize,
+    current: Option<(SpeakerLabel, u64)>,
+    position: u64,
+}
+
+fn frame_db(frame: impl Iterator<Item = f32>, frame_size: usize) -> f32 {
+    let energy = frame.map(|v| v * v).sum::<f32>() / frame_size as f32;
+    10. * (energy + 1e-10).log10()
+}
+
+impl Diarizer {
+    pub fn new(cfg: DiarizerConfig) -> Self {
+        let frame_size = usize::max(cfg.sample_rate * cfg.frame_ms / 1000, 1);
+        let hangover_frames = cfg.hangover_ms / usize::max(cfg.frame_ms, 1);
+        Self {
+            cfg,
+            frame_size,
+            hangover_frames,
+            user: VecDeque::new(),
+            agent: VecDeque::new(),
+            user_hangover: 0,
+            agent_hangover: 0,
+            current: None,
+            position: 0,
+        }
+    }
+
+    /// Processes the next samples of both channels, which can have different lengths, and
+    /// returns the segments that have ended.
+    pub fn push(&mut self, user: &[f32], agent: &[f32]) -> Vec<SpeakerSegment> {
+        self.user.extend(user.iter());
+        self.agent.extend(agent.iter());
+        let mut segments = vec![];
+        while self.user.len() >= self.frame_size && self.agent.len() >= self.frame_size {
+            let user_db = frame_db(self.user.drain(..self.frame_size), self.frame_size);
+            let agent_db = frame_db(self.agent.drain(..self.frame_size), self.frame_size);
+            let margin = self.cfg.crosstalk_margin_db;
+            let user_active = user_db > self.cfg.threshold_db && user_db > agent_db - margin;
+            let agent_active = agent_db > self.cfg.threshold_db && agent_db > user_db - margin;
+            if user_active {
+                self.user_hangover = self.hangover_frames + 1
+            }
+            if agent_active {
+                self.agent_hangover = self.hangover_frames + 1
+            }
+            let label = match (self.user_hangover > 0, self.agent_hangover > 0) {
+                (true, true) => Some(SpeakerLabel::Overlap),
+                (true, false) => Some(SpeakerLabel::User),
+                (false, true) => Some(SpeakerLabel::Agent),
+                (false, false) => None,
+            };
+            self.user_hangover = self.user_hangover.saturating_sub(1);
+            self.agent_hangover = self.agent_hangover.saturating_sub(1);
+            if self.current.map(|(l, _)| l) != label {
+                segments.extend(self.close_segment());
+                self.current = label.map(|l| (l, self.position));
+            }
+            self.position += self.frame_size as u64;
+        }
+        segments
+    }
+
+    fn close_segment(&mut self) -> Option<SpeakerSegment> {
+        let (label, start_sample) = self.current.take()?;
+        Some(SpeakerSegment { label, start_sample, end_sample: self.position })
+    }
+
+    /// Returns the segment in progress, if any, the remaining partial frames are dropped.
+    pub fn finish(&mut self) -> Option<SpeakerSegment> {
+        self.user.clear();
+        self.agent.clear();
+        self.close_segment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(len: usize, amplitude: f32) -> Vec<f32> {
+        (0..len).map(|i| amplitude * (i as f32 * 0.1).sin()).collect()
+    }
+
+    #[test]
+    fn segments() {
+        let s = |seconds: f32| (seconds * 16_000.) as usize;
+        // The user speaks with some of their voice leaking into the agent channel, then the agent
+        // speaks alone and both speak at the same time.
+        let mut user = tone(s(1.), 0.3);
+        let mut agent: Vec<f32> = user.iter().map(|v| v * 0.01).collect();
+        user.extend(vec![0.; s(1.5)]);
+        agent.extend(vec![0.; s(0.5)]);
+        agent.extend(tone(s(1.), 0.3));
+        user.extend(tone(s(0.5), 0.3));
+        agent.extend(tone(s(0.5), 0.3));
+        user.extend(vec![0.; s(0.5)]);
+        agent.extend(vec![0.; s(0.5)]);
+        let mut diarizer = Diarizer::new(DiarizerConfig::new(16_000));
+        let mut segments = vec![];
+        // The channels are pushed in chunks of different sizes.
+        let mut user_chunks = user.chunks(1_000);
+        let mut agent_chunks = agent.chunks(700);
+        loop {
+            match (user_chunks.next(), agent_chunks.next()) {
+                (None, None) => break,
+                (u, a) => segments.extend(diarizer.push(u.unwrap_or(&[]), a.unwrap_or(&[]))),
+            }
+        }
+        assert_eq!(diarizer.finish(), None);
+        let segment = |label, start, end| SpeakerSegment {
+            label,
+            start_sample: s(start) as u64,
+            end_sample: s(end) as u64,
+        };
+        // Segments are extended by the 200ms hangover.
+        let expected = [
+            segment(SpeakerLabel::User, 0., 1.2),
+            segment(SpeakerLabel::Agent, 1.5, 2.5),
+            segment(SpeakerLabel::Overlap, 2.5, 3.2),
+        ];
+        assert_eq!(segments, expected);
+        assert_eq!(segments[1].end(16_000), std::time::Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn finish_in_progress() {
+        let mut diarizer = Diarizer::new(DiarizerConfig::new(16_000));
+        assert!(diarizer.push(&tone(16_000, 0.3), &vec![0.; 16_100]).is_empty());
+        let segment = diarizer.finish().unwrap();
+        assert_eq!(segment.label, SpeakerLabel::User);
+        assert_eq!((segment.start_sample, segment.end_sample), (0, 16_000));
+        assert_eq!(diarizer.finish(), None);
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Authenticated encryption framing around raw opus packets, for end-to-end encrypted audio
+// channels that do not run over SRTP.
+// Each frame is the 8 bytes big-endian sequence number followed by the sealed packet. The nonce
+// is the sequence number xored with a per-stream salt, and the sequence number is used as
+// associated data so that frames cannot be reordered or replayed.
+
+use crate::Result;
+
+const SEQ_LEN: usize = 8;
+pub const NONCE_LEN: usize = 12;
+
+/// An AEAD cipher with a 96 bits nonce. The `aes-gcm` and `chacha20poly1305` features implement
+/// this for the RustCrypto `aes_gcm::Aes128Gcm`, `aes_gcm::Aes256Gcm` and
+/// `chacha20poly1305::ChaCha20Poly1305`.
+pub trait Aead {
+    fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>>;
+    fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+macro_rules! impl_aead {
+    ($krate:ident, $($cipher:ident),+) => {$(
+        impl Aead for $krate::$cipher {
+            fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+                use $krate::aead::{generic_array::GenericArray, Aead, Payload};
+                self.encrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+                    .map_err(|_| crate::Error::msg("encryption failed"))
+            }
+
+            fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+                use $krate::aead::{generic_array::GenericArray, Aead, Payload};
+                self.decrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+                    .map_err(|_| crate::Error::msg("invalid encrypted frame"))
+            }
+        }
+    )+};
+}
+
+#[cfg(feature = "aes-gcm")]
+impl_aead!(aes_gcm, Aes128Gcm, Aes256Gcm);
+#[cfg(feature = "chacha20poly1305")]
+impl_aead!(chacha20poly1305, ChaCha20Poly1305);
+
+fn nonce(salt: &[u8; NONCE_LEN], seq: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = *salt;
+    for (n, s) in nonce[NONCE_LEN - SEQ_LEN..].iter_mut().zip(seq.to_be_bytes()) {
+        *n ^= s
+    }
+    nonce
+}
+
+/// Encrypts the packets of one stream. The `salt` has to be unique for each stream using the
+/// key, e.g. a different salt for each direction of a channel and for each session, otherwise
+/// the nonces of the streams collide and the cipher is broken. It is typically derived along
+/// with the key, the receiver needs the same salt.
+pub struct Sealer<A: Aead> {
+    aead: A,
+    salt: [u8; NONCE_LEN],
+    seq: u64,
+}
+
+impl<A: Aead> Sealer<A> {
+    pub fn new(aead: A, salt: [u8; NONCE_LEN]) -> Self {
+        Self { aead, salt, seq: 0 }
+    }
+
+    /// Sequence number that will be used for the next packet.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Fails once the sequence numbers are exhausted, the key has to be rotated well before.
+    pub fn seal(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        let Some(next_seq) = self.seq.checked_add(1) else {
+            crate::bail!("no sequence number left for this key")
+        };
+        let header = self.seq.to_be_bytes();
+        let sealed = self.aead.seal(&nonce(&self.salt, self.seq), &header, packet)?;
+        self.seq = next_seq;
+        let mut frame = Vec::with_capacity(SEQ_LEN + sealed.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&sealed);
+        Ok(frame)
+    }
+}
+
+/// Decrypts the frames of a stream sealed with the same key and salt.
+pub struct Opener<A: Aead> {
+    aead: A,
+    salt: [u8; NONCE_LEN],
+    last_seq: Option<u64>,
+}
+
+impl<A: Aead> Opener<A> {
+    pub fn new(aead: A, salt: [u8; NONCE_LEN]) -> Self {
+        Self { aead, salt, last_seq: None }
+    }
+
+    /// Authenticates and decrypts a frame, returning its sequence number and the opus packet.
+    /// Frames with a sequence number that is not larger than the last opened one are rejected.
+    pub fn open(&mut self, frame: &[u8]) -> Result<(u64, Vec<u8>)> {
+        if frame.len() < SEQ_LEN {
+            crate::bail!("encrypted frame is too short {}", frame.len())
+        }
+        let (header, sealed) = frame.split_at(SEQ_LEN);
+        let mut seq = [0u8; SEQ_LEN];
+        seq.copy_from_slice(header);
+        let seq = u64::from_be_bytes(seq);
+        if self.last_seq.is_some_and(|last_seq| seq <= last_seq) {
+            crate::bail!("replayed or reordered encrypted frame {seq}")
+        }
+        let packet = self.aead.open(&nonce(&self.salt, seq), header, sealed)?;
+        self.last_seq = Some(seq);
+        Ok((seq, packet))
+    }
+
+    /// Opens a frame and decodes its packet with `decoder`, appending the pcm to `pcm`. The
+    /// sequence numbers of the frames are used to detect and conceal the lost packets as
+    /// `opus::Decoder::decode_framed` does.
+    pub fn open_and_decode(
+        &mut self,
+        frame: &[u8],
+        decoder: &mut crate::opus::Decoder,
+        pcm: &mut Vec<f32>,
+    ) -> Result<crate::opus::SequenceStatus> {
+        let (seq, packet) = self.open(frame)?;
+        decoder.decode_sequenced(seq, &packet, pcm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: [u8; NONCE_LEN] = [3; NONCE_LEN];
+
+    #[test]
+    fn nonces() {
+        assert_eq!(nonce(&[0; NONCE_LEN], 0x0102), [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(nonce(&SALT, 0)[..], SALT[..]);
+        assert_ne!(nonce(&SALT, 1), nonce(&[2; NONCE_LEN], 1));
+    }
+
+    // A cipher that only checks the framing, the tag is the sum of the nonce and aad bytes.
+    struct Xor;
+
+    impl Aead for Xor {
+        fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+            let tag = nonce.iter().chain(aad).fold(0u8, |t, b| t.wrapping_add(*b));
+            Ok(plaintext.iter().map(|b| b ^ 0x55).chain([tag]).collect())
+        }
+
+        fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+            match ciphertext.split_last() {
+                Some((tag, data)) if [*tag] == self.seal(nonce, aad, &[])?[..] => {
+                    Ok(data.iter().map(|b| b ^ 0x55).collect())
+                }
+                _ => crate::bail!("invalid encrypted frame"),
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_exhausted() {
+        let mut sealer = Sealer { aead: Xor, salt: SALT, seq: u64::MAX - 1 };
+        let frame = sealer.seal(b"last").unwrap();
+        assert!(sealer.seal(b"too many").is_err());
+        assert_eq!(sealer.seq(), u64::MAX);
+        let mut opener = Opener::new(Xor, SALT);
+        assert_eq!(opener.open(&frame).unwrap(), (u64::MAX - 1, b"last".to_vec()));
+    }
+
+    #[test]
+    fn open_and_decode() -> Result<()> {
+        let mut encoder = crate::opus::Encoder::new(&crate::opus::EncoderConfig::new(24_000))?;
+        let frame_size = encoder.frame_size();
+        let pcm: Vec<f32> = (0..4 * frame_size).map(|i| 0.3 * (i as f32 * 0.05).sin()).collect();
+        let packets = encoder.encode(&pcm)?;
+        assert_eq!(packets.len(), 4);
+        let mut sealer = Sealer::new(Xor, SALT);
+        let frames = packets.iter().map(|p| sealer.seal(&p.data)).collect::<Result<Vec<_>>>()?;
+        let mut decoder = crate::opus::Decoder::new(24_000, false)?;
+        decoder.set_concealment(crate::opus::ConcealmentPolicy::Silence);
+        let mut opener = Opener::new(Xor, SALT);
+        let mut out = vec![];
+        opener.open_and_decode(&frames[0], &mut decoder, &mut out)?;
+        // The second frame is lost and concealed.
+        let status = opener.open_and_decode(&frames[2], &mut decoder, &mut out)?;
+        assert_eq!(status, crate::opus::SequenceStatus::InOrder { lost: 1 });
+        opener.open_and_decode(&frames[3], &mut decoder, &mut out)?;
+        assert_eq!(out.len(), 4 * frame_size);
+        assert!(opener.open_and_decode(&frames[1], &mut decoder, &mut out).is_err());
+        // Frames sealed with another salt do not open.
+        let mut opener = Opener::new(Xor, [4; NONCE_LEN]);
+        assert!(opener.open(&frames[0]).is_err());
+        Ok(())
+    }
+
+    #[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+    fn round_trip<A: Aead>(new: impl Fn() -> A) {
+        let mut sealer = Sealer::new(new(), SALT);
+        let mut opener = Opener::new(new(), SALT);
+        for packet in [&b""[..], b"\x78", &[0x42; 300]] {
+            let frame = sealer.seal(packet).unwrap();
+            assert_eq!(frame.len(), SEQ_LEN + packet.len() + 16);
+            if !packet.is_empty() {
+                assert_ne!(&frame[SEQ_LEN..SEQ_LEN + packet.len()], packet);
+            }
+            let (seq, opened) = opener.open(&frame).unwrap();
+            assert_eq!(seq, sealer.seq() - 1);
+            assert_eq!(opened, packet);
+        }
+    }
+
+    #[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+    fn tampered_frames_are_rejected<A: Aead>(new: impl Fn() -> A, other_key: A) {
+        let mut sealer = Sealer::new(new(), SALT);
+        let frame = sealer.seal(b"opus packet").unwrap();
+        for index in [0, SEQ_LEN, frame.len() - 1] {
+            let mut tampered = frame.clone();
+            tampered[index] ^= 1;
+            let mut opener = Opener::new(new(), SALT);
+            assert!(opener.open(&tampered).is_err(), "byte {index}");
+        }
+        assert!(Opener::new(other_key, SALT).open(&frame).is_err());
+        assert!(Opener::new(new(), [4; NONCE_LEN]).open(&frame).is_err());
+        let mut opener = Opener::new(new(), SALT);
+        assert!(opener.open(&frame[..frame.len() - 1]).is_err());
+        // Replays are rejected once the frame has been opened.
+        assert!(opener.open(&frame).is_ok());
+        assert!(opener.open(&frame).is_err());
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    #[test]
+    fn chacha20poly1305() {
+        use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+        let new = || ChaCha20Poly1305::new(&[7; 32].into());
+        round_trip(new);
+        tampered_frames_are_rejected(new, ChaCha20Poly1305::new(&[8; 32].into()));
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn aes_gcm() {
+        use aes_gcm::{Aes128Gcm, Aes256Gcm, KeyInit};
+        let new = || Aes128Gcm::new(&[7; 16].into());
+        round_trip(new);
+        tampered_frames_are_rejected(new, Aes128Gcm::new(&[8; 16].into()));
+        let new = || Aes256Gcm::new(&[7; 32].into());
+        round_trip(new);
+        tampered_frames_are_rejected(new, Aes256Gcm::new(&[8; 32].into()));
+    }
+}
@@ -24,9 +24,26 @@ pub enum Error {
     #[error("unsupported ogg version {0}")]
     OggUnsupportedVersion(u8),
 
+    #[error("ogg data buffered beyond the limit of {0} bytes")]
+    OggBufferLimit(usize),
+
+    #[error(
+        "ogg checksum mismatch for page {page_sequence}, expected {expected:08x}, got {actual:08x}"
+    )]
+    OggChecksumMismatch { page_sequence: u32, expected: u32, actual: u32 },
+
+    #[error(
+        "unsupported opus sample rate {0}, expected one of {rates:?}, the input can be resampled with kaudio::resample or by setting EncoderConfig::resample",
+        rates = crate::opus::SAMPLE_RATES
+    )]
+    OpusUnsupportedSampleRate(usize),
+
     #[error("opus pcm was not found")]
     OpusMissingPcm,
 
+    #[error("no data received for {0:?}")]
+    Stalled(std::time::Duration),
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
@@ -48,6 +65,10 @@ pub enum Error {
     #[error("path: {path:?} {inner}")]
     WithPath { inner: Box<Self>, path: std::path::PathBuf },
 
+    /// Adding the position in the stream where the error happened.
+    #[error("{inner} at {position}")]
+    WithPosition { inner: Box<Self>, position: crate::ogg_pager::StreamPosition },
+
     #[error("{inner}\n{backtrace}")]
     WithBacktrace { inner: Box<Self>, backtrace: Box<std::backtrace::Backtrace> },
 }
@@ -73,6 +94,19 @@ macro_rules! bail {
     };
 }
 
+impl From<crate::parse::ParseError> for Error {
+    fn from(err: crate::parse::ParseError) -> Self {
+        use crate::parse::ParseError as P;
+        match err {
+            P::UnexpectedCapturePattern(pattern) => Self::OggUnexpectedCapturePattern(pattern),
+            P::UnsupportedVersion(version) => Self::OggUnsupportedVersion(version),
+            P::UnexpectedSignature(signature) => Self::OggUnexpectedSignature(signature),
+            P::UnexpectedLenForOpusHead(len) => Self::OggUnexpectedLenForOpusHead(len),
+            err => Self::msg(err),
+        }
+    }
+}
+
 impl Error {
     pub fn wrap(err: impl std::fmt::Display + Send + Sync + 'static) -> Self {
         Self::Wrapped(Box::new(err)).bt()
@@ -99,6 +133,30 @@ impl Error {
         Self::WithPath { inner: Box::new(self), path: p.as_ref().to_path_buf() }
     }
 
+    /// Attaches `position` to the error, unless a position has already been attached.
+    pub fn with_position(self, position: crate::ogg_pager::StreamPosition) -> Self {
+        if self.stream_position().is_some() {
+            return self;
+        }
+        match self {
+            Self::WithBacktrace { inner, backtrace } => {
+                Self::WithBacktrace { inner: Box::new(inner.with_position(position)), backtrace }
+            }
+            _ => Self::WithPosition { inner: Box::new(self), position },
+        }
+    }
+
+    /// The position in the stream where the error happened, when known.
+    pub fn stream_position(&self) -> Option<&crate::ogg_pager::StreamPosition> {
+        match self {
+            Self::WithPosition { position, .. } => Some(position),
+            Self::Context { inner, .. }
+            | Self::WithPath { inner, .. }
+            | Self::WithBacktrace { inner, .. } => inner.stream_position(),
+            _ => None,
+        }
+    }
+
     pub fn context(self, c: impl std::fmt::Display + Send + Sync + 'static) -> Self {
         Self::Context { inner: Box::new(self), context: Box::new(c) }
     }
@@ -0,0 +1,119 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Fan-out of a single decoded pcm stream to several sample rates, e.g. 48kHz for recording,
+// 24kHz for a model and 16kHz for a VAD. Each output is resampled with its delay compensated so
+// all the outputs are aligned with the input, the chunks carry their position on the common
+// timeline as the outputs do not all lag the input by the same amount.
+
+use crate::Result;
+
+#[derive(Debug, Clone)]
+pub struct FanOutChunk {
+    pub sample_rate: usize,
+    /// Position of the first sample of `pcm`, in samples per channel at `sample_rate` since the
+    /// start of the stream.
+    pub start: u64,
+    /// Interleaved when there is more than one channel.
+    pub pcm: Vec<f32>,
+}
+
+impl FanOutChunk {
+    pub fn start_time(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.start as f64 / self.sample_rate as f64)
+    }
+
+    pub fn end_time(&self, channels: usize) -> std::time::Duration {
+        let end = self.start + (self.pcm.len() / channels) as u64;
+        std::time::Duration::from_secs_f64(end as f64 / self.sample_rate as f64)
+    }
+}
+
+struct Output {
+    resampler: crate::resample::StreamResampler,
+    position: u64,
+}
+
+impl Output {
+    fn chunk(&mut self, pcm: Vec<f32>) -> FanOutChunk {
+        let start = self.position;
+        self.position += (pcm.len() / self.resampler.channels()) as u64;
+        FanOutChunk { sample_rate: self.resampler.output_sample_rate(), start, pcm }
+    }
+}
+
+pub struct FanOut {
+    input_sample_rate: usize,
+    channels: usize,
+    outputs: Vec<Output>,
+}
+
+impl FanOut {
+    /// Resamples interleaved pcm with `channels` channels at `input_sample_rate` to each of
+    /// `output_sample_rates`. The outputs at the input rate are passed through unchanged.
+    pub fn new(
+        input_sample_rate: usize,
+        channels: usize,
+        output_sample_rates: &[usize],
+    ) -> Result<Self> {
+        if output_sample_rates.is_empty() {
+            crate::bail!("a fan-out requires at least one output")
+        }
+        let outputs = output_sample_rates
+            .iter()
+            .map(|&sample_rate| {
+                let resampler = crate::resample::StreamResampler::new(
+                    input_sample_rate,
+                    sample_rate,
+                    channels,
+                )?;
+                Ok(Output { resampler, position: 0 })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { input_sample_rate, channels, outputs })
+    }
+
+    pub fn input_sample_rate(&self) -> usize {
+        self.input_sample_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// The rates of the outputs, in the order of the chunks returned by `process`.
+    pub fn output_sample_rates(&self) -> Vec<usize> {
+        self.outputs.iter().map(|o| o.resampler.output_sample_rate()).collect()
+    }
+
+    /// Pushes `pcm`, which must hold whole frames, and returns one chunk per output. A chunk
+    /// may be empty while its resampler waits for more input.
+    pub fn process(&mut self, pcm: &[f32]) -> Result<Vec<FanOutChunk>> {
+        let mut chunks = Vec::with_capacity(self.outputs.len());
+        for output in self.outputs.iter_mut() {
+            let pcm = output.resampler.process(pcm)?;
+            chunks.push(output.chunk(pcm))
+        }
+        Ok(chunks)
+    }
+
+    /// Returns the remaining samples of each output, the outputs then all end at the end of the
+    /// input.
+    pub fn flush(&mut self) -> Result<Vec<FanOutChunk>> {
+        let mut chunks = Vec::with_capacity(self.outputs.len());
+        for output in self.outputs.iter_mut() {
+            let pcm = output.resampler.flush()?;
+            chunks.push(output.chunk(pcm))
+        }
+        Ok(chunks)
+    }
+
+    /// Clears the state of all the outputs so that they can be used for a new stream.
+    pub fn reset(&mut self) {
+        for output in self.outputs.iter_mut() {
+            output.resampler.reset();
+            output.position = 0;
+        }
+    }
+}
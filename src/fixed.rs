@@ -0,0 +1,104 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Integer only pcm processing for targets without a fast fpu, e.g. Cortex-M microcontrollers.
+// Like `parse` and `crc`, this only relies on `core` and `alloc` so that the page parsing, the
+// header parsing and the resampling of the i16 decode path can be built without std. The libopus
+// decoding itself goes through `ogg_opus::DecoderI16` which requires std.
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Streaming linear interpolation resampler working on interleaved `i16` samples with integer
+/// arithmetic only, this is a lot less accurate than the rubato based resamplers.
+#[derive(Debug, Clone)]
+pub struct LinearResamplerI16 {
+    in_rate: u64,
+    out_rate: u64,
+    // Position of the next output frame between `prev` and the next input frame, expressed in
+    // units of 1/out_rate input frames.
+    pos: u64,
+    prev: Vec<i16>,
+}
+
+impl LinearResamplerI16 {
+    /// Returns `None` when one of the sample rates or the number of channels is zero.
+    pub fn new(sr_in: usize, sr_out: usize, channels: usize) -> Option<Self> {
+        if sr_in == 0 || sr_out == 0 || channels == 0 {
+            return None;
+        }
+        let prev = vec![0; channels];
+        Some(Self { in_rate: sr_in as u64, out_rate: sr_out as u64, pos: 0, prev })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.prev.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.pos = 0;
+        self.prev.fill(0);
+    }
+
+    /// Appends the resampled frames to `pcm_out`, a trailing partial frame in `pcm_in` is
+    /// ignored.
+    pub fn process(&mut self, pcm_in: &[i16], pcm_out: &mut Vec<i16>) {
+        let out_rate = self.out_rate as i64;
+        for frame in pcm_in.chunks_exact(self.prev.len()) {
+            while self.pos < self.out_rate {
+                for (&prev, &next) in self.prev.iter().zip(frame.iter()) {
+                    let (prev, next) = (prev as i64, next as i64);
+                    let v = prev + (next - prev) * self.pos as i64 / out_rate;
+                    pcm_out.push(v as i16);
+                }
+                self.pos += self.in_rate;
+            }
+            self.pos -= self.out_rate;
+            self.prev.copy_from_slice(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample() {
+        assert!(LinearResamplerI16::new(0, 16_000, 1).is_none());
+        assert!(LinearResamplerI16::new(48_000, 16_000, 0).is_none());
+        let mut resampler = LinearResamplerI16::new(48_000, 16_000, 1).unwrap();
+        let pcm_in: Vec<i16> = (0..4_800).map(|i| (i % 300) as i16).collect();
+        let mut pcm_out = vec![];
+        for chunk in pcm_in.chunks(333) {
+            resampler.process(chunk, &mut pcm_out);
+        }
+        assert_eq!(pcm_out.len(), 1_600);
+        assert_eq!(pcm_out[1..5], [2, 5, 8, 11]);
+
+        // Upsampling interpolates between the input samples.
+        let mut resampler = LinearResamplerI16::new(8_000, 16_000, 1).unwrap();
+        let mut pcm_out = vec![];
+        resampler.process(&[100, 200, 300], &mut pcm_out);
+        assert_eq!(pcm_out, [0, 50, 100, 150, 200, 250]);
+    }
+
+    #[test]
+    fn resample_interleaved() {
+        let mut resampler = LinearResamplerI16::new(16_000, 8_000, 2).unwrap();
+        assert_eq!(resampler.channels(), 2);
+        let pcm_in: Vec<i16> = (0..100).flat_map(|i| [i, -i]).collect();
+        let mut pcm_out = vec![];
+        resampler.process(&pcm_in, &mut pcm_out);
+        assert_eq!(pcm_out.len(), 100);
+        for frame in pcm_out.chunks_exact(2) {
+            assert_eq!(frame[0], -frame[1]);
+        }
+        resampler.reset();
+        let mut again = vec![];
+        resampler.process(&pcm_in, &mut again);
+        assert_eq!(again, pcm_out);
+    }
+}
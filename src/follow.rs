@@ -0,0 +1,58 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// "tail -f" for ogg/opus files: follow a file that is still being written by another process
+// and decode the pages as they get appended.
+
+use crate::Result;
+use std::io::Read;
+
+pub struct Follower {
+    file: std::fs::File,
+    path: std::path::PathBuf,
+    decoder: crate::ogg_opus::Decoder,
+    chunk: Vec<u8>,
+    // Number of bytes read from the file so far.
+    pos: u64,
+}
+
+impl Follower {
+    pub fn open<P: AsRef<std::path::Path>>(
+        path: P,
+        decoder: crate::ogg_opus::Decoder,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).map_err(|e| crate::Error::from(e).with_path(path))?;
+        Ok(Self { file, path: path.to_path_buf(), decoder, chunk: vec![], pos: 0 })
+    }
+
+    /// Number of bytes consumed from the file so far.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Reads the bytes that have been appended to the file since the last call and feeds them
+    /// to the decoder. Incomplete pages are kept around until the writer completes them.
+    pub fn poll(&mut self) -> Result<Option<&[f32]>> {
+        self.chunk.clear();
+        let read = self.file.read_to_end(&mut self.chunk)?;
+        self.pos += read as u64;
+        self.decoder.decode(&self.chunk)
+    }
+
+    /// Waits until the file grows past the current read position, checking its length every
+    /// `poll_interval`.
+    pub async fn wait_for_data(&mut self, poll_interval: std::time::Duration) -> Result<()> {
+        loop {
+            let len = tokio::fs::metadata(&self.path).await?.len();
+            if len < self.pos {
+                crate::bail!("{:?} was truncated ({len} < {})", self.path, self.pos)
+            }
+            if len > self.pos {
+                return Ok(());
+            }
+            tokio::time::sleep(poll_interval).await
+        }
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Text framing of the encoder output for web clients that cannot use binary websocket frames:
+// each chunk of ogg pages is sent as a JSON object holding the base64 payload and timestamps,
+// either as newline-delimited JSON or as server-sent events.
+
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    NdJson,
+    Sse,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AudioEvent {
+    pub seq: u64,
+    /// Start and end of the encoded audio in seconds.
+    pub start: f64,
+    pub end: f64,
+    /// Base64 encoded ogg data. Concatenating the decoded payloads of all the events, including
+    /// the last one returned by `EventEncoder::finish`, results in a valid ogg/opus stream.
+    pub data: String,
+}
+
+pub struct EventEncoder {
+    encoder: crate::ogg_opus::Encoder,
+    events: Events,
+}
+
+// The state of the event stream, kept apart from the encoder which is consumed by `finish`.
+struct Events {
+    framing: Framing,
+    seq: u64,
+    pending: Vec<u8>,
+    start_granule_position: u64,
+}
+
+impl Events {
+    fn frame(&mut self, data: &[u8], end_granule_position: u64) -> Result<String> {
+        self.pending.extend_from_slice(data);
+        let event = AudioEvent {
+            seq: self.seq,
+            start: self.start_granule_position as f64 / 48_000.,
+            end: end_granule_position as f64 / 48_000.,
+            data: crate::base64::encode(&self.pending),
+        };
+        self.pending.clear();
+        self.seq += 1;
+        self.start_granule_position = end_granule_position;
+        let json = serde_json::to_string(&event).map_err(crate::Error::wrap)?;
+        let framed = match self.framing {
+            Framing::NdJson => format!("{json}\n"),
+            Framing::Sse => format!("event: audio\nid: {}\ndata: {json}\n\n", event.seq),
+        };
+        Ok(framed)
+    }
+}
+
+impl EventEncoder {
+    pub fn new(encoder: crate::ogg_opus::Encoder, framing: Framing) -> Self {
+        let pending = encoder.header_data().to_vec();
+        let start_granule_position = encoder.granule_position();
+        let events = Events { framing, seq: 0, pending, start_granule_position };
+        Self { encoder, events }
+    }
+
+    /// Encodes `pcm` and returns the framed event, or `None` if no page was completed. The first
+    /// event also carries the ogg headers.
+    pub fn encode_page(&mut self, pcm: &[f32]) -> Result<Option<String>> {
+        let data = self.encoder.encode_page(pcm)?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let end_granule_position = self.encoder.granule_position();
+        self.events.frame(&data, end_granule_position).map(Some)
+    }
+
+    /// Finishes the ogg stream, see [`crate::ogg_opus::Encoder::finish`], and returns the last
+    /// framed event which holds the end of stream page.
+    pub fn finish(mut self) -> Result<String> {
+        let data = self.encoder.finish()?;
+        // The audio ends at the granule position of the last page on which a packet ends.
+        let mut end_granule_position = self.events.start_granule_position;
+        for range in crate::parse::page_ranges(&data)? {
+            let granule_position = crate::parse::PageHeader::read(&data[range]).granule_position;
+            if granule_position != u64::MAX {
+                end_granule_position = granule_position
+            }
+        }
+        self.events.frame(&data, end_granule_position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events() {
+        let pcm: Vec<f32> = (0..30_000).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        let reference = crate::ogg_opus::Encoder::new(48_000).unwrap().encode_file(&pcm).unwrap();
+        for framing in [Framing::NdJson, Framing::Sse] {
+            let encoder = crate::ogg_opus::Encoder::new(48_000).unwrap();
+            let mut encoder = EventEncoder::new(encoder, framing);
+            let mut framed = vec![];
+            for pcm in pcm.chunks(4_800) {
+                framed.extend(encoder.encode_page(pcm).unwrap());
+            }
+            framed.push(encoder.finish().unwrap());
+            let mut data = vec![];
+            let mut end = 0.;
+            for (seq, framed) in framed.iter().enumerate() {
+                let json = match framing {
+                    Framing::NdJson => framed.strip_suffix('\n').unwrap(),
+                    Framing::Sse => {
+                        let prefix = format!("event: audio\nid: {seq}\ndata: ");
+                        framed.strip_prefix(&prefix).unwrap().strip_suffix("\n\n").unwrap()
+                    }
+                };
+                let event: AudioEvent = serde_json::from_str(json).unwrap();
+                assert_eq!(event.seq, seq as u64);
+                assert_eq!(event.start, end);
+                assert!(event.end >= event.start);
+                end = event.end;
+                data.extend(crate::base64::decode(&event.data).unwrap());
+            }
+            // The last event ends with the actual samples rather than with the padded frame.
+            assert_eq!(end, 30_000. / 48_000.);
+            assert_eq!(data, reference);
+        }
+    }
+}
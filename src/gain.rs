@@ -0,0 +1,65 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Gain changes applied with a short linear ramp, stepping the gain between two chunks of pcm
+// produces an audible click.
+
+/// Duration of the ramps used when a gain is changed mid-stream.
+pub const DEFAULT_RAMP: std::time::Duration = std::time::Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct GainRamp {
+    // The gain being ramped to, and the gain currently applied.
+    gain: f32,
+    current: f32,
+    // Gain increment per sample during a ramp.
+    step: f32,
+    remaining: usize,
+    ramp_len: usize,
+}
+
+impl GainRamp {
+    /// A unity gain ramping over `ramp` when changed, for pcm at `sample_rate`.
+    pub fn new(sample_rate: usize, ramp: std::time::Duration) -> Self {
+        let ramp_len = (ramp.as_micros() as usize * sample_rate / 1_000_000).max(1);
+        Self { gain: 1., current: 1., step: 0., remaining: 0, ramp_len }
+    }
+
+    /// The gain reached at the end of the current ramp.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Changes the gain, the following samples ramp linearly from the current gain.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+        self.step = (gain - self.current) / self.ramp_len as f32;
+        self.remaining = self.ramp_len;
+    }
+
+    /// Changes the gain without any ramp, e.g. before the first samples are processed.
+    pub fn reset(&mut self, gain: f32) {
+        self.current = gain;
+        self.gain = gain;
+        self.remaining = 0;
+    }
+
+    /// Whether `apply` leaves the samples unchanged.
+    pub fn is_unity(&self) -> bool {
+        self.remaining == 0 && self.current == 1.
+    }
+
+    /// Applies the gain to interleaved pcm with `channels` channels.
+    pub fn apply(&mut self, pcm: &mut [f32], channels: usize) {
+        let channels = channels.max(1);
+        for frame in pcm.chunks_mut(channels) {
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                self.current =
+                    if self.remaining == 0 { self.gain } else { self.current + self.step };
+            }
+            frame.iter_mut().for_each(|v| *v *= self.current)
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Minimal ID3v2 support: computing the size of a tag so that it can be skipped before decoding
+// mp3 data, and extracting the text frames (title, artist, ...).
+// https://id3.org/id3v2.4.0-structure
+
+use crate::Result;
+use std::collections::HashMap;
+
+pub const HEADER_LEN: usize = 10;
+
+fn syncsafe(b: &[u8]) -> usize {
+    b.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7f) as usize)
+}
+
+fn plain(b: &[u8]) -> usize {
+    b.iter().fold(0, |acc, &b| (acc << 8) | b as usize)
+}
+
+/// Returns the total size of the ID3v2 tag starting `data`, including the header and optional
+/// footer, or `None` if `data` does not start with an ID3v2 tag.
+pub fn tag_size(data: &[u8]) -> Option<usize> {
+    if data.len() < HEADER_LEN || &data[..3] != b"ID3" || data[3] == 0xff || data[4] == 0xff {
+        return None;
+    }
+    if data[6..10].iter().any(|&b| b & 0x80 != 0) {
+        return None;
+    }
+    let footer = if data[5] & 0x10 != 0 { HEADER_LEN } else { 0 };
+    Some(HEADER_LEN + syncsafe(&data[6..10]) + footer)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Id3Tags {
+    pub version: u8,
+    /// Text frames indexed by frame id, e.g. TIT2 for the title.
+    pub frames: HashMap<String, String>,
+}
+
+impl Id3Tags {
+    fn text(&self, ids: &[&str]) -> Option<&str> {
+        ids.iter().find_map(|id| self.frames.get(*id)).map(|s| s.as_str())
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.text(&["TIT2", "TT2"])
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.text(&["TPE1", "TP1"])
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.text(&["TALB", "TAL"])
+    }
+}
+
+fn decode_utf16(data: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| {
+            if big_endian {
+                u16::from_be_bytes([c[0], c[1]])
+            } else {
+                u16::from_le_bytes([c[0], c[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn decode_text(data: &[u8]) -> String {
+    let (encoding, data) = match data.split_first() {
+        None => return String::new(),
+        Some((e, d)) => (*e, d),
+    };
+    let text = match encoding {
+        0 => data.iter().map(|&b| b as char).collect(),
+        1 => match data {
+            [0xff, 0xfe, rest @ ..] => decode_utf16(rest, false),
+            [0xfe, 0xff, rest @ ..] => decode_utf16(rest, true),
+            _ => decode_utf16(data, true),
+        },
+        2 => decode_utf16(data, true),
+        _ => String::from_utf8_lossy(data).to_string(),
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
+/// Parses the text frames of the ID3v2 tag starting `data`, returns `None` if `data` does not
+/// start with an ID3v2 tag. Versions 2.2, 2.3 and 2.4 are supported, unsynchronized and
+/// compressed frames are skipped.
+pub fn parse(data: &[u8]) -> Result<Option<Id3Tags>> {
+    let size = match tag_size(data) {
+        None => return Ok(None),
+        Some(size) => size,
+    };
+    if data.len() < size {
+        crate::bail!("truncated id3 tag, {} bytes available out of {size}", data.len())
+    }
+    let version = data[3];
+    let flags = data[5];
+    let mut pos = HEADER_LEN;
+    let end = HEADER_LEN + syncsafe(&data[6..10]);
+    if flags & 0x40 != 0 && version >= 3 {
+        if pos + 4 > end {
+            crate::bail!("truncated id3 extended header")
+        }
+        // Extended header, its size includes itself in v2.4 but not in v2.3.
+        let ext_size = match version {
+            3 => plain(&data[pos..pos + 4]) + 4,
+            _ => syncsafe(&data[pos..pos + 4]),
+        };
+        pos += ext_size
+    }
+    let (id_len, size_len, frame_header_len) = if version == 2 { (3, 3, 6) } else { (4, 4, 10) };
+    let mut frames = HashMap::new();
+    while pos + frame_header_len <= end {
+        let id = &data[pos..pos + id_len];
+        // Padding
+        if id[0] == 0 {
+            break;
+        }
+        let size_bytes = &data[pos + id_len..pos + id_len + size_len];
+        let frame_size = if version == 4 { syncsafe(size_bytes) } else { plain(size_bytes) };
+        let start = pos + frame_header_len;
+        let frame_end = usize::min(start + frame_size, end);
+        // Compression, encryption and unsynchronisation are not supported.
+        let skip = match version {
+            3 => data[pos + 9] & 0xc0 != 0,
+            4 => data[pos + 9] & 0x0e != 0,
+            _ => false,
+        };
+        if !skip && id[0] == b'T' && id != b"TXXX" && id != b"TXX" {
+            let id = String::from_utf8_lossy(id).to_string();
+            frames.insert(id, decode_text(&data[start..frame_end]));
+        }
+        pos = start + frame_size;
+    }
+    Ok(Some(Id3Tags { version, frames }))
+}
+
+/// Reads the ID3v2 tag at the beginning of a file, if any.
+pub fn read_file<P: AsRef<std::path::Path>>(path: P) -> Result<Option<Id3Tags>> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut header = [0u8; HEADER_LEN];
+    if file.read_exact(&mut header).is_err() {
+        return Ok(None);
+    }
+    let size = match tag_size(&header) {
+        None => return Ok(None),
+        Some(size) => size,
+    };
+    let mut data = header.to_vec();
+    data.resize(size, 0);
+    file.read_exact(&mut data[HEADER_LEN..])?;
+    parse(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syncsafe_bytes(v: usize) -> [u8; 4] {
+        [(v >> 21) as u8 & 0x7f, (v >> 14) as u8 & 0x7f, (v >> 7) as u8 & 0x7f, v as u8 & 0x7f]
+    }
+
+    fn frame(version: u8, id: &[u8], flags: u8, body: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        match version {
+            2 => frame.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]),
+            3 => frame.extend_from_slice(&(body.len() as u32).to_be_bytes()),
+            _ => frame.extend_from_slice(&syncsafe_bytes(body.len())),
+        }
+        if version > 2 {
+            frame.extend_from_slice(&[0, flags]);
+        }
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn tag(version: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+        let mut tag = vec![b'I', b'D', b'3', version, 0, flags];
+        tag.extend_from_slice(&syncsafe_bytes(body.len()));
+        tag.extend_from_slice(body);
+        tag
+    }
+
+    #[test]
+    fn tag_size_and_footer() {
+        let data = tag(4, 0, &[0; 300]);
+        assert_eq!(tag_size(&data), Some(310));
+        assert_eq!(tag_size(&tag(4, 0x10, &[0; 300])), Some(320));
+        assert_eq!(tag_size(&data[..9]), None);
+        assert_eq!(tag_size(b"OggS\0\0\0\0\0\0"), None);
+        let mut invalid = data.clone();
+        invalid[7] = 0x80;
+        assert_eq!(tag_size(&invalid), None);
+    }
+
+    #[test]
+    fn parse_v24() {
+        let mut body = frame(4, b"TIT2", 0, b"\x03Caf\xc3\xa9\0");
+        body.extend(frame(4, b"TPE1", 0, b"\x01\xff\xfeA\0r\0t\0"));
+        body.extend(frame(4, b"TALB", 0, b"\x02\0A\0l\0b"));
+        body.extend(frame(4, b"TXXX", 0, b"\x03key\0value"));
+        // Compressed frames are skipped.
+        body.extend(frame(4, b"TCON", 0x08, b"\x03Jazz"));
+        body.extend(frame(4, b"APIC", 0, &[0; 200]));
+        body.extend([0; 64]);
+        let tags = parse(&tag(4, 0, &body)).unwrap().unwrap();
+        assert_eq!(tags.version, 4);
+        assert_eq!(tags.title(), Some("Café"));
+        assert_eq!(tags.artist(), Some("Art"));
+        assert_eq!(tags.album(), Some("Alb"));
+        assert_eq!(tags.frames.len(), 3);
+    }
+
+    #[test]
+    fn parse_v23_and_v22() {
+        // A v2.3 extended header, its size does not include the size field.
+        let mut body = vec![0, 0, 0, 6, 0, 0, 0, 0, 0, 0];
+        body.extend(frame(3, b"TIT2", 0, b"\x00Title"));
+        body.extend(frame(3, b"TPE1", 0x80, b"\x00Artist"));
+        let tags = parse(&tag(3, 0x40, &body)).unwrap().unwrap();
+        assert_eq!(tags.title(), Some("Title"));
+        assert_eq!(tags.artist(), None);
+        let body = frame(2, b"TT2", 0, b"\x00Old");
+        let tags = parse(&tag(2, 0, &body)).unwrap().unwrap();
+        assert_eq!(tags.title(), Some("Old"));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse(b"not a tag").unwrap().is_none());
+        let data = tag(4, 0, &frame(4, b"TIT2", 0, b"\x03Title"));
+        assert!(parse(&data[..data.len() - 1]).is_err());
+        assert!(parse(&tag(4, 0x40, &[0, 0])).is_err());
+        // A frame size past the end of the tag is clamped to the tag.
+        let mut body = frame(4, b"TIT2", 0, b"\x03Title");
+        body[4..8].copy_from_slice(&syncsafe_bytes(1000));
+        let tags = parse(&tag(4, 0, &body)).unwrap().unwrap();
+        assert_eq!(tags.title(), Some("Title"));
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// End-to-end latency instrumentation. The encoder and decoder mark the times at which stream
+// positions (granule positions, 48kHz) go through the different stages of the pipeline and the
+// tracker reports the encode, transport and decode components of the latency.
+// Times are taken from the monotonic clock of the current process, so the encoder and decoder
+// have to share the same tracker, e.g. in a loopback test or a server relaying the audio.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Number of marks kept per stage.
+const MAX_MARKS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Input = 0,
+    Encoded = 1,
+    Received = 2,
+    Output = 3,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub mean: Duration,
+    pub max: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Acc {
+    count: usize,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Acc {
+    fn add(&mut self, d: Duration) {
+        self.count += 1;
+        self.sum += d;
+        self.max = self.max.max(d);
+    }
+
+    fn stats(&self) -> LatencyStats {
+        let mean = if self.count == 0 { Duration::ZERO } else { self.sum / self.count as u32 };
+        LatencyStats { count: self.count, mean, max: self.max }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencyReport {
+    /// From the pcm being pushed to the encoder to the packet containing it being produced.
+    pub encode: LatencyStats,
+    /// From the packet being produced to it being received by the decoder.
+    pub transport: LatencyStats,
+    /// From the packet being received to the decoded pcm being returned.
+    pub decode: LatencyStats,
+    pub end_to_end: LatencyStats,
+}
+
+#[derive(Default)]
+struct Inner {
+    marks: [VecDeque<(u64, Instant)>; 4],
+    // encode, transport, decode, end to end
+    accs: [Acc; 4],
+}
+
+impl Inner {
+    // Time at which the first position >= granule_position went through `stage`.
+    fn lookup(&self, stage: Stage, granule_position: u64) -> Option<Instant> {
+        let marks = &self.marks[stage as usize];
+        let idx = marks.partition_point(|(g, _)| *g < granule_position);
+        marks.get(idx).map(|(_, t)| *t)
+    }
+
+    fn mark(&mut self, stage: Stage, granule_position: u64, now: Instant) {
+        // Pages on which no packet ends have a granule position of -1, which is not a position.
+        if granule_position == u64::MAX {
+            return;
+        }
+        let prev = match stage {
+            Stage::Input => None,
+            Stage::Encoded => Some(Stage::Input),
+            Stage::Received => Some(Stage::Encoded),
+            Stage::Output => Some(Stage::Received),
+        };
+        if let Some(prev) = prev {
+            if let Some(t) = self.lookup(prev, granule_position) {
+                self.accs[prev as usize].add(now.saturating_duration_since(t))
+            }
+        }
+        if stage == Stage::Output {
+            if let Some(t) = self.lookup(Stage::Input, granule_position) {
+                self.accs[3].add(now.saturating_duration_since(t))
+            }
+        }
+        let marks = &mut self.marks[stage as usize];
+        // Positions are expected to be monotonic, older marks are dropped otherwise.
+        while marks.back().is_some_and(|(g, _)| *g >= granule_position) {
+            marks.pop_back();
+        }
+        marks.push_back((granule_position, now));
+        if marks.len() > MAX_MARKS {
+            marks.pop_front();
+        }
+    }
+}
+
+/// Shared latency tracker, clones refer to the same underlying data.
+#[derive(Clone, Default)]
+pub struct LatencyTracker {
+    inner: std::sync::Arc<std::sync::Mutex<Inner>>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn mark(&self, stage: Stage, granule_position: u64) {
+        let now = Instant::now();
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.mark(stage, granule_position, now)
+        }
+    }
+
+    /// The pcm up to `granule_position` has been pushed to the encoder.
+    pub fn mark_input(&self, granule_position: u64) {
+        self.mark(Stage::Input, granule_position)
+    }
+
+    /// A packet ending at `granule_position` has been produced by the encoder.
+    pub fn mark_encoded(&self, granule_position: u64) {
+        self.mark(Stage::Encoded, granule_position)
+    }
+
+    /// A packet ending at `granule_position` has been received by the decoder.
+    pub fn mark_received(&self, granule_position: u64) {
+        self.mark(Stage::Received, granule_position)
+    }
+
+    /// The pcm up to `granule_position` has been returned by the decoder.
+    pub fn mark_output(&self, granule_position: u64) {
+        self.mark(Stage::Output, granule_position)
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        match self.inner.lock() {
+            Ok(inner) => LatencyReport {
+                encode: inner.accs[0].stats(),
+                transport: inner.accs[1].stats(),
+                decode: inner.accs[2].stats(),
+                end_to_end: inner.accs[3].stats(),
+            },
+            Err(_) => LatencyReport::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_without_granule_position() {
+        let t0 = Instant::now();
+        let ms = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut inner = Inner::default();
+        inner.mark(Stage::Input, 960, ms(0));
+        inner.mark(Stage::Encoded, 960, ms(5));
+        inner.mark(Stage::Received, 960, ms(20));
+        // A page on which the next packet starts but does not end.
+        inner.mark(Stage::Received, u64::MAX, ms(30));
+        inner.mark(Stage::Output, 960, ms(40));
+        inner.mark(Stage::Input, 1920, ms(40));
+        inner.mark(Stage::Encoded, 1920, ms(45));
+        inner.mark(Stage::Output, u64::MAX, ms(50));
+        assert!(inner.marks.iter().all(|m| m.iter().all(|(g, _)| *g != u64::MAX)));
+        assert_eq!(inner.lookup(Stage::Received, 1920), None);
+        let [encode, transport, decode, end_to_end] = inner.accs.map(|a| a.stats());
+        assert_eq!((encode.count, encode.max), (2, Duration::from_millis(5)));
+        assert_eq!((transport.count, transport.max), (1, Duration::from_millis(15)));
+        assert_eq!((decode.count, decode.max), (1, Duration::from_millis(20)));
+        assert_eq!((end_to_end.count, end_to_end.max), (1, Duration::from_millis(40)));
+    }
+}
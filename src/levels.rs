@@ -0,0 +1,124 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Running statistics of pcm sample values, to catch level problems during ingestion: all-zero
+// channels, large DC offsets, or int16 samples passed as floats without scaling.
+
+/// Number of bins of the amplitude histogram, evenly spread over [-1, 1].
+pub const HISTOGRAM_BINS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleStats {
+    count: u64,
+    min: f32,
+    max: f32,
+    mean: f64,
+    // Sum of the squared differences to the mean, updated with Welford's algorithm.
+    m2: f64,
+    zeros: u64,
+    out_of_range: u64,
+    histogram: [u64; HISTOGRAM_BINS],
+}
+
+impl SampleStats {
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            mean: 0.,
+            m2: 0.,
+            zeros: 0,
+            out_of_range: 0,
+            histogram: [0; HISTOGRAM_BINS],
+        }
+    }
+
+    pub fn push(&mut self, pcm: &[f32]) {
+        for &v in pcm.iter() {
+            self.count += 1;
+            self.min = self.min.min(v);
+            self.max = self.max.max(v);
+            let delta = v as f64 - self.mean;
+            self.mean += delta / self.count as f64;
+            self.m2 += delta * (v as f64 - self.mean);
+            if v == 0. {
+                self.zeros += 1
+            }
+            if !(-1.0..=1.0).contains(&v) {
+                self.out_of_range += 1
+            }
+            // Values outside of [-1, 1] are counted in the edge bins.
+            let bin = ((v + 1.) * 0.5 * HISTOGRAM_BINS as f32) as isize;
+            self.histogram[bin.clamp(0, HISTOGRAM_BINS as isize - 1) as usize] += 1;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// `None` when no sample has been pushed.
+    pub fn min(&self) -> Option<f32> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<f32> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// The DC offset.
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn variance(&self) -> f64 {
+        if self.count == 0 {
+            0.
+        } else {
+            self.m2 / self.count as f64
+        }
+    }
+
+    /// Root mean square level, including the DC offset.
+    pub fn rms(&self) -> f64 {
+        (self.variance() + self.mean * self.mean).sqrt()
+    }
+
+    /// Peak level in dBFS, -inf for silence.
+    pub fn peak_db(&self) -> f32 {
+        let peak = f32::max(self.max().unwrap_or(0.).abs(), self.min().unwrap_or(0.).abs());
+        20. * peak.log10()
+    }
+
+    /// Number of samples that are exactly zero.
+    pub fn zeros(&self) -> u64 {
+        self.zeros
+    }
+
+    /// Number of samples outside of [-1, 1], these get clipped by the encoder.
+    pub fn out_of_range(&self) -> u64 {
+        self.out_of_range
+    }
+
+    /// Sample counts per bin, bin `i` covers `[-1 + 2i/N, -1 + 2(i+1)/N)`.
+    pub fn histogram(&self) -> &[u64; HISTOGRAM_BINS] {
+        &self.histogram
+    }
+
+    /// All the samples pushed so far are zeros, e.g. a muted or disconnected channel.
+    pub fn is_silent(&self) -> bool {
+        self.count > 0 && self.zeros == self.count
+    }
+}
+
+impl Default for SampleStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
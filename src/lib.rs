@@ -2,42 +2,190 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+// Only the `parse`, `crc` and `fixed` modules are available without the `std` feature.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod align;
+#[cfg(feature = "std")]
+pub mod archive;
+#[cfg(feature = "std")]
+pub mod base64;
+#[cfg(feature = "std")]
+pub mod capture;
+#[cfg(feature = "std")]
+pub mod classify;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod codecs;
+#[cfg(feature = "std")]
+pub mod concat;
+#[cfg(feature = "std")]
+pub mod convert;
+pub mod crc;
+#[cfg(feature = "std")]
+pub mod device;
+#[cfg(feature = "std")]
+pub mod diarize;
+#[cfg(feature = "std")]
+pub mod envelope;
+#[cfg(feature = "std")]
 mod error;
+#[cfg(feature = "std")]
+pub mod fanout;
+pub mod fixed;
+#[cfg(feature = "std")]
+pub mod follow;
+#[cfg(feature = "std")]
+pub mod framing;
+#[cfg(feature = "std")]
+pub mod gain;
+#[cfg(feature = "std")]
+pub mod id3;
+#[cfg(feature = "std")]
+pub mod latency;
+#[cfg(feature = "std")]
+pub mod levels;
+#[cfg(feature = "std")]
+pub mod loopback;
+#[cfg(all(feature = "mmap", unix))]
+pub mod mmap;
+#[cfg(feature = "std")]
 pub mod ogg_opus;
+#[cfg(feature = "std")]
 pub mod ogg_pager;
+#[cfg(feature = "std")]
+pub mod opus;
+pub mod parse;
+#[cfg(feature = "std")]
+pub mod pcm;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod quality;
+#[cfg(feature = "std")]
+pub mod rechunk;
+#[cfg(feature = "std")]
+pub mod recorder;
+#[cfg(feature = "std")]
+pub mod recover;
+#[cfg(feature = "std")]
+pub mod redundancy;
+#[cfg(feature = "std")]
+pub mod resample;
+#[cfg(feature = "std")]
+pub mod speed;
+#[cfg(feature = "std")]
+pub mod splice;
+#[cfg(any(feature = "ndarray", feature = "candle"))]
+pub mod tensor;
+#[cfg(feature = "std")]
+pub mod transcode;
+#[cfg(feature = "std")]
+pub mod validate;
+#[cfg(feature = "std")]
 pub mod wav;
 
+#[cfg(feature = "std")]
 use error::{Error, Result};
+#[cfg(feature = "std")]
 use std::collections::VecDeque;
 
-pub struct AudioOutputData_ {
-    resampled_data: std::collections::VecDeque<f32>,
-    resampler: rubato::FastFixedIn<f32>,
-    output_buffer: Vec<f32>,
-    input_buffer: Vec<f32>,
+// The rubato resamplers used for the `resample::Interpolation` variants.
+#[cfg(feature = "std")]
+enum RubatoResampler<T: rubato::Sample> {
+    Polynomial(rubato::FastFixedIn<T>),
+    Sinc(rubato::SincFixedIn<T>),
+}
+
+#[cfg(feature = "std")]
+impl<T: rubato::Sample> RubatoResampler<T> {
+    fn new(
+        input_sample_rate: usize,
+        output_sample_rate: usize,
+        interpolation: resample::Interpolation,
+    ) -> Result<Self> {
+        let resample_ratio = output_sample_rate as f64 / input_sample_rate as f64;
+        let resampler = match interpolation {
+            resample::Interpolation::Polynomial => Self::Polynomial(rubato::FastFixedIn::new(
+                resample_ratio,
+                f64::max(resample_ratio, 1.0),
+                rubato::PolynomialDegree::Septic,
+                1024,
+                1,
+            )?),
+            resample::Interpolation::Sinc(params) => Self::Sinc(rubato::SincFixedIn::new(
+                resample_ratio,
+                1.0,
+                params.to_rubato(),
+                1024,
+                1,
+            )?),
+        };
+        Ok(resampler)
+    }
+
+    fn resampler(&self) -> &dyn rubato::VecResampler<T> {
+        match self {
+            Self::Polynomial(r) => r,
+            Self::Sinc(r) => r,
+        }
+    }
+
+    fn resampler_mut(&mut self) -> &mut dyn rubato::VecResampler<T> {
+        match self {
+            Self::Polynomial(r) => r,
+            Self::Sinc(r) => r,
+        }
+    }
+
+    fn reset(&mut self) {
+        use rubato::Resampler;
+        match self {
+            Self::Polynomial(r) => r.reset(),
+            Self::Sinc(r) => r.reset(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub struct AudioOutputData_<T: rubato::Sample = f32> {
+    resampled_data: std::collections::VecDeque<T>,
+    resampler: RubatoResampler<T>,
+    output_buffer: Vec<T>,
+    input_buffer: Vec<T>,
     input_len: usize,
     // The number of (resampled) samples that have been seen so far.
     total_samples: usize,
     // Some subtitle index together with the index at which it should get printed.
     subs: VecDeque<(usize, String)>,
-    mean_squares: f32,
+    // Of the last chunk of resampled samples.
+    mean_squares: f64,
 }
 
-impl AudioOutputData_ {
+// The resampling runs on `T`, the level is computed in f64 for both sample types.
+#[cfg(feature = "std")]
+impl<T: rubato::Sample + Into<f64>> AudioOutputData_<T> {
     pub fn new(input_sample_rate: usize, output_sample_rate: usize) -> Result<Self> {
-        use rubato::Resampler;
+        Self::new_with_interpolation(
+            input_sample_rate,
+            output_sample_rate,
+            resample::Interpolation::Polynomial,
+        )
+    }
 
+    pub fn new_with_interpolation(
+        input_sample_rate: usize,
+        output_sample_rate: usize,
+        interpolation: resample::Interpolation,
+    ) -> Result<Self> {
         let resampled_data = std::collections::VecDeque::with_capacity(output_sample_rate * 10);
-        let resample_ratio = output_sample_rate as f64 / input_sample_rate as f64;
-        let resampler = rubato::FastFixedIn::new(
-            resample_ratio,
-            f64::max(resample_ratio, 1.0),
-            rubato::PolynomialDegree::Septic,
-            1024,
-            1,
-        )?;
-        let input_buffer = resampler.input_buffer_allocate(true).remove(0);
-        let output_buffer = resampler.output_buffer_allocate(true).remove(0);
+        let resampler = RubatoResampler::new(input_sample_rate, output_sample_rate, interpolation)?;
+        let input_buffer = resampler.resampler().input_buffer_allocate(true).remove(0);
+        let output_buffer = resampler.resampler().output_buffer_allocate(true).remove(0);
         Ok(Self {
             resampled_data,
             resampler,
@@ -58,10 +206,36 @@ impl AudioOutputData_ {
         self.resampled_data.len()
     }
 
+    /// Number of input samples processed at once by the underlying resampler, samples are
+    /// buffered until a full chunk is available.
+    pub fn input_chunk_size(&self) -> usize {
+        self.input_buffer.len()
+    }
+
+    /// Maximum number of output samples produced per input chunk.
+    pub fn max_output_chunk_size(&self) -> usize {
+        self.output_buffer.len()
+    }
+
+    /// Number of input samples waiting for a full chunk.
+    pub fn pending_input(&self) -> usize {
+        self.input_len
+    }
+
+    /// Delay introduced by the resampler filter, in output samples. This does not include the
+    /// samples waiting for a full input chunk.
+    pub fn output_delay(&self) -> usize {
+        match &self.resampler {
+            RubatoResampler::Polynomial(r) => rubato::Resampler::output_delay(r),
+            // The rubato sinc resamplers already drop the filter delay from their output,
+            // contrary to what their `output_delay` reports.
+            RubatoResampler::Sinc(_) => 0,
+        }
+    }
+
     pub fn reset(&mut self) {
-        use rubato::Resampler;
-        self.output_buffer.fill(0.);
-        self.input_buffer.fill(0.);
+        self.output_buffer.fill(T::coerce(0.));
+        self.input_buffer.fill(T::coerce(0.));
         self.total_samples = 0;
         self.resampler.reset();
         self.resampled_data.clear();
@@ -69,7 +243,7 @@ impl AudioOutputData_ {
         self.mean_squares = 0.;
     }
 
-    pub fn take_all(&mut self) -> Vec<f32> {
+    pub fn take_all(&mut self) -> Vec<T> {
         let mut data = Vec::with_capacity(self.resampled_data.len());
         while let Some(elem) = self.resampled_data.pop_back() {
             data.push(elem);
@@ -77,8 +251,9 @@ impl AudioOutputData_ {
         data
     }
 
+    /// Level of the last chunk of resampled samples, `10 + log10(mean square)`, 0 for silence.
     pub fn db10(&self) -> f32 {
-        10. + (self.mean_squares + 1e-10).log10()
+        (10. + (self.mean_squares + 1e-10).log10()) as f32
     }
 
     pub fn clear(&mut self) {
@@ -91,7 +266,7 @@ impl AudioOutputData_ {
     }
 
     // Assumes that the input buffer is large enough.
-    fn push_input_buffer(&mut self, samples: &[f32]) {
+    fn push_input_buffer(&mut self, samples: &[T]) {
         self.input_buffer[self.input_len..self.input_len + samples.len()].copy_from_slice(samples);
         self.input_len += samples.len();
         self.total_samples += samples.len();
@@ -101,9 +276,7 @@ impl AudioOutputData_ {
         self.subs.push_back((self.total_samples, sub))
     }
 
-    pub fn push_samples(&mut self, samples: &[f32]) -> Result<()> {
-        use rubato::Resampler;
-
+    pub fn push_samples(&mut self, samples: &[T]) -> Result<()> {
         let mut pos_in = 0;
         loop {
             let rem = self.input_buffer.len() - self.input_len;
@@ -113,98 +286,362 @@ impl AudioOutputData_ {
             if self.input_len < self.input_buffer.len() {
                 break;
             }
-            let (_, out_len) = self.resampler.process_into_buffer(
-                &[&self.input_buffer],
-                &mut [&mut self.output_buffer],
+            let (_, out_len) = self.resampler.resampler_mut().process_into_buffer(
+                std::slice::from_ref(&self.input_buffer),
+                std::slice::from_mut(&mut self.output_buffer),
                 None,
             )?;
+            let mut sum_squares = 0f64;
             for &elem in self.output_buffer[..out_len].iter() {
+                let v: f64 = elem.into();
+                sum_squares += v * v;
                 self.resampled_data.push_front(elem)
             }
+            self.mean_squares = sum_squares / usize::max(out_len, 1) as f64;
             self.input_len = 0;
         }
         Ok(())
     }
 }
 
-fn conv<T>(samples: &mut Vec<f32>, data: std::borrow::Cow<symphonia::core::audio::AudioBuffer<T>>)
-where
+#[cfg(feature = "std")]
+fn conv<T>(
+    planar: &mut [Vec<f32>],
+    data: std::borrow::Cow<symphonia::core::audio::AudioBuffer<T>>,
+    sources: &[usize],
+) where
     T: symphonia::core::sample::Sample,
     f32: symphonia::core::conv::FromSample<T>,
 {
     use symphonia::core::audio::Signal;
     use symphonia::core::conv::FromSample;
-    samples.extend(data.chan(0).iter().map(|v| f32::from_sample(*v)))
+    for (samples, &src) in planar.iter_mut().zip(sources.iter()) {
+        samples.extend(data.chan(src).iter().map(|v| f32::from_sample(*v)))
+    }
 }
 
+/// Decodes the first channel of an audio file, returns the pcm data and the sample rate.
+#[cfg(feature = "std")]
 pub fn pcm_decode<P: AsRef<std::path::Path>>(path: P) -> Result<(Vec<f32>, u32)> {
-    use symphonia::core::audio::{AudioBufferRef, Signal};
-
-    let src = std::fs::File::open(path)?;
-    let mss = symphonia::core::io::MediaSourceStream::new(Box::new(src), Default::default());
-    let hint = symphonia::core::probe::Hint::new();
-    let meta_opts: symphonia::core::meta::MetadataOptions = Default::default();
-    let fmt_opts: symphonia::core::formats::FormatOptions = Default::default();
-    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
-    let mut format = probed.format;
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
-        .expect("no supported audio tracks");
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &Default::default())
-        .expect("unsupported codec");
-    let track_id = track.id;
-    let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
-    let mut pcm_data = Vec::new();
-    while let Ok(packet) = format.next_packet() {
-        while !format.metadata().is_latest() {
-            format.metadata().pop();
+    let (mut planar, sample_rate) = decode_channels(path, &[0], None)?;
+    Ok((planar.swap_remove(0), sample_rate))
+}
+
+/// Decodes an audio file with the channels selected by `channel_map`, one buffer per output
+/// channel. The file must have `channel_map.input_channels` channels.
+#[cfg(feature = "std")]
+pub fn pcm_decode_channels<P: AsRef<std::path::Path>>(
+    path: P,
+    channel_map: &ChannelMap,
+) -> Result<(Vec<Vec<f32>>, u32)> {
+    decode_channels(path, &channel_map.sources, Some(channel_map.input_channels))
+}
+
+#[cfg(feature = "std")]
+fn decode_channels<P: AsRef<std::path::Path>>(
+    path: P,
+    sources: &[usize],
+    input_channels: Option<usize>,
+) -> Result<(Vec<Vec<f32>>, u32)> {
+    let src = open_audio_file(path)?;
+    let mut pcm_data = vec![Vec::new(); sources.len()];
+    let mut sample_rate = 0;
+    decode_source(Box::new(src), sources, input_channels, |pcm, sr| {
+        sample_rate = sr;
+        for (all, pcm) in pcm_data.iter_mut().zip(pcm.iter()) {
+            all.extend_from_slice(pcm)
+        }
+        Ok(())
+    })?;
+    Ok((pcm_data, sample_rate))
+}
+
+// Opens an audio file positioned after its ID3v2 tags if any, so that they don't get in the way
+// of the format detection.
+#[cfg(feature = "std")]
+pub(crate) fn open_audio_file<P: AsRef<std::path::Path>>(path: P) -> Result<std::fs::File> {
+    use std::io::{Read, Seek};
+
+    let mut src = std::fs::File::open(path)?;
+    let mut header = [0u8; id3::HEADER_LEN];
+    let skip = match src.read_exact(&mut header) {
+        Ok(()) => id3::tag_size(&header).unwrap_or(0),
+        Err(_) => 0,
+    };
+    src.seek(std::io::SeekFrom::Start(skip as u64))?;
+    Ok(src)
+}
+
+// Decodes the audio read from `src`, `f` is called for each decoded packet with the channels
+// selected by `sources` and the sample rate. All the channels are selected when `sources` is
+// empty.
+#[cfg(feature = "std")]
+pub(crate) fn decode_source(
+    src: Box<dyn symphonia::core::io::MediaSource>,
+    sources: &[usize],
+    input_channels: Option<usize>,
+    mut f: impl FnMut(&[Vec<f32>], u32) -> Result<()>,
+) -> Result<()> {
+    let mut reader = AudioFileReader::from_source(src, sources, input_channels)?;
+    let sample_rate = reader.sample_rate();
+    while let Some(pcm) = reader.next_chunk()? {
+        f(pcm, sample_rate)?;
+    }
+    Ok(())
+}
+
+/// Decodes a whole audio file in any of the formats supported by symphonia, e.g. mp3, flac,
+/// ogg/vorbis, wav or m4a, returns one buffer per channel and the sample rate.
+#[cfg(feature = "std")]
+pub fn read_audio_file<P: AsRef<std::path::Path>>(path: P) -> Result<(Vec<Vec<f32>>, u32)> {
+    let mut reader = AudioFileReader::open(path)?;
+    let mut pcm_data = vec![Vec::new(); reader.channels()];
+    while let Some(pcm) = reader.next_chunk()? {
+        for (all, pcm) in pcm_data.iter_mut().zip(pcm.iter()) {
+            all.extend_from_slice(pcm)
+        }
+    }
+    Ok((pcm_data, reader.sample_rate()))
+}
+
+/// Decodes an audio file incrementally, each item holds the pcm decoded from one packet of the
+/// file, one buffer per channel.
+#[cfg(feature = "std")]
+pub struct AudioFileReader {
+    format: Box<dyn symphonia::core::formats::FormatReader>,
+    decoder: Box<dyn symphonia::core::codecs::Decoder>,
+    track_id: u32,
+    sample_rate: u32,
+    sources: Vec<usize>,
+    pcm: Vec<Vec<f32>>,
+}
+
+#[cfg(feature = "std")]
+impl AudioFileReader {
+    /// Opens the file at `path` and reads its headers, with all its channels selected.
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let src = open_audio_file(path)?;
+        Self::from_source(Box::new(src), &[], None)
+    }
+
+    fn from_source(
+        src: Box<dyn symphonia::core::io::MediaSource>,
+        sources: &[usize],
+        input_channels: Option<usize>,
+    ) -> Result<Self> {
+        let mss = symphonia::core::io::MediaSourceStream::new(src, Default::default());
+        let probed = codecs::probe(mss)?;
+        let format = probed.format;
+        let Some(track) = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
+        else {
+            bail!("no supported audio tracks")
+        };
+        let decoder = codecs::make_decoder(&track.codec_params)?;
+        let track_id = track.id;
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+        let channels = track.codec_params.channels.map(|c| c.count());
+        let sources = match (sources.is_empty(), channels) {
+            (false, _) => sources.to_vec(),
+            (true, Some(channels)) => (0..channels).collect(),
+            (true, None) => bail!("unknown number of channels"),
+        };
+        if let Some(channels) = channels {
+            if let Some(n) = input_channels.filter(|&n| n != channels) {
+                bail!("channel map expects {n} channels, the file has {channels}")
+            }
+            if let Some(src) = sources.iter().find(|&&src| src >= channels) {
+                bail!("channel {src} is out of range for a file with {channels} channels")
+            }
         }
-        if packet.track_id() != track_id {
-            continue;
+        let pcm = vec![Vec::new(); sources.len()];
+        Ok(Self { format, decoder, track_id, sample_rate, sources, pcm })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Number of channels in each decoded chunk.
+    pub fn channels(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Decodes the next packet of the file, `None` at the end of the file. The returned buffers
+    /// are reused by the following calls.
+    pub fn next_chunk(&mut self) -> Result<Option<&[Vec<f32>]>> {
+        use symphonia::core::audio::AudioBufferRef;
+
+        let packet = loop {
+            // Read errors, including the end of the stream, stop the decoding.
+            let Ok(packet) = self.format.next_packet() else { return Ok(None) };
+            while !self.format.metadata().is_latest() {
+                self.format.metadata().pop();
+            }
+            if packet.track_id() == self.track_id {
+                break packet;
+            }
+        };
+        let (pcm_data, sources) = (&mut self.pcm, self.sources.as_slice());
+        pcm_data.iter_mut().for_each(|c| c.clear());
+        match self.decoder.decode(&packet)? {
+            AudioBufferRef::F32(buf) => conv(pcm_data, buf, sources),
+            AudioBufferRef::U8(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::U16(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::U24(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::U32(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::S8(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::S16(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::S24(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::S32(data) => conv(pcm_data, data, sources),
+            AudioBufferRef::F64(data) => conv(pcm_data, data, sources),
         }
-        match decoder.decode(&packet)? {
-            AudioBufferRef::F32(buf) => pcm_data.extend(buf.chan(0)),
-            AudioBufferRef::U8(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::U16(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::U24(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::U32(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S8(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S16(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S24(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S32(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::F64(data) => conv(&mut pcm_data, data),
+        Ok(Some(&self.pcm))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Iterator for AudioFileReader {
+    type Item = Result<Vec<Vec<f32>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().map(|pcm| pcm.map(|pcm| pcm.to_vec())).transpose()
+    }
+}
+
+/// Resamples a whole mono buffer of `f32` or `f64` samples, see [`resample::resample`]. The
+/// output is aligned with the input and has the same duration.
+#[cfg(feature = "std")]
+pub fn resample<T: rubato::Sample + Into<f64>>(
+    pcm_in: &[T],
+    sr_in: usize,
+    sr_out: usize,
+) -> Result<Vec<T>> {
+    resample::resample(pcm_in, sr_in, sr_out)
+}
+
+/// Pads `pcm` with silence so that its length is a multiple of `multiple`, e.g.
+/// `opus::ENCODER_FRAME_SIZE` or the 1920 samples of an 80ms step at 24kHz. Returns the number
+/// of padding samples that have been added.
+#[cfg(feature = "std")]
+pub fn pad_to_multiple<T: Clone + Default>(pcm: &mut Vec<T>, multiple: usize) -> usize {
+    if multiple == 0 {
+        return 0;
+    }
+    let padding = (multiple - pcm.len() % multiple) % multiple;
+    pcm.resize(pcm.len() + padding, T::default());
+    padding
+}
+
+/// Pads `pcm` with silence up to `duration`, returns the number of padding samples that have
+/// been added. Longer inputs are left untouched.
+#[cfg(feature = "std")]
+pub fn pad_to_duration<T: Clone + Default>(
+    pcm: &mut Vec<T>,
+    sample_rate: usize,
+    duration: std::time::Duration,
+) -> usize {
+    let target_len = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+    let padding = target_len.saturating_sub(pcm.len());
+    pcm.resize(pcm.len() + padding, T::default());
+    padding
+}
+
+/// Splits interleaved multichannel pcm into one buffer per channel, e.g. to feed each speaker of
+/// a stereo decode to ASR separately. Trailing samples that do not form a full frame are
+/// ignored.
+#[cfg(feature = "std")]
+pub fn deinterleave<T: Copy>(pcm: &[T], channels: usize) -> Vec<Vec<T>> {
+    let mut planar = vec![vec![]; channels];
+    deinterleave_into(pcm, &mut planar);
+    planar
+}
+
+/// Same as `deinterleave` but appends to existing per-channel buffers, the number of channels
+/// is the number of buffers.
+#[cfg(feature = "std")]
+pub fn deinterleave_into<T: Copy>(pcm: &[T], planar: &mut [Vec<T>]) {
+    let channels = planar.len();
+    if channels == 0 {
+        return;
+    }
+    for buf in planar.iter_mut() {
+        buf.reserve(pcm.len() / channels)
+    }
+    for frame in pcm.chunks_exact(channels) {
+        for (buf, &v) in planar.iter_mut().zip(frame.iter()) {
+            buf.push(v)
         }
     }
-    Ok((pcm_data, sample_rate))
 }
 
-pub fn resample(pcm_in: &[f32], sr_in: usize, sr_out: usize) -> Result<Vec<f32>> {
-    use rubato::Resampler;
+/// Selects, reorders, drops or duplicates the channels of interleaved pcm: output channel `i`
+/// is a copy of input channel `sources[i]`. E.g. `ChannelMap::select(8, 3)` takes the fourth
+/// channel of an 8 channels recorder as mono input.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMap {
+    pub input_channels: usize,
+    pub sources: Vec<usize>,
+}
 
-    let mut pcm_out =
-        Vec::with_capacity((pcm_in.len() as f64 * sr_out as f64 / sr_in as f64) as usize + 1024);
+#[cfg(feature = "std")]
+impl ChannelMap {
+    pub fn new(input_channels: usize, sources: Vec<usize>) -> Result<Self> {
+        if let Some(src) = sources.iter().find(|&&src| src >= input_channels) {
+            bail!("channel {src} is out of range for {input_channels} input channels")
+        }
+        Ok(Self { input_channels, sources })
+    }
 
-    let mut resampler = rubato::FftFixedInOut::<f32>::new(sr_in, sr_out, 1024, 1)?;
-    let mut output_buffer = resampler.output_buffer_allocate(true);
-    let mut pos_in = 0;
-    while pos_in + resampler.input_frames_next() < pcm_in.len() {
-        let (in_len, out_len) =
-            resampler.process_into_buffer(&[&pcm_in[pos_in..]], &mut output_buffer, None)?;
-        pos_in += in_len;
-        pcm_out.extend_from_slice(&output_buffer[0][..out_len]);
+    /// Keeps a single channel.
+    pub fn select(input_channels: usize, channel: usize) -> Result<Self> {
+        Self::new(input_channels, vec![channel])
     }
 
-    if pos_in < pcm_in.len() {
-        let (_in_len, out_len) = resampler.process_partial_into_buffer(
-            Some(&[&pcm_in[pos_in..]]),
-            &mut output_buffer,
-            None,
-        )?;
-        pcm_out.extend_from_slice(&output_buffer[0][..out_len]);
+    pub fn output_channels(&self) -> usize {
+        self.sources.len()
     }
 
-    Ok(pcm_out)
+    /// Maps interleaved pcm to interleaved pcm, trailing samples that do not form a full frame
+    /// are ignored.
+    pub fn apply<T: Copy>(&self, pcm: &[T]) -> Vec<T> {
+        let mut out = Vec::with_capacity(pcm.len() / self.input_channels * self.sources.len());
+        for frame in pcm.chunks_exact(self.input_channels) {
+            out.extend(self.sources.iter().map(|&src| frame[src]))
+        }
+        out
+    }
+
+    /// Same as `deinterleave_into` but with the channel mapping applied, there must be one
+    /// buffer per output channel.
+    pub fn deinterleave_into<T: Copy>(&self, pcm: &[T], planar: &mut [Vec<T>]) {
+        for buf in planar.iter_mut() {
+            buf.reserve(pcm.len() / self.input_channels)
+        }
+        for frame in pcm.chunks_exact(self.input_channels) {
+            for (buf, &src) in planar.iter_mut().zip(self.sources.iter()) {
+                buf.push(frame[src])
+            }
+        }
+    }
+
+    pub fn deinterleave<T: Copy>(&self, pcm: &[T]) -> Vec<Vec<T>> {
+        let mut planar = vec![vec![]; self.sources.len()];
+        self.deinterleave_into(pcm, &mut planar);
+        planar
+    }
+}
+
+/// Interleaves per-channel buffers, the output is truncated to the shortest channel.
+#[cfg(feature = "std")]
+pub fn interleave<T: Copy, C: AsRef<[T]>>(planar: &[C]) -> Vec<T> {
+    let len = planar.iter().map(|c| c.as_ref().len()).min().unwrap_or(0);
+    let mut pcm = Vec::with_capacity(len * planar.len());
+    for i in 0..len {
+        pcm.extend(planar.iter().map(|c| c.as_ref()[i]))
+    }
+    pcm
 }
@@ -0,0 +1,76 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Round-trip latency measurement of an audio device setup: a known chirp is played on the
+// output, recorded back on the input and the delay is found by cross-correlation. kaudio does
+// not drive audio devices itself, the playback and capture are left to the caller, see
+// `device::play_and_record` for devices implementing the `device` traits.
+
+use crate::Result;
+
+/// A linear sine sweep from `f0` to `f1` Hz with short fades at both ends, `duration` long.
+pub fn chirp(sample_rate: usize, duration: std::time::Duration, f0: f32, f1: f32) -> Vec<f32> {
+    let len = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+    let total = duration.as_secs_f32();
+    let fade = usize::min(sample_rate / 100, len / 2).max(1);
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            let phase = 2. * std::f32::consts::PI * (f0 * t + (f1 - f0) * t * t / (2. * total));
+            let gain = usize::min(usize::min(i, len - 1 - i), fade) as f32 / fade as f32;
+            0.5 * gain * phase.sin()
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoopbackLatency {
+    pub latency: std::time::Duration,
+    pub lag_samples: usize,
+    /// Normalized cross-correlation at the detected lag, low values indicate that the chirp was
+    /// not picked up by the capture, e.g. because of a muted device.
+    pub confidence: f32,
+}
+
+/// Finds the delay of `played` in `captured`, the capture must have started at the same time as
+/// the playback and last at least `max_latency` longer than `played`.
+pub fn measure_latency(
+    played: &[f32],
+    captured: &[f32],
+    sample_rate: usize,
+    max_latency: std::time::Duration,
+) -> Result<LoopbackLatency> {
+    let max_lag = (max_latency.as_secs_f64() * sample_rate as f64) as usize;
+    let alignment = crate::align::align(played, captured, max_lag)?;
+    if alignment.lag < 0 {
+        crate::bail!("the capture is ahead of the playback by {} samples", -alignment.lag)
+    }
+    let lag_samples = alignment.lag as usize;
+    Ok(LoopbackLatency {
+        latency: std::time::Duration::from_secs_f64(lag_samples as f64 / sample_rate as f64),
+        lag_samples,
+        confidence: alignment.confidence,
+    })
+}
+
+/// Plays a one second chirp using `play_and_record`, which has to play the given pcm and return
+/// what was captured meanwhile, and reports the round-trip latency.
+pub fn measure_loopback<F>(
+    sample_rate: usize,
+    max_latency: std::time::Duration,
+    mut play_and_record: F,
+) -> Result<LoopbackLatency>
+where
+    F: FnMut(&[f32]) -> Result<Vec<f32>>,
+{
+    let mut played = chirp(sample_rate, std::time::Duration::from_secs(1), 200., 8000.);
+    // Trailing silence so that the whole chirp can be captured.
+    crate::pad_to_duration(
+        &mut played,
+        sample_rate,
+        std::time::Duration::from_secs(1) + max_latency,
+    );
+    let captured = play_and_record(&played)?;
+    measure_latency(&played, &captured, sample_rate, max_latency)
+}
@@ -0,0 +1,162 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Read-only memory mapped files, so that large files can be scanned without read syscalls or
+// copying the data in an intermediary buffer. The mapped data can be inspected, indexed and
+// decoded with the seekable decoder.
+
+use crate::Result;
+
+pub struct MmapFile {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by this struct.
+unsafe impl Send for MmapFile {}
+unsafe impl Sync for MmapFile {}
+
+impl MmapFile {
+    /// Maps the whole file read-only.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or by any other, while the
+    /// mapping is alive. The mapped data is exposed as a `&[u8]` that has to stay immutable, and
+    /// reading pages past the end of a truncated file raises a SIGBUS.
+    pub unsafe fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).map_err(|e| crate::Error::from(e).with_path(path))?;
+        let Ok(len) = usize::try_from(file.metadata()?.len()) else {
+            crate::bail!("{} is too large to be mapped", path.display())
+        };
+        if len == 0 {
+            // mmap does not support empty mappings.
+            return Ok(Self { ptr: std::ptr::null_mut(), len: 0 });
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            return Err(crate::Error::from(err).with_path(path));
+        }
+        Ok(Self { ptr, len })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    /// A reader over the mapped data.
+    pub fn cursor(&self) -> std::io::Cursor<&[u8]> {
+        std::io::Cursor::new(self.as_slice())
+    }
+
+    /// See [`crate::ogg_opus::inspect`].
+    pub fn inspect(&self) -> Result<crate::ogg_opus::StreamInfo> {
+        crate::ogg_opus::inspect(self.as_slice())
+    }
+
+    /// See [`crate::ogg_opus::SeekIndex::build`].
+    pub fn seek_index(&self, interval: std::time::Duration) -> Result<crate::ogg_opus::SeekIndex> {
+        crate::ogg_opus::SeekIndex::build(&mut self.cursor(), interval)
+    }
+
+    /// Opens the seekable decoder on the mapped data, the decoder keeps the mapping alive.
+    pub fn into_decoder(
+        self,
+        cfg: crate::ogg_opus::DecoderConfig,
+    ) -> Result<crate::ogg_opus::FileDecoder<std::io::Cursor<Self>>> {
+        crate::ogg_opus::FileDecoder::open_with_config(std::io::Cursor::new(self), cfg)
+    }
+}
+
+impl std::ops::Deref for MmapFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for MmapFile {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("kaudio-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn read_all<R: std::io::Read + std::io::Seek>(
+        mut decoder: crate::ogg_opus::FileDecoder<R>,
+    ) -> Vec<f32> {
+        let mut all = vec![];
+        while let Some(pcm) = decoder.read().unwrap() {
+            all.extend_from_slice(&pcm)
+        }
+        all
+    }
+
+    #[test]
+    fn mapped_file() {
+        let pcm: Vec<f32> = (0..2 * 48_000).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        let data = crate::ogg_opus::Encoder::new(48_000).unwrap().encode_file(&pcm).unwrap();
+        let path = temp_file("mapped.ogg", &data);
+        let file = unsafe { MmapFile::open(&path) }.unwrap();
+        assert_eq!(&file[..], &data[..]);
+        let info = file.inspect().unwrap();
+        assert_eq!(info.head.channel_count, 1);
+        let interval = std::time::Duration::from_millis(500);
+        let index = file.seek_index(interval).unwrap();
+        let expected =
+            crate::ogg_opus::SeekIndex::build(&mut std::io::Cursor::new(&data), interval);
+        assert_eq!(index.to_bytes(), expected.unwrap().to_bytes());
+
+        let cfg = crate::ogg_opus::DecoderConfig::new(48_000, 0);
+        let mapped = read_all(file.into_decoder(cfg.clone()).unwrap());
+        let cursor = std::io::Cursor::new(&data);
+        let expected = crate::ogg_opus::FileDecoder::open_with_config(cursor, cfg).unwrap();
+        assert_eq!(mapped, read_all(expected));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn empty_and_missing_files() {
+        let path = temp_file("empty.ogg", &[]);
+        let file = unsafe { MmapFile::open(&path) }.unwrap();
+        assert!(file.is_empty());
+        assert!(file.inspect().is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(unsafe { MmapFile::open(&path) }.is_err());
+    }
+}
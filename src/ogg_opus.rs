@@ -2,162 +2,845 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+use crate::ogg_pager::PacketEnd;
 use crate::Result;
 
-#[repr(Rust, packed)]
-#[derive(Debug, Clone)]
-pub struct OpusHead {
-    pub magic_signature: [u8; 8],
-    pub version: u8,
-    pub channel_count: u8,
-    pub pre_skip: u16,
-    pub sample_rate: u32,
-    pub output_gain: i16,
-    pub mapping_family: u8,
+pub use crate::parse::OpusHead;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpusTags {
+    pub vendor: String,
+    /// The user comments as (key, value) pairs, keys are compared case-insensitively.
+    pub comments: Vec<(String, String)>,
 }
 
-impl OpusHead {
+impl OpusTags {
     pub fn from_slice(data: &[u8]) -> Result<Self> {
-        let l = std::mem::size_of::<OpusHead>();
-        if data.len() != l {
-            return Err(crate::Error::OggUnexpectedLenForOpusHead(data.len()));
+        fn read_u32(data: &[u8], pos: &mut usize) -> Result<usize> {
+            let Some(bytes) = data.get(*pos..*pos + 4) else {
+                crate::bail!("unexpected end of OpusTags at {pos}")
+            };
+            *pos += 4;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+        }
+        fn read_str<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a str> {
+            let len = read_u32(data, pos)?;
+            let Some(bytes) = data.get(*pos..*pos + len) else {
+                crate::bail!("unexpected end of OpusTags at {pos}")
+            };
+            *pos += len;
+            std::str::from_utf8(bytes).map_err(crate::Error::wrap)
+        }
+
+        if data.len() < 8 || &data[..8] != b"OpusTags" {
+            let mut signature = [0u8; 8];
+            let len = usize::min(data.len(), 8);
+            signature[..len].copy_from_slice(&data[..len]);
+            return Err(crate::Error::OggUnexpectedSignature(signature));
+        }
+        let mut pos = 8;
+        let vendor = read_str(data, &mut pos)?.to_string();
+        let n_comments = read_u32(data, &mut pos)?;
+        let mut comments = Vec::with_capacity(usize::min(n_comments, 1024));
+        for _ in 0..n_comments {
+            let comment = read_str(data, &mut pos)?;
+            match comment.split_once('=') {
+                Some((key, value)) => comments.push((key.to_string(), value.to_string())),
+                None => crate::bail!("invalid comment in OpusTags {comment:?}"),
+            }
+        }
+        Ok(Self { vendor, comments })
+    }
+
+    /// Tags with the default vendor string and no comments, comments can be added with
+    /// `with_comment`.
+    pub fn new() -> Self {
+        Self { vendor: DEFAULT_VENDOR.to_string(), comments: vec![] }
+    }
+
+    pub fn with_vendor(mut self, vendor: impl Into<String>) -> Self {
+        self.vendor = vendor.into();
+        self
+    }
+
+    /// Appends a comment, e.g. TITLE, ARTIST or a custom field. Keys are made of printable ASCII
+    /// characters other than '=', the encoder rejects other keys.
+    pub fn with_comment(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.comments.push((key.into(), value.into()));
+        self
+    }
+
+    /// Serializes the tags as an OpusTags packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![];
+        // Writing to a vec cannot fail.
+        let _ = write_opus_tags(&mut data, &self.vendor, &self.comments);
+        data
+    }
+
+    /// Returns the first value associated with `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.comments.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the chapters stored as CHAPTERxxx/CHAPTERxxxNAME comments, sorted by position.
+    pub fn chapters(&self) -> Vec<Chapter> {
+        let mut chapters = vec![];
+        for (key, value) in self.comments.iter() {
+            let key = key.to_ascii_uppercase();
+            let Some(index) = key.strip_prefix("CHAPTER") else { continue };
+            if index.len() != 3 || !index.bytes().all(|b| b.is_ascii_digit()) {
+                continue;
+            }
+            let Some(granule_position) = Chapter::parse_time(value) else { continue };
+            let name = self.get(&format!("CHAPTER{index}NAME")).unwrap_or_default().to_string();
+            chapters.push(Chapter { granule_position, name })
+        }
+        chapters.sort_by_key(|c| c.granule_position);
+        chapters
+    }
+}
+
+// Comment keys are ASCII characters between 0x20 and 0x7D except '=', see RFC 7845 5.2.
+fn valid_comment_key(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|b| (0x20..=0x7d).contains(&b) && b != b'=')
+}
+
+/// A chapter mark, stored in the OpusTags using the CHAPTERxxx convention recognized by most
+/// players.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    /// Start of the chapter as a granule position, i.e. in samples at 48kHz.
+    pub granule_position: u64,
+    pub name: String,
+}
+
+impl Chapter {
+    pub fn new(granule_position: u64, name: impl Into<String>) -> Self {
+        Self { granule_position, name: name.into() }
+    }
+
+    /// The comments for this chapter, `index` must be below 1000.
+    pub fn comments(&self, index: usize) -> [(String, String); 2] {
+        let ms = self.granule_position / 48;
+        let time = format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        );
+        [
+            (format!("CHAPTER{index:03}"), time),
+            (format!("CHAPTER{index:03}NAME"), self.name.clone()),
+        ]
+    }
+
+    // Parses a HH:MM:SS.sss time into a granule position.
+    fn parse_time(time: &str) -> Option<u64> {
+        let mut parts = time.trim().splitn(3, ':');
+        let hours: u64 = parts.next()?.parse().ok()?;
+        let minutes: u64 = parts.next()?.parse().ok()?;
+        let seconds: f64 = parts.next()?.parse().ok()?;
+        if !seconds.is_finite() || seconds < 0. {
+            return None;
         }
-        let head: Self = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const Self) };
-        if &head.magic_signature != b"OpusHead" {
-            return Err(crate::Error::OggUnexpectedSignature(head.magic_signature));
+        let ms = (hours * 3600 + minutes * 60) * 1000 + (seconds * 1000.).round() as u64;
+        Some(ms * 48)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub head: OpusHead,
+    pub tags: OpusTags,
+    pub last_granule_position: u64,
+    pub duration: std::time::Duration,
+}
+
+/// Extracts the headers and duration of a complete ogg/opus stream held in memory, e.g. a
+/// memory mapped file. Only the header pages and the last page of the stream are parsed.
+pub fn inspect(data: &[u8]) -> Result<StreamInfo> {
+    let mut pr = crate::ogg_pager::PacketReader::new();
+    // The OpusTags packet can span many pages when it holds pictures, the data is fed in chunks
+    // until both header packets are complete.
+    let mut chunks = data.chunks(65_536);
+    let mut next_packet = |what: &str| -> Result<Vec<u8>> {
+        loop {
+            if let Some(packet) = pr.next()? {
+                return Ok(packet);
+            }
+            match chunks.next() {
+                Some(chunk) => pr.append_bytes(chunk),
+                None => crate::bail!("no {what} packet found"),
+            }
+        }
+    };
+    let head = OpusHead::from_slice(&next_packet("OpusHead")?)?;
+    let tags = OpusTags::from_slice(&next_packet("OpusTags")?)?;
+    let last_granule_position = crate::ogg_pager::last_granule_position(data).unwrap_or(0);
+    // Granule positions always use a 48kHz rate for opus.
+    let samples = last_granule_position.saturating_sub(head.pre_skip as u64);
+    let duration = std::time::Duration::from_secs_f64(samples as f64 / 48_000.);
+    Ok(StreamInfo { head, tags, last_granule_position, duration })
+}
+
+pub use crate::opus::EncoderConfig;
+
+/// How `Encoder` groups the encoded packets into ogg pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Paging {
+    /// Each packet is written on its own page as soon as it is encoded, for live streaming.
+    PacketPerPage,
+    /// Packets are accumulated on a page until it holds `max_duration` of audio or `max_bytes`
+    /// of packet data. This reduces the ogg overhead for files, at the cost of holding back one
+    /// packet and up to a full page before it is returned.
+    Grouped { max_duration: std::time::Duration, max_bytes: usize },
+}
+
+impl Paging {
+    /// Pages of up to one second, the default recommended for files by RFC 7845.
+    pub fn file() -> Self {
+        Self::Grouped {
+            max_duration: std::time::Duration::from_secs(1),
+            max_bytes: MAX_PAGE_SEGMENTS * 255,
         }
-        Ok(head)
     }
 }
 
-// This must be an allowed value among 120, 240, 480, 960, 1920, and 2880.
-// Using a different value would result in a BadArg "invalid argument" error when calling encode.
-// https://opus-codec.org/docs/opus_api-1.2/group__opus__encoder.html#ga4ae9905859cd241ef4bb5c59cd5e5309
-const OPUS_ENCODER_FRAME_SIZE: usize = 960;
+// An ogg page has at most 255 lacing values, each packet uses one per 255 bytes plus one.
+const MAX_PAGE_SEGMENTS: usize = 255;
+
+// The packets on the page being filled in `Paging::Grouped` mode.
+#[derive(Debug, Clone, Default)]
+struct GroupedPage {
+    // Granule position at the start of the page.
+    start: u64,
+    bytes: usize,
+    segments: usize,
+    // The last packet, only written once it is known whether the next one fits on the page.
+    pending: Option<crate::opus::Packet>,
+}
 
 pub struct Encoder {
-    pw: ogg::PacketWriter<'static, Vec<u8>>,
-    encoder: opus2::Encoder,
-    total_data: usize,
-    sample_rate: usize,
+    pw: crate::ogg_pager::PageWriter,
+    encoder: crate::opus::Encoder,
     header_data: Vec<u8>,
-    out_pcm: std::collections::VecDeque<f32>,
-    opus_buf: Vec<u8>,
+    step_size: Option<usize>,
+    step_index: u64,
+    latency_tracker: Option<crate::latency::LatencyTracker>,
+    paging: Paging,
+    page: GroupedPage,
 }
 
-fn write_opus_header<W: std::io::Write>(w: &mut W) -> std::io::Result<()> {
+pub(crate) fn write_opus_header<W: std::io::Write>(
+    w: &mut W,
+    channel_mapping: &crate::opus::ChannelMapping,
+) -> std::io::Result<()> {
     use byteorder::WriteBytesExt;
 
     // https://wiki.xiph.org/OggOpus#ID_Header
     w.write_all(b"OpusHead")?;
     w.write_u8(1)?; // version
-    w.write_u8(1)?; // channel count
+    w.write_u8(channel_mapping.channels() as u8)?; // channel count
     w.write_u16::<byteorder::LittleEndian>(3840)?; // pre-skip
     w.write_u32::<byteorder::LittleEndian>(48000)?; //  sample-rate in Hz
     w.write_i16::<byteorder::LittleEndian>(0)?; // output gain Q7.8 in dB
-    w.write_u8(0)?; // channel map
+    w.write_u8(channel_mapping.family)?; // channel map
+    if channel_mapping.family != 0 {
+        w.write_u8(channel_mapping.streams)?;
+        w.write_u8(channel_mapping.coupled_streams)?;
+        w.write_all(&channel_mapping.mapping)?;
+    }
     Ok(())
 }
 
-fn write_opus_tags<W: std::io::Write>(w: &mut W) -> std::io::Result<()> {
+/// Vendor string written in the OpusTags header unless `EncoderConfig::vendor` is set.
+pub const DEFAULT_VENDOR: &str = "KyutaiMoshi";
+
+pub(crate) fn write_opus_tags<W: std::io::Write>(
+    w: &mut W,
+    vendor: &str,
+    comments: &[(String, String)],
+) -> std::io::Result<()> {
     use byteorder::WriteBytesExt;
 
     // https://wiki.xiph.org/OggOpus#Comment_Header
-    let vendor = "KyutaiMoshi";
     w.write_all(b"OpusTags")?;
     w.write_u32::<byteorder::LittleEndian>(vendor.len() as u32)?; // vendor string length
     w.write_all(vendor.as_bytes())?; // vendor string, UTF8 encoded
-    w.write_u32::<byteorder::LittleEndian>(0u32)?; // number of tags
+    w.write_u32::<byteorder::LittleEndian>(comments.len() as u32)?; // number of tags
+    for (key, value) in comments.iter() {
+        let comment = format!("{key}={value}");
+        w.write_u32::<byteorder::LittleEndian>(comment.len() as u32)?;
+        w.write_all(comment.as_bytes())?;
+    }
     Ok(())
 }
 
+// Formats a unix timestamp as RFC3339 in UTC, e.g. 2024-03-01T12:34:56Z.
+fn rfc3339(unix_secs: u64) -> String {
+    let days = (unix_secs / 86400) as i64;
+    let secs = unix_secs % 86400;
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let (h, m, s) = (secs / 3600, (secs / 60) % 60, secs % 60);
+    format!("{year:04}-{month:02}-{day:02}T{h:02}:{m:02}:{s:02}Z")
+}
+
+fn tags_comments(cfg: &EncoderConfig) -> Vec<(String, String)> {
+    let mut comments: Vec<(String, String)> = cfg
+        .chapters
+        .iter()
+        .enumerate()
+        .flat_map(|(index, chapter)| chapter.comments(index))
+        .collect();
+    comments.extend(cfg.comments.iter().cloned());
+    if cfg.creation_metadata {
+        let mut stamps = vec![("ENCODER", format!("kaudio {}", env!("CARGO_PKG_VERSION")))];
+        if !cfg.deterministic {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            stamps.push(("CREATION_TIME", rfc3339(now)))
+        }
+        stamps.push(("SAMPLE_RATE", cfg.sample_rate.to_string()));
+        for (key, value) in stamps {
+            if !cfg.comments.iter().any(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                comments.push((key.to_string(), value))
+            }
+        }
+    }
+    comments
+}
+
 impl Encoder {
+    /// The sample rate must be one of [`crate::opus::SAMPLE_RATES`].
     pub fn new(sample_rate: usize) -> Result<Self> {
-        let encoder = opus2::Encoder::new(
-            sample_rate as u32,
-            opus2::Channels::Mono,
-            opus2::Application::Voip,
-        )?;
-        let all_data = Vec::new();
-        let mut pw = ogg::PacketWriter::new(all_data);
+        Self::new_with_config(EncoderConfig::new(sample_rate))
+    }
+
+    pub fn new_with_config(cfg: EncoderConfig) -> Result<Self> {
+        let encoder = crate::opus::Encoder::new(&cfg)?;
+        let mut pw = crate::ogg_pager::PageWriter::new(cfg.serial);
         let mut head = Vec::new();
-        write_opus_header(&mut head)?;
-        pw.write_packet(head, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
+        write_opus_header(&mut head, encoder.channel_mapping())?;
+        pw.write_packet(&head, 0, PacketEnd::EndPage);
         let mut tags = Vec::new();
-        write_opus_tags(&mut tags)?;
-        pw.write_packet(tags, 42, ogg::PacketWriteEndInfo::EndPage, 0)?;
-        let header_data = {
-            let inner = pw.inner_mut();
-            let data = inner.to_vec();
-            inner.clear();
-            data
-        };
-        let out_pcm = std::collections::VecDeque::with_capacity(2 * OPUS_ENCODER_FRAME_SIZE);
-        let opus_buf = vec![0u8; 50_000];
-        Ok(Self { encoder, pw, header_data, total_data: 0, out_pcm, opus_buf, sample_rate })
+        if cfg.chapters.len() > 1000 {
+            crate::bail!("too many chapters {}, at most 1000 are supported", cfg.chapters.len())
+        }
+        let comments = tags_comments(&cfg);
+        if let Some((key, _)) = comments.iter().find(|(key, _)| !valid_comment_key(key)) {
+            crate::bail!("invalid OpusTags comment key {key:?}")
+        }
+        let vendor = cfg.vendor.as_deref().unwrap_or(DEFAULT_VENDOR);
+        write_opus_tags(&mut tags, vendor, &comments)?;
+        pw.write_packet(&tags, 0, PacketEnd::EndPage);
+        let header_data = pw.take_data();
+        Ok(Self {
+            encoder,
+            pw,
+            header_data,
+            step_size: cfg.step_size,
+            step_index: 0,
+            latency_tracker: None,
+            paging: cfg.paging,
+            page: GroupedPage::default(),
+        })
     }
 
     pub fn header_data(&self) -> &[u8] {
         self.header_data.as_slice()
     }
 
+    /// Position of the end of the last encoded frame, in samples at 48kHz.
+    pub fn granule_position(&self) -> u64 {
+        self.encoder.granule_position()
+    }
+
+    /// Number of samples per channel encoded in each packet.
+    pub fn frame_size(&self) -> usize {
+        self.encoder.frame_size()
+    }
+
+    pub fn channels(&self) -> usize {
+        self.encoder.channels()
+    }
+
+    /// Number of samples per channel waiting for a full frame to be available.
+    pub fn buffered_samples(&self) -> usize {
+        self.encoder.buffered_samples()
+    }
+
+    /// See [`crate::opus::Encoder::input_stats`].
+    pub fn input_stats(&self) -> &crate::levels::SampleStats {
+        self.encoder.input_stats()
+    }
+
+    /// See [`crate::opus::Encoder::int16_scaling_detected`].
+    pub fn int16_scaling_detected(&self) -> bool {
+        self.encoder.int16_scaling_detected()
+    }
+
+    /// See [`crate::opus::Encoder::set_bitrate_controller`].
+    pub fn set_bitrate_controller(&mut self, controller: crate::opus::BitrateController) {
+        self.encoder.set_bitrate_controller(controller)
+    }
+
+    pub fn report_congestion(&mut self, signals: crate::opus::CongestionSignals) {
+        self.encoder.report_congestion(signals)
+    }
+
+    pub fn settings(&self) -> crate::opus::EncoderSettings {
+        self.encoder.settings()
+    }
+
+    /// See [`crate::opus::Encoder::set_bitrate`], the new bitrate applies within the same ogg
+    /// stream.
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<()> {
+        self.encoder.set_bitrate(bitrate)
+    }
+
+    /// See [`crate::opus::Encoder::set_complexity`].
+    pub fn set_complexity(&mut self, complexity: u8) -> Result<()> {
+        self.encoder.set_complexity(complexity)
+    }
+
+    /// Marks the input and output times of the encoder on `tracker`.
+    pub fn set_latency_tracker(&mut self, tracker: crate::latency::LatencyTracker) {
+        self.latency_tracker = Some(tracker)
+    }
+
+    fn mark_input(&self, pcm_len: usize) {
+        if let Some(tracker) = self.latency_tracker.as_ref() {
+            let buffered = self.encoder.buffered_samples() as u64;
+            let input = (pcm_len / self.encoder.input_channels()) as u64;
+            let granule_position = self.encoder.granule_position()
+                + buffered * 48_000 / self.encoder.sample_rate() as u64
+                + input * 48_000 / self.encoder.input_sample_rate() as u64;
+            tracker.mark_input(granule_position)
+        }
+    }
+
+    /// Registers a callback invoked for each page produced by `encode_page`.
+    pub fn set_page_hook(&mut self, hook: crate::ogg_pager::PageHook) {
+        self.pw.set_page_hook(hook)
+    }
+
     pub fn encode_page(&mut self, pcm: &[f32]) -> Result<Vec<u8>> {
         let mut encoded = vec![];
-        self.out_pcm.extend(pcm.iter());
-        let nchunks = self.out_pcm.len() / OPUS_ENCODER_FRAME_SIZE;
-        for _chunk_id in 0..nchunks {
-            let mut chunk = Vec::with_capacity(OPUS_ENCODER_FRAME_SIZE);
-            for _i in 0..OPUS_ENCODER_FRAME_SIZE {
-                let v = match self.out_pcm.pop_front() {
-                    None => return Err(crate::Error::OpusMissingPcm),
-                    Some(v) => v,
-                };
-                chunk.push(v)
-            }
-            self.total_data += chunk.len();
-            let size = self.encoder.encode_float(&chunk, &mut self.opus_buf)?;
-            // The granule position uses a fixed rate of 48kHz even if the underlying audio uses a
-            // different rate.
-            // This does not matter when reading ogg files in chrome but should be set properly for
-            // VLC to work.
-            let absgp = self.total_data as u64 * 48_000 / self.sample_rate as u64;
-            if size > 0 {
-                self.pw.write_packet(
-                    self.opus_buf[..size].to_vec(),
-                    42,
-                    ogg::PacketWriteEndInfo::EndPage,
-                    absgp,
-                )?;
-                let data = self.pw.inner_mut();
-                if !data.is_empty() {
-                    encoded.extend_from_slice(data);
-                    data.clear()
+        self.encode_page_into(pcm, &mut encoded)?;
+        Ok(encoded)
+    }
+
+    /// Same as `encode_page` but appends the pages to `encoded`, so that a single buffer can be
+    /// reused for a whole stream.
+    pub fn encode_page_into(&mut self, pcm: &[f32], encoded: &mut Vec<u8>) -> Result<()> {
+        self.mark_input(pcm.len());
+        let packets = self.encoder.encode(pcm)?;
+        self.write_pages(packets, encoded)
+    }
+
+    /// Appends `duration` of silence, see [`crate::opus::Encoder::push_silence`]. This keeps the
+    /// timeline correct when there is nothing to send for a while, returns the encoded pages.
+    pub fn push_silence(&mut self, duration: std::time::Duration) -> Result<Vec<u8>> {
+        let packets = self.encoder.push_silence(duration)?;
+        let mut encoded = vec![];
+        self.write_pages(packets, &mut encoded)?;
+        Ok(encoded)
+    }
+
+    fn write_pages(
+        &mut self,
+        packets: Vec<crate::opus::Packet>,
+        encoded: &mut Vec<u8>,
+    ) -> Result<()> {
+        for packet in packets {
+            match self.paging {
+                Paging::PacketPerPage => {
+                    let granule_position = packet.granule_position;
+                    self.pw.write_packet(&packet.data, granule_position, PacketEnd::EndPage);
+                    self.take_page(granule_position, encoded);
                 }
+                Paging::Grouped { max_duration, max_bytes } => {
+                    let max_duration = max_duration.as_micros() as u64 * 48 / 1000;
+                    self.push_grouped(packet, max_duration, max_bytes, encoded)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds `packet` to the current page, the pending packet ends the page when `packet` does
+    // not fit on it.
+    fn push_grouped(
+        &mut self,
+        packet: crate::opus::Packet,
+        max_duration: u64,
+        max_bytes: usize,
+        encoded: &mut Vec<u8>,
+    ) -> Result<()> {
+        let segments = packet.data.len() / 255 + 1;
+        if let Some(pending) = self.page.pending.take() {
+            let fits = self.page.bytes + packet.data.len() <= max_bytes
+                && self.page.segments + segments <= MAX_PAGE_SEGMENTS
+                && packet.granule_position.saturating_sub(self.page.start) <= max_duration;
+            let granule_position = pending.granule_position;
+            let end = if fits { PacketEnd::Normal } else { PacketEnd::EndPage };
+            self.pw.write_packet(&pending.data, granule_position, end);
+            if !fits {
+                self.take_page(granule_position, encoded);
+                self.page = GroupedPage { start: granule_position, ..GroupedPage::default() };
             }
+        } else if self.page.bytes == 0 {
+            let duration = packet_duration(&packet.data);
+            self.page.start = packet.granule_position.saturating_sub(duration);
         }
+        self.page.bytes += packet.data.len();
+        self.page.segments += segments;
+        self.page.pending = Some(packet);
+        Ok(())
+    }
+
+    /// Pads the last partial frame with silence and writes it on a final page flagged as the end
+    /// of the stream, its granule position marks the end of the actual samples so that players
+    /// trim the padding. When there is no partial frame, an empty end of stream page is written.
+    /// Returns the final bytes.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut packets = self.encoder.flush()?;
+        let mut encoded = vec![];
+        let last = match self.paging {
+            Paging::PacketPerPage => {
+                let last = packets.pop();
+                self.write_pages(packets, &mut encoded)?;
+                last
+            }
+            Paging::Grouped { .. } => {
+                self.write_pages(packets, &mut encoded)?;
+                self.page.pending.take()
+            }
+        };
+        // A page without any packet has no granule position.
+        let granule_position = last.as_ref().map_or(u64::MAX, |p| p.granule_position);
+        if let Some(packet) = last {
+            self.pw.write_packet(&packet.data, granule_position, PacketEnd::Normal);
+        }
+        self.pw.end_stream();
+        self.take_page(granule_position, &mut encoded);
         Ok(encoded)
     }
+
+    /// Encodes `pcm` as a complete ogg/opus file, including the header pages and the end of
+    /// stream page. Use `Paging::file` in the config to get pages sized for files.
+    pub fn encode_file(self, pcm: &[f32]) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.encode_to_writer(pcm, &mut data)?;
+        Ok(data)
+    }
+
+    /// Same as `encode_file` but the pages are written to `w` as they are produced.
+    pub fn encode_to_writer<W: std::io::Write>(mut self, pcm: &[f32], w: &mut W) -> Result<()> {
+        if self.granule_position() > 0 || self.buffered_samples() > 0 {
+            crate::bail!("encode_file requires an encoder that has not encoded anything yet")
+        }
+        w.write_all(&self.header_data)?;
+        // About one second of pcm per call.
+        let chunk_size = self.encoder.input_sample_rate() * self.encoder.input_channels();
+        let mut encoded = vec![];
+        for pcm in pcm.chunks(chunk_size) {
+            self.encode_page_into(pcm, &mut encoded)?;
+            w.write_all(&encoded)?;
+            encoded.clear();
+        }
+        w.write_all(&self.finish()?)?;
+        Ok(())
+    }
+
+    /// Encodes exactly one step of pcm data as configured by `EncoderConfig::step_size`, all the
+    /// packets for the step are written on a single page whatever the `EncoderConfig::paging`.
+    /// Packets still held back by `Paging::Grouped` are written at the start of this page.
+    pub fn encode_step(&mut self, pcm: &[f32]) -> Result<EncodedStep> {
+        let Some(step_size) = self.step_size else {
+            crate::bail!("encode_step requires EncoderConfig::step_size to be set")
+        };
+        let step_len = step_size * self.encoder.input_channels();
+        if pcm.len() != step_len {
+            crate::bail!("encode_step expects {step_len} samples, got {}", pcm.len())
+        }
+        self.mark_input(pcm.len());
+        // With `Paging::Grouped`, the packet held back by `encode_page` goes on the step page
+        // ahead of the step packets so that it is not lost or written out of order.
+        let mut packets: Vec<_> = self.page.pending.take().into_iter().collect();
+        packets.extend(self.encoder.encode(pcm)?);
+        self.page = GroupedPage::default();
+        let n_packets = packets.len();
+        let mut data = vec![];
+        for (i, packet) in packets.into_iter().enumerate() {
+            let end = if i + 1 == n_packets { PacketEnd::EndPage } else { PacketEnd::Normal };
+            self.pw.write_packet(&packet.data, packet.granule_position, end);
+        }
+        let granule_position = self.encoder.granule_position();
+        self.take_page(granule_position, &mut data);
+        let step = EncodedStep { step_index: self.step_index, granule_position, data };
+        self.step_index += 1;
+        Ok(step)
+    }
+
+    // Moves the pages written by the page writer to `encoded`.
+    fn take_page(&mut self, granule_position: u64, encoded: &mut Vec<u8>) {
+        let data = self.pw.take_data();
+        if data.is_empty() {
+            return;
+        }
+        if let Some(tracker) = self.latency_tracker.as_ref() {
+            tracker.mark_encoded(granule_position)
+        }
+        if encoded.is_empty() {
+            // Hand out the page buffer rather than copying it.
+            *encoded = data
+        } else {
+            encoded.extend_from_slice(&data);
+        }
+    }
+}
+
+/// The output of `Encoder::encode_step`, a single ogg page holding a full step.
+#[derive(Debug, Clone)]
+pub struct EncodedStep {
+    pub step_index: u64,
+    pub granule_position: u64,
+    pub data: Vec<u8>,
+}
+
+/// When the decoders return the decoded pcm to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flush as soon as some samples have been decoded. For the sync decoders, this flushes
+    /// everything decoded from the data passed to a single `decode` call.
+    PerPacket,
+    /// Flush once at least this number of samples per channel are available.
+    EveryNSamples(usize),
+    /// Flush the available samples once this duration has elapsed since the last flush.
+    Interval(std::time::Duration),
+    /// Only flush on explicit calls to `flush`.
+    Explicit,
+}
+
+impl FlushPolicy {
+    fn should_flush(&self, size_in_buf: usize, last_flush: std::time::Instant) -> bool {
+        match self {
+            Self::PerPacket => size_in_buf > 0,
+            Self::EveryNSamples(n) => size_in_buf >= *n,
+            Self::Interval(d) => size_in_buf > 0 && last_flush.elapsed() >= *d,
+            Self::Explicit => false,
+        }
+    }
+
+    fn buffer_capacity(&self, sample_rate: usize) -> usize {
+        match self {
+            Self::EveryNSamples(n) => n + sample_rate * 5,
+            _ => sample_rate * 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecoderConfig {
+    pub sample_rate: usize,
+    pub flush_policy: FlushPolicy,
+    /// Always run libopus at 48kHz and resample its output to `sample_rate` rather than asking
+    /// libopus for a lower internal rate. This trades some CPU for better quality.
+    pub decode_at_48khz: bool,
+    /// How gaps in the stream, detected through the page granule positions, are filled.
+    pub concealment: crate::opus::ConcealmentPolicy,
+    /// Longest gap that gets concealed, the decoder position is rebased on the pages following
+    /// longer gaps, e.g. a corrupt granule position. See `opus::Decoder::set_max_gap`.
+    pub max_gap: std::time::Duration,
+    /// Drop the pre-skip samples at the start of the stream and apply the output gain, as
+    /// specified in the OpusHead header.
+    pub apply_pre_skip_and_gain: bool,
+    /// What to do with pages whose checksum does not match, checksums are not checked by
+    /// default.
+    pub checksum_policy: crate::ogg_pager::ChecksumPolicy,
+    /// Skip garbage up to the next page rather than failing, see `PageReader::set_resync`.
+    pub resync: bool,
+    /// Duration decoded and discarded before the target of a seek so that the decoder state has
+    /// converged when the output starts, RFC 7845 recommends at least 80ms.
+    pub preroll: std::time::Duration,
+    /// `AsyncDecoder::read` fails when no data has been received for this duration, so that
+    /// dead streams can be torn down. See `AsyncDecoder::is_stalled`.
+    pub watchdog: Option<std::time::Duration>,
+    /// Limit on the ogg data buffered by the decoder, including a packet spanning several pages,
+    /// decoding fails when a stream exceeds it. See `PacketReader::set_max_buffered`.
+    pub max_buffered: Option<usize>,
+}
+
+impl DecoderConfig {
+    pub fn new(sample_rate: usize, flush_every_n_samples: usize) -> Self {
+        let flush_policy = FlushPolicy::EveryNSamples(flush_every_n_samples);
+        Self {
+            sample_rate,
+            flush_policy,
+            decode_at_48khz: false,
+            concealment: crate::opus::ConcealmentPolicy::Nothing,
+            max_gap: std::time::Duration::from_secs(1),
+            apply_pre_skip_and_gain: true,
+            checksum_policy: crate::ogg_pager::ChecksumPolicy::Ignore,
+            resync: false,
+            preroll: std::time::Duration::from_millis(80),
+            watchdog: None,
+            max_buffered: None,
+        }
+    }
+}
+
+// Called before decoding the first packet of each page, the start of the page is compared to
+// the current decoder position to detect missing pages. Returns the number of samples appended
+// to `pcm`.
+fn conceal_gap(
+    pr: &crate::ogg_pager::PacketReader,
+    decoder: &mut crate::opus::Decoder,
+    packet: &[u8],
+    pcm: &mut Vec<f32>,
+) -> Result<usize> {
+    let Some(page_start) = page_start(pr, packet) else { return Ok(0) };
+    if decoder.stats().packets == 0 {
+        decoder.set_position(page_start);
+        return Ok(0);
+    }
+    if page_start > decoder.position() {
+        let len = decoder.conceal_before(page_start - decoder.position(), Some(packet), pcm)?;
+        // Gaps above the decoder maximum are not concealed, the position jumps to the page.
+        decoder.set_position(page_start);
+        return Ok(len);
+    }
+    Ok(0)
+}
+
+// Duration of a packet in samples at 48kHz, 0 if the packet cannot be parsed.
+fn packet_duration(packet: &[u8]) -> u64 {
+    crate::opus::Toc::parse(packet).map_or(0, |toc| toc.duration_us() as u64 * 48 / 1000)
+}
+
+// Start position of the current page, `packet` being its first packet. `None` when no packet ends
+// on the page.
+fn page_start(pr: &crate::ogg_pager::PacketReader, packet: &[u8]) -> Option<u64> {
+    let granule_position = pr.last_granule_position();
+    if granule_position == u64::MAX {
+        return None;
+    }
+    let page_duration =
+        packet_duration(packet) + pr.pending_packets().map(packet_duration).sum::<u64>();
+    Some(granule_position.saturating_sub(page_duration))
 }
 
-pub struct AsyncDecoder {
-    pr_ogg: ogg::reading::async_api::PacketReader<tokio::io::DuplexStream>,
-    decoder: opus2::Decoder,
+// Returns true when `packet` ends before the preroll that precedes the seek target. The first
+// decoded packet drops its output up to the seek target.
+fn skip_before_seek_target(
+    pr: &crate::ogg_pager::PacketReader,
+    decoder: &mut crate::opus::Decoder,
+    preroll: u64,
+    target: u64,
+    packet: &[u8],
+) -> bool {
+    if pr.is_first_of_page() {
+        match page_start(pr, packet) {
+            Some(page_start) => decoder.set_position(page_start),
+            // The position is only known once a packet ends on a page.
+            None => return true,
+        }
+    }
+    let start = decoder.position();
+    let end = start + packet_duration(packet);
+    if end + preroll <= target {
+        decoder.set_position(end);
+        return true;
+    }
+    if start < target {
+        decoder.set_pre_skip((target - start) as usize)
+    }
+    false
+}
+
+// Re-creates the libopus decoder when the OpusHead packet announces a different channel layout,
+// and sets up the pre-skip and output gain of the stream.
+pub(crate) fn apply_head(
+    decoder: &mut crate::opus::Decoder,
+    packet: &[u8],
+    apply_pre_skip_and_gain: bool,
+) -> Result<OpusHead> {
+    let head = OpusHead::from_slice(packet)?;
+    let mapping = OpusHead::channel_mapping(packet)?;
+    if &mapping != decoder.channel_mapping() {
+        decoder.set_channel_mapping(&mapping)?
+    }
+    if apply_pre_skip_and_gain {
+        decoder.set_pre_skip(head.pre_skip as usize);
+        // The output gain is stored in Q7.8 dB.
+        decoder.set_output_gain(head.output_gain as f32 / 256.);
+    }
+    Ok(head)
+}
+
+// See `Decoder::start_offset`.
+fn start_offset(
+    head: Option<&OpusHead>,
+    decoder: &crate::opus::Decoder,
+    apply_pre_skip_and_gain: bool,
+) -> Option<usize> {
+    let pre_skip = head?.pre_skip as usize;
+    if apply_pre_skip_and_gain {
+        Some(0)
+    } else {
+        Some(pre_skip * decoder.sample_rate() / 48_000)
+    }
+}
+
+pub struct AsyncDecoder<R = tokio::io::DuplexStream> {
+    reader: R,
+    read_buf: Vec<u8>,
+    pr_ogg: crate::ogg_pager::PacketReader,
+    decoder: crate::opus::Decoder,
     pcm_buf: Vec<f32>,
     size_in_buf: usize,
-    flush_every_n_samples: usize,
+    flush_policy: FlushPolicy,
+    last_flush: std::time::Instant,
+    apply_pre_skip_and_gain: bool,
+    head: Option<OpusHead>,
+    tags: Option<OpusTags>,
+    watchdog: Option<std::time::Duration>,
+    // When data was last received, or when the last stall was reported.
+    last_data: tokio::time::Instant,
+    stalled: bool,
+    // Samples per channel returned to the caller.
+    samples_returned: u64,
+    latency_tracker: Option<crate::latency::LatencyTracker>,
 }
 
 pub type Sender = tokio::sync::mpsc::UnboundedSender<Vec<u8>>;
 
 impl AsyncDecoder {
     pub fn new(sample_rate: usize, flush_every_n_samples: usize) -> Result<(Self, Sender)> {
+        Self::new_with_config(DecoderConfig::new(sample_rate, flush_every_n_samples))
+    }
+
+    /// The data sent on the returned channel is forwarded to the decoder by a spawned task, use
+    /// `from_reader` to read directly from a socket or a body stream.
+    pub fn new_with_config(cfg: DecoderConfig) -> Result<(Self, Sender)> {
         use tokio::io::AsyncWriteExt;
 
-        let pcm_buf = vec![0f32; flush_every_n_samples + sample_rate * 5];
         let (mut tx_tokio, rx_tokio) = tokio::io::duplex(100_000);
         let (tx_sync, mut rx_sync) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
-        let pr_ogg = ogg::reading::async_api::PacketReader::new(rx_tokio);
-        let decoder = opus2::Decoder::new(sample_rate as u32, opus2::Channels::Mono)?;
+        let s = Self::from_reader(rx_tokio, cfg)?;
         tokio::task::spawn(async move {
             // It is important to use a tokio mpsc channel here to avoid starving the other
             // threads.
@@ -166,74 +849,1320 @@ impl AsyncDecoder {
             }
             Ok::<_, crate::Error>(())
         });
-        let s = Self { pr_ogg, decoder, pcm_buf, size_in_buf: 0, flush_every_n_samples };
         Ok((s, tx_sync))
     }
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> AsyncDecoder<R> {
+    /// Decodes the ogg/opus stream read from `reader`, e.g. a `TcpStream` or a websocket or
+    /// http body adapted to `AsyncRead`, without any intermediate task. The end of the reader
+    /// ends the stream.
+    pub fn from_reader(reader: R, cfg: DecoderConfig) -> Result<Self> {
+        let pcm_buf = Vec::with_capacity(cfg.flush_policy.buffer_capacity(cfg.sample_rate));
+        let mut pr_ogg = crate::ogg_pager::PacketReader::new();
+        pr_ogg.set_checksum_policy(cfg.checksum_policy);
+        pr_ogg.set_resync(cfg.resync);
+        pr_ogg.set_max_buffered(cfg.max_buffered)?;
+        let mut decoder = crate::opus::Decoder::new(cfg.sample_rate, cfg.decode_at_48khz)?;
+        decoder.set_concealment(cfg.concealment.clone());
+        decoder.set_max_gap(cfg.max_gap);
+        Ok(Self {
+            reader,
+            read_buf: vec![0u8; 4096],
+            pr_ogg,
+            decoder,
+            pcm_buf,
+            size_in_buf: 0,
+            flush_policy: cfg.flush_policy,
+            last_flush: std::time::Instant::now(),
+            apply_pre_skip_and_gain: cfg.apply_pre_skip_and_gain,
+            head: None,
+            tags: None,
+            watchdog: cfg.watchdog,
+            last_data: tokio::time::Instant::now(),
+            stalled: false,
+            samples_returned: 0,
+            latency_tracker: None,
+        })
+    }
+
+    /// Changes the playback speed, between 0.5 and 2, this can be called mid-stream.
+    pub fn set_playback_speed(&mut self, speed: f64, mode: crate::speed::SpeedMode) -> Result<()> {
+        self.decoder.set_playback_speed(speed, mode)
+    }
+
+    /// Registers a callback invoked for each page once it has been fully read.
+    pub fn set_page_hook(&mut self, hook: crate::ogg_pager::PageHook) {
+        self.pr_ogg.set_page_hook(hook)
+    }
+
+    /// Marks the input and output times of the decoder on `tracker`.
+    pub fn set_latency_tracker(&mut self, tracker: crate::latency::LatencyTracker) {
+        self.latency_tracker = Some(tracker)
+    }
+
+    pub fn stats(&self) -> &crate::opus::DecoderStats {
+        self.decoder.stats()
+    }
+
+    /// Number of channels of the stream, as announced by its OpusHead packet. The decoded pcm is
+    /// interleaved when there is more than one channel.
+    pub fn channels(&self) -> usize {
+        self.decoder.channels()
+    }
+
+    /// Number of pages with a checksum mismatch, see `DecoderConfig::checksum_policy`.
+    pub fn corrupt_pages(&self) -> u64 {
+        self.pr_ogg.corrupt_pages()
+    }
+
+    /// The OpusHead header of the stream, once it has been read.
+    pub fn head(&self) -> Option<&OpusHead> {
+        self.head.as_ref()
+    }
+
+    /// The OpusTags header of the stream, once it has been read. This is `None` when the header
+    /// is malformed, the audio can still be decoded.
+    pub fn tags(&self) -> Option<&OpusTags> {
+        self.tags.as_ref()
+    }
+
+    /// Number of samples at the start of the decoded output that precede the start of the
+    /// stream, i.e. the OpusHead pre-skip at the output rate. This is 0 when the decoder drops
+    /// the pre-skip, see `DecoderConfig::apply_pre_skip_and_gain`, and `None` until the OpusHead
+    /// packet has been read. Output sample `start_offset` is at time 0 of the encoded audio, on
+    /// top of the resampling delay reported by `output_delay`.
+    pub fn start_offset(&self) -> Option<usize> {
+        start_offset(self.head.as_ref(), &self.decoder, self.apply_pre_skip_and_gain)
+    }
+
+    /// See [`crate::opus::Decoder::output_delay`].
+    pub fn output_delay(&self) -> usize {
+        self.decoder.output_delay()
+    }
+
+    /// Number of samples per channel returned by `read` and `flush` so far.
+    pub fn samples_decoded(&self) -> u64 {
+        self.samples_returned
+    }
+
+    /// Position in the stream of the end of the audio returned so far, as a granule position
+    /// (samples at 48kHz). Gaps concealed in the stream are included.
+    pub fn granule_position(&self) -> u64 {
+        let pending = (self.size_in_buf / self.decoder.channels()) as u64;
+        let pending = pending * 48_000 / self.decoder.sample_rate() as u64;
+        self.decoder.position().saturating_sub(pending)
+    }
+
+    /// Time of the end of the audio returned so far, from the start of the stream after the
+    /// pre-skip. This can drive progress bars and subtitle synchronization.
+    pub fn position(&self) -> std::time::Duration {
+        let pre_skip = self.head.as_ref().map_or(0, |head| head.pre_skip as u64);
+        let samples = self.granule_position().saturating_sub(pre_skip);
+        std::time::Duration::from_secs_f64(samples as f64 / 48_000.)
+    }
+
+    /// Whether the last read failed because of the watchdog, this is reset once some data is
+    /// received. Reading again after a stall waits for another watchdog duration.
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
 
     pub async fn read(&mut self) -> Result<Option<&[f32]>> {
-        use futures_util::StreamExt;
+        let size = self.read_inner().await?;
+        Ok(size.map(|size| &self.pcm_buf[..size]))
+    }
+
+    /// Same as `read` but returns an owned buffer that can be sent to other tasks, the internal
+    /// buffer is handed out rather than copied.
+    pub async fn read_owned(&mut self) -> Result<Option<Vec<f32>>> {
+        let size = self.read_inner().await?;
+        Ok(size.map(|_| take_buf(&mut self.pcm_buf)))
+    }
 
+    /// Same as `read` but returns one buffer per channel.
+    pub async fn read_planar(&mut self) -> Result<Option<Vec<Vec<f32>>>> {
+        let size = self.read_inner().await?;
+        let channels = self.decoder.channels();
+        Ok(size.map(|size| crate::deinterleave(&self.pcm_buf[..size], channels)))
+    }
+
+    /// Turns the decoder into a stream of the buffers returned by `read_owned`, followed by the
+    /// samples left below the flush threshold once the reader has ended, so that it composes
+    /// with the `StreamExt` combinators. A stall reported by the watchdog does not end the
+    /// stream, the stream ends after any other error.
+    pub fn into_stream(self) -> impl futures_util::Stream<Item = Result<Vec<f32>>> {
+        futures_util::stream::unfold(Some(self), |decoder| async move {
+            let mut decoder = decoder?;
+            match decoder.read_owned().await {
+                Ok(Some(pcm)) => Some((Ok(pcm), Some(decoder))),
+                Ok(None) => {
+                    let pcm = decoder.flush();
+                    (!pcm.is_empty()).then(|| (Ok(pcm.to_vec()), None))
+                }
+                Err(err) if decoder.is_stalled() => Some((Err(err), Some(decoder))),
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
+
+    // Returns the number of samples to flush at the start of `pcm_buf`, errors are annotated
+    // with the position of the last page read.
+    async fn read_inner(&mut self) -> Result<Option<usize>> {
+        match self.read_packets().await {
+            Ok(size) => Ok(size),
+            Err(err) => Err(err.with_position(self.pr_ogg.stream_position())),
+        }
+    }
+
+    async fn read_packets(&mut self) -> Result<Option<usize>> {
+        use tokio::io::AsyncReadExt;
+
+        self.pcm_buf.truncate(self.size_in_buf);
         loop {
-            let packet = match self.pr_ogg.next().await {
-                None => return Ok(None),
-                Some(v) => v?,
+            let packet = match self.pr_ogg.advance()? {
+                true => self.pr_ogg.packet().map_or(&[][..], |packet| packet.data),
+                false => {
+                    let read = self.reader.read(&mut self.read_buf);
+                    let n = match self.watchdog {
+                        None => read.await?,
+                        Some(watchdog) => {
+                            match tokio::time::timeout_at(self.last_data + watchdog, read).await {
+                                Ok(n) => n?,
+                                Err(_) => {
+                                    self.stalled = true;
+                                    self.last_data = tokio::time::Instant::now();
+                                    return Err(crate::Error::Stalled(watchdog));
+                                }
+                            }
+                        }
+                    };
+                    if n == 0 {
+                        return Ok(None);
+                    }
+                    self.last_data = tokio::time::Instant::now();
+                    self.stalled = false;
+                    self.pr_ogg.append_bytes_bounded(&self.read_buf[..n])?;
+                    continue;
+                }
             };
-            if packet.data.starts_with(b"OpusHead") || packet.data.starts_with(b"OpusTags") {
+            if packet.starts_with(b"OpusHead") {
+                let head = apply_head(&mut self.decoder, packet, self.apply_pre_skip_and_gain)?;
+                self.head = Some(head);
                 continue;
             }
-            let read_size = self.decoder.decode_float(
-                &packet.data,
-                &mut self.pcm_buf[self.size_in_buf..],
-                /* Forward Error Correction */ false,
-            )?;
-            self.size_in_buf += read_size;
-            // flush the data every half timestep
-            if self.size_in_buf >= self.flush_every_n_samples {
-                let size_in_buf = self.size_in_buf;
-                self.size_in_buf = 0;
-                return Ok(Some(&self.pcm_buf[..size_in_buf]));
+            if packet.starts_with(b"OpusTags") {
+                self.tags = OpusTags::from_slice(packet).ok();
+                continue;
+            }
+            if let Some(tracker) = self.latency_tracker.as_ref() {
+                tracker.mark_received(self.pr_ogg.last_granule_position())
+            }
+            if self.pr_ogg.is_first_of_page() {
+                self.size_in_buf +=
+                    conceal_gap(&self.pr_ogg, &mut self.decoder, packet, &mut self.pcm_buf)?;
             }
+            self.size_in_buf += self.decoder.decode(packet, &mut self.pcm_buf)?;
+            let frames = self.size_in_buf / self.decoder.channels();
+            if self.flush_policy.should_flush(frames, self.last_flush) {
+                return Ok(Some(self.take_flush()));
+            }
+        }
+    }
+
+    fn take_flush(&mut self) -> usize {
+        if let Some(tracker) = self.latency_tracker.as_ref() {
+            tracker.mark_output(self.pr_ogg.last_granule_position())
         }
+        self.last_flush = std::time::Instant::now();
+        let size = std::mem::take(&mut self.size_in_buf);
+        self.samples_returned += (size / self.decoder.channels()) as u64;
+        size
     }
+
+    /// Returns the samples decoded since the last flush, whatever the flush policy. When the
+    /// stream has ended, this returns the samples that have not reached the flush threshold.
+    pub fn flush(&mut self) -> &[f32] {
+        let size = self.take_flush();
+        &self.pcm_buf[..size]
+    }
+}
+
+// Hands out the flushed samples, all the samples in `pcm_buf` are flushed at once.
+fn take_buf<T>(pcm_buf: &mut Vec<T>) -> Vec<T> {
+    let capacity = pcm_buf.capacity();
+    std::mem::replace(pcm_buf, Vec::with_capacity(capacity))
 }
 
 pub struct Decoder {
     pr_ogg: crate::ogg_pager::PacketReader,
-    decoder: opus2::Decoder,
+    decoder: crate::opus::Decoder,
     pcm_buf: Vec<f32>,
     size_in_buf: usize,
-    flush_every_n_samples: usize,
+    flush_policy: FlushPolicy,
+    last_flush: std::time::Instant,
+    latency_tracker: Option<crate::latency::LatencyTracker>,
+    apply_pre_skip_and_gain: bool,
+    head: Option<OpusHead>,
+    tags: Option<OpusTags>,
+    preroll: u64,
+    // Target granule position of a seek, until the first page after the seek has been read.
+    seek_target: Option<u64>,
 }
 
 impl Decoder {
     pub fn new(sample_rate: usize, flush_every_n_samples: usize) -> Result<Self> {
-        let pcm_buf = vec![0f32; flush_every_n_samples + sample_rate * 5];
-        let pr_ogg = crate::ogg_pager::PacketReader::new();
-        let decoder = opus2::Decoder::new(sample_rate as u32, opus2::Channels::Mono)?;
-        let s = Self { pr_ogg, decoder, pcm_buf, size_in_buf: 0, flush_every_n_samples };
+        Self::new_with_config(DecoderConfig::new(sample_rate, flush_every_n_samples))
+    }
+
+    pub fn new_with_config(cfg: DecoderConfig) -> Result<Self> {
+        let pcm_buf = Vec::with_capacity(cfg.flush_policy.buffer_capacity(cfg.sample_rate));
+        let mut pr_ogg = crate::ogg_pager::PacketReader::new();
+        pr_ogg.set_checksum_policy(cfg.checksum_policy);
+        pr_ogg.set_resync(cfg.resync);
+        pr_ogg.set_max_buffered(cfg.max_buffered)?;
+        let mut decoder = crate::opus::Decoder::new(cfg.sample_rate, cfg.decode_at_48khz)?;
+        decoder.set_concealment(cfg.concealment.clone());
+        decoder.set_max_gap(cfg.max_gap);
+        let s = Self {
+            pr_ogg,
+            decoder,
+            pcm_buf,
+            size_in_buf: 0,
+            flush_policy: cfg.flush_policy,
+            last_flush: std::time::Instant::now(),
+            latency_tracker: None,
+            apply_pre_skip_and_gain: cfg.apply_pre_skip_and_gain,
+            head: None,
+            tags: None,
+            preroll: cfg.preroll.as_micros() as u64 * 48 / 1000,
+            seek_target: None,
+        };
         Ok(s)
     }
 
+    /// Decodes the ogg/opus stream pulled from `reader`, e.g. a file or a pipe, rather than from
+    /// the chunks passed to `decode`. The end of the reader ends the stream.
+    pub fn from_reader<R: std::io::Read>(reader: R, cfg: DecoderConfig) -> Result<ReadDecoder<R>> {
+        let decoder = Self::new_with_config(cfg)?;
+        Ok(ReadDecoder { reader, decoder, buf: vec![0u8; FILE_CHUNK_SIZE], ended: false })
+    }
+
+    /// Changes the playback speed, between 0.5 and 2, this can be called mid-stream.
+    pub fn set_playback_speed(&mut self, speed: f64, mode: crate::speed::SpeedMode) -> Result<()> {
+        self.decoder.set_playback_speed(speed, mode)
+    }
+
+    /// Registers a callback invoked for each page once it has been fully read.
+    pub fn set_page_hook(&mut self, hook: crate::ogg_pager::PageHook) {
+        self.pr_ogg.set_page_hook(hook)
+    }
+
+    pub fn stats(&self) -> &crate::opus::DecoderStats {
+        self.decoder.stats()
+    }
+
+    /// Number of channels of the stream, as announced by its OpusHead packet. The decoded pcm is
+    /// interleaved when there is more than one channel.
+    pub fn channels(&self) -> usize {
+        self.decoder.channels()
+    }
+
+    /// Number of pages with a checksum mismatch, see `DecoderConfig::checksum_policy`.
+    pub fn corrupt_pages(&self) -> u64 {
+        self.pr_ogg.corrupt_pages()
+    }
+
+    /// The OpusHead header of the stream, once it has been read.
+    pub fn head(&self) -> Option<&OpusHead> {
+        self.head.as_ref()
+    }
+
+    /// The OpusTags header of the stream, once it has been read. This is `None` when the header
+    /// is malformed, the audio can still be decoded.
+    pub fn tags(&self) -> Option<&OpusTags> {
+        self.tags.as_ref()
+    }
+
+    /// Number of samples at the start of the decoded output that precede the start of the
+    /// stream, i.e. the OpusHead pre-skip at the output rate. This is 0 when the decoder drops
+    /// the pre-skip, see `DecoderConfig::apply_pre_skip_and_gain`, and `None` until the OpusHead
+    /// packet has been read. Output sample `start_offset` is at time 0 of the encoded audio, on
+    /// top of the resampling delay reported by `output_delay`.
+    pub fn start_offset(&self) -> Option<usize> {
+        start_offset(self.head.as_ref(), &self.decoder, self.apply_pre_skip_and_gain)
+    }
+
+    /// See [`crate::opus::Decoder::output_delay`].
+    pub fn output_delay(&self) -> usize {
+        self.decoder.output_delay()
+    }
+
+    /// Marks the input and output times of the decoder on `tracker`.
+    pub fn set_latency_tracker(&mut self, tracker: crate::latency::LatencyTracker) {
+        self.latency_tracker = Some(tracker)
+    }
+
     pub fn decode(&mut self, data: &[u8]) -> Result<Option<&[f32]>> {
-        self.pr_ogg.append_bytes(data);
-        while let Some(packet) = self.pr_ogg.next()? {
-            if packet.starts_with(b"OpusHead") || packet.starts_with(b"OpusTags") {
+        let size = self.decode_inner(data)?;
+        Ok(size.map(|size| &self.pcm_buf[..size]))
+    }
+
+    /// Same as `decode` but returns an owned buffer that can be sent to other threads, the
+    /// internal buffer is handed out rather than copied.
+    pub fn decode_owned(&mut self, data: &[u8]) -> Result<Option<Vec<f32>>> {
+        let size = self.decode_inner(data)?;
+        Ok(size.map(|_| take_buf(&mut self.pcm_buf)))
+    }
+
+    /// Prepares the decoder for data starting at a different point of the stream, the data
+    /// passed to the next `decode` call has to start at a page boundary, ideally on a page
+    /// starting at least `preroll` before `granule_position`. The packets before the preroll are
+    /// skipped, the preroll is decoded and discarded, and the output then starts exactly at
+    /// `granule_position`. Any buffered samples are dropped.
+    pub fn seek(&mut self, granule_position: u64) -> Result<()> {
+        self.pr_ogg.reset();
+        self.decoder.reset()?;
+        self.decoder.set_pre_skip(0);
+        self.pcm_buf.clear();
+        self.size_in_buf = 0;
+        self.seek_target = Some(granule_position);
+        Ok(())
+    }
+
+    /// Same as `decode` but returns one buffer per channel.
+    pub fn decode_planar(&mut self, data: &[u8]) -> Result<Option<Vec<Vec<f32>>>> {
+        let channels = self.decoder.channels();
+        let size = self.decode_inner(data)?;
+        Ok(size.map(|size| crate::deinterleave(&self.pcm_buf[..size], channels)))
+    }
+
+    fn decode_inner(&mut self, data: &[u8]) -> Result<Option<usize>> {
+        self.pcm_buf.truncate(self.size_in_buf);
+        // With a `max_buffered` limit, the data is appended as the pages are read.
+        let mut data = data;
+        loop {
+            let n = self.pr_ogg.try_append(data);
+            data = &data[n..];
+            self.decode_packets()
+                .map_err(|err| err.with_position(self.pr_ogg.stream_position()))?;
+            if data.is_empty() {
+                break;
+            }
+            if n == 0 {
+                self.pr_ogg.append_bytes_bounded(data)?;
+            }
+        }
+        let frames = self.size_in_buf / self.decoder.channels();
+        let pcm = if self.flush_policy.should_flush(frames, self.last_flush) {
+            Some(self.take_flush())
+        } else {
+            None
+        };
+        Ok(pcm)
+    }
+
+    fn decode_packets(&mut self) -> Result<()> {
+        while self.pr_ogg.advance()? {
+            let Some(packet) = self.pr_ogg.packet() else { break };
+            let packet = packet.data;
+            if packet.starts_with(b"OpusHead") {
+                let head = apply_head(&mut self.decoder, packet, self.apply_pre_skip_and_gain)?;
+                self.head = Some(head);
                 continue;
             }
-            let read_size = self.decoder.decode_float(
-                &packet,
-                &mut self.pcm_buf[self.size_in_buf..],
-                /* Forward Error Correction */ false,
-            )?;
-            self.size_in_buf += read_size;
+            if packet.starts_with(b"OpusTags") {
+                self.tags = OpusTags::from_slice(packet).ok();
+                continue;
+            }
+            if let Some(tracker) = self.latency_tracker.as_ref() {
+                tracker.mark_received(self.pr_ogg.last_granule_position())
+            }
+            if let Some(target) = self.seek_target {
+                let preroll = self.preroll;
+                if skip_before_seek_target(&self.pr_ogg, &mut self.decoder, preroll, target, packet)
+                {
+                    continue;
+                }
+                self.seek_target = None;
+            }
+            if self.pr_ogg.is_first_of_page() {
+                self.size_in_buf +=
+                    conceal_gap(&self.pr_ogg, &mut self.decoder, packet, &mut self.pcm_buf)?;
+            }
+            self.size_in_buf += self.decoder.decode(packet, &mut self.pcm_buf)?;
+        }
+        Ok(())
+    }
+
+    fn take_flush(&mut self) -> usize {
+        if let Some(tracker) = self.latency_tracker.as_ref() {
+            tracker.mark_output(self.pr_ogg.last_granule_position())
+        }
+        self.last_flush = std::time::Instant::now();
+        std::mem::take(&mut self.size_in_buf)
+    }
+
+    /// Returns the samples decoded since the last flush, whatever the flush policy.
+    pub fn flush(&mut self) -> &[f32] {
+        let size = self.take_flush();
+        &self.pcm_buf[..size]
+    }
+}
+
+// Size of the chunks read by `FileDecoder` and `ReadDecoder`.
+const FILE_CHUNK_SIZE: usize = 16_384;
+
+/// Decoder reading its input from a `std::io::Read`, created with `Decoder::from_reader`.
+pub struct ReadDecoder<R> {
+    reader: R,
+    decoder: Decoder,
+    buf: Vec<u8>,
+    ended: bool,
+}
+
+impl<R: std::io::Read> ReadDecoder<R> {
+    /// The underlying decoder, e.g. for the stream headers and the stats.
+    pub fn decoder(&self) -> &Decoder {
+        &self.decoder
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut Decoder {
+        &mut self.decoder
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads until some samples are flushed according to the flush policy of the config. Once
+    /// the reader has ended, the samples below the flush threshold are returned and then `None`.
+    pub fn read(&mut self) -> Result<Option<&[f32]>> {
+        let size = self.read_inner()?;
+        Ok(size.map(|size| &self.decoder.pcm_buf[..size]))
+    }
+
+    /// Same as `read` but returns an owned buffer, the internal buffer is handed out rather than
+    /// copied.
+    pub fn read_owned(&mut self) -> Result<Option<Vec<f32>>> {
+        let size = self.read_inner()?;
+        Ok(size.map(|_| take_buf(&mut self.decoder.pcm_buf)))
+    }
+
+    fn read_inner(&mut self) -> Result<Option<usize>> {
+        loop {
+            if self.ended {
+                let size = self.decoder.take_flush();
+                return Ok((size > 0).then_some(size));
+            }
+            let n = match self.reader.read(&mut self.buf) {
+                Ok(n) => n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if n == 0 {
+                self.ended = true;
+                continue;
+            }
+            if let Some(size) = self.decoder.decode_inner(&self.buf[..n])? {
+                return Ok(Some(size));
+            }
+        }
+    }
+}
+
+impl<R: std::io::Read> Iterator for ReadDecoder<R> {
+    type Item = Result<Vec<f32>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_owned().transpose()
+    }
+}
+
+/// Decoder for ogg/opus files supporting random access. The page headers are scanned when the
+/// file is opened to index the granule positions of the opus stream, the page bodies are only
+/// read when decoding.
+pub struct FileDecoder<R> {
+    reader: R,
+    decoder: Decoder,
+    index: SeekIndex,
+    sample_rate: usize,
+    apply_pre_skip_and_gain: bool,
+    // Position of the next returned sample and end of the stream, in samples at the output rate.
+    position: u64,
+    end: u64,
+    buf: Vec<u8>,
+    ended: bool,
+}
+
+impl<R: std::io::Read + std::io::Seek> FileDecoder<R> {
+    pub fn open(reader: R, sample_rate: usize) -> Result<Self> {
+        Self::open_with_config(reader, DecoderConfig::new(sample_rate, 0))
+    }
+
+    /// The flush policy of `cfg` is not used, each `read` returns the samples decoded from the
+    /// next chunk of the file.
+    pub fn open_with_config(mut reader: R, cfg: DecoderConfig) -> Result<Self> {
+        let index = SeekIndex::build(&mut reader, std::time::Duration::ZERO)?;
+        Self::open_with_index(reader, cfg, index)
+    }
+
+    /// Same as `open_with_config` with an index built beforehand by `SeekIndex::build`, the page
+    /// headers are then not scanned. The index is rejected if the size of the file changed.
+    pub fn open_with_index(
+        mut reader: R,
+        mut cfg: DecoderConfig,
+        index: SeekIndex,
+    ) -> Result<Self> {
+        let len = reader.seek(std::io::SeekFrom::End(0))?;
+        index.check(len)?;
+        cfg.flush_policy = FlushPolicy::Explicit;
+        let sample_rate = cfg.sample_rate;
+        let apply_pre_skip_and_gain = cfg.apply_pre_skip_and_gain;
+        let mut decoder = Decoder::new_with_config(cfg)?;
+        reader.seek(std::io::SeekFrom::Start(0))?;
+        let mut headers = vec![0u8; index.data_offset as usize];
+        reader.read_exact(&mut headers)?;
+        decoder.decode(&headers)?;
+        let Some(head) = decoder.head() else { crate::bail!("no OpusHead packet found") };
+        let pre_skip = if apply_pre_skip_and_gain { head.pre_skip as u64 } else { 0 };
+        let end =
+            index.last_granule_position.saturating_sub(pre_skip) * sample_rate as u64 / 48_000;
+        Ok(Self {
+            reader,
+            decoder,
+            index,
+            sample_rate,
+            apply_pre_skip_and_gain,
+            position: 0,
+            end,
+            buf: vec![0u8; FILE_CHUNK_SIZE],
+            ended: false,
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.decoder.channels()
+    }
+
+    pub fn head(&self) -> Option<&OpusHead> {
+        self.decoder.head()
+    }
+
+    pub fn tags(&self) -> Option<&OpusTags> {
+        self.decoder.tags()
+    }
+
+    /// Duration of the audio, from the granule position of the last page.
+    pub fn duration(&self) -> std::time::Duration {
+        let pre_skip = self.head().map_or(0, |head| head.pre_skip as u64);
+        let samples = self.index.last_granule_position.saturating_sub(pre_skip);
+        std::time::Duration::from_secs_f64(samples as f64 / 48_000.)
+    }
+
+    /// The index used for seeking, it can be saved to open the file again with
+    /// `open_with_index`.
+    pub fn seek_index(&self) -> &SeekIndex {
+        &self.index
+    }
+
+    /// Position of the next sample returned by `read`. When the pre-skip is not applied, this
+    /// includes the pre-skip samples at the start of the stream.
+    pub fn position(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.position as f64 / self.sample_rate as f64)
+    }
+
+    /// Returns the next decoded samples, possibly none when the chunk read does not complete any
+    /// packet, or `None` at the end of the stream. The padding after the end of the stream, as
+    /// given by the granule position of the last page, is dropped.
+    pub fn read(&mut self) -> Result<Option<Vec<f32>>> {
+        if self.ended {
+            return Ok(None);
         }
-        let pcm = if self.size_in_buf >= self.flush_every_n_samples {
-            let size_in_buf = self.size_in_buf;
-            self.size_in_buf = 0;
-            Some(&self.pcm_buf[..size_in_buf])
+        let n = self.reader.read(&mut self.buf)?;
+        if n == 0 {
+            self.ended = true;
+        } else {
+            self.decoder.decode(&self.buf[..n])?;
+        }
+        let channels = self.decoder.channels();
+        let mut pcm = self.decoder.flush().to_vec();
+        let available = self.end.saturating_sub(self.position) as usize;
+        pcm.truncate(available * channels);
+        self.position += (pcm.len() / channels) as u64;
+        if self.ended && pcm.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(pcm))
+    }
+
+    /// Moves to `position`, the following `read` calls return the audio starting exactly at
+    /// this position. The data is read from a page at least the preroll of the `DecoderConfig`
+    /// before the target, so that the decoder state has converged once the output starts.
+    pub fn seek_to(&mut self, position: std::time::Duration) -> Result<()> {
+        let samples = position.as_nanos() * self.sample_rate as u128 / 1_000_000_000;
+        let samples = u64::min(samples as u64, self.end);
+        let pre_skip = match self.head() {
+            Some(head) if self.apply_pre_skip_and_gain => head.pre_skip as u64,
+            _ => 0,
+        };
+        let target = samples * 48_000 / self.sample_rate as u64 + pre_skip;
+        let preroll_start = target.saturating_sub(self.decoder.preroll);
+        // The page following the last page ending before the preroll starts before it.
+        let entries = &self.index.entries;
+        let offset = match entries.partition_point(|(_, g)| *g <= preroll_start) {
+            0 => self.index.data_offset,
+            i => entries[i - 1].0,
+        };
+        self.reader.seek(std::io::SeekFrom::Start(offset))?;
+        self.decoder.seek(target)?;
+        self.position = samples;
+        self.ended = false;
+        Ok(())
+    }
+}
+
+/// Granule positions of an ogg/opus file and the byte offsets to read from to reach them. The
+/// index is built once by scanning the page headers and can be stored next to the file, so that
+/// `FileDecoder::open_with_index` does not have to scan the file again.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SeekIndex {
+    /// Size of the indexed file, to detect a stale index.
+    pub file_len: u64,
+    /// Offset of the first audio page, the pages before only hold headers.
+    pub data_offset: u64,
+    pub last_granule_position: u64,
+    /// End offset and granule position of pages of the opus stream on which a packet ends, in
+    /// increasing order.
+    pub entries: Vec<(u64, u64)>,
+}
+
+const SEEK_INDEX_MAGIC: &[u8; 8] = b"KSeekIdx";
+
+impl SeekIndex {
+    /// Scans the page headers of `reader`, skipping the page bodies, and keeps at most one page
+    /// per `interval`, a zero interval keeps all the pages. A seek reads from the last indexed
+    /// page before the target, so longer intervals trade a smaller index for more data read
+    /// after each seek. Truncated pages at the end of the file are ignored.
+    pub fn build<R: std::io::Read + std::io::Seek>(
+        reader: &mut R,
+        interval: std::time::Duration,
+    ) -> Result<Self> {
+        let interval = interval.as_micros() as u64 * 48 / 1000;
+        let len = reader.seek(std::io::SeekFrom::End(0))?;
+        reader.seek(std::io::SeekFrom::Start(0))?;
+        let hdr_size = crate::ogg_pager::PAGE_HEADER_SIZE as u64;
+        let mut entries: Vec<(u64, u64)> = vec![];
+        let mut last_granule_position = 0;
+        let mut serial = None;
+        let mut data_offset = None;
+        let mut header_packets = 0;
+        let mut offset = 0;
+        let mut header = vec![0u8; hdr_size as usize];
+        let mut table = [0u8; 255];
+        while offset + hdr_size <= len {
+            reader.read_exact(&mut header)?;
+            let hdr = crate::ogg_pager::PageHeader::read(&header);
+            if &hdr.capture_pattern != b"OggS" {
+                return Err(crate::Error::OggUnexpectedCapturePattern(hdr.capture_pattern));
+            }
+            let nsegments = hdr.page_segments as usize;
+            if offset + hdr_size + nsegments as u64 > len {
+                break;
+            }
+            reader.read_exact(&mut table[..nsegments])?;
+            let body_len = table[..nsegments].iter().map(|v| *v as u64).sum::<u64>();
+            let end = offset + hdr_size + nsegments as u64 + body_len;
+            if end > len {
+                break;
+            }
+            reader.seek(std::io::SeekFrom::Start(end))?;
+            let page_serial = hdr.bitstream_serial;
+            let granule_position = hdr.granule_position;
+            let opus_page = *serial.get_or_insert(page_serial) == page_serial;
+            if opus_page && data_offset.is_none() {
+                // The audio starts on the page following the end of the OpusTags packet, the
+                // second packet of the stream, which can span several pages.
+                header_packets += table[..nsegments].iter().filter(|v| **v < 255).count();
+                if header_packets >= 2 {
+                    data_offset = Some(end)
+                }
+            } else if opus_page && granule_position != u64::MAX {
+                last_granule_position = granule_position;
+                let due = entries
+                    .last()
+                    .is_none_or(|(_, g)| granule_position >= g.saturating_add(interval));
+                if due {
+                    entries.push((end, granule_position))
+                }
+            }
+            offset = end;
+        }
+        Ok(Self {
+            file_len: len,
+            data_offset: data_offset.unwrap_or(offset),
+            last_granule_position,
+            entries,
+        })
+    }
+
+    // Rejects an index that does not match a file of `len` bytes, e.g. a stale or corrupt one,
+    // before any of its offsets is used.
+    fn check(&self, len: u64) -> Result<()> {
+        if len != self.file_len {
+            crate::bail!("seek index built for {} bytes, the file has {len} bytes", self.file_len)
+        }
+        if self.data_offset > len {
+            crate::bail!("seek index data offset {} is past the end of the file", self.data_offset)
+        }
+        let sorted = self.entries.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 <= w[1].1);
+        if !sorted || self.entries.last().is_some_and(|(offset, _)| *offset > len) {
+            crate::bail!("seek index entries are not sorted offsets within the file")
+        }
+        Ok(())
+    }
+
+    /// Compact binary serialization, 16 bytes per entry.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(40 + 16 * self.entries.len());
+        data.extend_from_slice(SEEK_INDEX_MAGIC);
+        for v in [self.file_len, self.data_offset, self.last_granule_position] {
+            data.extend_from_slice(&v.to_le_bytes())
+        }
+        for (offset, granule_position) in self.entries.iter() {
+            data.extend_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&granule_position.to_le_bytes());
+        }
+        data
+    }
+
+    /// Parses an index serialized with `to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let Some(fields) = data.strip_prefix(SEEK_INDEX_MAGIC) else {
+            crate::bail!("not a seek index")
+        };
+        if fields.len() < 24 || !(fields.len() - 24).is_multiple_of(16) {
+            crate::bail!("unexpected seek index length {}", data.len())
+        }
+        let mut values = fields
+            .chunks_exact(8)
+            .map(|v| u64::from_le_bytes([v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7]]));
+        // The length has been checked above.
+        let mut next = || values.next().unwrap_or_default();
+        let (file_len, data_offset, last_granule_position) = (next(), next(), next());
+        let entries = (0..(fields.len() - 24) / 16).map(|_| (next(), next())).collect();
+        Ok(Self { file_len, data_offset, last_granule_position, entries })
+    }
+}
+
+/// Ogg/Opus decoder producing interleaved `i16` samples without any floating point computation,
+/// this is intended for embedded targets where the float path is too slow. The output can
+/// optionally be resampled using the integer linear interpolation of [`crate::fixed`]. Unlike
+/// the page parsing and the resampling, this decoder requires the `std` feature as it goes
+/// through the libopus bindings. The channels are the ones of the OpusHead, including multistream
+/// channel mappings, the pre-skip is applied but not the output gain.
+pub struct DecoderI16 {
+    pr_ogg: crate::ogg_pager::PacketReader,
+    // With mapping family 0, the multistream decoder handles plain mono or stereo packets.
+    decoder: opus2::MSDecoder,
+    channels: usize,
+    resampler: Option<crate::fixed::LinearResamplerI16>,
+    opus_sample_rate: usize,
+    sample_rate: usize,
+    // Samples per channel at the libopus rate still to be dropped from the output.
+    pre_skip: usize,
+    frame_buf: Vec<i16>,
+    pcm_buf: Vec<i16>,
+    size_in_buf: usize,
+    flush_policy: FlushPolicy,
+    last_flush: std::time::Instant,
+}
+
+impl DecoderI16 {
+    pub fn new(sample_rate: usize, flush_every_n_samples: usize) -> Result<Self> {
+        Self::new_with_resampling(sample_rate, sample_rate, flush_every_n_samples)
+    }
+
+    /// Decodes the stream at `opus_sample_rate`, which has to be supported by libopus, and
+    /// resamples the output to `sample_rate`.
+    pub fn new_with_resampling(
+        opus_sample_rate: usize,
+        sample_rate: usize,
+        flush_every_n_samples: usize,
+    ) -> Result<Self> {
+        let flush_policy = FlushPolicy::EveryNSamples(flush_every_n_samples);
+        let pcm_buf = Vec::with_capacity(flush_policy.buffer_capacity(opus_sample_rate));
+        let mut decoder = Self {
+            pr_ogg: crate::ogg_pager::PacketReader::new(),
+            decoder: opus2::MSDecoder::new(opus_sample_rate as u32, 1, 0, &[0])?,
+            channels: 1,
+            resampler: None,
+            opus_sample_rate,
+            sample_rate,
+            pre_skip: 0,
+            frame_buf: vec![],
+            pcm_buf,
+            size_in_buf: 0,
+            flush_policy,
+            last_flush: std::time::Instant::now(),
+        };
+        decoder.set_channel_mapping(&crate::opus::ChannelMapping::mono())?;
+        Ok(decoder)
+    }
+
+    // Re-creates the libopus decoder and the resampler once the OpusHead has been read.
+    fn set_channel_mapping(&mut self, channel_mapping: &crate::opus::ChannelMapping) -> Result<()> {
+        let channels = channel_mapping.channels();
+        if channels == 0 {
+            crate::bail!("no channel in channel mapping")
+        }
+        self.decoder = opus2::MSDecoder::new(
+            self.opus_sample_rate as u32,
+            channel_mapping.streams,
+            channel_mapping.coupled_streams,
+            &channel_mapping.mapping,
+        )?;
+        self.resampler = if self.opus_sample_rate == self.sample_rate {
+            None
+        } else {
+            let (sr_in, sr_out) = (self.opus_sample_rate, self.sample_rate);
+            match crate::fixed::LinearResamplerI16::new(sr_in, sr_out, channels) {
+                None => crate::bail!("invalid sample rates for resampling {sr_in} -> {sr_out}"),
+                resampler => resampler,
+            }
+        };
+        // 120ms is the longest possible duration for an opus packet.
+        self.frame_buf = vec![0i16; self.opus_sample_rate * 120 / 1000 * channels];
+        self.channels = channels;
+        Ok(())
+    }
+
+    /// Number of interleaved channels in the output, set by the OpusHead of the stream.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn set_flush_policy(&mut self, flush_policy: FlushPolicy) {
+        self.flush_policy = flush_policy
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Result<Option<&[i16]>> {
+        let size = self.decode_inner(data)?;
+        Ok(size.map(|size| &self.pcm_buf[..size]))
+    }
+
+    /// Same as `decode` but returns an owned buffer.
+    pub fn decode_owned(&mut self, data: &[u8]) -> Result<Option<Vec<i16>>> {
+        let size = self.decode_inner(data)?;
+        Ok(size.map(|_| take_buf(&mut self.pcm_buf)))
+    }
+
+    fn decode_inner(&mut self, data: &[u8]) -> Result<Option<usize>> {
+        self.pr_ogg.append_bytes(data);
+        self.decode_packets().map_err(|err| err.with_position(self.pr_ogg.stream_position()))?;
+        let frames = self.size_in_buf / self.channels;
+        let pcm = if self.flush_policy.should_flush(frames, self.last_flush) {
+            Some(self.take_flush())
         } else {
             None
         };
         Ok(pcm)
     }
+
+    fn decode_packets(&mut self) -> Result<()> {
+        while self.pr_ogg.advance()? {
+            let Some(packet) = self.pr_ogg.packet() else { break };
+            let packet = packet.data;
+            if packet.starts_with(b"OpusHead") {
+                let head = OpusHead::from_slice(packet)?;
+                let channel_mapping = OpusHead::channel_mapping(packet)?;
+                self.set_channel_mapping(&channel_mapping)?;
+                self.pre_skip = head.pre_skip as usize * self.opus_sample_rate / 48_000;
+                continue;
+            }
+            if packet.starts_with(b"OpusTags") || !crate::opus::has_frames(packet) {
+                continue;
+            }
+            let read_size = self.decoder.decode(
+                packet,
+                &mut self.frame_buf,
+                /* Forward Error Correction */ false,
+            )?;
+            let skip = usize::min(self.pre_skip, read_size);
+            self.pre_skip -= skip;
+            let frame = &self.frame_buf[skip * self.channels..read_size * self.channels];
+            self.pcm_buf.truncate(self.size_in_buf);
+            match self.resampler.as_mut() {
+                None => self.pcm_buf.extend_from_slice(frame),
+                Some(resampler) => resampler.process(frame, &mut self.pcm_buf),
+            }
+            self.size_in_buf = self.pcm_buf.len();
+        }
+        Ok(())
+    }
+
+    fn take_flush(&mut self) -> usize {
+        self.last_flush = std::time::Instant::now();
+        std::mem::take(&mut self.size_in_buf)
+    }
+
+    /// Returns the samples decoded since the last flush, whatever the flush policy.
+    pub fn flush(&mut self) -> &[i16] {
+        let size = self.take_flush();
+        &self.pcm_buf[..size]
+    }
+}
+
+impl Default for OpusTags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoder_i16() {
+        for (channels, sample_rate) in [(1, 48_000), (1, 16_000), (2, 48_000), (3, 24_000)] {
+            let mut cfg = EncoderConfig::new(48_000);
+            cfg.channels = channels;
+            let pcm: Vec<f32> = (0..48_000 * channels)
+                .map(|i| 0.3 * ((i / channels) as f32 * 0.01).sin())
+                .collect();
+            let data = Encoder::new_with_config(cfg).unwrap().encode_file(&pcm).unwrap();
+            let mut decoder = DecoderI16::new_with_resampling(48_000, sample_rate, 0).unwrap();
+            let pcm = decoder.decode_owned(&data).unwrap().unwrap();
+            assert_eq!(decoder.channels(), channels);
+            // The pre-skip of 3840 samples at 48kHz is dropped.
+            assert_eq!(pcm.len(), 44_160 * sample_rate / 48_000 * channels);
+            for channel in 0..channels {
+                let max = pcm.iter().skip(channel).step_by(channels).map(|v| v.abs()).max();
+                assert!(max.unwrap() > 5_000, "{channels} {sample_rate} {channel} {max:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn inspect_large_tags() {
+        // A cover picture makes the OpusTags packet span several pages and more than 64KiB.
+        let picture = "A".repeat(200_000);
+        let mut cfg = EncoderConfig::new(24_000);
+        cfg.comments.push(("METADATA_BLOCK_PICTURE".to_string(), picture.clone()));
+        let pcm: Vec<f32> = (0..24_000).map(|i| 0.3 * (i as f32 * 0.05).sin()).collect();
+        let data = Encoder::new_with_config(cfg).unwrap().encode_file(&pcm).unwrap();
+        let info = inspect(&data).unwrap();
+        assert_eq!(info.head.channel_count, 1);
+        assert_eq!(info.tags.get("METADATA_BLOCK_PICTURE"), Some(picture.as_str()));
+        assert!(info.last_granule_position >= 48_000);
+        assert!(inspect(&data[..100_000]).is_err());
+    }
+
+    #[test]
+    fn deterministic_output() {
+        let mut stereo = EncoderConfig::new(44_100);
+        stereo.channels = 2;
+        stereo.resample = true;
+        stereo.bitrate = Some(48_000);
+        stereo.paging = Paging::file();
+        for mut cfg in [EncoderConfig::new(24_000), stereo] {
+            cfg.creation_metadata = true;
+            cfg.deterministic = true;
+            cfg.serial = 1234;
+            let len = cfg.sample_rate * cfg.channels;
+            let pcm: Vec<f32> = (0..len).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+            let encode = |cfg: &EncoderConfig| {
+                Encoder::new_with_config(cfg.clone()).unwrap().encode_file(&pcm).unwrap()
+            };
+            let data = encode(&cfg);
+            assert_eq!(data, encode(&cfg));
+            let info = inspect(&data).unwrap();
+            assert!(info.tags.get("ENCODER").is_some());
+            assert!(info.tags.get("CREATION_TIME").is_none());
+            let mut pages = crate::ogg_pager::PageReader::new();
+            pages.append_bytes(&data);
+            while let Some(page) = pages.next().unwrap() {
+                assert_eq!(page.serial(), 1234);
+            }
+
+            cfg.deterministic = false;
+            let info = inspect(&encode(&cfg)).unwrap();
+            assert!(info.tags.get("CREATION_TIME").is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn async_decoder_latency() {
+        let tracker = crate::latency::LatencyTracker::new();
+        let mut encoder = Encoder::new(24_000).unwrap();
+        encoder.set_latency_tracker(tracker.clone());
+        let mut data = encoder.header_data().to_vec();
+        let pcm: Vec<f32> = (0..1920).map(|i| 0.3 * (i as f32 * 0.05).sin()).collect();
+        for _ in 0..10 {
+            data.extend_from_slice(&encoder.encode_page(&pcm).unwrap());
+        }
+        let cfg = DecoderConfig::new(24_000, 1920);
+        let mut decoder = AsyncDecoder::from_reader(data.as_slice(), cfg).unwrap();
+        decoder.set_latency_tracker(tracker.clone());
+        while decoder.read().await.unwrap().is_some() {}
+        let report = tracker.report();
+        assert!(report.transport.count > 0);
+        assert!(report.decode.count > 0);
+        assert!(report.end_to_end.count > 0);
+    }
+
+    // The pages of a mono 48kHz stream with one 20ms packet per page.
+    fn packet_pages(n_packets: usize) -> Vec<Vec<u8>> {
+        let pcm: Vec<f32> = (0..n_packets * 960).map(|i| 0.3 * (i as f32 * 0.05).sin()).collect();
+        let data = Encoder::new(48_000).unwrap().encode_file(&pcm).unwrap();
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        ranges.into_iter().map(|r| data[r].to_vec()).collect()
+    }
+
+    fn decode_pages(
+        pages: &[Vec<u8>],
+        concealment: crate::opus::ConcealmentPolicy,
+    ) -> (Vec<f32>, crate::opus::DecoderStats) {
+        let mut cfg = DecoderConfig::new(48_000, 960);
+        cfg.concealment = concealment;
+        cfg.apply_pre_skip_and_gain = false;
+        let mut decoder = Decoder::new_with_config(cfg).unwrap();
+        let mut pcm = vec![];
+        for page in pages {
+            if let Some(out) = decoder.decode(page).unwrap() {
+                pcm.extend_from_slice(out)
+            }
+        }
+        pcm.extend_from_slice(decoder.flush());
+        (pcm, decoder.stats().clone())
+    }
+
+    struct Zeros;
+
+    impl crate::opus::Concealer for Zeros {
+        fn conceal(&mut self, _ctx: &crate::opus::ConcealmentContext) -> Vec<f32> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn concealment_policies() {
+        use crate::opus::ConcealmentPolicy as P;
+        let mut pages = packet_pages(10);
+        let (full, _) = decode_pages(&pages, P::Nothing);
+        // The two header pages are followed by the audio pages, drop two of these.
+        pages.drain(5..7);
+        let callback: crate::opus::ConcealmentCallback =
+            std::sync::Arc::new(|len, pcm: &mut Vec<f32>| pcm.resize(pcm.len() + len, 0.));
+        let external = std::sync::Arc::new(std::sync::Mutex::new(Zeros));
+        let (pcm, stats) = decode_pages(&pages, P::Nothing);
+        assert_eq!(pcm.len(), full.len() - 2 * 960);
+        assert_eq!(stats.discontinuities, 0);
+        for concealment in [
+            P::Silence,
+            P::Plc,
+            P::Fec,
+            P::RepeatFade,
+            P::Callback(callback),
+            P::External(external),
+        ] {
+            let name = format!("{concealment:?}");
+            let (pcm, stats) = decode_pages(&pages, concealment);
+            assert_eq!(pcm.len(), full.len(), "{name}");
+            assert_eq!(stats.discontinuities, 0, "{name}");
+            // The audio before the gap is untouched.
+            assert_eq!(pcm[..3 * 960], full[..3 * 960], "{name}");
+        }
+    }
+
+    #[test]
+    fn granule_position_jump() {
+        // The stream jumps far ahead from the fourth audio page on, e.g. a restarted encoder.
+        let mut pages = packet_pages(10);
+        for page in pages[5..].iter_mut() {
+            let hdr = crate::parse::PageHeader::read(page);
+            if hdr.granule_position == u64::MAX {
+                continue;
+            }
+            let granule_position = hdr.granule_position + (1 << 40);
+            let (serial, sequence) = (hdr.bitstream_serial, hdr.page_sequence);
+            crate::parse::rewrite_page(page, hdr.header_type, granule_position, serial, sequence);
+        }
+        let (full, _) = decode_pages(&packet_pages(10), crate::opus::ConcealmentPolicy::Nothing);
+        let (pcm, stats) = decode_pages(&pages, crate::opus::ConcealmentPolicy::Silence);
+        assert_eq!(stats.discontinuities, 1);
+        assert_eq!(pcm.len(), full.len());
+    }
+
+    fn read_all<R: std::io::Read + std::io::Seek>(decoder: &mut FileDecoder<R>) -> Vec<f32> {
+        let mut pcm = vec![];
+        while let Some(out) = decoder.read().unwrap() {
+            pcm.extend_from_slice(&out)
+        }
+        pcm
+    }
+
+    // Three seconds of a mono 48kHz stream, with one packet per page.
+    fn file_data() -> Vec<u8> {
+        let pcm: Vec<f32> = (0..3 * 48_000).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        Encoder::new(48_000).unwrap().encode_file(&pcm).unwrap()
+    }
+
+    #[test]
+    fn file_decoder_seek() {
+        let data = file_data();
+        let mut decoder = FileDecoder::open(std::io::Cursor::new(&data), 48_000).unwrap();
+        let full = read_all(&mut decoder);
+        assert_eq!(full.len() as f64, decoder.duration().as_secs_f64() * 48_000.);
+        decoder.seek_to(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(decoder.position(), std::time::Duration::from_secs(1));
+        let tail = read_all(&mut decoder);
+        assert_eq!(tail.len(), full.len() - 48_000);
+        // The preroll makes the output converge to the one of a decode from the start.
+        let diff = tail.iter().zip(&full[48_000..]).map(|(a, b)| (a - b).abs());
+        assert!(diff.fold(0f32, f32::max) < 0.05);
+    }
+
+    #[test]
+    fn seek_index() {
+        let data = file_data();
+        let mut cursor = std::io::Cursor::new(&data);
+        let index = SeekIndex::build(&mut cursor, std::time::Duration::from_millis(500)).unwrap();
+        assert_eq!(index.file_len, data.len() as u64);
+        assert!(index.entries.len() >= 5 && index.entries.len() <= 7);
+        assert_eq!(SeekIndex::from_bytes(&index.to_bytes()).unwrap(), index);
+        let open = |index: SeekIndex| {
+            let reader = std::io::Cursor::new(&data);
+            FileDecoder::open_with_index(reader, DecoderConfig::new(48_000, 0), index)
+        };
+        let mut decoder = open(index.clone()).unwrap();
+        let remaining = decoder.duration() - std::time::Duration::from_secs(2);
+        decoder.seek_to(std::time::Duration::from_secs(2)).unwrap();
+        assert_eq!(read_all(&mut decoder).len() as f64, remaining.as_secs_f64() * 48_000.);
+        // Corrupt or stale indexes are rejected before reading the file.
+        let mut corrupt = index.clone();
+        corrupt.data_offset = u64::MAX;
+        assert!(open(corrupt).is_err());
+        let mut corrupt = index.clone();
+        corrupt.entries.reverse();
+        assert!(open(corrupt).is_err());
+        let mut corrupt = index.clone();
+        corrupt.entries.push((u64::MAX, u64::MAX));
+        assert!(open(corrupt).is_err());
+        let mut corrupt = index;
+        corrupt.file_len += 1;
+        assert!(open(corrupt).is_err());
+    }
+
+    #[test]
+    fn seek_index_corrupt_granule_position() {
+        let mut data = file_data();
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        let page = &mut data[ranges[10].clone()];
+        let hdr = crate::parse::PageHeader::read(page);
+        let (serial, sequence) = (hdr.bitstream_serial, hdr.page_sequence);
+        crate::parse::rewrite_page(page, hdr.header_type, u64::MAX - 1, serial, sequence);
+        let mut cursor = std::io::Cursor::new(&data);
+        let index = SeekIndex::build(&mut cursor, std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(index.entries.last(), Some(&(ranges[10].end as u64, u64::MAX - 1)));
+    }
+
+    // A cover picture makes the OpusTags packet span several pages.
+    fn file_data_with_picture(picture: &str) -> Vec<u8> {
+        let mut cfg = EncoderConfig::new(48_000);
+        cfg.comments.push(("METADATA_BLOCK_PICTURE".to_string(), picture.to_string()));
+        let pcm: Vec<f32> = (0..3 * 48_000).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        Encoder::new_with_config(cfg).unwrap().encode_file(&pcm).unwrap()
+    }
+
+    #[test]
+    fn file_decoder_large_tags() {
+        let picture = "A".repeat(200_000);
+        let data = file_data_with_picture(&picture);
+        let mut cursor = std::io::Cursor::new(&data);
+        let index = SeekIndex::build(&mut cursor, std::time::Duration::ZERO).unwrap();
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        let tags_end = ranges.iter().position(|r| r.start as u64 == index.data_offset).unwrap();
+        assert!(tags_end > 2);
+        let mut decoder = FileDecoder::open(std::io::Cursor::new(&data), 48_000).unwrap();
+        assert_eq!(decoder.head().unwrap().channel_count, 1);
+        let tags = decoder.tags().unwrap();
+        assert_eq!(tags.get("METADATA_BLOCK_PICTURE"), Some(picture.as_str()));
+        let full = read_all(&mut decoder);
+        assert_eq!(full.len() as f64, decoder.duration().as_secs_f64() * 48_000.);
+    }
+
+    #[test]
+    fn page_sequence_with_large_tags() {
+        let data = file_data_with_picture(&"A".repeat(200_000));
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        for (index, range) in ranges.iter().enumerate() {
+            let hdr = crate::parse::PageHeader::read(&data[range.clone()]);
+            assert_eq!(hdr.page_sequence, index as u32);
+            assert_eq!(hdr.is_eos(), index + 1 == ranges.len());
+        }
+    }
+
+    #[test]
+    fn encode_step_after_grouped_pages() {
+        let mut cfg = EncoderConfig::new(48_000);
+        cfg.step_size = Some(1920);
+        cfg.paging = Paging::file();
+        let mut encoder = Encoder::new_with_config(cfg).unwrap();
+        let pcm: Vec<f32> = (0..4 * 1920).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        let mut data = encoder.header_data().to_vec();
+        data.extend(encoder.encode_page(&pcm[..2 * 1920]).unwrap());
+        let step = encoder.encode_step(&pcm[2 * 1920..3 * 1920]).unwrap();
+        assert_eq!(step.granule_position, 3 * 1920);
+        data.extend(&step.data);
+        data.extend(encoder.encode_page(&pcm[3 * 1920..]).unwrap());
+        data.extend(encoder.finish().unwrap());
+        assert_eq!(crate::validate::validate(&data), vec![]);
+        let info = inspect(&data).unwrap();
+        assert_eq!(info.last_granule_position, 4 * 1920);
+        let mut decoder = FileDecoder::open(std::io::Cursor::new(&data), 48_000).unwrap();
+        let decoded = read_all(&mut decoder);
+        // The packets are in order when the audio matches the one of a single page per packet.
+        let reference = Encoder::new(48_000).unwrap().encode_file(&pcm).unwrap();
+        let mut decoder = FileDecoder::open(std::io::Cursor::new(&reference), 48_000).unwrap();
+        assert_eq!(decoded, read_all(&mut decoder));
+    }
+
+    #[test]
+    fn seek_with_large_tags() {
+        let data = file_data_with_picture(&"A".repeat(200_000));
+        let mut decoder = FileDecoder::open(std::io::Cursor::new(&data), 48_000).unwrap();
+        let full = read_all(&mut decoder);
+        // Before the first indexed page, the data is read from the end of the headers.
+        for seconds in [0., 0.05, 1.5] {
+            decoder.seek_to(std::time::Duration::from_secs_f64(seconds)).unwrap();
+            let start = (seconds * 48_000.) as usize;
+            let tail = read_all(&mut decoder);
+            assert_eq!(tail.len(), full.len() - start, "{seconds}");
+            let diff = tail.iter().zip(&full[start..]).map(|(a, b)| (a - b).abs());
+            assert!(diff.fold(0f32, f32::max) < 0.05, "{seconds}");
+        }
+    }
+
+    #[tokio::test]
+    async fn async_decoder_large_granule_positions() {
+        let offset = u64::MAX / 2;
+        let mut pages = packet_pages(10);
+        for page in pages[2..].iter_mut() {
+            let hdr = crate::parse::PageHeader::read(page);
+            if hdr.granule_position == u64::MAX {
+                continue;
+            }
+            let granule_position = hdr.granule_position + offset;
+            let (serial, sequence) = (hdr.bitstream_serial, hdr.page_sequence);
+            crate::parse::rewrite_page(page, hdr.header_type, granule_position, serial, sequence);
+        }
+        let data = pages.concat();
+        let cfg = DecoderConfig::new(48_000, 960);
+        let mut decoder = AsyncDecoder::from_reader(data.as_slice(), cfg).unwrap();
+        while decoder.read().await.unwrap().is_some() {}
+        let expected = offset as f64 / 48_000.;
+        assert!((decoder.position().as_secs_f64() - expected).abs() < 1.);
+    }
 }
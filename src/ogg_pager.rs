@@ -8,108 +8,810 @@
 
 use crate::Result;
 
-// https://xiph.org/ogg/doc/framing.html
-#[repr(Rust, packed)]
-#[derive(Debug, Clone)]
-pub struct OggHeader {
-    pub capture_pattern: [u8; 4],
-    pub version: u8,
-    pub header_type: u8,
-    pub granule_position: u64,
-    pub bitstream_serial: u32,
-    pub page_sequence: u32,
-    pub checksum: u32,
-    pub page_segments: u8,
+pub use crate::parse::{last_granule_position, PageHeader, PAGE_HEADER_SIZE};
+pub(crate) use crate::parse::{rewrite_page, BEGIN_OF_STREAM, CONTINUED_PACKET, END_OF_STREAM};
+
+// Same as `parse::page_ranges` with the crate errors.
+pub(crate) fn page_ranges(data: &[u8]) -> Result<Vec<std::ops::Range<usize>>> {
+    Ok(crate::parse::page_ranges(data)?)
+}
+
+/// Shifts the granule positions of all the pages in `data` by `offset` and recomputes the page
+/// checksums, the packets are left untouched. Header pages, with a granule position of 0, and
+/// pages without any packet ending on them are not modified. `data` must only contain complete
+/// pages. Returns the number of rewritten pages.
+pub fn shift_granule_positions(data: &mut [u8], offset: i64) -> Result<usize> {
+    let mut rewritten = 0;
+    for range in page_ranges(data)? {
+        let page = &mut data[range];
+        let hdr = PageHeader::read(page);
+        let granule_position = hdr.granule_position;
+        if granule_position == 0 || granule_position == u64::MAX {
+            continue;
+        }
+        let granule_position = match granule_position.checked_add_signed(offset) {
+            Some(g) => g,
+            None => {
+                crate::bail!("granule position {granule_position} cannot be shifted by {offset}")
+            }
+        };
+        rewrite_page(
+            page,
+            hdr.header_type,
+            granule_position,
+            hdr.bitstream_serial,
+            hdr.page_sequence,
+        );
+        rewritten += 1;
+    }
+    Ok(rewritten)
 }
 
 pub struct Page {
-    pub header: OggHeader,
+    pub(crate) header: PageHeader,
     pub segments: Vec<Vec<u8>>,
 }
 
+impl Page {
+    /// Position of the end of the last packet completed on the page, `u64::MAX` when no packet
+    /// ends on the page.
+    pub fn granule_position(&self) -> u64 {
+        self.header.granule_position
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.header.bitstream_serial
+    }
+
+    pub fn page_sequence(&self) -> u32 {
+        self.header.page_sequence
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.header.checksum
+    }
+
+    /// Whether the first segment continues a packet from the previous page.
+    pub fn is_continued(&self) -> bool {
+        self.header.is_continued()
+    }
+
+    /// Whether this is the first page of the logical stream.
+    pub fn is_bos(&self) -> bool {
+        self.header.is_bos()
+    }
+
+    /// Whether this is the last page of the logical stream.
+    pub fn is_eos(&self) -> bool {
+        self.header.is_eos()
+    }
+
+    /// Size of the page in bytes, including its header and segment table.
+    pub fn size(&self) -> usize {
+        PAGE_HEADER_SIZE
+            + self.segments.len()
+            + self.segments.iter().map(|s| s.len()).sum::<usize>()
+    }
+}
+
+/// A page borrowing the buffer of the `PageReader` it was read from.
+#[derive(Clone, Copy)]
+pub struct PageRef<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PageRef<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn header(&self) -> PageHeader {
+        PageHeader::read(self.data)
+    }
+
+    /// See [`Page::granule_position`].
+    pub fn granule_position(&self) -> u64 {
+        self.header().granule_position
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.header().bitstream_serial
+    }
+
+    pub fn page_sequence(&self) -> u32 {
+        self.header().page_sequence
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.header().checksum
+    }
+
+    /// Whether the first segment continues a packet from the previous page.
+    pub fn is_continued(&self) -> bool {
+        self.header().is_continued()
+    }
+
+    /// Whether this is the first page of the logical stream.
+    pub fn is_bos(&self) -> bool {
+        self.header().is_bos()
+    }
+
+    /// Whether this is the last page of the logical stream.
+    pub fn is_eos(&self) -> bool {
+        self.header().is_eos()
+    }
+
+    /// The whole page, including its header and segment table.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &'a [u8]> {
+        crate::parse::segments(self.data)
+    }
+
+    pub fn to_page(&self) -> Page {
+        Page { header: self.header(), segments: self.segments().map(|s| s.to_vec()).collect() }
+    }
+}
+
+/// Information about a completed page, passed to page hooks. This is meant for segmenters and
+/// indexers that need to know about safe cut points in the byte stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageInfo {
+    pub granule_position: u64,
+    pub page_sequence: u32,
+    /// Offset of the first byte of the page in the stream.
+    pub byte_offset: u64,
+    pub size: usize,
+}
+
+pub type PageHook = Box<dyn FnMut(&PageInfo) + Send>;
+
+/// Where an error happened in an ogg stream, attached to the errors of the readers and decoders
+/// and available through `Error::stream_position`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StreamPosition {
+    /// Offset in the stream of the page being read.
+    pub byte_offset: Option<u64>,
+    pub page_sequence: Option<u32>,
+    pub granule_position: Option<u64>,
+}
+
+impl StreamPosition {
+    /// The granule position as a time, assuming a 48kHz granule rate as used by opus. This is
+    /// `None` when the granule position is too large to be a time, e.g. on a corrupt page.
+    pub fn time(&self) -> Option<std::time::Duration> {
+        let micros = u128::from(self.granule_position?) * 1000 / 48;
+        u64::try_from(micros).ok().map(std::time::Duration::from_micros)
+    }
+}
+
+impl std::fmt::Display for StreamPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(byte_offset) = self.byte_offset {
+            parts.push(format!("byte offset {byte_offset}"))
+        }
+        if let Some(page_sequence) = self.page_sequence {
+            parts.push(format!("page {page_sequence}"))
+        }
+        if let (Some(granule_position), Some(time)) = (self.granule_position, self.time()) {
+            parts.push(format!("granule position {granule_position} ({:.3}s)", time.as_secs_f64()))
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// What the page readers do with pages whose checksum does not match their content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumPolicy {
+    /// Return an error.
+    Reject,
+    /// Drop the page and continue with the next one, the packets spanning the page are dropped.
+    SkipPage,
+    /// Do not check the checksums.
+    #[default]
+    Ignore,
+}
+
+/// Size of the largest possible page, with 255 segments of 255 bytes.
+pub const MAX_PAGE_SIZE: usize = 27 + 255 + 255 * 255;
+
 pub struct PageReader {
     data: Vec<u8>,
+    // Start of the unread data. The consumed bytes are only dropped when more data is appended so
+    // that the pages returned by `next_page` can borrow the buffer.
+    start: usize,
+    position: u64,
+    checksum_policy: ChecksumPolicy,
+    corrupt_pages: u64,
+    resync: bool,
+    skipped_bytes: u64,
+    max_buffered: Option<usize>,
 }
 
 impl PageReader {
     pub fn new() -> Self {
-        Self { data: vec![] }
+        Self {
+            data: vec![],
+            start: 0,
+            position: 0,
+            checksum_policy: ChecksumPolicy::Ignore,
+            corrupt_pages: 0,
+            resync: false,
+            skipped_bytes: 0,
+            max_buffered: None,
+        }
+    }
+
+    /// Limits the number of unread bytes held by the reader, see `try_append`. The limit has to
+    /// be at least `MAX_PAGE_SIZE` so that any page can be read, there is no limit by default.
+    pub fn set_max_buffered(&mut self, max_buffered: Option<usize>) -> Result<()> {
+        if let Some(max_buffered) = max_buffered.filter(|&m| m < MAX_PAGE_SIZE) {
+            crate::bail!("max_buffered {max_buffered} is smaller than a page of {MAX_PAGE_SIZE}")
+        }
+        self.max_buffered = max_buffered;
+        Ok(())
+    }
+
+    /// Number of bytes appended that have not been consumed by a page yet.
+    pub fn buffered(&self) -> usize {
+        self.data.len() - self.start
+    }
+
+    /// When set, data that does not start with a valid page header is skipped up to the next
+    /// capture pattern rather than resulting in an error, e.g. for streams joined mid-broadcast.
+    /// With checksum validation, a page with a checksum mismatch is assumed to be a false match
+    /// of the capture pattern and the scan continues from the following byte.
+    pub fn set_resync(&mut self, resync: bool) {
+        self.resync = resync
     }
 
+    /// Number of bytes skipped while resynchronizing.
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped_bytes
+    }
+
+    pub fn set_checksum_policy(&mut self, checksum_policy: ChecksumPolicy) {
+        self.checksum_policy = checksum_policy
+    }
+
+    /// Number of pages with a checksum mismatch, only counted when checksums are checked.
+    pub fn corrupt_pages(&self) -> u64 {
+        self.corrupt_pages
+    }
+
+    /// Number of bytes consumed by the pages returned so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Appends `data` whatever the `max_buffered` limit.
     pub fn append_bytes(&mut self, data: &[u8]) {
+        if self.start > 0 {
+            self.data.drain(..self.start);
+            self.start = 0;
+        }
         self.data.extend_from_slice(data)
     }
 
+    /// Appends as much of `data` as the `max_buffered` limit allows and returns the number of
+    /// bytes accepted. The remaining bytes have to be appended again once some pages have been
+    /// read, this applies backpressure to the producer rather than buffering without bound.
+    pub fn try_append(&mut self, data: &[u8]) -> usize {
+        let len = match self.max_buffered {
+            None => data.len(),
+            Some(max_buffered) => {
+                usize::min(data.len(), max_buffered.saturating_sub(self.buffered()))
+            }
+        };
+        self.append_bytes(&data[..len]);
+        len
+    }
+
+    /// Drops the buffered data, the settings and counters are kept.
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.start = 0;
+        self.position = 0;
+    }
+
+    /// Same as `next_page` but returns an owned page.
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Option<Page>> {
-        let hdr_size = std::mem::size_of::<OggHeader>();
-        if self.data.len() < hdr_size {
-            return Ok(None);
-        }
-        let hdr: OggHeader =
-            unsafe { std::ptr::read_unaligned(self.data.as_ptr() as *const OggHeader) };
-        if &hdr.capture_pattern != b"OggS" {
-            return Err(crate::Error::OggUnexpectedCapturePattern(hdr.capture_pattern));
-        }
-        if hdr.version != 0 {
-            return Err(crate::Error::OggUnsupportedVersion(hdr.version));
-        }
-        let nsegments = hdr.page_segments as usize;
-        if self.data.len() < hdr_size + nsegments {
-            return Ok(None);
-        }
-        let segment_table = &self.data[hdr_size..hdr_size + nsegments];
-        let page_size =
-            hdr_size + nsegments + segment_table.iter().map(|v| *v as usize).sum::<usize>();
-        if self.data.len() < page_size {
-            return Ok(None);
+        Ok(self.next_page()?.map(|page| page.to_page()))
+    }
+
+    /// Returns the next complete page, borrowing the internal buffer rather than copying it.
+    pub fn next_page(&mut self) -> Result<Option<PageRef<'_>>> {
+        let range = self.next_page_range()?;
+        Ok(range.map(|range| PageRef::new(&self.data[range])))
+    }
+
+    // Range of the next complete page in `data`, which stays valid until more data is appended.
+    pub(crate) fn next_page_range(&mut self) -> Result<Option<std::ops::Range<usize>>> {
+        loop {
+            let page_size = match self.next_page_size() {
+                Ok(Some(page_size)) => page_size,
+                Ok(None) => return Ok(None),
+                Err(err) if !self.resync => {
+                    let position =
+                        StreamPosition { byte_offset: Some(self.position), ..Default::default() };
+                    return Err(err.with_position(position));
+                }
+                Err(_) => {
+                    if self.skip_to_capture_pattern() {
+                        continue;
+                    }
+                    return Ok(None);
+                }
+            };
+            if self.checksum_policy != ChecksumPolicy::Ignore {
+                let page = &self.data[self.start..self.start + page_size];
+                let hdr = PageHeader::read(page);
+                let crc = crate::parse::page_checksum(page);
+                if crc != hdr.checksum {
+                    self.corrupt_pages += 1;
+                    if self.checksum_policy == ChecksumPolicy::Reject {
+                        let position = StreamPosition {
+                            byte_offset: Some(self.position),
+                            page_sequence: Some(hdr.page_sequence),
+                            granule_position: Some(hdr.granule_position),
+                        };
+                        let err = crate::Error::OggChecksumMismatch {
+                            page_sequence: hdr.page_sequence,
+                            expected: hdr.checksum,
+                            actual: crc,
+                        };
+                        return Err(err.with_position(position));
+                    }
+                    if self.resync {
+                        self.skip(1);
+                    } else {
+                        self.start += page_size;
+                        self.position += page_size as u64;
+                    }
+                    continue;
+                }
+            }
+            let range = self.start..self.start + page_size;
+            self.start += page_size;
+            self.position += page_size as u64;
+            return Ok(Some(range));
         }
+    }
 
-        let mut segments = Vec::with_capacity(nsegments);
-        let mut start_offset = hdr_size + nsegments;
-        for &slen in segment_table.iter() {
-            segments.push(self.data[start_offset..start_offset + slen as usize].to_vec());
-            start_offset += slen as usize;
+    pub(crate) fn page_data(&self, range: std::ops::Range<usize>) -> &[u8] {
+        &self.data[range]
+    }
+
+    // Drops the data up to the next capture pattern after the first byte, returns false if there
+    // is none in the buffer. A partial capture pattern at the end of the buffer is kept.
+    fn skip_to_capture_pattern(&mut self) -> bool {
+        match self.data[self.start..].windows(4).skip(1).position(|w| w == b"OggS") {
+            Some(pos) => {
+                self.skip(pos + 1);
+                true
+            }
+            None => {
+                self.skip((self.data.len() - self.start).saturating_sub(3));
+                false
+            }
         }
-        self.data.drain(..page_size);
-        Ok(Some(Page { header: hdr, segments }))
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.start += len;
+        self.position += len as u64;
+        self.skipped_bytes += len as u64;
+    }
+
+    // Size of the page at the start of the buffer, `None` if it is not complete yet.
+    fn next_page_size(&self) -> Result<Option<usize>> {
+        Ok(crate::parse::page_size(&self.data[self.start..])?)
     }
 }
 
 pub struct PacketReader {
     page_reader: PageReader,
-    segments: Vec<Vec<u8>>,
-    packets: std::collections::VecDeque<Vec<u8>>,
+    // The packets completed on the current page followed by the start of the packet spanning
+    // the next page, this buffer is reused across pages.
+    packet_data: Vec<u8>,
+    packets: std::collections::VecDeque<std::ops::Range<usize>>,
+    // Start of the incomplete packet in `packet_data`.
+    partial_start: usize,
+    // The last packet returned by `advance`.
+    current: Option<std::ops::Range<usize>>,
+    page_hook: Option<PageHook>,
+    last_granule_position: u64,
+    first_of_page: bool,
+    // Serial of the logical stream being read, the pages of the other multiplexed streams are
+    // skipped until this stream ends.
+    serial: Option<u32>,
+    ended: bool,
+    // The page on which the last returned packet was completed.
+    last_page: Option<PageInfo>,
+}
+
+/// A packet borrowing the buffer of the `PacketReader` it was read from.
+#[derive(Debug, Clone, Copy)]
+pub struct Packet<'a> {
+    pub data: &'a [u8],
+    /// Granule position of the page on which the packet was completed.
+    pub granule_position: u64,
+    /// Whether this is the first packet completed on its page.
+    pub first_of_page: bool,
 }
 
 impl PacketReader {
     pub fn new() -> Self {
         Self {
             page_reader: PageReader::new(),
-            segments: vec![],
+            packet_data: vec![],
             packets: std::collections::VecDeque::new(),
+            partial_start: 0,
+            current: None,
+            page_hook: None,
+            last_granule_position: 0,
+            first_of_page: false,
+            serial: None,
+            ended: false,
+            last_page: None,
+        }
+    }
+
+    /// Position of the page on which the last returned packet was completed, to locate the
+    /// errors happening when processing this packet.
+    pub fn stream_position(&self) -> StreamPosition {
+        match self.last_page {
+            None => StreamPosition {
+                byte_offset: Some(self.page_reader.position()),
+                ..Default::default()
+            },
+            Some(page) => StreamPosition {
+                byte_offset: Some(page.byte_offset),
+                page_sequence: Some(page.page_sequence),
+                granule_position: (page.granule_position != u64::MAX)
+                    .then_some(page.granule_position),
+            },
         }
     }
 
+    /// Serial of the logical stream being read. With multiplexed streams, only the packets of
+    /// the first stream are returned, a chained stream is picked up once it has ended.
+    pub fn serial(&self) -> Option<u32> {
+        self.serial
+    }
+
+    /// Granule position of the last page that has been read.
+    pub fn last_granule_position(&self) -> u64 {
+        self.last_granule_position
+    }
+
+    /// Whether the last packet returned by `next` is the first packet completed on the page with
+    /// granule position `last_granule_position`.
+    pub fn is_first_of_page(&self) -> bool {
+        self.first_of_page
+    }
+
+    /// The packets completed on the current page that have not been returned yet.
+    pub fn pending_packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.packets.iter().map(|p| &self.packet_data[p.clone()])
+    }
+
+    /// Registers a callback invoked for each page once it has been fully read.
+    pub fn set_page_hook(&mut self, hook: PageHook) {
+        self.page_hook = Some(hook)
+    }
+
+    pub fn set_checksum_policy(&mut self, checksum_policy: ChecksumPolicy) {
+        self.page_reader.set_checksum_policy(checksum_policy)
+    }
+
+    /// See [`PageReader::corrupt_pages`].
+    pub fn corrupt_pages(&self) -> u64 {
+        self.page_reader.corrupt_pages()
+    }
+
+    /// See [`PageReader::set_resync`].
+    pub fn set_resync(&mut self, resync: bool) {
+        self.page_reader.set_resync(resync)
+    }
+
+    /// See [`PageReader::skipped_bytes`].
+    pub fn skipped_bytes(&self) -> u64 {
+        self.page_reader.skipped_bytes()
+    }
+
     pub fn append_bytes(&mut self, data: &[u8]) {
         self.page_reader.append_bytes(data)
     }
 
+    /// Limits both the unread bytes, see [`PageReader::try_append`], and the size of a packet
+    /// spanning several pages, reading a larger packet results in an error.
+    pub fn set_max_buffered(&mut self, max_buffered: Option<usize>) -> Result<()> {
+        self.page_reader.set_max_buffered(max_buffered)
+    }
+
+    /// See [`PageReader::buffered`].
+    pub fn buffered(&self) -> usize {
+        self.page_reader.buffered()
+    }
+
+    /// See [`PageReader::try_append`].
+    pub fn try_append(&mut self, data: &[u8]) -> usize {
+        self.page_reader.try_append(data)
+    }
+
+    /// Appends `data`, failing when it does not fit within the `max_buffered` limit. The bytes
+    /// that fit are appended.
+    pub fn append_bytes_bounded(&mut self, data: &[u8]) -> Result<()> {
+        if self.page_reader.try_append(data) < data.len() {
+            let limit = self.page_reader.max_buffered.unwrap_or(usize::MAX);
+            return Err(crate::Error::OggBufferLimit(limit));
+        }
+        Ok(())
+    }
+
+    /// Drops the buffered data and the packets not returned yet, the following bytes have to
+    /// start at a page boundary. The page hook and the settings are kept.
+    pub fn reset(&mut self) {
+        self.page_reader.clear();
+        self.packet_data.clear();
+        self.packets.clear();
+        self.partial_start = 0;
+        self.current = None;
+        self.first_of_page = false;
+        self.ended = false;
+        self.last_page = None;
+    }
+
+    /// Same as `next_packet` but returns an owned copy of the packet data.
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Option<Vec<u8>>> {
-        while let Some(page) = self.page_reader.next()? {
-            for segment in page.segments.into_iter() {
+        Ok(self.next_packet()?.map(|packet| packet.data.to_vec()))
+    }
+
+    /// Returns the next packet, pages are only read once all the packets completed on the
+    /// previous pages have been returned. The packet borrows a buffer that is reused, so
+    /// reading a stream does not allocate once the buffers have grown to the largest page.
+    pub fn next_packet(&mut self) -> Result<Option<Packet<'_>>> {
+        self.advance()?;
+        Ok(self.packet())
+    }
+
+    /// Moves to the next packet, returns false when no complete packet is available. The packet
+    /// is then accessed through `packet`, which only borrows the reader immutably.
+    pub fn advance(&mut self) -> Result<bool> {
+        let read_page = self.packets.is_empty();
+        if read_page {
+            // All the packets completed on the current page have been returned.
+            self.packet_data.drain(..self.partial_start);
+            self.partial_start = 0;
+        }
+        while self.packets.is_empty() {
+            let corrupt_pages = self.page_reader.corrupt_pages();
+            let skipped_bytes = self.page_reader.skipped_bytes();
+            let Some(range) = self.page_reader.next_page_range()? else { break };
+            if self.page_reader.corrupt_pages() != corrupt_pages
+                || self.page_reader.skipped_bytes() != skipped_bytes
+            {
+                // The packet spanning the skipped data is incomplete.
+                self.packet_data.truncate(self.partial_start);
+            }
+            let page = PageRef::new(self.page_reader.page_data(range));
+            match self.serial {
+                Some(serial) if serial == page.serial() => self.ended = page.is_eos(),
+                // Once the stream has ended, the reader moves to the next chained stream, which
+                // starts with a BOS page. Pages of other multiplexed streams are skipped.
+                Some(_) if !self.ended || !page.is_bos() => continue,
+                _ => {
+                    self.serial = Some(page.serial());
+                    self.ended = page.is_eos();
+                }
+            }
+            // The start of a continued packet has been lost, e.g. in a skipped page, its end is
+            // dropped.
+            let mut orphaned = page.is_continued() && self.packet_data.len() == self.partial_start;
+            self.last_granule_position = page.granule_position();
+            let size = page.size();
+            let info = PageInfo {
+                granule_position: page.granule_position(),
+                page_sequence: page.page_sequence(),
+                byte_offset: self.page_reader.position() - size as u64,
+                size,
+            };
+            self.last_page = Some(info);
+            if let Some(hook) = self.page_hook.as_mut() {
+                hook(&info)
+            }
+            for segment in page.segments() {
                 let slen = segment.len();
-                self.segments.push(segment);
+                if orphaned {
+                    orphaned = slen == 255;
+                    continue;
+                }
+                self.packet_data.extend_from_slice(segment);
+                if let Some(limit) = self.page_reader.max_buffered {
+                    if self.packet_data.len() - self.partial_start > limit {
+                        return Err(crate::Error::OggBufferLimit(limit)
+                            .with_position(self.stream_position()));
+                    }
+                }
                 if slen < 255 {
-                    let packet = self.segments.concat();
-                    self.packets.push_back(packet);
-                    self.segments.clear();
+                    self.packets.push_back(self.partial_start..self.packet_data.len());
+                    self.partial_start = self.packet_data.len();
                 }
             }
         }
-        let p = self.packets.pop_front();
-        Ok(p)
+        self.current = self.packets.pop_front();
+        self.first_of_page = read_page && self.current.is_some();
+        Ok(self.current.is_some())
+    }
+
+    /// The packet reached by the last call to `advance`, if any.
+    pub fn packet(&self) -> Option<Packet<'_>> {
+        self.current.as_ref().map(|range| Packet {
+            data: &self.packet_data[range.clone()],
+            granule_position: self.last_granule_position,
+            first_of_page: self.first_of_page,
+        })
+    }
+}
+
+/// How a packet passed to `PageWriter::write_packet` ends the current page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketEnd {
+    /// The page is only written once full, or once a later packet ends it.
+    Normal,
+    EndPage,
+    /// Ends the page and marks it as the last page of the logical stream.
+    EndStream,
+}
+
+// Maximum number of segments per page.
+const MAX_SEGMENTS: usize = 255;
+
+/// Writes the packets of a single logical stream as ogg pages, including their checksums. The
+/// completed pages are accumulated until `take_data` is called.
+pub struct PageWriter {
+    serial: u32,
+    page_sequence: u32,
+    // Lacing values and body of the page being built.
+    segments: Vec<u8>,
+    body: Vec<u8>,
+    // Granule position of the last packet completed on the page being built.
+    granule_position: Option<u64>,
+    // Whether the page being built starts with the continuation of a packet.
+    continued: bool,
+    data: Vec<u8>,
+    page_hook: Option<PageHook>,
+    bytes_written: u64,
+}
+
+impl PageWriter {
+    pub fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            page_sequence: 0,
+            segments: Vec::with_capacity(MAX_SEGMENTS),
+            body: vec![],
+            granule_position: None,
+            continued: false,
+            data: vec![],
+            page_hook: None,
+            bytes_written: 0,
+        }
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Sequence number of the next page to be written.
+    pub fn page_sequence(&self) -> u32 {
+        self.page_sequence
+    }
+
+    /// Registers a callback invoked for each page once it has been written.
+    pub fn set_page_hook(&mut self, hook: PageHook) {
+        self.page_hook = Some(hook)
+    }
+
+    /// Appends a packet ending at `granule_position`, packets that do not fit on the current page
+    /// continue on the following ones.
+    pub fn write_packet(&mut self, packet: &[u8], granule_position: u64, end: PacketEnd) {
+        let mut remaining = packet;
+        let mut first_segment = true;
+        loop {
+            if self.segments.len() == MAX_SEGMENTS {
+                self.write_page(false);
+                // The next page only starts with a continuation when the packet is split.
+                self.continued = !first_segment;
+            }
+            first_segment = false;
+            let len = usize::min(remaining.len(), 255);
+            self.segments.push(len as u8);
+            self.body.extend_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            // A packet ends on the first segment shorter than 255 bytes.
+            if len < 255 {
+                break;
+            }
+        }
+        self.granule_position = Some(granule_position);
+        match end {
+            PacketEnd::Normal => {}
+            PacketEnd::EndPage => self.write_page(false),
+            PacketEnd::EndStream => self.write_page(true),
+        }
+    }
+
+    /// Writes the page being built flagged as the last page of the logical stream, or an empty
+    /// end of stream page when all the packets are already on complete pages.
+    pub fn end_stream(&mut self) {
+        self.write_page(true)
+    }
+
+    /// Writes the last packets of the stream with their granule positions, each on its own page,
+    /// the page of the last one being flagged as the end of the stream. Ogg/opus streams can only
+    /// trim the padding of their last packet on the end of stream page.
+    pub fn end_stream_with<'a>(&mut self, packets: impl IntoIterator<Item = (&'a [u8], u64)>) {
+        let mut packets = packets.into_iter().peekable();
+        while let Some((packet, granule_position)) = packets.next() {
+            let end = if packets.peek().is_some() { PacketEnd::EndPage } else { PacketEnd::Normal };
+            self.write_packet(packet, granule_position, end);
+        }
+        self.end_stream()
+    }
+
+    /// Returns the pages written so far.
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    fn write_page(&mut self, end_of_stream: bool) {
+        let hdr_size = PAGE_HEADER_SIZE;
+        let start = self.data.len();
+        self.data.extend_from_slice(b"OggS");
+        // The remaining header fields are filled by `rewrite_page`.
+        self.data.resize(start + hdr_size - 1, 0);
+        self.data.push(self.segments.len() as u8);
+        self.data.extend_from_slice(&self.segments);
+        self.data.extend_from_slice(&self.body);
+        let mut header_type = 0;
+        if self.continued {
+            header_type |= CONTINUED_PACKET
+        }
+        if self.page_sequence == 0 {
+            header_type |= BEGIN_OF_STREAM
+        }
+        if end_of_stream {
+            header_type |= END_OF_STREAM
+        }
+        // Pages on which no packet ends have a granule position of -1.
+        let granule_position = self.granule_position.take().unwrap_or(u64::MAX);
+        let page = &mut self.data[start..];
+        rewrite_page(page, header_type, granule_position, self.serial, self.page_sequence);
+        if let Some(hook) = self.page_hook.as_mut() {
+            let info = PageInfo {
+                granule_position,
+                page_sequence: self.page_sequence,
+                byte_offset: self.bytes_written,
+                size: page.len(),
+            };
+            hook(&info)
+        }
+        self.bytes_written += page.len() as u64;
+        self.page_sequence += 1;
+        self.segments.clear();
+        self.body.clear();
+        self.continued = false;
     }
 }
 
@@ -124,3 +826,101 @@ impl Default for PacketReader {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `packets` on a single stream and reads them back with the ogg crate, returns the
+    // pages read with checksum verification.
+    fn round_trip(packets: &[Vec<u8>]) -> Vec<Page> {
+        let mut writer = PageWriter::new(7);
+        for (index, packet) in packets.iter().enumerate() {
+            let end =
+                if index + 1 == packets.len() { PacketEnd::EndStream } else { PacketEnd::Normal };
+            writer.write_packet(packet, index as u64 * 960, end);
+        }
+        let data = writer.take_data();
+        let mut reader = ogg::PacketReader::new(std::io::Cursor::new(&data));
+        for packet in packets.iter() {
+            let read = reader.read_packet_expected().unwrap();
+            assert_eq!(&read.data, packet);
+        }
+        assert!(reader.read_packet().unwrap().is_none());
+        let mut pages = PageReader::new();
+        pages.set_checksum_policy(ChecksumPolicy::Reject);
+        pages.append_bytes(&data);
+        std::iter::from_fn(|| pages.next().unwrap()).collect()
+    }
+
+    #[test]
+    fn packet_ending_on_the_last_segment() {
+        // 254 full segments and a partial one fill the 255 lacing values of the first page.
+        let packets = vec![vec![1u8; 254 * 255 + 100], vec![2u8; 10]];
+        let pages = round_trip(&packets);
+        assert_eq!(pages.len(), 2);
+        assert!(pages[0].is_bos() && !pages[0].is_continued());
+        assert!(!pages[1].is_continued() && pages[1].is_eos());
+        assert_eq!(pages[0].granule_position(), 0);
+        assert_eq!(pages[1].granule_position(), 960);
+    }
+
+    #[test]
+    fn packets_with_a_multiple_of_255_bytes() {
+        // The packets are terminated by a zero length segment, which for the largest one is the
+        // only segment of the next page.
+        let packets = vec![vec![1u8; 510], vec![2u8; 255 * 255], vec![], vec![3u8; 255]];
+        let pages = round_trip(&packets);
+        assert_eq!(pages.len(), 2);
+        assert!(pages[1].is_continued());
+        assert_eq!(pages[0].granule_position(), 0);
+        assert_eq!(pages[1].granule_position(), 3 * 960);
+    }
+
+    #[test]
+    fn packet_spanning_several_pages() {
+        let packets = vec![vec![5u8; 3 * 255 * 255 + 17]];
+        let pages = round_trip(&packets);
+        assert_eq!(pages.len(), 4);
+        assert!(pages[1..].iter().all(|p| p.is_continued()));
+        assert!(pages[..3].iter().all(|p| p.granule_position() == u64::MAX));
+        let sequences: Vec<u32> = pages.iter().map(|p| p.page_sequence()).collect();
+        assert_eq!(sequences, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn chained_stream_after_interleaved_serials() {
+        // One page per packet, the first page of each writer is a BOS page.
+        let page = |writer: &mut PageWriter, byte: u8, end: PacketEnd| {
+            writer.write_packet(&[byte; 10], 960, end);
+            writer.take_data()
+        };
+        let (mut a, mut b, mut c) = (PageWriter::new(1), PageWriter::new(2), PageWriter::new(3));
+        let pages = [
+            page(&mut a, 1, PacketEnd::EndPage),
+            page(&mut b, 2, PacketEnd::EndPage),
+            page(&mut a, 1, PacketEnd::EndPage),
+            page(&mut b, 2, PacketEnd::EndPage),
+            page(&mut a, 1, PacketEnd::EndStream),
+            // Continues the multiplexed stream after the end of the one being read.
+            page(&mut b, 2, PacketEnd::EndPage),
+            page(&mut c, 3, PacketEnd::EndPage),
+            page(&mut b, 2, PacketEnd::EndStream),
+            page(&mut c, 3, PacketEnd::EndStream),
+        ];
+        let mut reader = PacketReader::new();
+        reader.append_bytes(&pages.concat());
+        let firsts: Vec<u8> = std::iter::from_fn(|| reader.next().unwrap()).map(|p| p[0]).collect();
+        assert_eq!(firsts, [1, 1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn stream_position_time() {
+        let position = |g| StreamPosition { granule_position: g, ..Default::default() };
+        assert_eq!(position(None).time(), None);
+        assert_eq!(position(Some(96_000)).time(), Some(std::time::Duration::from_secs(2)));
+        let max = u64::MAX / 1000 * 48;
+        assert!(position(Some(max)).time().is_some());
+        assert_eq!(position(Some(u64::MAX)).time(), None);
+    }
+}
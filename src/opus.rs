@@ -0,0 +1,1445 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Opus packet level encoding/decoding, independent of the container used to carry the packets.
+
+use crate::Result;
+
+pub use crate::parse::{has_frames, is_dtx, Bandwidth, ChannelMapping, Mode, Toc};
+
+/// The sample rates supported by the opus encoder.
+pub const SAMPLE_RATES: [usize; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+
+// This must be an allowed value among 120, 240, 480, 960, 1920, and 2880.
+// Using a different value would result in a BadArg "invalid argument" error when calling encode.
+// https://opus-codec.org/docs/opus_api-1.2/group__opus__encoder.html#ga4ae9905859cd241ef4bb5c59cd5e5309
+/// Number of samples per frame used by the encoder, unless `EncoderConfig::frame_duration` is set.
+pub const ENCODER_FRAME_SIZE: usize = 960;
+
+// 120ms is the longest possible duration for an opus packet.
+const MAX_PACKET_DURATION_MS: usize = 120;
+
+/// The libopus application, this tunes the encoder for the kind of signal being encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Application {
+    /// Favors speech intelligibility.
+    Voip,
+    /// Favors faithfulness to the input, e.g. for music.
+    Audio,
+    /// Minimal algorithmic delay, disables the speech optimized modes.
+    LowDelay,
+}
+
+impl Application {
+    fn to_opus(self) -> opus2::Application {
+        match self {
+            Self::Voip => opus2::Application::Voip,
+            Self::Audio => opus2::Application::Audio,
+            Self::LowDelay => opus2::Application::LowDelay,
+        }
+    }
+}
+
+/// Bitrate mode of the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vbr {
+    /// Constant bitrate, every packet of a given duration has the same size.
+    Constant,
+    /// Variable bitrate, with the bitrate constrained to stay close to the target.
+    Constrained,
+    /// Variable bitrate, the libopus default.
+    Unconstrained,
+}
+
+/// Duration of each packet. Packets of up to 20ms hold a single frame, shorter frames lower the
+/// latency for realtime uses at the cost of a lower coding efficiency, below 10ms libopus only
+/// uses its CELT mode. Longer packets are encoded as multi-frame packets of 20ms frames, these
+/// are more efficient at very low bitrates, e.g. for archival, at the cost of latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDuration {
+    Ms2_5,
+    Ms5,
+    Ms10,
+    Ms20,
+    Ms40,
+    Ms60,
+    Ms80,
+    Ms100,
+    Ms120,
+}
+
+impl FrameDuration {
+    pub fn as_micros(&self) -> usize {
+        match self {
+            Self::Ms2_5 => 2_500,
+            Self::Ms5 => 5_000,
+            Self::Ms10 => 10_000,
+            Self::Ms20 => 20_000,
+            Self::Ms40 => 40_000,
+            Self::Ms60 => 60_000,
+            Self::Ms80 => 80_000,
+            Self::Ms100 => 100_000,
+            Self::Ms120 => 120_000,
+        }
+    }
+
+    /// Rounded down, 2ms for `Ms2_5`.
+    pub fn as_millis(&self) -> usize {
+        self.as_micros() / 1000
+    }
+
+    /// Number of samples per packet at `sample_rate`.
+    pub fn samples(&self, sample_rate: usize) -> usize {
+        sample_rate * self.as_micros() / 1_000_000
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderConfig {
+    pub sample_rate: usize,
+    /// Pad every opus packet to exactly this number of bytes, the encoder is also prevented from
+    /// producing larger packets. With encrypted transports, this avoids leaking speech activity
+    /// through the packet lengths.
+    pub constant_packet_size: Option<usize>,
+    /// Chapter marks written in the OpusTags header, only used by the ogg encoder.
+    pub chapters: Vec<crate::ogg_opus::Chapter>,
+    /// Number of samples per step for `ogg_opus::Encoder::encode_step`, this must be a multiple
+    /// of the encoder frame size.
+    pub step_size: Option<usize>,
+    /// Extra comments written in the OpusTags header, only used by the ogg encoder.
+    pub comments: Vec<(String, String)>,
+    /// Vendor string of the OpusTags header, `ogg_opus::DEFAULT_VENDOR` when not set.
+    pub vendor: Option<String>,
+    /// Stamp the ENCODER, CREATION_TIME and SAMPLE_RATE comments in the OpusTags header, unless
+    /// these keys are already present in `comments`.
+    pub creation_metadata: bool,
+    /// Serial of the ogg logical stream, 42 by default.
+    pub serial: u32,
+    /// Produce byte-identical outputs when encoding the same pcm twice: the time-derived
+    /// CREATION_TIME comment is never written. The libopus settings only depend on this config.
+    /// Note that libopus only guarantees identical outputs for the same library build and CPU
+    /// feature set.
+    pub deterministic: bool,
+    /// Target bitrate in bits per second, libopus picks it automatically when not set.
+    pub bitrate: Option<u32>,
+    /// Between 0 and 10, higher values are slower but give a better quality. The libopus
+    /// default, 9 on most platforms, is used when not set.
+    pub complexity: Option<u8>,
+    pub vbr: Vbr,
+    /// `Application::Voip` by default, `Application::Audio` works better for music.
+    pub application: Application,
+    /// Duration of each packet, packets of `ENCODER_FRAME_SIZE` samples are used when not set.
+    pub frame_duration: Option<FrameDuration>,
+    /// Detect pcm mistakenly scaled to ±32768 rather than ±1 and rescale it. Once detected, the
+    /// rescaling applies to the rest of the stream and a warning is logged through the `log`
+    /// crate, see also `Encoder::int16_scaling_detected`.
+    pub fix_int16_scaling: bool,
+    /// Number of channels, the pcm passed to the encoder is interleaved. Streams with more than
+    /// two channels use the Vorbis channel order, mapping family 1, up to 8 channels and no
+    /// predefined order, mapping family 255, above that.
+    pub channels: usize,
+    /// Applied to the pcm passed to the encoder, which then has `input_channels` interleaved
+    /// channels. The map must produce `channels` channels.
+    pub channel_map: Option<crate::ChannelMap>,
+    /// Accept any `sample_rate`, the pcm is resampled to 48kHz before being encoded when the
+    /// rate is not one of [`SAMPLE_RATES`]. The resampler delay is compensated so that the
+    /// encoded stream stays aligned with the input.
+    pub resample: bool,
+    /// Expected packet loss in percent, used by libopus to tune the in-band FEC data that lets
+    /// the decoders recover a lost packet from the next one. No FEC data is produced when not set.
+    pub expected_packet_loss: Option<u8>,
+    /// How the ogg encoder groups packets into pages, one packet per page by default.
+    pub paging: crate::ogg_opus::Paging,
+    /// Discontinuous transmission, during silences the encoder only produces a comfort noise
+    /// update every 400ms and DTX packets in between, see `is_dtx`. The DTX packets are
+    /// still returned so that the granule positions and sequence numbers do not skip, the ogg
+    /// encoder writes them as one byte packets. Receivers conceal the packets a transport does
+    /// not send as lost ones, with `ConcealmentPolicy::Plc` this generates the comfort noise.
+    pub dtx: bool,
+}
+
+impl EncoderConfig {
+    pub fn new(sample_rate: usize) -> Self {
+        Self {
+            sample_rate,
+            constant_packet_size: None,
+            chapters: vec![],
+            step_size: None,
+            comments: vec![],
+            vendor: None,
+            creation_metadata: false,
+            serial: 42,
+            deterministic: false,
+            bitrate: None,
+            complexity: None,
+            vbr: Vbr::Unconstrained,
+            application: Application::Voip,
+            frame_duration: None,
+            fix_int16_scaling: false,
+            channels: 1,
+            channel_map: None,
+            resample: false,
+            expected_packet_loss: None,
+            paging: crate::ogg_opus::Paging::PacketPerPage,
+            dtx: false,
+        }
+    }
+
+    /// Uses the vendor and comments of `tags` for the OpusTags header, replacing the comments
+    /// set so far.
+    pub fn set_tags(&mut self, tags: crate::ogg_opus::OpusTags) {
+        self.vendor = Some(tags.vendor);
+        self.comments = tags.comments;
+    }
+
+    /// Matches the Moshi model cadence: 24kHz audio processed in steps of 80ms (1920 samples),
+    /// each step producing a single ogg page.
+    pub fn moshi_steps() -> Self {
+        let mut cfg = Self::new(24_000);
+        cfg.step_size = Some(1920);
+        cfg
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    /// Position of the end of the packet in the stream, this uses a 48kHz rate whatever the
+    /// sample rate of the encoded audio, as for ogg granule positions.
+    pub granule_position: u64,
+    /// Monotonic sequence number, starting from 0 for the first packet of the stream.
+    pub seq: u64,
+}
+
+const SEQ_LEN: usize = 8;
+
+impl Packet {
+    /// Serializes the packet for the raw packet mode: the 8 bytes big-endian sequence number
+    /// followed by the opus data.
+    pub fn to_framed(&self) -> Vec<u8> {
+        let mut framed = Vec::with_capacity(SEQ_LEN + self.data.len());
+        framed.extend_from_slice(&self.seq.to_be_bytes());
+        framed.extend_from_slice(&self.data);
+        framed
+    }
+
+    /// Splits a framed packet into its sequence number and opus data.
+    pub fn parse_framed(framed: &[u8]) -> Result<(u64, &[u8])> {
+        if framed.len() < SEQ_LEN {
+            crate::bail!("framed packet is too short {}", framed.len())
+        }
+        let (seq, data) = framed.split_at(SEQ_LEN);
+        let seq =
+            u64::from_be_bytes([seq[0], seq[1], seq[2], seq[3], seq[4], seq[5], seq[6], seq[7]]);
+        Ok((seq, data))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceStatus {
+    /// The packet follows the previous one, `lost` packets are missing in between.
+    InOrder { lost: u64 },
+    /// The packet is a duplicate or arrived after a more recent one, it has been dropped.
+    Late,
+}
+
+/// Tracks the sequence numbers of received packets to detect discontinuities.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    next_seq: Option<u64>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The tracker starts over after `u64::MAX`, the packet that follows is in order.
+    pub fn observe(&mut self, seq: u64) -> SequenceStatus {
+        match self.next_seq {
+            Some(next_seq) if seq < next_seq => SequenceStatus::Late,
+            next_seq => {
+                self.next_seq = seq.checked_add(1);
+                SequenceStatus::InOrder { lost: seq - next_seq.unwrap_or(seq) }
+            }
+        }
+    }
+}
+
+/// Network conditions reported by the caller, all the fields are optional.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CongestionSignals {
+    pub rtt: Option<std::time::Duration>,
+    /// Fraction of lost packets, between 0 and 1.
+    pub loss_rate: Option<f32>,
+    /// Bandwidth estimate from the transport, in bits per second.
+    pub available_bitrate: Option<u32>,
+}
+
+/// Encoder settings that can be changed mid-stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderSettings {
+    /// Bitrate in bits per second.
+    pub bitrate: u32,
+    /// Between 0 and 10.
+    pub complexity: u8,
+}
+
+/// Maps the reported congestion signals and the current settings to the settings to use for the
+/// next frames.
+pub type BitrateController =
+    Box<dyn FnMut(&CongestionSignals, &EncoderSettings) -> EncoderSettings + Send>;
+
+/// A simple additive increase / multiplicative decrease controller: the bitrate is reduced by
+/// 30% when the loss rate goes above 5% or the rtt above 300ms, and increased by 1kbps
+/// otherwise, staying between `min_bitrate` and `max_bitrate`.
+pub fn aimd_controller(min_bitrate: u32, max_bitrate: u32) -> BitrateController {
+    Box::new(move |signals, settings| {
+        let congested = signals.loss_rate.is_some_and(|l| l > 0.05)
+            || signals.rtt.is_some_and(|rtt| rtt > std::time::Duration::from_millis(300));
+        let mut bitrate =
+            if congested { settings.bitrate * 7 / 10 } else { settings.bitrate + 1000 };
+        if let Some(available) = signals.available_bitrate {
+            bitrate = bitrate.min(available)
+        }
+        let bitrate = bitrate.clamp(min_bitrate, max_bitrate);
+        EncoderSettings { bitrate, ..*settings }
+    })
+}
+
+// Float pcm is expected in [-1, 1], samples well above that but still in the int16 range are
+// most likely int16 values converted with `as f32` and no scaling.
+fn is_int16_scaled(pcm: &[f32]) -> bool {
+    let peak = pcm.iter().fold(0f32, |m, v| m.max(v.abs()));
+    peak > 2. && peak <= 32768.
+}
+
+/// Encodes pcm data into raw opus packets, without any container.
+pub struct Encoder {
+    // With mapping family 0, the multistream encoder produces plain mono or stereo packets.
+    encoder: opus2::MSEncoder,
+    channel_mapping: ChannelMapping,
+    channel_map: Option<crate::ChannelMap>,
+    total_data: usize,
+    seq: u64,
+    sample_rate: usize,
+    input_sample_rate: usize,
+    resampler: Option<crate::resample::StreamResampler>,
+    frame_size: usize,
+    out_pcm: std::collections::VecDeque<f32>,
+    opus_buf: Vec<u8>,
+    constant_packet_size: Option<usize>,
+    settings: EncoderSettings,
+    // The settings from the config, restored by `reset`.
+    initial_settings: EncoderSettings,
+    input_stats: crate::levels::SampleStats,
+    fix_int16_scaling: bool,
+    int16_scaling_detected: bool,
+    controller: Option<BitrateController>,
+    pending_signals: Option<CongestionSignals>,
+    dtx: bool,
+    initial_dtx: bool,
+}
+
+impl Encoder {
+    /// The sample rate must be one of [`SAMPLE_RATES`], other rates are rejected with
+    /// `Error::OpusUnsupportedSampleRate` unless `EncoderConfig::resample` is set.
+    pub fn new(cfg: &EncoderConfig) -> Result<Self> {
+        let (sample_rate, resampler) = if SAMPLE_RATES.contains(&cfg.sample_rate) {
+            (cfg.sample_rate, None)
+        } else if cfg.resample && cfg.sample_rate > 0 {
+            if cfg.step_size.is_some() {
+                crate::bail!("step_size cannot be used when resampling the input")
+            }
+            (
+                48_000,
+                Some(crate::resample::StreamResampler::new(cfg.sample_rate, 48_000, cfg.channels)?),
+            )
+        } else {
+            return Err(crate::Error::OpusUnsupportedSampleRate(cfg.sample_rate));
+        };
+        let frame_size = match cfg.frame_duration {
+            None => ENCODER_FRAME_SIZE,
+            Some(d) => d.samples(sample_rate),
+        };
+        if let Some(step_size) = cfg.step_size {
+            if step_size == 0 || step_size % frame_size != 0 {
+                crate::bail!("step size {step_size} is not a multiple of {frame_size}")
+            }
+        }
+        if let Some(map) = cfg.channel_map.as_ref() {
+            if map.output_channels() != cfg.channels {
+                crate::bail!(
+                    "channel map produces {} channels, expected {}",
+                    map.output_channels(),
+                    cfg.channels
+                )
+            }
+        }
+        let family = match cfg.channels {
+            0 | 256.. => crate::bail!("unsupported channel count {}", cfg.channels),
+            1 | 2 => 0,
+            3..=8 => 1,
+            _ => 255,
+        };
+        let opus2::SurroundEncoder { mut encoder, streams, coupled_streams, mapping } =
+            opus2::MSEncoder::new_surround(
+                sample_rate as u32,
+                cfg.channels as u8,
+                family,
+                cfg.application.to_opus(),
+            )?;
+        let channel_mapping =
+            ChannelMapping { family: family as u8, streams, coupled_streams, mapping };
+        if let Some(bitrate) = cfg.bitrate {
+            encoder.set_bitrate(opus2::Bitrate::Bits(bitrate as i32))?;
+        }
+        if let Some(complexity) = cfg.complexity {
+            if complexity > 10 {
+                crate::bail!("complexity {complexity} is not between 0 and 10")
+            }
+            encoder.set_complexity(complexity as i32)?;
+        }
+        encoder.set_vbr(cfg.vbr != Vbr::Constant)?;
+        encoder.set_vbr_constraint(cfg.vbr == Vbr::Constrained)?;
+        if let Some(loss) = cfg.expected_packet_loss {
+            if loss > 100 {
+                crate::bail!("expected_packet_loss {loss} is not between 0 and 100")
+            }
+            encoder.set_inband_fec(true)?;
+            encoder.set_packet_loss_perc(loss as i32)?;
+        }
+        encoder.set_dtx(cfg.dtx)?;
+        let bitrate = match encoder.get_bitrate()? {
+            opus2::Bitrate::Bits(bits) => bits as u32,
+            _ => 0,
+        };
+        let settings = EncoderSettings { bitrate, complexity: encoder.get_complexity()? as u8 };
+        let out_pcm = std::collections::VecDeque::with_capacity(2 * frame_size * cfg.channels);
+        let opus_buf = match cfg.constant_packet_size {
+            // The smallest possible opus packet is a single TOC byte.
+            Some(0) => crate::bail!("constant_packet_size must be at least 1"),
+            Some(size) => vec![0u8; size],
+            // A 120ms packet takes at most 7650 bytes per stream.
+            None => vec![0u8; usize::max(50_000, 8_000 * streams as usize)],
+        };
+        Ok(Self {
+            encoder,
+            channel_mapping,
+            channel_map: cfg.channel_map.clone(),
+            total_data: 0,
+            seq: 0,
+            out_pcm,
+            opus_buf,
+            sample_rate,
+            input_sample_rate: cfg.sample_rate,
+            resampler,
+            frame_size,
+            constant_packet_size: cfg.constant_packet_size,
+            settings,
+            initial_settings: settings,
+            input_stats: crate::levels::SampleStats::new(),
+            fix_int16_scaling: cfg.fix_int16_scaling,
+            int16_scaling_detected: false,
+            controller: None,
+            pending_signals: None,
+            dtx: cfg.dtx,
+            initial_dtx: cfg.dtx,
+        })
+    }
+
+    /// Brings the encoder back to the state it had when created, so that it can be reused for
+    /// another stream without re-creating the libopus encoder. The bitrate controller is removed.
+    pub fn reset(&mut self) -> Result<()> {
+        self.encoder.reset_state()?;
+        if self.settings.bitrate != self.initial_settings.bitrate {
+            self.encoder.set_bitrate(opus2::Bitrate::Bits(self.initial_settings.bitrate as i32))?;
+        }
+        if self.settings.complexity != self.initial_settings.complexity {
+            self.encoder.set_complexity(self.initial_settings.complexity as i32)?;
+        }
+        self.settings = self.initial_settings;
+        self.set_dtx(self.initial_dtx)?;
+        self.total_data = 0;
+        self.seq = 0;
+        self.out_pcm.clear();
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset()
+        }
+        self.input_stats.reset();
+        self.int16_scaling_detected = false;
+        self.controller = None;
+        self.pending_signals = None;
+        Ok(())
+    }
+
+    /// Position of the end of the last encoded frame, in samples at 48kHz.
+    pub fn granule_position(&self) -> u64 {
+        self.total_data as u64 * 48_000 / self.sample_rate as u64
+    }
+
+    /// Number of samples per channel waiting for a full frame to be available.
+    pub fn buffered_samples(&self) -> usize {
+        self.out_pcm.len() / self.channels()
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channel_mapping.channels()
+    }
+
+    /// Number of interleaved channels in the pcm passed to `encode`, this differs from
+    /// `channels` when a channel map is set.
+    pub fn input_channels(&self) -> usize {
+        self.channel_map.as_ref().map_or(self.channels(), |m| m.input_channels)
+    }
+
+    pub fn channel_mapping(&self) -> &ChannelMapping {
+        &self.channel_mapping
+    }
+
+    /// The rate at which the audio is encoded, this is 48kHz when resampling the input.
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// The sample rate of the pcm passed to `encode`.
+    pub fn input_sample_rate(&self) -> usize {
+        self.input_sample_rate
+    }
+
+    /// Number of samples per channel encoded in each packet.
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    pub fn settings(&self) -> EncoderSettings {
+        self.settings
+    }
+
+    /// Levels of the pcm pushed to the encoder, excluding the silence added by `push_silence`.
+    pub fn input_stats(&self) -> &crate::levels::SampleStats {
+        &self.input_stats
+    }
+
+    /// Whether the input has been detected as int16-scaled and is being rescaled, this is only
+    /// set when `EncoderConfig::fix_int16_scaling` is enabled. A warning is logged on detection,
+    /// this lets callers without a logger report the issue to fix the input.
+    pub fn int16_scaling_detected(&self) -> bool {
+        self.int16_scaling_detected
+    }
+
+    /// Registers a controller that is invoked with the signals passed to `report_congestion`.
+    pub fn set_bitrate_controller(&mut self, controller: BitrateController) {
+        self.controller = Some(controller)
+    }
+
+    /// Enables or disables discontinuous transmission from the next frame on, see
+    /// `EncoderConfig::dtx`.
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<()> {
+        self.encoder.set_dtx(dtx)?;
+        self.dtx = dtx;
+        Ok(())
+    }
+
+    pub fn dtx(&self) -> bool {
+        self.dtx
+    }
+
+    /// Whether `packet`, produced by this encoder, is a DTX packet.
+    pub fn is_dtx(&self, packet: &Packet) -> bool {
+        is_dtx(&packet.data, self.channel_mapping.streams as usize)
+    }
+
+    /// The resulting settings are applied at the start of the next frame to be encoded.
+    pub fn report_congestion(&mut self, signals: CongestionSignals) {
+        self.pending_signals = Some(signals)
+    }
+
+    fn apply_controller(&mut self) -> Result<()> {
+        let (Some(controller), Some(signals)) =
+            (self.controller.as_mut(), self.pending_signals.take())
+        else {
+            return Ok(());
+        };
+        let settings = controller(&signals, &self.settings);
+        self.apply_settings(settings)
+    }
+
+    fn apply_settings(&mut self, settings: EncoderSettings) -> Result<()> {
+        if settings.bitrate != self.settings.bitrate {
+            self.encoder.set_bitrate(opus2::Bitrate::Bits(settings.bitrate as i32))?;
+        }
+        if settings.complexity != self.settings.complexity {
+            self.encoder.set_complexity(settings.complexity.min(10) as i32)?;
+        }
+        self.settings = settings;
+        Ok(())
+    }
+
+    /// Changes the target bitrate, in bits per second, from the next frame on. The samples
+    /// already buffered for a partial frame are encoded with the new bitrate, the stream is not
+    /// interrupted.
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<()> {
+        if bitrate == 0 {
+            crate::bail!("the bitrate has to be positive")
+        }
+        self.apply_settings(EncoderSettings { bitrate, ..self.settings })
+    }
+
+    /// Changes the complexity, between 0 and 10, from the next frame on, see `set_bitrate`.
+    pub fn set_complexity(&mut self, complexity: u8) -> Result<()> {
+        if complexity > 10 {
+            crate::bail!("complexity {complexity} is not between 0 and 10")
+        }
+        self.apply_settings(EncoderSettings { complexity, ..self.settings })
+    }
+
+    /// Delay added by the encoder, in samples at the encoder sample rate.
+    pub fn lookahead(&mut self) -> Result<usize> {
+        Ok(self.encoder.get_lookahead()? as usize)
+    }
+
+    /// Encodes as many full frames as possible, the remaining samples are kept for the next call.
+    /// When a channel map is set, `pcm` must only contain full frames of interleaved samples.
+    pub fn encode(&mut self, pcm: &[f32]) -> Result<Vec<Packet>> {
+        let mapped;
+        let pcm = match self.channel_map.as_ref() {
+            None => pcm,
+            Some(map) => {
+                mapped = map.apply(pcm);
+                mapped.as_slice()
+            }
+        };
+        self.input_stats.push(pcm);
+        if self.fix_int16_scaling && !self.int16_scaling_detected && is_int16_scaled(pcm) {
+            log::warn!("encoder input looks scaled to ±32768 rather than ±1, rescaling it");
+            self.int16_scaling_detected = true
+        }
+        if self.int16_scaling_detected {
+            let pcm: Vec<f32> = pcm.iter().map(|v| v / 32768.).collect();
+            self.encode_resampled(&pcm)
+        } else {
+            self.encode_resampled(pcm)
+        }
+    }
+
+    fn encode_resampled(&mut self, pcm: &[f32]) -> Result<Vec<Packet>> {
+        match self.resampler.as_mut() {
+            None => self.encode_frames(pcm),
+            Some(resampler) => {
+                let pcm = resampler.process(pcm)?;
+                self.encode_frames(&pcm)
+            }
+        }
+    }
+
+    fn encode_frames(&mut self, pcm: &[f32]) -> Result<Vec<Packet>> {
+        let mut packets = vec![];
+        self.out_pcm.extend(pcm.iter());
+        let chunk_len = self.frame_size * self.channels();
+        let nchunks = self.out_pcm.len() / chunk_len;
+        for _chunk_id in 0..nchunks {
+            let mut chunk = Vec::with_capacity(chunk_len);
+            for _i in 0..chunk_len {
+                let v = match self.out_pcm.pop_front() {
+                    None => return Err(crate::Error::OpusMissingPcm),
+                    Some(v) => v,
+                };
+                chunk.push(v)
+            }
+            self.apply_controller()?;
+            self.total_data += self.frame_size;
+            let mut size = self.encoder.encode_float(&chunk, &mut self.opus_buf)?;
+            if let Some(packet_size) = self.constant_packet_size {
+                let streams = self.channel_mapping.streams;
+                opus2::packet::multistream_pad(&mut self.opus_buf[..packet_size], size, streams)?;
+                size = packet_size;
+            }
+            // The granule position uses a fixed rate of 48kHz even if the underlying audio uses a
+            // different rate.
+            // This does not matter when reading ogg files in chrome but should be set properly for
+            // VLC to work.
+            let granule_position = self.granule_position();
+            if size > 0 {
+                let data = self.opus_buf[..size].to_vec();
+                packets.push(Packet { data, granule_position, seq: self.seq });
+                self.seq += 1;
+            }
+        }
+        Ok(packets)
+    }
+
+    /// Appends `duration` of silence after the pcm pushed so far, the granule positions advance
+    /// as if zeros had been pushed. Silent frames compress to a few bytes each.
+    pub fn push_silence(&mut self, duration: std::time::Duration) -> Result<Vec<Packet>> {
+        let zeros = vec![0f32; self.frame_size * self.channels()];
+        // When resampling, the silence goes through the resampler after the pending input.
+        let sample_rate = self.input_sample_rate;
+        let mut remaining = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+        let mut packets = vec![];
+        while remaining > 0 {
+            let n = usize::min(remaining, self.frame_size);
+            packets.extend(self.encode_resampled(&zeros[..n * self.channels()])?);
+            remaining -= n;
+        }
+        Ok(packets)
+    }
+
+    /// Flushes the resampler if any, then pads the last partial frame with silence and encodes
+    /// it. The granule position of the last packet marks the end of the actual samples so that
+    /// the padding can be trimmed.
+    pub fn flush(&mut self) -> Result<Vec<Packet>> {
+        let mut packets = match self.resampler.as_mut() {
+            None => vec![],
+            Some(resampler) => {
+                let pcm = resampler.flush()?;
+                self.encode_frames(&pcm)?
+            }
+        };
+        let buffered = self.buffered_samples();
+        if buffered == 0 {
+            return Ok(packets);
+        }
+        let end = self.granule_position() + buffered as u64 * 48_000 / self.sample_rate as u64;
+        let zeros = vec![0f32; (self.frame_size - buffered) * self.channels()];
+        let mut padded = self.encode_frames(&zeros)?;
+        if let Some(packet) = padded.last_mut() {
+            packet.granule_position = end
+        }
+        packets.extend(padded);
+        Ok(packets)
+    }
+}
+
+/// Counters updated by the decoder, useful to check what the remote encoder actually sends.
+#[derive(Debug, Clone, Default)]
+pub struct DecoderStats {
+    pub packets: u64,
+    /// Packets without any frame, see `has_frames`, these are not decoded.
+    pub empty_packets: u64,
+    /// Packets reported as lost by the sequence numbers of the raw packet mode.
+    pub lost_packets: u64,
+    /// Gaps longer than the maximum gap, these were skipped rather than concealed.
+    pub discontinuities: u64,
+    /// Number of samples per channel produced, at the output sample rate.
+    pub samples: u64,
+    /// Table of contents of the last decoded packet.
+    pub last_toc: Option<Toc>,
+    pub packets_per_mode: std::collections::HashMap<Mode, u64>,
+    pub packets_per_bandwidth: std::collections::HashMap<Bandwidth, u64>,
+    /// Levels of the decoded pcm.
+    pub levels: crate::levels::SampleStats,
+}
+
+/// Called with the number of missing samples at the output sample rate, counting all the
+/// channels, the concealment samples have to be appended to the vector.
+pub type ConcealmentCallback = std::sync::Arc<dyn Fn(usize, &mut Vec<f32>) + Send + Sync>;
+
+/// The information passed to an external concealment implementation.
+pub struct ConcealmentContext<'a> {
+    /// The most recent pcm output by libopus, decoded or concealed, up to 200ms. This is
+    /// interleaved for streams with multiple channels.
+    pub history: &'a [f32],
+    /// Number of missing samples per channel at `sample_rate`.
+    pub gap: usize,
+    pub channels: usize,
+    /// The rate at which libopus runs, this can differ from the decoder output rate.
+    pub sample_rate: usize,
+    /// The first packet received after the gap, when available. With DRED, this packet carries
+    /// the redundancy needed to reconstruct the gap.
+    pub next_packet: Option<&'a [u8]>,
+}
+
+/// An extension point for external concealment, e.g. a neural PLC or a DRED decoder.
+pub trait Concealer: Send {
+    /// Returns the interleaved concealment pcm for the gap, this is padded with silence or
+    /// truncated to `ctx.gap` samples per channel.
+    fn conceal(&mut self, ctx: &ConcealmentContext) -> Vec<f32>;
+}
+
+// Duration of the decoded pcm kept for the external concealment.
+const HISTORY_MS: usize = 200;
+
+// Default for `Decoder::set_max_gap`, one second at 48kHz.
+const DEFAULT_MAX_GAP: u64 = 48_000;
+
+/// What the decoders do about gaps in the stream, i.e. lost packets in the raw packet mode or
+/// missing pages in the ogg decoders.
+#[derive(Clone, Default)]
+pub enum ConcealmentPolicy {
+    /// Gaps are skipped, the output is shorter than the stream.
+    #[default]
+    Nothing,
+    Silence,
+    /// Opus packet loss concealment.
+    Plc,
+    /// Recovers the end of the gap from the in-band FEC data of the packet following it when
+    /// available, see `EncoderConfig::expected_packet_loss`, and uses `Plc` for the rest.
+    Fec,
+    /// Repeat the last decoded frame with a gain fading to zero over the gap.
+    RepeatFade,
+    Callback(ConcealmentCallback),
+    /// Runs at the libopus rate, before the resampling and speed stages.
+    External(std::sync::Arc<std::sync::Mutex<dyn Concealer>>),
+}
+
+impl std::fmt::Debug for ConcealmentPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nothing => write!(f, "Nothing"),
+            Self::Silence => write!(f, "Silence"),
+            Self::Plc => write!(f, "Plc"),
+            Self::Fec => write!(f, "Fec"),
+            Self::RepeatFade => write!(f, "RepeatFade"),
+            Self::Callback(_) => write!(f, "Callback"),
+            Self::External(_) => write!(f, "External"),
+        }
+    }
+}
+
+pub struct Decoder {
+    // With mapping family 0, the multistream decoder handles plain mono or stereo packets.
+    decoder: opus2::MSDecoder,
+    channel_mapping: ChannelMapping,
+    // One resampler per channel when libopus runs at a different rate from the requested output
+    // rate.
+    resamplers: Vec<crate::AudioOutputData_>,
+    interpolation: crate::resample::Interpolation,
+    planar: Vec<Vec<f32>>,
+    speed: Option<crate::speed::SpeedChanger>,
+    sample_rate: usize,
+    // Interleaved, large enough for the longest packet.
+    frame_buf: Vec<f32>,
+    // The last decoded frame at the libopus rate, for the repeat concealment.
+    last_frame: Vec<f32>,
+    // The most recent pcm at the libopus rate, for the external concealment.
+    history: Vec<f32>,
+    opus_sample_rate: usize,
+    sequence_tracker: SequenceTracker,
+    stats: DecoderStats,
+    concealment: ConcealmentPolicy,
+    // Longest gap that gets concealed, at 48kHz.
+    max_gap: u64,
+    // Position in the stream at 48kHz, including the concealed gaps.
+    position: u64,
+    // Samples at the libopus rate still to be dropped from the output.
+    pre_skip: usize,
+    gain: crate::gain::GainRamp,
+}
+
+impl Decoder {
+    /// Creates a decoder producing pcm data at `sample_rate`. When `decode_at_48khz` is set,
+    /// libopus always runs at 48kHz and its output is resampled to `sample_rate`, this results in
+    /// better quality than asking libopus for a lower internal rate but uses more CPU.
+    pub fn new(sample_rate: usize, decode_at_48khz: bool) -> Result<Self> {
+        Self::new_with_channel_mapping(sample_rate, decode_at_48khz, &ChannelMapping::mono())
+    }
+
+    /// Same as `new` for streams with multiple channels, the decoded pcm is interleaved.
+    pub fn new_with_channel_mapping(
+        sample_rate: usize,
+        decode_at_48khz: bool,
+        channel_mapping: &ChannelMapping,
+    ) -> Result<Self> {
+        let opus_sample_rate = if decode_at_48khz { 48_000 } else { sample_rate };
+        let mut decoder = Self {
+            decoder: opus2::MSDecoder::new(opus_sample_rate as u32, 1, 0, &[0])?,
+            channel_mapping: ChannelMapping::mono(),
+            resamplers: vec![],
+            interpolation: crate::resample::Interpolation::Polynomial,
+            planar: vec![],
+            speed: None,
+            sample_rate,
+            frame_buf: vec![],
+            last_frame: vec![],
+            history: vec![],
+            opus_sample_rate,
+            sequence_tracker: SequenceTracker::new(),
+            stats: DecoderStats::default(),
+            concealment: ConcealmentPolicy::Nothing,
+            max_gap: DEFAULT_MAX_GAP,
+            position: 0,
+            pre_skip: 0,
+            gain: crate::gain::GainRamp::new(opus_sample_rate, crate::gain::DEFAULT_RAMP),
+        };
+        decoder.set_channel_mapping(channel_mapping)?;
+        Ok(decoder)
+    }
+
+    /// Re-creates the libopus decoder for a different channel mapping, e.g. once the OpusHead
+    /// of an ogg stream has been read. This resets the decoder state.
+    pub fn set_channel_mapping(&mut self, channel_mapping: &ChannelMapping) -> Result<()> {
+        let channels = channel_mapping.channels();
+        if channels == 0 {
+            crate::bail!("no channel in channel mapping")
+        }
+        if channels > 1 && self.speed.is_some() {
+            crate::bail!("playback speed changes are only supported for mono streams")
+        }
+        self.decoder = opus2::MSDecoder::new(
+            self.opus_sample_rate as u32,
+            channel_mapping.streams,
+            channel_mapping.coupled_streams,
+            &channel_mapping.mapping,
+        )?;
+        self.resamplers = self.new_resamplers(channels)?;
+        self.planar = vec![vec![]; channels];
+        self.frame_buf =
+            vec![0f32; self.opus_sample_rate * MAX_PACKET_DURATION_MS / 1000 * channels];
+        self.last_frame.clear();
+        self.history.clear();
+        self.channel_mapping = channel_mapping.clone();
+        Ok(())
+    }
+
+    fn new_resamplers(&self, channels: usize) -> Result<Vec<crate::AudioOutputData_>> {
+        if self.opus_sample_rate == self.sample_rate {
+            return Ok(vec![]);
+        }
+        (0..channels)
+            .map(|_| {
+                crate::AudioOutputData_::new_with_interpolation(
+                    self.opus_sample_rate,
+                    self.sample_rate,
+                    self.interpolation,
+                )
+            })
+            .collect()
+    }
+
+    /// Sets how the libopus output is resampled when it runs at a different rate from the output
+    /// rate, e.g. a short sinc filter to limit the aliasing in realtime paths. This drops the
+    /// samples held by the resamplers, it should be called before decoding.
+    pub fn set_interpolation(
+        &mut self,
+        interpolation: crate::resample::Interpolation,
+    ) -> Result<()> {
+        self.interpolation = interpolation;
+        self.resamplers = self.new_resamplers(self.channels())?;
+        Ok(())
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channel_mapping.channels()
+    }
+
+    pub fn channel_mapping(&self) -> &ChannelMapping {
+        &self.channel_mapping
+    }
+
+    /// The rate of the decoded pcm.
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Resets the libopus and resampler states, e.g. before decoding from a different point in
+    /// the stream. The position and stats are kept.
+    pub fn reset(&mut self) -> Result<()> {
+        let channel_mapping = self.channel_mapping.clone();
+        self.set_channel_mapping(&channel_mapping)
+    }
+
+    // Brings the decoder back to the state of a freshly created decoder for `channel_mapping`,
+    // the libopus decoder is only re-created when the mapping changes.
+    pub(crate) fn recycle(&mut self, channel_mapping: &ChannelMapping) -> Result<()> {
+        self.speed = None;
+        if *channel_mapping == self.channel_mapping {
+            self.decoder.reset_state()?;
+            self.resamplers.iter_mut().for_each(|r| r.reset());
+            self.planar.iter_mut().for_each(|p| p.clear());
+            self.last_frame.clear();
+            self.history.clear();
+        } else {
+            self.set_channel_mapping(channel_mapping)?;
+        }
+        self.sequence_tracker = SequenceTracker::new();
+        self.stats = DecoderStats::default();
+        self.concealment = ConcealmentPolicy::Nothing;
+        self.max_gap = DEFAULT_MAX_GAP;
+        self.position = 0;
+        self.pre_skip = 0;
+        self.gain.reset(1.);
+        Ok(())
+    }
+
+    /// Drops the first `pre_skip` samples at 48kHz from the decoded output, e.g. the pre-skip of
+    /// an OpusHead header. This applies to the output following this call.
+    pub fn set_pre_skip(&mut self, pre_skip: usize) {
+        self.pre_skip = pre_skip * self.opus_sample_rate / 48_000
+    }
+
+    /// Scales the decoded output by a gain in dB, e.g. the output gain of an OpusHead header.
+    /// Changes made once some output has been produced are applied with a short ramp.
+    pub fn set_output_gain(&mut self, gain_db: f32) {
+        let gain = 10f32.powf(gain_db / 20.);
+        if self.stats.samples == 0 {
+            self.gain.reset(gain)
+        } else {
+            self.gain.set_gain(gain)
+        }
+    }
+
+    pub fn set_concealment(&mut self, concealment: ConcealmentPolicy) {
+        self.concealment = concealment
+    }
+
+    /// Longest gap that gets concealed, one second by default. Longer gaps, e.g. a sequence
+    /// number or granule position jumping far ahead, are discontinuities: the decoder resyncs on
+    /// the next packet without producing any output for the gap.
+    pub fn set_max_gap(&mut self, max_gap: std::time::Duration) {
+        self.max_gap = u64::try_from(max_gap.as_micros() * 48 / 1000).unwrap_or(u64::MAX)
+    }
+
+    /// Position of the end of the decoded audio in the stream, in samples at 48kHz, including the
+    /// concealed gaps.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Moves the position without producing any output, e.g. to the start of an ogg stream.
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position
+    }
+
+    /// Changes the playback speed, between 0.5 and 2, this can be called mid-stream. This is
+    /// only supported for mono streams.
+    pub fn set_playback_speed(&mut self, speed: f64, mode: crate::speed::SpeedMode) -> Result<()> {
+        if self.channels() > 1 {
+            crate::bail!("playback speed changes are only supported for mono streams")
+        }
+        match self.speed.as_mut() {
+            Some(s) if s.mode() == mode => s.set_speed(speed)?,
+            _ => self.speed = Some(crate::speed::SpeedChanger::new(mode, self.sample_rate, speed)?),
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &DecoderStats {
+        &self.stats
+    }
+
+    /// The resampler applied to the libopus output, if any. With multiple channels, this is the
+    /// resampler of the first channel, all the channels use the same settings.
+    pub fn resampler(&self) -> Option<&crate::AudioOutputData_> {
+        self.resamplers.first()
+    }
+
+    /// Delay of the decoded output in samples at the output rate, on top of the codec delay. When
+    /// resampling, this includes the samples held until a full resampler chunk is available.
+    pub fn output_delay(&self) -> usize {
+        match self.resamplers.first() {
+            None => 0,
+            // libopus runs at 48kHz when resampling.
+            Some(r) => r.output_delay() + r.pending_input() * self.sample_rate / 48_000,
+        }
+    }
+
+    /// Decodes a packet framed with [`Packet::to_framed`], late packets are dropped and the
+    /// returned status reports how many packets were lost since the previous one.
+    pub fn decode_framed(&mut self, framed: &[u8], pcm: &mut Vec<f32>) -> Result<SequenceStatus> {
+        let (seq, data) = Packet::parse_framed(framed)?;
+        self.decode_sequenced(seq, data, pcm)
+    }
+
+    /// Same as `decode_framed` for a packet whose sequence number is carried separately, e.g.
+    /// by an `envelope::Opener`.
+    pub fn decode_sequenced(
+        &mut self,
+        seq: u64,
+        data: &[u8],
+        pcm: &mut Vec<f32>,
+    ) -> Result<SequenceStatus> {
+        let status = self.sequence_tracker.observe(seq);
+        if let SequenceStatus::InOrder { lost } = status {
+            self.stats.lost_packets += lost;
+            // Lost packets are assumed to have the same duration as the last decoded one.
+            let duration =
+                self.stats.last_toc.map_or(0, |toc| toc.duration_us() as u64 * 48 / 1000);
+            if lost > 0 && duration > 0 {
+                self.conceal_before(lost.saturating_mul(duration), Some(data), pcm)?;
+            }
+        }
+        if status != SequenceStatus::Late {
+            self.decode(data, pcm)?;
+        }
+        Ok(status)
+    }
+
+    /// Decodes a single opus packet and appends the resulting pcm data to `pcm`, returns the
+    /// number of samples that have been appended, i.e. the number of samples per channel times
+    /// the number of channels.
+    ///
+    /// Packets without any frame, see `has_frames`, produce no samples and leave the position
+    /// unchanged, libopus would otherwise treat an empty packet as a lost one.
+    pub fn decode(&mut self, packet: &[u8], pcm: &mut Vec<f32>) -> Result<usize> {
+        self.stats.packets += 1;
+        // Malformed packets, with no frame count, are left to libopus to report.
+        if !has_frames(packet) && (packet.is_empty() || Toc::parse(packet).is_ok()) {
+            self.stats.empty_packets += 1;
+            return Ok(0);
+        }
+        if let Ok(toc) = Toc::parse(packet) {
+            self.stats.last_toc = Some(toc);
+            *self.stats.packets_per_mode.entry(toc.mode).or_default() += 1;
+            *self.stats.packets_per_bandwidth.entry(toc.bandwidth).or_default() += 1;
+        }
+        let read_size = self.decoder.decode_float(
+            packet,
+            &mut self.frame_buf,
+            /* Forward Error Correction */ false,
+        )?;
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(&self.frame_buf[..read_size * self.channels()]);
+        self.position += (read_size * 48_000 / self.opus_sample_rate) as u64;
+        self.output(read_size, pcm)
+    }
+
+    /// Fills a gap of `duration` samples at 48kHz according to the concealment policy, returns
+    /// the number of samples appended to `pcm`. Gaps longer than the maximum gap, see
+    /// `set_max_gap`, are skipped and leave the position unchanged.
+    pub fn conceal(&mut self, duration: u64, pcm: &mut Vec<f32>) -> Result<usize> {
+        self.conceal_before(duration, None, pcm)
+    }
+
+    /// Decodes `packet` after `lost` packets have been lost, these are assumed to have the same
+    /// duration as the last decoded packet. The last lost packet is recovered from the in-band
+    /// FEC data of `packet` when available, the libopus PLC is used for the others, whatever the
+    /// concealment policy. Returns the number of samples appended to `pcm`.
+    pub fn decode_with_loss(
+        &mut self,
+        packet: &[u8],
+        lost: u64,
+        pcm: &mut Vec<f32>,
+    ) -> Result<usize> {
+        let mut len = 0;
+        if lost > 0 {
+            self.stats.lost_packets += lost;
+            let duration = lost.saturating_mul(self.last_packet_duration());
+            len += self.conceal_with(ConcealmentPolicy::Fec, duration, Some(packet), pcm)?;
+        }
+        Ok(len + self.decode(packet, pcm)?)
+    }
+
+    /// Conceals a lost packet with the libopus PLC when the following packet is not available,
+    /// e.g. because of a jitter buffer deadline. The lost packet is assumed to have the same
+    /// duration as the last decoded packet.
+    pub fn report_lost_packet(&mut self, pcm: &mut Vec<f32>) -> Result<usize> {
+        self.stats.lost_packets += 1;
+        let duration = self.last_packet_duration();
+        self.conceal_with(ConcealmentPolicy::Plc, duration, None, pcm)
+    }
+
+    // Duration of the last decoded packet in samples at 48kHz, 20ms if no packet has been decoded.
+    fn last_packet_duration(&self) -> u64 {
+        self.stats.last_toc.map_or(960, |toc| toc.duration_us() as u64 * 48 / 1000)
+    }
+
+    // Same as `conceal`, with the packet following the gap if available.
+    pub(crate) fn conceal_before(
+        &mut self,
+        duration: u64,
+        next_packet: Option<&[u8]>,
+        pcm: &mut Vec<f32>,
+    ) -> Result<usize> {
+        self.conceal_with(self.concealment.clone(), duration, next_packet, pcm)
+    }
+
+    fn conceal_with(
+        &mut self,
+        concealment: ConcealmentPolicy,
+        duration: u64,
+        next_packet: Option<&[u8]>,
+        pcm: &mut Vec<f32>,
+    ) -> Result<usize> {
+        if duration > self.max_gap {
+            self.stats.discontinuities += 1;
+            return Ok(0);
+        }
+        self.position += duration;
+        let channels = self.channels();
+        let opus_len = (duration as usize * self.opus_sample_rate).div_ceil(48_000);
+        // Chunks of 20ms, libopus PLC requires multiples of 2.5ms.
+        let chunk = self.opus_sample_rate / 50;
+        let step = self.opus_sample_rate / 400;
+        let mut len = 0;
+        match concealment {
+            ConcealmentPolicy::Nothing => {}
+            ConcealmentPolicy::Callback(f) => {
+                let len_before = pcm.len();
+                f(opus_len * self.sample_rate / self.opus_sample_rate * channels, pcm);
+                len = pcm.len() - len_before;
+                self.stats.samples += (len / channels) as u64;
+                self.stats.levels.push(&pcm[len_before..]);
+            }
+            ConcealmentPolicy::External(concealer) => {
+                let history_len = self.opus_sample_rate * HISTORY_MS / 1000 * channels;
+                let ctx = ConcealmentContext {
+                    history: &self.history[self.history.len().saturating_sub(history_len)..],
+                    gap: opus_len,
+                    channels,
+                    sample_rate: self.opus_sample_rate,
+                    next_packet,
+                };
+                let mut concealed = match concealer.lock() {
+                    Ok(mut concealer) => concealer.conceal(&ctx),
+                    Err(_) => crate::bail!("concealer mutex poisoned"),
+                };
+                concealed.resize(opus_len * channels, 0.);
+                for start in (0..opus_len).step_by(chunk) {
+                    let n = usize::min(chunk, opus_len - start);
+                    self.frame_buf[..n * channels]
+                        .copy_from_slice(&concealed[start * channels..(start + n) * channels]);
+                    len += self.output(n, pcm)?;
+                }
+            }
+            ConcealmentPolicy::Silence => {
+                for start in (0..opus_len).step_by(chunk) {
+                    let n = usize::min(chunk, opus_len - start);
+                    self.frame_buf[..n * channels].fill(0.);
+                    len += self.output(n, pcm)?;
+                }
+            }
+            ConcealmentPolicy::Plc => len += self.plc(opus_len, pcm)?,
+            ConcealmentPolicy::Fec => {
+                let opus_len = opus_len.div_ceil(step) * step;
+                // The FEC data covers the frame preceding the packet, with the same duration as
+                // the packet frames.
+                let fec = next_packet
+                    .filter(|p| has_frames(p))
+                    .and_then(|p| Some((p, Toc::parse(p).ok()?)));
+                let fec_len = fec.map_or(0, |(_, toc)| {
+                    let frame_len = toc.frame_duration_us as usize * self.opus_sample_rate;
+                    usize::min(frame_len / 1_000_000, opus_len)
+                });
+                len += self.plc(opus_len - fec_len, pcm)?;
+                if let Some((packet, _)) = fec.filter(|_| fec_len > 0) {
+                    let buf = &mut self.frame_buf[..fec_len * channels];
+                    let n = self.decoder.decode_float(packet, buf, true)?;
+                    len += self.output(n, pcm)?;
+                }
+            }
+            ConcealmentPolicy::RepeatFade => {
+                let last_frame = std::mem::take(&mut self.last_frame);
+                let last_len = last_frame.len() / channels;
+                for start in (0..opus_len).step_by(chunk) {
+                    let n = usize::min(chunk, opus_len - start);
+                    for (i, v) in self.frame_buf[..n * channels].iter_mut().enumerate() {
+                        let (pos, channel) = (start + i / channels, i % channels);
+                        let gain = 1. - pos as f32 / opus_len as f32;
+                        *v = match last_len {
+                            0 => 0.,
+                            l => last_frame[(pos % l) * channels + channel] * gain,
+                        }
+                    }
+                    len += self.output(n, pcm)?;
+                }
+                self.last_frame = last_frame;
+            }
+        }
+        Ok(len)
+    }
+
+    // Runs the libopus PLC for `len` samples per channel at the libopus rate, rounded up to a
+    // multiple of 2.5ms.
+    fn plc(&mut self, len: usize, pcm: &mut Vec<f32>) -> Result<usize> {
+        let channels = self.channels();
+        // Chunks of 20ms, libopus PLC requires multiples of 2.5ms.
+        let chunk = self.opus_sample_rate / 50;
+        let step = self.opus_sample_rate / 400;
+        let len = len.div_ceil(step) * step;
+        let mut out_len = 0;
+        for start in (0..len).step_by(chunk) {
+            let n = usize::min(chunk, len - start);
+            let buf = &mut self.frame_buf[..n * channels];
+            let n = self.decoder.decode_float(&[], buf, false)?;
+            out_len += self.output(n, pcm)?;
+        }
+        Ok(out_len)
+    }
+
+    // Runs the first `len` samples per channel of `frame_buf` through the resampling and speed
+    // stages.
+    fn output(&mut self, len: usize, pcm: &mut Vec<f32>) -> Result<usize> {
+        let channels = self.channels();
+        if !self.gain.is_unity() {
+            self.gain.apply(&mut self.frame_buf[..len * channels], channels)
+        }
+        let skip = usize::min(self.pre_skip, len);
+        self.pre_skip -= skip;
+        let frame = &self.frame_buf[skip * channels..len * channels];
+        let history_len = self.opus_sample_rate * HISTORY_MS / 1000 * channels;
+        self.history.extend_from_slice(frame);
+        if self.history.len() > 2 * history_len {
+            self.history.drain(..self.history.len() - history_len);
+        }
+        let len_before = pcm.len();
+        let resampled = match self.resamplers.as_mut_slice() {
+            [] => None,
+            [resampler] => {
+                resampler.push_samples(frame)?;
+                Some(resampler.take_all())
+            }
+            resamplers => {
+                self.planar.iter_mut().for_each(|c| c.clear());
+                crate::deinterleave_into(frame, &mut self.planar);
+                for (resampler, pcm) in resamplers.iter_mut().zip(self.planar.iter()) {
+                    resampler.push_samples(pcm)?
+                }
+                let planar: Vec<_> = resamplers.iter_mut().map(|r| r.take_all()).collect();
+                Some(crate::interleave(&planar))
+            }
+        };
+        let frame = resampled.as_deref().unwrap_or(frame);
+        match self.speed.as_mut() {
+            None => pcm.extend_from_slice(frame),
+            Some(speed) => speed.process(frame, pcm),
+        }
+        let len = pcm.len() - len_before;
+        self.stats.samples += (len / channels) as u64;
+        self.stats.levels.push(&pcm[len_before..]);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(n: usize) -> Result<Vec<Packet>> {
+        let mut encoder = Encoder::new(&EncoderConfig::new(48_000))?;
+        let pcm: Vec<f32> = (0..n * 960).map(|i| (i as f32 * 0.05).sin() * 0.3).collect();
+        encoder.encode(&pcm)
+    }
+
+    fn framed(packet: &Packet, seq: u64) -> Vec<u8> {
+        Packet { seq, ..packet.clone() }.to_framed()
+    }
+
+    #[test]
+    fn sequence_tracker() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.observe(3), SequenceStatus::InOrder { lost: 0 });
+        assert_eq!(tracker.observe(4), SequenceStatus::InOrder { lost: 0 });
+        assert_eq!(tracker.observe(7), SequenceStatus::InOrder { lost: 2 });
+        assert_eq!(tracker.observe(5), SequenceStatus::Late);
+        assert_eq!(tracker.observe(7), SequenceStatus::Late);
+        assert_eq!(tracker.observe(u64::MAX), SequenceStatus::InOrder { lost: u64::MAX - 8 });
+        assert_eq!(tracker.observe(0), SequenceStatus::InOrder { lost: 0 });
+    }
+
+    #[test]
+    fn decode_framed_gaps() -> Result<()> {
+        let packets = packets(4)?;
+        assert_eq!(packets.len(), 4);
+        let mut decoder = Decoder::new(48_000, true)?;
+        decoder.set_concealment(ConcealmentPolicy::Silence);
+        let mut pcm = vec![];
+        decoder.decode_framed(&framed(&packets[0], 0), &mut pcm)?;
+        assert_eq!(pcm.len(), 960);
+        // Two lost packets are concealed.
+        let status = decoder.decode_framed(&framed(&packets[1], 3), &mut pcm)?;
+        assert_eq!(status, SequenceStatus::InOrder { lost: 2 });
+        assert_eq!(pcm.len(), 4 * 960);
+        assert_eq!(decoder.position(), 4 * 960);
+        assert_eq!(decoder.decode_framed(&framed(&packets[1], 2), &mut pcm)?, SequenceStatus::Late);
+        // A jump far ahead is a discontinuity, the decoder resyncs without concealing it.
+        let seq = 3 + (1 << 40);
+        decoder.decode_framed(&framed(&packets[2], seq), &mut pcm)?;
+        assert_eq!(pcm.len(), 5 * 960);
+        assert_eq!(decoder.position(), 5 * 960);
+        assert_eq!(decoder.stats().discontinuities, 1);
+        decoder.decode_framed(&framed(&packets[3], u64::MAX), &mut pcm)?;
+        assert_eq!(decoder.stats().discontinuities, 2);
+        assert_eq!(pcm.len(), 6 * 960);
+        Ok(())
+    }
+
+    #[test]
+    fn max_gap() -> Result<()> {
+        let packets = packets(2)?;
+        let mut decoder = Decoder::new(48_000, true)?;
+        decoder.set_concealment(ConcealmentPolicy::Silence);
+        decoder.set_max_gap(std::time::Duration::from_millis(40));
+        let mut pcm = vec![];
+        decoder.decode_framed(&framed(&packets[0], 0), &mut pcm)?;
+        decoder.decode_framed(&framed(&packets[1], 4), &mut pcm)?;
+        assert_eq!(pcm.len(), 2 * 960);
+        assert_eq!(decoder.stats().discontinuities, 1);
+        assert_eq!(decoder.conceal(1920, &mut pcm)?, 1920);
+        assert_eq!(decoder.conceal(1921, &mut pcm)?, 0);
+        assert_eq!(decoder.stats().discontinuities, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn int16_scaling() -> Result<()> {
+        let pcm: Vec<f32> = (0..10 * 960).map(|i| (i as f32 * 0.05).sin() * 0.3).collect();
+        let scaled: Vec<f32> = pcm.iter().map(|v| v * 32768.).collect();
+        let mut cfg = EncoderConfig::new(48_000);
+        let mut reference = Encoder::new(&cfg)?;
+        let reference = reference.encode(&pcm)?;
+        cfg.fix_int16_scaling = true;
+        let mut encoder = Encoder::new(&cfg)?;
+        encoder.encode(&pcm)?;
+        assert!(!encoder.int16_scaling_detected());
+        let mut encoder = Encoder::new(&cfg)?;
+        let packets = encoder.encode(&scaled)?;
+        assert!(encoder.int16_scaling_detected());
+        assert_eq!(packets.len(), reference.len());
+        for (packet, reference) in packets.iter().zip(reference.iter()) {
+            assert_eq!(packet.data, reference.data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fec_recovery() -> Result<()> {
+        let mut cfg = EncoderConfig::new(16_000);
+        cfg.application = Application::Voip;
+        cfg.bitrate = Some(24_000);
+        cfg.expected_packet_loss = Some(20);
+        cfg.frame_duration = Some(FrameDuration::Ms20);
+        let mut encoder = Encoder::new(&cfg)?;
+        // A voiced sound with a varying pitch, which the PLC cannot extrapolate.
+        let pcm: Vec<f32> = (0..30 * 320)
+            .map(|i| {
+                let t = i as f32 / 16_000.;
+                let f0 = 140. + 60. * (2. * std::f32::consts::PI * 3. * t).sin();
+                (1..6).map(|h| (2. * std::f32::consts::PI * f0 * h as f32 * t).sin()).sum::<f32>()
+                    * 0.1
+            })
+            .collect();
+        let packets = encoder.encode(&pcm)?;
+        assert_eq!(packets.len(), 30);
+        let decode = |lost: Option<usize>, fec: bool| -> Result<(Vec<f32>, DecoderStats)> {
+            let mut decoder = Decoder::new(16_000, false)?;
+            let mut out = vec![];
+            for (i, packet) in packets.iter().enumerate() {
+                if Some(i) == lost {
+                    if !fec {
+                        decoder.report_lost_packet(&mut out)?;
+                    }
+                } else if Some(i) == lost.map(|l| l + 1) && fec {
+                    decoder.decode_with_loss(&packet.data, 1, &mut out)?;
+                } else {
+                    decoder.decode(&packet.data, &mut out)?;
+                }
+            }
+            Ok((out, decoder.stats().clone()))
+        };
+        let (reference, _) = decode(None, false)?;
+        let (plc, plc_stats) = decode(Some(20), false)?;
+        let (fec, fec_stats) = decode(Some(20), true)?;
+        assert_eq!(plc.len(), reference.len());
+        assert_eq!(fec.len(), reference.len());
+        assert_eq!((plc_stats.lost_packets, fec_stats.lost_packets), (1, 1));
+        let error = |pcm: &[f32]| -> f32 {
+            let range = 20 * 320..21 * 320;
+            pcm[range.clone()].iter().zip(&reference[range]).map(|(a, b)| (a - b).powi(2)).sum()
+        };
+        let (plc_error, fec_error) = (error(&plc), error(&fec));
+        assert!(fec_error < 0.5 * plc_error, "fec {fec_error} plc {plc_error}");
+        Ok(())
+    }
+}
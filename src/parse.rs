@@ -0,0 +1,475 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Parsing of the ogg pages and of the opus headers and packet TOC. This module, like `crc`, only
+// relies on `core` and `alloc` so that it can be built for targets without std, e.g. firmware
+// sharing the container code with the servers. The buffering readers, the writers and the I/O
+// in `ogg_pager` and `ogg_opus` are built on top of it.
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+use alloc::vec::Vec;
+
+/// The errors of the parsing functions, they convert to the crate errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedCapturePattern([u8; 4]),
+    UnsupportedVersion(u8),
+    /// The data ends within a page, `part` is the header, the segment table or the page.
+    TruncatedPage {
+        part: &'static str,
+        offset: usize,
+    },
+    UnexpectedSignature([u8; 8]),
+    UnexpectedLenForOpusHead(usize),
+    UnsupportedChannelCount {
+        family: u8,
+        channels: u8,
+    },
+    EmptyPacket,
+    MissingFrameCount,
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnexpectedCapturePattern(p) => write!(f, "unexpected ogg capture pattern {p:?}"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported ogg version {v}"),
+            Self::TruncatedPage { part, offset } => {
+                write!(f, "truncated ogg {part} at offset {offset}")
+            }
+            Self::UnexpectedSignature(s) => write!(f, "unexpected ogg signature {s:?}"),
+            Self::UnexpectedLenForOpusHead(l) => write!(f, "unexpected len for opus head {l}"),
+            Self::UnsupportedChannelCount { family, channels } => {
+                write!(f, "unexpected channel count {channels} for mapping family {family}")
+            }
+            Self::EmptyPacket => write!(f, "empty opus packet"),
+            Self::MissingFrameCount => write!(f, "missing frame count in code 3 opus packet"),
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+pub type ParseResult<T> = core::result::Result<T, ParseError>;
+
+// https://xiph.org/ogg/doc/framing.html
+/// Size of the fixed part of a page header, before the segment table.
+pub const PAGE_HEADER_SIZE: usize = 27;
+
+// Offsets of the fields in the page header.
+const HEADER_TYPE_OFFSET: usize = 5;
+const GRANULE_POSITION_OFFSET: usize = 6;
+const SERIAL_OFFSET: usize = 14;
+const PAGE_SEQUENCE_OFFSET: usize = 18;
+const CHECKSUM_OFFSET: usize = 22;
+const PAGE_SEGMENTS_OFFSET: usize = 26;
+
+/// Header type flag of a page starting with the continuation of a packet.
+pub const CONTINUED_PACKET: u8 = 0x01;
+/// Header type flag of the first page of a logical stream.
+pub const BEGIN_OF_STREAM: u8 = 0x02;
+/// Header type flag of the last page of a logical stream.
+pub const END_OF_STREAM: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeader {
+    pub capture_pattern: [u8; 4],
+    pub version: u8,
+    pub header_type: u8,
+    pub granule_position: u64,
+    pub bitstream_serial: u32,
+    pub page_sequence: u32,
+    pub checksum: u32,
+    pub page_segments: u8,
+}
+
+impl PageHeader {
+    /// Reads the header fields at the start of `page` without validating them, `page` must hold
+    /// at least `PAGE_HEADER_SIZE` bytes.
+    pub fn read(page: &[u8]) -> Self {
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([page[pos], page[pos + 1], page[pos + 2], page[pos + 3]])
+        };
+        let mut granule_position = [0u8; 8];
+        granule_position
+            .copy_from_slice(&page[GRANULE_POSITION_OFFSET..GRANULE_POSITION_OFFSET + 8]);
+        Self {
+            capture_pattern: [page[0], page[1], page[2], page[3]],
+            version: page[4],
+            header_type: page[HEADER_TYPE_OFFSET],
+            granule_position: u64::from_le_bytes(granule_position),
+            bitstream_serial: u32_at(SERIAL_OFFSET),
+            page_sequence: u32_at(PAGE_SEQUENCE_OFFSET),
+            checksum: u32_at(CHECKSUM_OFFSET),
+            page_segments: page[PAGE_SEGMENTS_OFFSET],
+        }
+    }
+
+    /// Reads the header at the start of `data`, checking the capture pattern and the version.
+    pub fn parse(data: &[u8]) -> ParseResult<Self> {
+        if data.len() < PAGE_HEADER_SIZE {
+            return Err(ParseError::TruncatedPage { part: "page header", offset: 0 });
+        }
+        let hdr = Self::read(data);
+        if &hdr.capture_pattern != b"OggS" {
+            return Err(ParseError::UnexpectedCapturePattern(hdr.capture_pattern));
+        }
+        if hdr.version != 0 {
+            return Err(ParseError::UnsupportedVersion(hdr.version));
+        }
+        Ok(hdr)
+    }
+
+    /// Whether the first segment continues a packet from the previous page.
+    pub fn is_continued(&self) -> bool {
+        self.header_type & CONTINUED_PACKET != 0
+    }
+
+    /// Whether this is the first page of the logical stream.
+    pub fn is_bos(&self) -> bool {
+        self.header_type & BEGIN_OF_STREAM != 0
+    }
+
+    /// Whether this is the last page of the logical stream.
+    pub fn is_eos(&self) -> bool {
+        self.header_type & END_OF_STREAM != 0
+    }
+}
+
+/// Size of the page at the start of `data`, `None` if the page is not complete yet.
+pub fn page_size(data: &[u8]) -> ParseResult<Option<usize>> {
+    if data.len() < PAGE_HEADER_SIZE {
+        return Ok(None);
+    }
+    let hdr = PageHeader::parse(data)?;
+    let nsegments = hdr.page_segments as usize;
+    if data.len() < PAGE_HEADER_SIZE + nsegments {
+        return Ok(None);
+    }
+    let segment_table = &data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + nsegments];
+    let page_size =
+        PAGE_HEADER_SIZE + nsegments + segment_table.iter().map(|v| *v as usize).sum::<usize>();
+    if data.len() < page_size {
+        return Ok(None);
+    }
+    Ok(Some(page_size))
+}
+
+/// Returns the byte ranges of the pages in `data`, which must only contain complete pages.
+pub fn page_ranges(data: &[u8]) -> ParseResult<Vec<core::ops::Range<usize>>> {
+    let mut pos = 0;
+    let mut ranges = Vec::new();
+    while pos < data.len() {
+        if pos + PAGE_HEADER_SIZE > data.len() {
+            return Err(ParseError::TruncatedPage { part: "page header", offset: pos });
+        }
+        let hdr = PageHeader::read(&data[pos..]);
+        if &hdr.capture_pattern != b"OggS" {
+            return Err(ParseError::UnexpectedCapturePattern(hdr.capture_pattern));
+        }
+        let nsegments = hdr.page_segments as usize;
+        let table = pos + PAGE_HEADER_SIZE..pos + PAGE_HEADER_SIZE + nsegments;
+        if table.end > data.len() {
+            return Err(ParseError::TruncatedPage { part: "segment table", offset: pos });
+        }
+        let body_len = data[table.clone()].iter().map(|v| *v as usize).sum::<usize>();
+        let page_end = table.end + body_len;
+        if page_end > data.len() {
+            return Err(ParseError::TruncatedPage { part: "page", offset: pos });
+        }
+        ranges.push(pos..page_end);
+        pos = page_end;
+    }
+    Ok(ranges)
+}
+
+/// Returns the granule position of the last complete page in `data` by scanning backwards for
+/// the capture pattern, this avoids parsing the whole stream to get its duration.
+pub fn last_granule_position(data: &[u8]) -> Option<u64> {
+    let mut end = data.len();
+    while end >= PAGE_HEADER_SIZE {
+        let start = data[..end - PAGE_HEADER_SIZE + 4].windows(4).rposition(|w| w == b"OggS")?;
+        let hdr = PageHeader::read(&data[start..]);
+        let nsegments = hdr.page_segments as usize;
+        let table_end = start + PAGE_HEADER_SIZE + nsegments;
+        if hdr.version == 0 && table_end <= data.len() {
+            let body_len = data[start + PAGE_HEADER_SIZE..table_end]
+                .iter()
+                .map(|v| *v as usize)
+                .sum::<usize>();
+            // Pages that are not fully in the buffer are ignored.
+            if table_end + body_len <= data.len() && hdr.granule_position != u64::MAX {
+                return Some(hdr.granule_position);
+            }
+        }
+        end = start + PAGE_HEADER_SIZE - 1;
+    }
+    None
+}
+
+/// The checksum of a complete page, computed with its checksum field zeroed.
+pub fn page_checksum(page: &[u8]) -> u32 {
+    let crc = crate::crc::update(0, &page[..CHECKSUM_OFFSET]);
+    let crc = crate::crc::update(crc, &[0; 4]);
+    crate::crc::update(crc, &page[CHECKSUM_OFFSET + 4..])
+}
+
+/// Overwrites the header fields of a complete page and recomputes its checksum.
+pub fn rewrite_page(
+    page: &mut [u8],
+    header_type: u8,
+    granule_position: u64,
+    serial: u32,
+    page_sequence: u32,
+) {
+    page[HEADER_TYPE_OFFSET] = header_type;
+    page[GRANULE_POSITION_OFFSET..GRANULE_POSITION_OFFSET + 8]
+        .copy_from_slice(&granule_position.to_le_bytes());
+    page[SERIAL_OFFSET..SERIAL_OFFSET + 4].copy_from_slice(&serial.to_le_bytes());
+    page[PAGE_SEQUENCE_OFFSET..PAGE_SEQUENCE_OFFSET + 4]
+        .copy_from_slice(&page_sequence.to_le_bytes());
+    page[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].fill(0);
+    let crc = crate::crc::crc32(page);
+    page[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// The segments of a complete page as given by its lacing values. A packet ends on each segment
+/// shorter than 255 bytes, longer packets span several segments and possibly several pages.
+pub fn segments(page: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let table_end = PAGE_HEADER_SIZE + page[PAGE_SEGMENTS_OFFSET] as usize;
+    let (table, mut body) = page[PAGE_HEADER_SIZE..].split_at(table_end - PAGE_HEADER_SIZE);
+    table.iter().map(move |&len| {
+        let (segment, rest) = body.split_at(len as usize);
+        body = rest;
+        segment
+    })
+}
+
+#[repr(Rust, packed)]
+#[derive(Debug, Clone)]
+pub struct OpusHead {
+    pub magic_signature: [u8; 8],
+    pub version: u8,
+    pub channel_count: u8,
+    pub pre_skip: u16,
+    pub sample_rate: u32,
+    pub output_gain: i16,
+    pub mapping_family: u8,
+}
+
+impl OpusHead {
+    /// Parses the fixed part of the header, the channel mapping table that follows it for mapping
+    /// families other than 0 is ignored, see `channel_mapping`.
+    pub fn from_slice(data: &[u8]) -> ParseResult<Self> {
+        let l = core::mem::size_of::<OpusHead>();
+        if data.len() < l {
+            return Err(ParseError::UnexpectedLenForOpusHead(data.len()));
+        }
+        let head: Self = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const Self) };
+        if &head.magic_signature != b"OpusHead" {
+            return Err(ParseError::UnexpectedSignature(head.magic_signature));
+        }
+        Ok(head)
+    }
+
+    /// Parses the header in `data` and returns the channel mapping needed to decode the stream.
+    pub fn channel_mapping(data: &[u8]) -> ParseResult<ChannelMapping> {
+        let head = Self::from_slice(data)?;
+        let channels = head.channel_count;
+        let mapping = match head.mapping_family {
+            0 => match channels {
+                1 => ChannelMapping::mono(),
+                2 => ChannelMapping {
+                    family: 0,
+                    streams: 1,
+                    coupled_streams: 1,
+                    mapping: alloc::vec![0, 1],
+                },
+                _ => return Err(ParseError::UnsupportedChannelCount { family: 0, channels }),
+            },
+            family => {
+                let table = &data[core::mem::size_of::<OpusHead>()..];
+                if channels == 0 || table.len() < 2 + channels as usize {
+                    return Err(ParseError::UnexpectedLenForOpusHead(data.len()));
+                }
+                let (streams, coupled_streams) = (table[0], table[1]);
+                let mapping = table[2..2 + channels as usize].to_vec();
+                ChannelMapping { family, streams, coupled_streams, mapping }
+            }
+        };
+        Ok(mapping)
+    }
+}
+
+/// How the channels are coded in the opus streams of each packet, as written in the OpusHead
+/// header. See RFC 7845 section 5.1.1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMapping {
+    pub family: u8,
+    pub streams: u8,
+    pub coupled_streams: u8,
+    /// The stream channel used for each output channel.
+    pub mapping: Vec<u8>,
+}
+
+impl ChannelMapping {
+    pub fn mono() -> Self {
+        Self { family: 0, streams: 1, coupled_streams: 0, mapping: alloc::vec![0] }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.mapping.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Silk,
+    Hybrid,
+    Celt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Bandwidth {
+    /// 4kHz
+    Narrowband,
+    /// 6kHz
+    Mediumband,
+    /// 8kHz
+    Wideband,
+    /// 12kHz
+    SuperWideband,
+    /// 20kHz
+    Fullband,
+}
+
+/// The table of contents byte at the start of each opus packet, see RFC 6716 section 3.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Toc {
+    pub mode: Mode,
+    pub bandwidth: Bandwidth,
+    /// Duration of each frame in the packet, in microseconds.
+    pub frame_duration_us: u32,
+    pub stereo: bool,
+    pub frame_count: usize,
+}
+
+impl Toc {
+    pub fn parse(packet: &[u8]) -> ParseResult<Self> {
+        let Some(&toc) = packet.first() else { return Err(ParseError::EmptyPacket) };
+        let config = toc >> 3;
+        use Bandwidth as B;
+        let (mode, bandwidth, frame_duration_us) = match config {
+            0..=11 => {
+                let bandwidth = [B::Narrowband, B::Mediumband, B::Wideband][config as usize / 4];
+                (Mode::Silk, bandwidth, [10_000, 20_000, 40_000, 60_000][config as usize % 4])
+            }
+            12..=15 => {
+                let bandwidth = if config < 14 { B::SuperWideband } else { B::Fullband };
+                (Mode::Hybrid, bandwidth, [10_000, 20_000][config as usize % 2])
+            }
+            _ => {
+                let bandwidth = [B::Narrowband, B::Wideband, B::SuperWideband, B::Fullband]
+                    [(config as usize - 16) / 4];
+                (Mode::Celt, bandwidth, [2_500, 5_000, 10_000, 20_000][config as usize % 4])
+            }
+        };
+        let frame_count = match toc & 0x3 {
+            0 => 1,
+            1 | 2 => 2,
+            _ => match packet.get(1) {
+                Some(v) => (v & 0x3f) as usize,
+                None => return Err(ParseError::MissingFrameCount),
+            },
+        };
+        Ok(Self { mode, bandwidth, frame_duration_us, stereo: toc & 0x4 != 0, frame_count })
+    }
+
+    /// Duration of the whole packet, in microseconds.
+    pub fn duration_us(&self) -> u32 {
+        self.frame_duration_us * self.frame_count as u32
+    }
+}
+
+/// Whether `packet` holds at least one opus frame. Zero-length packets, which ogg streams can use
+/// to carry timing only, and code 3 packets without any frame, i.e. only padding, hold no audio.
+pub fn has_frames(packet: &[u8]) -> bool {
+    Toc::parse(packet).is_ok_and(|toc| toc.frame_count > 0)
+}
+
+/// Whether `packet`, made of `streams` opus streams, is a DTX packet: every stream only has a
+/// TOC byte, one or two frames with no data, which decoders conceal. These are the packets produced
+/// during silences when the encoder uses discontinuous transmission, realtime transports can
+/// skip sending them and let the receiver conceal the gap.
+pub fn is_dtx(packet: &[u8], streams: usize) -> bool {
+    // All the streams but the last one use the self-delimiting framing, with a length byte.
+    let Some(delimited) = streams.checked_sub(1) else { return false };
+    packet.len() == 2 * delimited + 1
+        && packet.chunks(2).all(|c| c[0] & 0x3 <= 1 && c.get(1).is_none_or(|&len| len == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a page with a header filled by `rewrite_page` around the given lacing values.
+    fn page(lacing: &[u8], header_type: u8, granule_position: u64) -> Vec<u8> {
+        let mut page = alloc::vec![0u8; PAGE_HEADER_SIZE];
+        page[..4].copy_from_slice(b"OggS");
+        page[PAGE_SEGMENTS_OFFSET] = lacing.len() as u8;
+        page.extend_from_slice(lacing);
+        for (i, &len) in lacing.iter().enumerate() {
+            page.extend(core::iter::repeat_n(i as u8, len as usize));
+        }
+        rewrite_page(&mut page, header_type, granule_position, 42, 0);
+        page
+    }
+
+    #[test]
+    fn pages() {
+        let mut data = page(&[19], BEGIN_OF_STREAM, 0);
+        data.extend_from_slice(&page(&[255, 10, 3], CONTINUED_PACKET, 960));
+        data.extend_from_slice(&page(&[255], END_OF_STREAM, u64::MAX));
+        let ranges = page_ranges(&data).unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(page_size(&data).unwrap(), Some(ranges[0].len()));
+        assert_eq!(page_size(&data[..ranges[0].end - 1]).unwrap(), None);
+        let headers: Vec<PageHeader> =
+            ranges.iter().map(|r| PageHeader::parse(&data[r.clone()]).unwrap()).collect();
+        assert!(headers[0].is_bos() && !headers[0].is_continued());
+        assert!(headers[1].is_continued() && !headers[1].is_eos());
+        assert!(headers[2].is_eos() && !headers[2].is_bos());
+        for (range, hdr) in ranges.iter().zip(headers.iter()) {
+            assert_eq!(page_checksum(&data[range.clone()]), hdr.checksum);
+            assert_eq!(hdr.bitstream_serial, 42);
+        }
+        let lens: Vec<usize> = segments(&data[ranges[1].clone()]).map(|s| s.len()).collect();
+        assert_eq!(lens, [255, 10, 3]);
+        // The last page has no packet ending on it.
+        assert_eq!(last_granule_position(&data), Some(960));
+        assert_eq!(
+            page_ranges(&data[..data.len() - 1]),
+            Err(ParseError::TruncatedPage { part: "page", offset: ranges[2].start })
+        );
+    }
+
+    #[test]
+    fn toc() {
+        // Celt fullband 20ms stereo, code 3 with 3 frames.
+        let toc = Toc::parse(&[31 << 3 | 0x4 | 0x3, 3]).unwrap();
+        assert_eq!(toc.mode, Mode::Celt);
+        assert_eq!(toc.bandwidth, Bandwidth::Fullband);
+        assert!(toc.stereo);
+        assert_eq!(toc.duration_us(), 60_000);
+        // Silk wideband 60ms.
+        let toc = Toc::parse(&[11 << 3]).unwrap();
+        assert_eq!(
+            (toc.mode, toc.bandwidth, toc.duration_us()),
+            (Mode::Silk, Bandwidth::Wideband, 60_000)
+        );
+        assert_eq!(Toc::parse(&[]), Err(ParseError::EmptyPacket));
+        assert_eq!(Toc::parse(&[0x3]), Err(ParseError::MissingFrameCount));
+        assert!(!has_frames(&[0x3, 0]));
+    }
+}
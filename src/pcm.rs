@@ -0,0 +1,133 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Conversions between the f32 pcm used by the rest of the crate and the integer formats of sound
+// cards and network protocols. An integer sample `v` with `b` bits maps to `v / 2^(b-1)`, so that
+// converting integers to floats and back is lossless. 24 bits samples are held in the low bits of
+// an `i32`. Integer to integer conversions go through f32, which is exact for 16 and 24 bits
+// sources. The layout conversions are `interleave` and `deinterleave`.
+
+use crate::Result;
+
+pub use crate::wav::SampleFormat;
+pub use crate::{deinterleave, deinterleave_into, interleave};
+
+/// How the rounding error is decorrelated from the signal when reducing the resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Plain rounding, the error is correlated with the signal and audible on quiet signals
+    /// converted to 16 bits.
+    #[default]
+    None,
+    /// Triangular pdf noise of +/-1 LSB added before rounding, the usual choice when converting
+    /// to 16 bits.
+    Triangular,
+}
+
+/// Converts f32 pcm to integer samples. The dither noise is generated from an internal state, the
+/// same quantizer should be used for all the chunks of a stream.
+#[derive(Debug, Clone)]
+pub struct Quantizer {
+    dither: Dither,
+    state: u32,
+}
+
+impl Quantizer {
+    pub fn new(dither: Dither) -> Self {
+        Self { dither, state: 0x9e37_79b9 }
+    }
+
+    pub fn dither(&self) -> Dither {
+        self.dither
+    }
+
+    // Uniform in [0, 1), xorshift32.
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn quantize(&mut self, v: f32, bits: u32) -> i32 {
+        let scale = (1u64 << (bits - 1)) as f64;
+        let mut v = v as f64 * scale;
+        if self.dither == Dither::Triangular {
+            v += (self.uniform() - self.uniform()) as f64
+        }
+        v.round().clamp(-scale, scale - 1.) as i32
+    }
+
+    pub fn to_i16(&mut self, pcm: &[f32]) -> Vec<i16> {
+        pcm.iter().map(|&v| self.quantize(v, 16) as i16).collect()
+    }
+
+    /// 24 bits samples, in the low bits of each `i32`.
+    pub fn to_i24(&mut self, pcm: &[f32]) -> Vec<i32> {
+        pcm.iter().map(|&v| self.quantize(v, 24)).collect()
+    }
+
+    pub fn to_i32(&mut self, pcm: &[f32]) -> Vec<i32> {
+        pcm.iter().map(|&v| self.quantize(v, 32)).collect()
+    }
+
+    /// Encodes the samples as little-endian bytes in `format`, e.g. for a wav data chunk or a
+    /// network stream. The f32 format is not dithered.
+    pub fn to_bytes(&mut self, pcm: &[f32], format: SampleFormat) -> Vec<u8> {
+        let mut data = Vec::with_capacity(pcm.len() * format.bytes_per_sample());
+        for &v in pcm.iter() {
+            match format {
+                SampleFormat::I16 => {
+                    data.extend_from_slice(&(self.quantize(v, 16) as i16).to_le_bytes())
+                }
+                SampleFormat::I24 => {
+                    data.extend_from_slice(&self.quantize(v, 24).to_le_bytes()[..3])
+                }
+                SampleFormat::I32 => data.extend_from_slice(&self.quantize(v, 32).to_le_bytes()),
+                SampleFormat::F32 => data.extend_from_slice(&v.to_le_bytes()),
+            }
+        }
+        data
+    }
+}
+
+pub fn i16_to_f32(pcm: &[i16]) -> Vec<f32> {
+    pcm.iter().map(|&v| v as f32 / 32_768.0).collect()
+}
+
+/// 24 bits samples, in the low bits of each `i32`.
+pub fn i24_to_f32(pcm: &[i32]) -> Vec<f32> {
+    pcm.iter().map(|&v| v as f32 / 8_388_608.0).collect()
+}
+
+pub fn i32_to_f32(pcm: &[i32]) -> Vec<f32> {
+    pcm.iter().map(|&v| v as f32 / 2_147_483_648.0).collect()
+}
+
+/// Decodes little-endian samples in `format`, the length of `data` must be a multiple of the
+/// sample size.
+pub fn from_bytes(data: &[u8], format: SampleFormat) -> Result<Vec<f32>> {
+    let bytes_per_sample = format.bytes_per_sample();
+    if !data.len().is_multiple_of(bytes_per_sample) {
+        crate::bail!("{} bytes is not a whole number of {format:?} samples", data.len())
+    }
+    let pcm = match format {
+        SampleFormat::I16 => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32_768.0)
+            .collect(),
+        SampleFormat::I24 => data
+            .chunks_exact(3)
+            .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        SampleFormat::I32 => data
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        SampleFormat::F32 => {
+            data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+        }
+    };
+    Ok(pcm)
+}
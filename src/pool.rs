@@ -0,0 +1,281 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Pools of ready to use encoders and decoders, for servers handling many short sessions where
+// creating the libopus state and the resamplers for each session adds up. The items are reset
+// when checked back in, the lock is only held to push or pop an item so checking items in and
+// out from tokio tasks does not block the runtime.
+
+use crate::Result;
+
+type ResetFn<T> = Box<dyn Fn(&mut T) -> Result<()> + Send + Sync>;
+
+struct Shared<T> {
+    idle: std::sync::Mutex<Vec<T>>,
+    max_idle: usize,
+    reset: ResetFn<T>,
+}
+
+impl<T> Shared<T> {
+    fn pop(&self) -> Option<T> {
+        match self.idle.lock() {
+            Ok(mut idle) => idle.pop(),
+            Err(_) => None,
+        }
+    }
+
+    // Items that cannot be reset are dropped rather than handed out in an unknown state.
+    fn checkin(&self, mut item: T) {
+        if (self.reset)(&mut item).is_err() {
+            return;
+        }
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < self.max_idle {
+                idle.push(item)
+            }
+        }
+    }
+
+    fn idle(&self) -> usize {
+        self.idle.lock().map_or(0, |idle| idle.len())
+    }
+}
+
+/// An item checked out of a pool, it is reset and returned to the pool when dropped.
+pub struct Pooled<T> {
+    item: Option<T>,
+    shared: std::sync::Arc<Shared<T>>,
+}
+
+impl<T> Pooled<T> {
+    /// Takes the item out of the pool for good.
+    pub fn detach(mut self) -> T {
+        // The item is only taken on drop or here.
+        self.item.take().unwrap()
+    }
+}
+
+impl<T> std::ops::Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().unwrap()
+    }
+}
+
+impl<T> std::ops::DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.shared.checkin(item)
+        }
+    }
+}
+
+/// Encoders sharing the same config, clones refer to the same pool.
+pub struct EncoderPool {
+    cfg: std::sync::Arc<crate::opus::EncoderConfig>,
+    shared: std::sync::Arc<Shared<crate::opus::Encoder>>,
+}
+
+impl EncoderPool {
+    /// Creates `size` encoders upfront, at most `size` idle encoders are kept afterwards.
+    pub fn new(cfg: &crate::opus::EncoderConfig, size: usize) -> Result<Self> {
+        let idle = (0..size).map(|_| crate::opus::Encoder::new(cfg)).collect::<Result<Vec<_>>>()?;
+        let shared = Shared {
+            idle: std::sync::Mutex::new(idle),
+            max_idle: size,
+            reset: Box::new(crate::opus::Encoder::reset),
+        };
+        Ok(Self { cfg: std::sync::Arc::new(cfg.clone()), shared: std::sync::Arc::new(shared) })
+    }
+
+    /// Returns an idle encoder, or a new one when the pool is empty.
+    pub fn checkout(&self) -> Result<Pooled<crate::opus::Encoder>> {
+        let item = match self.shared.pop() {
+            Some(encoder) => encoder,
+            None => crate::opus::Encoder::new(&self.cfg)?,
+        };
+        Ok(Pooled { item: Some(item), shared: self.shared.clone() })
+    }
+
+    /// Returns an encoder obtained elsewhere to the pool, e.g. after `Pooled::detach`. It must
+    /// have been created with the config of the pool.
+    pub fn checkin(&self, encoder: crate::opus::Encoder) {
+        self.shared.checkin(encoder)
+    }
+
+    /// Number of encoders ready to be checked out.
+    pub fn idle(&self) -> usize {
+        self.shared.idle()
+    }
+}
+
+impl Clone for EncoderPool {
+    fn clone(&self) -> Self {
+        Self { cfg: self.cfg.clone(), shared: self.shared.clone() }
+    }
+}
+
+/// Decoders sharing the same output rate and channel mapping, clones refer to the same pool.
+pub struct DecoderPool {
+    sample_rate: usize,
+    decode_at_48khz: bool,
+    channel_mapping: crate::opus::ChannelMapping,
+    shared: std::sync::Arc<Shared<crate::opus::Decoder>>,
+}
+
+impl DecoderPool {
+    /// Creates `size` decoders upfront, see `opus::Decoder::new_with_channel_mapping` for the
+    /// parameters. At most `size` idle decoders are kept afterwards.
+    pub fn new(
+        sample_rate: usize,
+        decode_at_48khz: bool,
+        channel_mapping: &crate::opus::ChannelMapping,
+        size: usize,
+    ) -> Result<Self> {
+        let idle = (0..size)
+            .map(|_| {
+                crate::opus::Decoder::new_with_channel_mapping(
+                    sample_rate,
+                    decode_at_48khz,
+                    channel_mapping,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Decoders checked in with a different mapping, e.g. after reading an OpusHead, are
+        // brought back to the mapping of the pool.
+        let mapping = channel_mapping.clone();
+        let shared = Shared {
+            idle: std::sync::Mutex::new(idle),
+            max_idle: size,
+            reset: Box::new(move |decoder: &mut crate::opus::Decoder| decoder.recycle(&mapping)),
+        };
+        Ok(Self {
+            sample_rate,
+            decode_at_48khz,
+            channel_mapping: channel_mapping.clone(),
+            shared: std::sync::Arc::new(shared),
+        })
+    }
+
+    /// Returns an idle decoder, or a new one when the pool is empty.
+    pub fn checkout(&self) -> Result<Pooled<crate::opus::Decoder>> {
+        let item = match self.shared.pop() {
+            Some(decoder) => decoder,
+            None => crate::opus::Decoder::new_with_channel_mapping(
+                self.sample_rate,
+                self.decode_at_48khz,
+                &self.channel_mapping,
+            )?,
+        };
+        Ok(Pooled { item: Some(item), shared: self.shared.clone() })
+    }
+
+    /// Returns a decoder obtained elsewhere to the pool, e.g. after `Pooled::detach`. It must
+    /// have been created with the output rate of the pool.
+    pub fn checkin(&self, decoder: crate::opus::Decoder) {
+        self.shared.checkin(decoder)
+    }
+
+    /// Number of decoders ready to be checked out.
+    pub fn idle(&self) -> usize {
+        self.shared.idle()
+    }
+}
+
+impl Clone for DecoderPool {
+    fn clone(&self) -> Self {
+        Self {
+            sample_rate: self.sample_rate,
+            decode_at_48khz: self.decode_at_48khz,
+            channel_mapping: self.channel_mapping.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm() -> Vec<f32> {
+        (0..10 * 960).map(|i| (i as f32 * 0.05).sin() * 0.3).collect()
+    }
+
+    #[test]
+    fn encoder_pool() -> Result<()> {
+        let cfg = crate::opus::EncoderConfig::new(48_000);
+        let reference = crate::opus::Encoder::new(&cfg)?.encode(&pcm())?;
+        let pool = EncoderPool::new(&cfg, 1)?;
+        assert_eq!(pool.idle(), 1);
+        {
+            let mut encoder = pool.checkout()?;
+            assert_eq!(pool.idle(), 0);
+            encoder.set_bitrate(12_000)?;
+            encoder.encode(&pcm()[..1_000])?;
+            // The pool is empty, a new encoder is created.
+            let _other = pool.clone().checkout()?;
+        }
+        // Only `size` encoders are kept.
+        assert_eq!(pool.idle(), 1);
+        let mut encoder = pool.checkout()?;
+        assert_eq!(encoder.granule_position(), 0);
+        assert_eq!(encoder.buffered_samples(), 0);
+        assert_eq!(encoder.settings(), crate::opus::Encoder::new(&cfg)?.settings());
+        let packets = encoder.encode(&pcm())?;
+        assert_eq!(packets.len(), reference.len());
+        for (packet, reference) in packets.iter().zip(reference.iter()) {
+            assert_eq!(packet.data, reference.data);
+        }
+        let encoder = encoder.detach();
+        assert_eq!(pool.idle(), 0);
+        pool.checkin(encoder);
+        assert_eq!(pool.idle(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn decoder_pool() -> Result<()> {
+        let packets =
+            crate::opus::Encoder::new(&crate::opus::EncoderConfig::new(48_000))?.encode(&pcm())?;
+        let mono = crate::opus::ChannelMapping::mono();
+        let decode = |decoder: &mut crate::opus::Decoder| -> Result<Vec<f32>> {
+            let mut pcm = vec![];
+            for packet in packets.iter() {
+                decoder.decode(&packet.data, &mut pcm)?;
+            }
+            Ok(pcm)
+        };
+        let reference = decode(&mut crate::opus::Decoder::new(24_000, true)?)?;
+        let pool = DecoderPool::new(24_000, true, &mono, 2)?;
+        {
+            let mut decoder = pool.checkout()?;
+            decode(&mut decoder)?;
+            let stereo = crate::opus::ChannelMapping {
+                family: 0,
+                streams: 1,
+                coupled_streams: 1,
+                mapping: vec![0, 1],
+            };
+            decoder.set_channel_mapping(&stereo)?;
+            assert_eq!(decoder.channels(), 2);
+        }
+        assert_eq!(pool.idle(), 2);
+        // The recycled decoder is back to the pool mapping with a fresh state.
+        let mut decoders = [pool.checkout()?, pool.checkout()?];
+        for decoder in decoders.iter_mut() {
+            assert_eq!(decoder.channel_mapping(), &mono);
+            assert_eq!(decoder.position(), 0);
+            assert_eq!(decoder.stats().packets, 0);
+            assert_eq!(decode(decoder)?, reference);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Objective evaluation of the codec, the round-trip harness encodes and decodes some pcm data
+// and compares the result with the original so that encoder settings can be tuned.
+
+use crate::Result;
+
+#[derive(Debug, Clone)]
+pub struct RoundTripConfig {
+    pub sample_rate: usize,
+    /// Maximum delay between the original and decoded signals, in samples, considered when
+    /// aligning the two signals.
+    pub max_lag: usize,
+    /// Frame size used for the spectral distortion computation.
+    pub fft_size: usize,
+}
+
+impl RoundTripConfig {
+    pub fn new(sample_rate: usize) -> Self {
+        // Look for delays up to 100ms.
+        Self { sample_rate, max_lag: sample_rate / 10, fft_size: 512 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RoundTripReport {
+    /// Delay of the decoded signal compared to the original one, in samples.
+    pub lag: usize,
+    pub snr_db: f64,
+    pub si_snr_db: f64,
+    pub sdr_db: f64,
+    /// STOI-style intelligibility estimate, between -1 and 1 (higher is better).
+    pub stoi: f64,
+    /// Log-spectral distance between the original and decoded signals, in dB.
+    pub spectral_distortion_db: f64,
+}
+
+/// Encodes and decodes `pcm` with the ogg/opus codec, aligns the decoded signal with the
+/// original one and reports the distortion.
+pub fn round_trip(pcm: &[f32], cfg: &RoundTripConfig) -> Result<RoundTripReport> {
+    let mut encoder = crate::ogg_opus::Encoder::new(cfg.sample_rate)?;
+    let mut ogg_data = encoder.header_data().to_vec();
+    // Pad the input so that the codec delay does not truncate the end of the signal.
+    let mut padded = pcm.to_vec();
+    padded.resize(pcm.len() + cfg.max_lag + cfg.sample_rate / 10, 0.);
+    ogg_data.extend_from_slice(&encoder.encode_page(&padded)?);
+
+    let mut decoder = crate::ogg_opus::Decoder::new(cfg.sample_rate, 0)?;
+    let decoded = decoder.decode(&ogg_data)?.unwrap_or_default();
+    if decoded.len() < pcm.len() {
+        crate::bail!("decoded {} samples, expected at least {}", decoded.len(), pcm.len())
+    }
+
+    let lag = crate::align::align(pcm, decoded, cfg.max_lag)?.lag.max(0) as usize;
+    let decoded = &decoded[lag..];
+    let len = usize::min(pcm.len(), decoded.len());
+    let (original, decoded) = (&pcm[..len], &decoded[..len]);
+    Ok(RoundTripReport {
+        lag,
+        snr_db: snr(original, decoded),
+        si_snr_db: si_snr(original, decoded),
+        sdr_db: sdr(original, decoded),
+        stoi: stoi(original, decoded, cfg.sample_rate)?,
+        spectral_distortion_db: spectral_distortion(original, decoded, cfg.fft_size)?,
+    })
+}
+
+fn energy(xs: impl Iterator<Item = f64>) -> f64 {
+    xs.map(|x| x * x).sum::<f64>()
+}
+
+fn db_ratio(num: f64, den: f64) -> f64 {
+    10. * ((num + 1e-20) / (den + 1e-20)).log10()
+}
+
+/// Signal to noise ratio in dB of `est` compared to `reference`, both signals are expected to be
+/// aligned.
+pub fn snr(reference: &[f32], est: &[f32]) -> f64 {
+    let signal = energy(reference.iter().map(|&v| v as f64));
+    let noise = energy(reference.iter().zip(est.iter()).map(|(&r, &e)| r as f64 - e as f64));
+    db_ratio(signal, noise)
+}
+
+/// Scale-invariant signal to noise ratio in dB, both signals are expected to be aligned.
+pub fn si_snr(reference: &[f32], est: &[f32]) -> f64 {
+    let mean = |xs: &[f32]| xs.iter().map(|&v| v as f64).sum::<f64>() / xs.len().max(1) as f64;
+    let (mean_r, mean_e) = (mean(reference), mean(est));
+    let r: Vec<f64> = reference.iter().map(|&v| v as f64 - mean_r).collect();
+    let e: Vec<f64> = est.iter().map(|&v| v as f64 - mean_e).collect();
+    let dot: f64 = r.iter().zip(e.iter()).map(|(r, e)| r * e).sum();
+    let alpha = dot / (energy(r.iter().copied()) + 1e-20);
+    let target = energy(r.iter().map(|r| alpha * r));
+    let noise = energy(r.iter().zip(e.iter()).map(|(r, e)| e - alpha * r));
+    db_ratio(target, noise)
+}
+
+// Length of the distortion filter allowed by the SDR computation.
+const SDR_FILTER_LEN: usize = 32;
+
+/// Signal to distortion ratio in dB, as defined by BSS-eval: `est` is projected on the subspace
+/// spanned by delayed versions of `reference` so that short linear filtering of the reference is
+/// not counted as distortion.
+pub fn sdr(reference: &[f32], est: &[f32]) -> f64 {
+    let len = usize::min(reference.len(), est.len());
+    let (r, e) = (&reference[..len], &est[..len]);
+    let l = SDR_FILTER_LEN;
+    let xcorr = |a: &[f32], b: &[f32], k: usize| -> f64 {
+        a[k..].iter().zip(b.iter()).map(|(&a, &b)| a as f64 * b as f64).sum()
+    };
+    // Solve the normal equations, the autocorrelation matrix of the reference is toeplitz.
+    let autocorr: Vec<f64> = (0..l).map(|k| xcorr(r, r, k.min(len))).collect();
+    let mut a: Vec<Vec<f64>> = (0..l)
+        .map(|i| {
+            let mut row: Vec<f64> = (0..l).map(|j| autocorr[i.abs_diff(j)]).collect();
+            row[i] += 1e-10;
+            row.push(xcorr(e, r, i.min(len)));
+            row
+        })
+        .collect();
+    let filter = solve_linear_system(&mut a);
+    let target: Vec<f64> =
+        (0..len).map(|n| (0..l.min(n + 1)).map(|k| filter[k] * r[n - k] as f64).sum()).collect();
+    let noise = energy(target.iter().zip(e.iter()).map(|(t, &e)| e as f64 - t));
+    db_ratio(energy(target.into_iter()), noise)
+}
+
+// Gaussian elimination with partial pivoting on an augmented matrix.
+fn solve_linear_system(a: &mut [Vec<f64>]) -> Vec<f64> {
+    let n = a.len();
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs())).unwrap_or(col);
+        a.swap(col, pivot);
+        if a[col][col].abs() < 1e-20 {
+            continue;
+        }
+        let (top, bottom) = a.split_at_mut(col + 1);
+        let pivot_row = &top[col];
+        for row in bottom.iter_mut() {
+            let f = row[col] / pivot_row[col];
+            for (v, p) in row[col..].iter_mut().zip(pivot_row[col..].iter()) {
+                *v -= f * p
+            }
+        }
+    }
+    let mut x = vec![0f64; n];
+    for row in (0..n).rev() {
+        let s: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        if a[row][row].abs() >= 1e-20 {
+            x[row] = (a[row][n] - s) / a[row][row]
+        }
+    }
+    x
+}
+
+/// STOI-style short-time objective intelligibility estimate. This follows the original STOI
+/// algorithm (one-third octave band envelopes over 384ms segments, with clipping at -15dB) but
+/// operates at the signal sample rate rather than resampling to 10kHz and does not remove
+/// silent frames, so the values are only comparable between runs of this implementation.
+/// The signals must have the same length, of at least one segment, and a sample rate of at least
+/// 8kHz so that the bands, which go up to 4.3kHz, are mostly below the Nyquist frequency.
+pub fn stoi(reference: &[f32], est: &[f32], sample_rate: usize) -> Result<f64> {
+    const N_BANDS: usize = 15;
+    const SEGMENT_LEN: usize = 30;
+    let clip = 1. + 10f64.powf(15. / 20.);
+
+    if sample_rate < 8_000 {
+        crate::bail!("stoi requires a sample rate of at least 8kHz, got {sample_rate}")
+    }
+    if reference.len() != est.len() {
+        crate::bail!("stoi signals have different lengths {} and {}", reference.len(), est.len())
+    }
+    let frame_len = sample_rate * 256 / 10_000;
+    // Frames overlap by half, the correlations are computed over segments of SEGMENT_LEN frames.
+    let min_len = frame_len + (SEGMENT_LEN - 1) * (frame_len / 2) + 1;
+    if reference.len() < min_len {
+        crate::bail!("stoi requires at least {min_len} samples, got {}", reference.len())
+    }
+    let nfft = (frame_len * 2).next_power_of_two();
+    let bands: Vec<(usize, usize)> = (0..N_BANDS)
+        .map(|k| {
+            let center = 150. * 2f64.powf(k as f64 / 3.);
+            let to_bin = |f: f64| (f * nfft as f64 / sample_rate as f64).round() as usize;
+            let lo = to_bin(center * 2f64.powf(-1. / 6.));
+            let hi = to_bin(center * 2f64.powf(1. / 6.)).min(nfft / 2 + 1);
+            (lo.min(hi), hi)
+        })
+        .collect();
+    let envelopes = |pcm: &[f32]| -> Result<Vec<[f64; N_BANDS]>> {
+        let mut planner = realfft::RealFftPlanner::<f64>::new();
+        let fft = planner.plan_fft_forward(nfft);
+        let mut input = fft.make_input_vec();
+        let mut spec = fft.make_output_vec();
+        let mut envs = vec![];
+        for start in (0..pcm.len().saturating_sub(frame_len)).step_by(frame_len / 2) {
+            input.fill(0.);
+            for (i, v) in input[..frame_len].iter_mut().enumerate() {
+                let w = 0.5 - 0.5 * (2. * std::f64::consts::PI * i as f64 / frame_len as f64).cos();
+                *v = pcm[start + i] as f64 * w;
+            }
+            fft.process(&mut input, &mut spec).map_err(crate::Error::wrap)?;
+            let mut env = [0f64; N_BANDS];
+            for (env, &(lo, hi)) in env.iter_mut().zip(bands.iter()) {
+                *env = spec[lo..hi].iter().map(|c| c.norm_sqr()).sum::<f64>().sqrt();
+            }
+            envs.push(env)
+        }
+        Ok(envs)
+    };
+    let (env_r, env_e) = (envelopes(reference)?, envelopes(est)?);
+    let n_frames = usize::min(env_r.len(), env_e.len());
+    let mut sum_corr = 0.;
+    let mut n_corr = 0;
+    for end in SEGMENT_LEN..=n_frames {
+        for band in 0..N_BANDS {
+            let x: Vec<f64> = env_r[end - SEGMENT_LEN..end].iter().map(|e| e[band]).collect();
+            let y: Vec<f64> = env_e[end - SEGMENT_LEN..end].iter().map(|e| e[band]).collect();
+            let scale = (energy(x.iter().copied()) / (energy(y.iter().copied()) + 1e-20)).sqrt();
+            let y: Vec<f64> =
+                y.iter().zip(x.iter()).map(|(&y, &x)| f64::min(y * scale, x * clip)).collect();
+            let mean_x = x.iter().sum::<f64>() / SEGMENT_LEN as f64;
+            let mean_y = y.iter().sum::<f64>() / SEGMENT_LEN as f64;
+            let dot: f64 = x.iter().zip(y.iter()).map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+            let norm_x = energy(x.iter().map(|x| x - mean_x)).sqrt();
+            let norm_y = energy(y.iter().map(|y| y - mean_y)).sqrt();
+            sum_corr += dot / (norm_x * norm_y + 1e-20);
+            n_corr += 1;
+        }
+    }
+    Ok(sum_corr / usize::max(n_corr, 1) as f64)
+}
+
+fn spectral_distortion(reference: &[f32], est: &[f32], fft_size: usize) -> Result<f64> {
+    if fft_size < 2 {
+        crate::bail!("spectral distortion requires an fft size of at least 2, got {fft_size}")
+    }
+    if reference.len() != est.len() {
+        crate::bail!(
+            "spectral distortion signals have different lengths {} and {}",
+            reference.len(),
+            est.len()
+        )
+    }
+    let mut planner = realfft::RealFftPlanner::<f64>::new();
+    let fft = planner.plan_fft_forward(fft_size);
+    let window: Vec<f64> = (0..fft_size)
+        .map(|i| 0.5 - 0.5 * (2. * std::f64::consts::PI * i as f64 / fft_size as f64).cos())
+        .collect();
+    let mut input = fft.make_input_vec();
+    let mut spec_r = fft.make_output_vec();
+    let mut spec_e = fft.make_output_vec();
+    let mut sum_distances = 0.;
+    let mut n_frames = 0;
+    for start in (0..reference.len().saturating_sub(fft_size)).step_by(fft_size / 2) {
+        for (spec, pcm) in [(&mut spec_r, reference), (&mut spec_e, est)] {
+            for (i, v) in input.iter_mut().enumerate() {
+                *v = pcm[start + i] as f64 * window[i]
+            }
+            fft.process(&mut input, spec).map_err(crate::Error::wrap)?;
+        }
+        let sum_sq: f64 = spec_r
+            .iter()
+            .zip(spec_e.iter())
+            .map(|(r, e)| {
+                let d = db_ratio(r.norm_sqr(), e.norm_sqr());
+                d * d
+            })
+            .sum();
+        sum_distances += (sum_sq / spec_r.len() as f64).sqrt();
+        n_frames += 1;
+    }
+    Ok(sum_distances / usize::max(n_frames, 1) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stoi_validation() {
+        let pcm = noise(16_000, 1);
+        assert!(stoi(&pcm, &pcm, 100).is_err());
+        assert!(stoi(&pcm, &pcm, 0).is_err());
+        assert!(stoi(&pcm, &pcm[..8_000], 16_000).is_err());
+        assert!(stoi(&pcm[..4_000], &pcm[..4_000], 16_000).is_err());
+        let identical = stoi(&pcm, &pcm, 16_000).unwrap();
+        assert!(identical > 0.99, "{identical}");
+        let unrelated = stoi(&pcm, &noise(16_000, 2), 16_000).unwrap();
+        assert!(unrelated < 0.5, "{unrelated}");
+    }
+
+    #[test]
+    fn spectral_distortion_validation() {
+        let pcm = noise(4_096, 1);
+        assert!(spectral_distortion(&pcm, &pcm, 0).is_err());
+        assert!(spectral_distortion(&pcm, &pcm, 1).is_err());
+        assert!(spectral_distortion(&pcm, &pcm[..1_000], 512).is_err());
+        assert!(spectral_distortion(&pcm, &pcm, 2).unwrap() < 1e-6);
+        let distortion = spectral_distortion(&pcm, &noise(4_096, 2), 512).unwrap();
+        assert!(distortion > 1., "{distortion}");
+    }
+}
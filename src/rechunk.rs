@@ -0,0 +1,81 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Conversion of a stream of pcm chunks of arbitrary sizes into chunks of a fixed number of
+// samples, as expected by the opus encoder or by models working on fixed size frames. The
+// samples that do not fill a chunk are carried over to the next call.
+
+use crate::Result;
+
+pub struct Rechunker<T = f32> {
+    chunk_size: usize,
+    channels: usize,
+    // Interleaved samples not yet returned, always less than a chunk between calls.
+    pending: Vec<T>,
+}
+
+impl<T: Copy + Default> Rechunker<T> {
+    /// Produces chunks of `chunk_size` samples per channel from interleaved pcm with `channels`
+    /// channels.
+    pub fn new(chunk_size: usize, channels: usize) -> Result<Self> {
+        if chunk_size == 0 || channels == 0 {
+            crate::bail!("unsupported chunk size {chunk_size} with {channels} channels")
+        }
+        Ok(Self { chunk_size, channels, pending: Vec::with_capacity(chunk_size * channels) })
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Number of samples per channel waiting for a full chunk to be available.
+    pub fn pending_samples(&self) -> usize {
+        self.pending.len() / self.channels
+    }
+
+    /// Appends `pcm` and returns the chunks completed by it, possibly none. `pcm` must only
+    /// contain full frames of interleaved samples.
+    pub fn push(&mut self, pcm: &[T]) -> Result<Vec<Vec<T>>> {
+        if !pcm.len().is_multiple_of(self.channels) {
+            crate::bail!("pcm length {} is not a multiple of {} channels", pcm.len(), self.channels)
+        }
+        let len = self.chunk_size * self.channels;
+        let mut chunks = vec![];
+        let mut pcm = pcm;
+        if !self.pending.is_empty() {
+            let n = usize::min(len - self.pending.len(), pcm.len());
+            self.pending.extend_from_slice(&pcm[..n]);
+            pcm = &pcm[n..];
+            if self.pending.len() < len {
+                return Ok(chunks);
+            }
+            chunks.push(std::mem::replace(&mut self.pending, Vec::with_capacity(len)));
+        }
+        let mut iter = pcm.chunks_exact(len);
+        chunks.extend(iter.by_ref().map(|chunk| chunk.to_vec()));
+        self.pending.extend_from_slice(iter.remainder());
+        Ok(chunks)
+    }
+
+    /// Returns the pending samples as a last chunk, padded with silence to a full chunk when
+    /// `pad` is set. Returns `None` when there are no pending samples.
+    pub fn flush(&mut self, pad: bool) -> Option<Vec<T>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        if pad {
+            self.pending.resize(self.chunk_size * self.channels, T::default())
+        }
+        Some(std::mem::take(&mut self.pending))
+    }
+
+    /// Drops the pending samples.
+    pub fn reset(&mut self) {
+        self.pending.clear()
+    }
+}
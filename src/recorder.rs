@@ -0,0 +1,397 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Recording of conversation sessions with several named pcm tracks, e.g. the user microphone
+// and the agent TTS. All the tracks share the same timeline, expressed in samples since the
+// start of the session, gaps in a track are filled with silence.
+
+use crate::ogg_pager::{PacketEnd, PageWriter};
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecorderMode {
+    /// A single ogg file with one logical opus stream per track.
+    MultiStream,
+    /// One ogg file per track.
+    SeparateTracks,
+    /// A single ogg file with all the tracks mixed together.
+    MixDown,
+    /// A single ogg file with a stereo stream, the first of the two tracks on the left channel
+    /// and the second on the right channel. The tracks stay sample aligned without being mixed.
+    Stereo,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    /// The sample rate of all the tracks, one of [`crate::opus::SAMPLE_RATES`].
+    pub sample_rate: usize,
+    pub mode: RecorderMode,
+}
+
+impl RecorderConfig {
+    pub fn new(sample_rate: usize, mode: RecorderMode) -> Self {
+        Self { sample_rate, mode }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TrackInfo {
+    pub name: String,
+    /// Index of the file in `Recording::files` containing this track.
+    pub file: usize,
+    /// Ogg serial of the logical stream containing this track, the tracks are mixed together in
+    /// the `MixDown` mode.
+    pub serial: u32,
+    /// Position of the first pushed sample on the session timeline.
+    pub start_sample: Option<u64>,
+    /// Position of the end of the track on the session timeline.
+    pub end_sample: u64,
+}
+
+/// The track of the user microphone in `SessionRecorder::stereo_duplex`, on the left channel.
+pub const USER_TRACK: usize = 0;
+/// The track of the agent TTS in `SessionRecorder::stereo_duplex`, on the right channel.
+pub const AGENT_TRACK: usize = 1;
+
+/// Shared timeline of the session, can be serialized to json next to the recorded files.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub sample_rate: usize,
+    pub mode: RecorderMode,
+    pub tracks: Vec<TrackInfo>,
+}
+
+pub struct Recording {
+    /// Name and ogg data of each output file, the name is the track name in the `SeparateTracks`
+    /// mode.
+    pub files: Vec<(String, Vec<u8>)>,
+    pub manifest: Manifest,
+}
+
+// A logical ogg stream being written.
+struct Stream {
+    encoder: crate::opus::Encoder,
+    pw: PageWriter,
+    writer: usize,
+}
+
+struct Track {
+    name: String,
+    stream: usize,
+    start_sample: Option<u64>,
+    position: u64,
+    // Samples waiting to be mixed or interleaved, only used in the `MixDown` and `Stereo` modes.
+    pending: std::collections::VecDeque<f32>,
+}
+
+pub struct SessionRecorder {
+    cfg: RecorderConfig,
+    // Name and data of each output file.
+    writers: Vec<(String, Vec<u8>)>,
+    streams: Vec<Stream>,
+    tracks: Vec<Track>,
+}
+
+impl SessionRecorder {
+    /// All the tracks have to be declared upfront as the ogg headers of all the logical streams
+    /// have to come first.
+    pub fn new(cfg: RecorderConfig, track_names: &[&str]) -> Result<Self> {
+        if track_names.is_empty() {
+            crate::bail!("a session recording requires at least one track")
+        }
+        if cfg.mode == RecorderMode::Stereo && track_names.len() != 2 {
+            crate::bail!("a stereo recording requires two tracks, got {}", track_names.len())
+        }
+        let shared_stream = matches!(cfg.mode, RecorderMode::MixDown | RecorderMode::Stereo);
+        let n_streams = if shared_stream { 1 } else { track_names.len() };
+        let writer_names: Vec<String> = match cfg.mode {
+            RecorderMode::MultiStream => vec!["session".to_string()],
+            RecorderMode::SeparateTracks => track_names.iter().map(|n| n.to_string()).collect(),
+            RecorderMode::MixDown => vec!["mix".to_string()],
+            RecorderMode::Stereo => vec!["stereo".to_string()],
+        };
+        let mut writers: Vec<_> = writer_names.into_iter().map(|name| (name, Vec::new())).collect();
+        let mut encoder_cfg = crate::opus::EncoderConfig::new(cfg.sample_rate);
+        if cfg.mode == RecorderMode::Stereo {
+            encoder_cfg.channels = 2
+        }
+        let mut streams = Vec::with_capacity(n_streams);
+        for index in 0..n_streams {
+            let writer = if cfg.mode == RecorderMode::SeparateTracks { index } else { 0 };
+            let encoder = crate::opus::Encoder::new(&encoder_cfg)?;
+            let pw = PageWriter::new(index as u32 + 1);
+            streams.push(Stream { encoder, pw, writer })
+        }
+        // The beginning of stream pages of all the logical streams have to come first.
+        for stream in streams.iter_mut() {
+            let mut head = Vec::new();
+            crate::ogg_opus::write_opus_header(&mut head, stream.encoder.channel_mapping())?;
+            stream.pw.write_packet(&head, 0, PacketEnd::EndPage);
+            writers[stream.writer].1.extend_from_slice(&stream.pw.take_data());
+        }
+        for (index, stream) in streams.iter_mut().enumerate() {
+            let title = match cfg.mode {
+                RecorderMode::MixDown | RecorderMode::Stereo => track_names.join("+"),
+                _ => track_names[index].to_string(),
+            };
+            let mut tags = Vec::new();
+            let comments = [("TITLE".to_string(), title)];
+            crate::ogg_opus::write_opus_tags(
+                &mut tags,
+                crate::ogg_opus::DEFAULT_VENDOR,
+                &comments,
+            )?;
+            stream.pw.write_packet(&tags, 0, PacketEnd::EndPage);
+            writers[stream.writer].1.extend_from_slice(&stream.pw.take_data());
+        }
+        let tracks = track_names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| Track {
+                name: name.to_string(),
+                stream: if shared_stream { 0 } else { index },
+                start_sample: None,
+                position: 0,
+                pending: std::collections::VecDeque::new(),
+            })
+            .collect();
+        Ok(Self { cfg, writers, streams, tracks })
+    }
+
+    /// Records a conversation as a single stereo file, the user microphone pushed on
+    /// `USER_TRACK` on the left channel and the agent TTS pushed on `AGENT_TRACK` on the right.
+    pub fn stereo_duplex(sample_rate: usize) -> Result<Self> {
+        Self::new(RecorderConfig::new(sample_rate, RecorderMode::Stereo), &["user", "agent"])
+    }
+
+    pub fn track_index(&self, name: &str) -> Option<usize> {
+        self.tracks.iter().position(|t| t.name == name)
+    }
+
+    /// Position of the end of a track on the session timeline, in samples.
+    pub fn position(&self, track: usize) -> Option<u64> {
+        self.tracks.get(track).map(|t| t.position)
+    }
+
+    /// Appends `pcm` at the end of a track.
+    pub fn push(&mut self, track: usize, pcm: &[f32]) -> Result<()> {
+        let position = match self.tracks.get(track) {
+            None => crate::bail!("unknown track index {track}"),
+            Some(t) => t.position,
+        };
+        self.push_at(track, position, pcm)
+    }
+
+    /// Writes `pcm` at `position` on the session timeline. The gap since the end of the track is
+    /// filled with silence, samples overlapping with what has already been written are dropped.
+    pub fn push_at(&mut self, track: usize, position: u64, pcm: &[f32]) -> Result<()> {
+        let t = match self.tracks.get_mut(track) {
+            None => crate::bail!("unknown track index {track}"),
+            Some(t) => t,
+        };
+        let overlap = usize::min(t.position.saturating_sub(position) as usize, pcm.len());
+        let pcm = &pcm[overlap..];
+        if pcm.is_empty() {
+            return Ok(());
+        }
+        let gap = position.saturating_sub(t.position) as usize;
+        if t.start_sample.is_none() {
+            t.start_sample = Some(t.position + gap as u64)
+        }
+        t.position += (gap + pcm.len()) as u64;
+        match self.cfg.mode {
+            RecorderMode::MixDown | RecorderMode::Stereo => {
+                t.pending.extend(std::iter::repeat_n(0f32, gap));
+                t.pending.extend(pcm.iter());
+                self.mix(false)
+            }
+            _ => {
+                let stream = t.stream;
+                if gap > 0 {
+                    self.encode(stream, &vec![0f32; gap])?;
+                }
+                self.encode(stream, pcm)
+            }
+        }
+    }
+
+    // Mixes, or interleaves in the `Stereo` mode, the samples available on all the tracks, or
+    // all the pending samples when flushing.
+    fn mix(&mut self, flush: bool) -> Result<()> {
+        let lens = self.tracks.iter().map(|t| t.pending.len());
+        let len = if flush { lens.max() } else { lens.min() }.unwrap_or(0);
+        if len == 0 {
+            return Ok(());
+        }
+        if self.cfg.mode == RecorderMode::Stereo {
+            let mut stereo = vec![0f32; 2 * len];
+            for (channel, t) in self.tracks.iter_mut().enumerate() {
+                let n = usize::min(len, t.pending.len());
+                for (s, v) in stereo.iter_mut().skip(channel).step_by(2).zip(t.pending.drain(..n)) {
+                    *s = v
+                }
+            }
+            return self.encode(0, &stereo);
+        }
+        let mut mixed = vec![0f32; len];
+        for t in self.tracks.iter_mut() {
+            let n = usize::min(len, t.pending.len());
+            for (m, v) in mixed.iter_mut().zip(t.pending.drain(..n)) {
+                *m += v
+            }
+        }
+        for m in mixed.iter_mut() {
+            *m = m.clamp(-1., 1.)
+        }
+        self.encode(0, &mixed)
+    }
+
+    fn encode(&mut self, stream: usize, pcm: &[f32]) -> Result<()> {
+        let s = &mut self.streams[stream];
+        for packet in s.encoder.encode(pcm)? {
+            s.pw.write_packet(&packet.data, packet.granule_position, PacketEnd::EndPage);
+        }
+        self.writers[s.writer].1.extend_from_slice(&s.pw.take_data());
+        Ok(())
+    }
+
+    /// Pads the last partial opus frame of each stream with silence and closes the logical
+    /// streams, the granule position of the last packet marks the end of the tracks.
+    pub fn finish(mut self) -> Result<Recording> {
+        if matches!(self.cfg.mode, RecorderMode::MixDown | RecorderMode::Stereo) {
+            self.mix(true)?;
+        }
+        for s in self.streams.iter_mut() {
+            let packets = s.encoder.flush()?;
+            s.pw.end_stream_with(packets.iter().map(|p| (&p.data[..], p.granule_position)));
+            self.writers[s.writer].1.extend_from_slice(&s.pw.take_data());
+        }
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|t| {
+                let stream = &self.streams[t.stream];
+                TrackInfo {
+                    name: t.name.clone(),
+                    file: stream.writer,
+                    serial: stream.pw.serial(),
+                    start_sample: t.start_sample,
+                    end_sample: t.position,
+                }
+            })
+            .collect();
+        let manifest = Manifest { sample_rate: self.cfg.sample_rate, mode: self.cfg.mode, tracks };
+        Ok(Recording { files: self.writers, manifest })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(len: usize, step: f32) -> Vec<f32> {
+        (0..len).map(|i| 0.5 * (i as f32 * step).sin()).collect()
+    }
+
+    // Returns the pages of the logical stream `serial` and the granule position of its last
+    // page on which a packet ends.
+    fn stream(data: &[u8], serial: u32) -> (Vec<u8>, u64) {
+        let mut pages = vec![];
+        let mut end = 0;
+        for range in crate::parse::page_ranges(data).unwrap() {
+            let hdr = crate::parse::PageHeader::read(&data[range.clone()]);
+            if hdr.bitstream_serial == serial {
+                pages.extend_from_slice(&data[range]);
+                if hdr.granule_position != u64::MAX {
+                    end = hdr.granule_position
+                }
+            }
+        }
+        (pages, end)
+    }
+
+    fn rms(pcm: impl Iterator<Item = f32>) -> f32 {
+        let (sum, len) = pcm.fold((0., 0), |(sum, len), v| (sum + v * v, len + 1));
+        (sum / len as f32).sqrt()
+    }
+
+    #[test]
+    fn modes() {
+        let modes = [
+            RecorderMode::MultiStream,
+            RecorderMode::SeparateTracks,
+            RecorderMode::MixDown,
+            RecorderMode::Stereo,
+        ];
+        for mode in modes {
+            let mut recorder =
+                SessionRecorder::new(RecorderConfig::new(48_000, mode), &["user", "agent"])
+                    .unwrap();
+            let (user, agent) = (recorder.track_index("user").unwrap(), 1);
+            for pcm in tone(48_000, 0.05).chunks(1_000) {
+                recorder.push(user, pcm).unwrap();
+            }
+            recorder.push_at(agent, 30_000, &tone(24_000, 0.08)).unwrap();
+            assert_eq!(recorder.position(agent), Some(54_000));
+            let recording = recorder.finish().unwrap();
+            let names: Vec<&str> = recording.files.iter().map(|(n, _)| n.as_str()).collect();
+            let tracks = &recording.manifest.tracks;
+            assert_eq!(tracks[agent].start_sample, Some(30_000));
+            assert_eq!(tracks[agent].end_sample, 54_000);
+            assert_eq!(tracks[user].end_sample, 48_000);
+            match mode {
+                RecorderMode::MultiStream => assert_eq!(names, ["session"]),
+                RecorderMode::SeparateTracks => assert_eq!(names, ["user", "agent"]),
+                RecorderMode::MixDown => assert_eq!(names, ["mix"]),
+                RecorderMode::Stereo => assert_eq!(names, ["stereo"]),
+            }
+            for (_, data) in recording.files.iter() {
+                assert_eq!(crate::validate::validate(data), vec![], "{mode:?}");
+            }
+            for track in tracks.iter() {
+                let (pages, end) = stream(&recording.files[track.file].1, track.serial);
+                // The last granule position marks the end of the samples, not of the padding.
+                let shared = matches!(mode, RecorderMode::MixDown | RecorderMode::Stereo);
+                let expected = if shared { 54_000 } else { track.end_sample };
+                assert_eq!(end, expected, "{mode:?} {}", track.name);
+                let info = crate::ogg_opus::inspect(&pages).unwrap();
+                let channels = if mode == RecorderMode::Stereo { 2 } else { 1 };
+                assert_eq!(info.head.channel_count as usize, channels);
+                assert!(info.tags.get("TITLE").is_some());
+            }
+        }
+        let cfg = RecorderConfig::new(48_000, RecorderMode::Stereo);
+        assert!(SessionRecorder::new(cfg, &["a", "b", "c"]).is_err());
+    }
+
+    #[test]
+    fn stereo_duplex() {
+        let mut recorder = SessionRecorder::stereo_duplex(48_000).unwrap();
+        // The user talks for half a second, then the agent answers for half a second, pushed
+        // with different chunk sizes.
+        for pcm in tone(24_000, 0.05).chunks(480) {
+            recorder.push(USER_TRACK, pcm).unwrap();
+        }
+        recorder.push_at(AGENT_TRACK, 24_000, &tone(12_000, 0.08)).unwrap();
+        recorder.push(AGENT_TRACK, &tone(12_000, 0.08)).unwrap();
+        let recording = recorder.finish().unwrap();
+        let data = &recording.files[0].1;
+        let mut decoder =
+            crate::ogg_opus::FileDecoder::open(std::io::Cursor::new(data), 48_000).unwrap();
+        let mut pcm = vec![];
+        while let Some(out) = decoder.read().unwrap() {
+            pcm.extend_from_slice(&out)
+        }
+        let channel = |channel: usize, range: std::ops::Range<usize>| {
+            rms(pcm[2 * range.start..2 * range.end].iter().skip(channel).step_by(2).copied())
+        };
+        // The windows leave some margin for the pre-skip and the encoder delay.
+        let (user, agent) = (4_800..19_200, 28_800..43_200);
+        assert!(channel(0, user.clone()) > 0.2, "{}", channel(0, user.clone()));
+        assert!(channel(1, user.clone()) < 0.02, "{}", channel(1, user.clone()));
+        assert!(channel(1, agent.clone()) > 0.2, "{}", channel(1, agent.clone()));
+        assert!(channel(0, agent.clone()) < 0.02, "{}", channel(0, agent.clone()));
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Salvaging truncated or corrupted ogg/opus files, e.g. recordings interrupted by a crash.
+// The input is scanned for pages with a valid checksum, packets split across damaged pages are
+// dropped and the remaining packets are written to a new, properly finalized, ogg stream with
+// contiguous granule positions.
+
+use crate::ogg_pager::PacketEnd;
+use crate::parse::{PageHeader, PAGE_HEADER_SIZE};
+use crate::Result;
+
+#[derive(Debug, Clone)]
+pub struct RecoveredStream {
+    /// The rebuilt ogg/opus file.
+    pub data: Vec<u8>,
+    /// Whether the original OpusHead packet could be recovered, a mono 48kHz header is written
+    /// otherwise.
+    pub head_recovered: bool,
+    pub pages_recovered: usize,
+    /// Bytes of the input that were not part of any valid page.
+    pub bytes_skipped: usize,
+    /// Packets that were lost because they spanned a damaged or missing page.
+    pub packets_dropped: usize,
+    pub packets: usize,
+    pub duration: std::time::Duration,
+}
+
+// Returns the page starting at `pos` if it is complete and its checksum is valid.
+fn valid_page(data: &[u8], pos: usize) -> Option<(PageHeader, std::ops::Range<usize>)> {
+    let hdr_size = PAGE_HEADER_SIZE;
+    if pos + hdr_size > data.len() {
+        return None;
+    }
+    let hdr = PageHeader::read(&data[pos..]);
+    if &hdr.capture_pattern != b"OggS" || hdr.version != 0 {
+        return None;
+    }
+    let table_end = pos + hdr_size + hdr.page_segments as usize;
+    let table = data.get(pos + hdr_size..table_end)?;
+    let page_end = table_end + table.iter().map(|v| *v as usize).sum::<usize>();
+    let page = data.get(pos..page_end)?;
+    if crate::parse::page_checksum(page) != hdr.checksum {
+        return None;
+    }
+    Some((hdr, pos..page_end))
+}
+
+/// Salvages all the decodable packets of a truncated or corrupted ogg/opus stream. Only the
+/// first logical stream is recovered.
+pub fn recover<R: std::io::Read>(mut reader: R) -> Result<RecoveredStream> {
+    let mut data = vec![];
+    // Read errors are expected on damaged media, keep whatever could be read.
+    if let Err(err) = reader.read_to_end(&mut data) {
+        if data.is_empty() {
+            return Err(err.into());
+        }
+    }
+    let hdr_size = PAGE_HEADER_SIZE;
+    let mut pos = 0;
+    let mut serial = None;
+    let mut last_sequence: Option<u32> = None;
+    let mut partial: Option<Vec<u8>> = None;
+    // Whether the segments are dropped until the end of a packet whose start has been lost, and
+    // whether that packet has already been counted as dropped.
+    let mut skipping = false;
+    let mut counted = false;
+    let mut packets = vec![];
+    let mut pages_recovered = 0;
+    let mut bytes_skipped = 0;
+    let mut packets_dropped = 0;
+    while pos < data.len() {
+        let Some((hdr, range)) = valid_page(&data, pos) else {
+            // Resync on the next capture pattern.
+            let next = data[pos + 1..]
+                .windows(4)
+                .position(|w| w == b"OggS")
+                .map_or(data.len(), |p| pos + 1 + p);
+            bytes_skipped += next - pos;
+            pos = next;
+            continue;
+        };
+        pos = range.end;
+        if *serial.get_or_insert(hdr.bitstream_serial) != hdr.bitstream_serial {
+            continue;
+        }
+        pages_recovered += 1;
+        let page_sequence = hdr.page_sequence;
+        let contiguous = last_sequence.is_some_and(|s| s.wrapping_add(1) == page_sequence);
+        last_sequence = Some(page_sequence);
+        let continued = hdr.is_continued();
+        if !(contiguous && continued) {
+            // The packet being read or skipped does not continue on this page.
+            let had_packet = partial.take().is_some() || skipping;
+            if had_packet && !counted {
+                packets_dropped += 1;
+            }
+            // Data continued from a page that has been lost is dropped, it is only counted once
+            // when it may be the end of the packet dropped above.
+            skipping = continued;
+            counted = continued && had_packet;
+        } else if partial.is_none() && !skipping {
+            skipping = true;
+            counted = false;
+        }
+        let nsegments = hdr.page_segments as usize;
+        let table = &data[range.start + hdr_size..range.start + hdr_size + nsegments];
+        let mut body = range.start + hdr_size + nsegments;
+        for &len in table.iter() {
+            let segment = &data[body..body + len as usize];
+            body += len as usize;
+            if !skipping {
+                partial.get_or_insert_with(Vec::new).extend_from_slice(segment);
+            }
+            if len < 255 {
+                if skipping {
+                    if !counted {
+                        packets_dropped += 1;
+                    }
+                    (skipping, counted) = (false, false);
+                } else if let Some(packet) = partial.take() {
+                    packets.push(packet)
+                }
+            }
+        }
+    }
+    if partial.is_some() || (skipping && !counted) {
+        packets_dropped += 1;
+    }
+
+    let head = packets.iter().find(|p| p.starts_with(b"OpusHead")).cloned();
+    let tags = packets.iter().find(|p| p.starts_with(b"OpusTags")).cloned();
+    let head_recovered = head.is_some();
+    let head = match head {
+        Some(head) => head,
+        None => {
+            let mut head = vec![];
+            crate::ogg_opus::write_opus_header(&mut head, &crate::opus::ChannelMapping::mono())?;
+            head
+        }
+    };
+    let tags = match tags {
+        Some(tags) => tags,
+        None => {
+            let mut tags = vec![];
+            crate::ogg_opus::write_opus_tags(&mut tags, crate::ogg_opus::DEFAULT_VENDOR, &[])?;
+            tags
+        }
+    };
+    // The audio packets with the granule positions of their end.
+    let mut granule_position = 0u64;
+    let audio: Vec<(Vec<u8>, u64)> = packets
+        .into_iter()
+        .filter(|p| !p.starts_with(b"OpusHead") && !p.starts_with(b"OpusTags"))
+        .filter_map(|p| {
+            let toc = crate::opus::Toc::parse(&p).ok()?;
+            granule_position += toc.duration_us() as u64 * 48 / 1000;
+            Some((p, granule_position))
+        })
+        .collect();
+
+    let serial = serial.unwrap_or(42);
+    let mut pw = crate::ogg_pager::PageWriter::new(serial);
+    pw.write_packet(&head, 0, PacketEnd::EndPage);
+    pw.write_packet(&tags, 0, PacketEnd::EndPage);
+    // This writes an empty end of stream page when no audio packet could be recovered.
+    pw.end_stream_with(audio.iter().map(|(p, granule_position)| (&p[..], *granule_position)));
+    let n_packets = audio.len();
+    Ok(RecoveredStream {
+        data: pw.take_data(),
+        head_recovered,
+        pages_recovered,
+        bytes_skipped,
+        packets_dropped,
+        packets: n_packets,
+        duration: std::time::Duration::from_secs_f64(granule_position as f64 / 48_000.),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two seconds of mono audio, 100 packets on their own pages, and the header pages.
+    fn file_data(picture: Option<&str>) -> Vec<u8> {
+        let mut cfg = crate::ogg_opus::EncoderConfig::new(48_000);
+        if let Some(picture) = picture {
+            cfg.comments.push(("METADATA_BLOCK_PICTURE".to_string(), picture.to_string()));
+        }
+        let pcm: Vec<f32> = (0..2 * 48_000).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        crate::ogg_opus::Encoder::new_with_config(cfg).unwrap().encode_file(&pcm).unwrap()
+    }
+
+    fn check(recovered: &RecoveredStream) {
+        assert_eq!(crate::validate::validate(&recovered.data), vec![]);
+        let info = crate::ogg_opus::inspect(&recovered.data).unwrap();
+        assert_eq!(info.last_granule_position, recovered.packets as u64 * 960);
+        assert_eq!(recovered.duration.as_millis(), recovered.packets as u128 * 20);
+    }
+
+    #[test]
+    fn intact() {
+        let data = file_data(None);
+        let recovered = recover(data.as_slice()).unwrap();
+        check(&recovered);
+        assert!(recovered.head_recovered);
+        assert_eq!(recovered.packets, 100);
+        assert_eq!((recovered.bytes_skipped, recovered.packets_dropped), (0, 0));
+    }
+
+    #[test]
+    fn truncated() {
+        let data = file_data(None);
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        // Cut in the middle of the page of the 50th packet.
+        let cut = (ranges[51].start + ranges[51].end) / 2;
+        let recovered = recover(&data[..cut]).unwrap();
+        check(&recovered);
+        assert!(recovered.head_recovered);
+        assert_eq!(recovered.packets, 49);
+        assert_eq!(recovered.pages_recovered, 51);
+        assert_eq!(recovered.bytes_skipped, cut - ranges[51].start);
+
+        // Nothing but the header pages left.
+        let recovered = recover(&data[..ranges[1].end]).unwrap();
+        check(&recovered);
+        assert_eq!(recovered.packets, 0);
+    }
+
+    #[test]
+    fn garbage() {
+        let data = file_data(None);
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        // Garbage between two pages and over the body of another one.
+        let mut damaged = data[..ranges[20].end].to_vec();
+        damaged.extend(std::iter::repeat_n(0x4f, 1000));
+        damaged.extend_from_slice(&data[ranges[20].end..]);
+        let page = ranges[40].start + 1000;
+        damaged[page + 30..page + 40].fill(0);
+        let recovered = recover(damaged.as_slice()).unwrap();
+        check(&recovered);
+        assert_eq!(recovered.packets, 99);
+        assert_eq!(recovered.bytes_skipped, 1000 + ranges[40].len());
+        assert_eq!(recovered.packets_dropped, 0);
+    }
+
+    #[test]
+    fn lost_continuation() {
+        // The OpusTags packet spans several pages, one of which is lost.
+        let data = file_data(Some(&"A".repeat(200_000)));
+        let ranges = crate::parse::page_ranges(&data).unwrap();
+        let mut damaged = data[..ranges[2].start].to_vec();
+        damaged.extend_from_slice(&data[ranges[2].end..]);
+        let recovered = recover(damaged.as_slice()).unwrap();
+        check(&recovered);
+        assert!(recovered.head_recovered);
+        assert_eq!(recovered.packets, 100);
+        assert_eq!(recovered.packets_dropped, 1);
+        let info = crate::ogg_opus::inspect(&recovered.data).unwrap();
+        assert_eq!(info.tags.get("METADATA_BLOCK_PICTURE"), None);
+    }
+}
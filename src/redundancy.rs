@@ -0,0 +1,171 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Application level redundancy for the raw packet mode on lossy links where the opus in-band
+// FEC is not enough: each framed packet is sent a second time `offset` packets later, so that a
+// burst of up to `offset` lost packets can be recovered. The receiver holds the packets back for
+// the same offset, drops the copies and hands the packets over in order.
+
+use crate::Result;
+
+/// Sender side, produces the framed packets to transmit including the delayed copies.
+pub struct Duplicator {
+    offset: usize,
+    history: std::collections::VecDeque<Vec<u8>>,
+}
+
+impl Duplicator {
+    /// Each packet is sent again after `offset` more packets, `offset` has to be at least 1.
+    pub fn new(offset: usize) -> Result<Self> {
+        if offset == 0 {
+            crate::bail!("the redundancy offset has to be at least 1")
+        }
+        Ok(Self { offset, history: std::collections::VecDeque::with_capacity(offset + 1) })
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the framed packets to send for `packet`: the packet itself, followed by the copy
+    /// of the packet `offset` packets earlier if any.
+    pub fn push(&mut self, packet: &crate::opus::Packet) -> Vec<Vec<u8>> {
+        let framed = packet.to_framed();
+        self.history.push_back(framed.clone());
+        let mut out = vec![framed];
+        if self.history.len() > self.offset {
+            out.extend(self.history.pop_front())
+        }
+        out
+    }
+
+    /// Returns the copies that have not been sent yet, at the end of the stream.
+    pub fn flush(&mut self) -> Vec<Vec<u8>> {
+        self.history.drain(..).collect()
+    }
+}
+
+/// Receiver side, the packets returned by `push` are meant for `opus::Decoder::decode_framed`.
+pub struct Deduplicator {
+    offset: u64,
+    // Sequence number of the next packet to return.
+    next_seq: Option<u64>,
+    last_seq: u64,
+    pending: std::collections::BTreeMap<u64, Vec<u8>>,
+    duplicates: u64,
+}
+
+impl Deduplicator {
+    /// `offset` has to match the offset of the sender.
+    pub fn new(offset: usize) -> Result<Self> {
+        if offset == 0 {
+            crate::bail!("the redundancy offset has to be at least 1")
+        }
+        Ok(Self {
+            offset: offset as u64,
+            next_seq: None,
+            last_seq: 0,
+            pending: std::collections::BTreeMap::new(),
+            duplicates: 0,
+        })
+    }
+
+    /// Number of copies dropped, i.e. packets received twice or after they were given up on.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Pushes a received framed packet and returns the framed packets that can be decoded, in
+    /// order. A missing packet is waited for until the packet carrying its copy has been
+    /// received, the following packets are then returned and the gap is left for the decoder
+    /// to conceal.
+    pub fn push(&mut self, framed: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (seq, _) = crate::opus::Packet::parse_framed(framed)?;
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        if seq < next_seq || self.pending.contains_key(&seq) {
+            self.duplicates += 1;
+            return Ok(vec![]);
+        }
+        self.last_seq = u64::max(self.last_seq, seq);
+        self.pending.insert(seq, framed.to_vec());
+        let mut out = vec![];
+        while let Some(entry) = self.pending.first_entry() {
+            let next_seq = self.next_seq.unwrap_or(*entry.key());
+            // The copy of `next_seq` is sent right after the packet `offset` packets later.
+            if *entry.key() != next_seq && self.last_seq <= next_seq + self.offset {
+                break;
+            }
+            self.next_seq = Some(*entry.key() + 1);
+            out.push(entry.remove())
+        }
+        Ok(out)
+    }
+
+    /// Returns the packets still held back, at the end of the stream.
+    pub fn flush(&mut self) -> Vec<Vec<u8>> {
+        if let Some((&last, _)) = self.pending.last_key_value() {
+            self.next_seq = Some(last + 1)
+        }
+        std::mem::take(&mut self.pending).into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The framed packets sent for 10 packets, with their sequence numbers.
+    fn sent(offset: usize) -> Vec<(u64, Vec<u8>)> {
+        let mut encoder =
+            crate::opus::Encoder::new(&crate::opus::EncoderConfig::new(48_000)).unwrap();
+        let pcm: Vec<f32> = (0..10 * 960).map(|i| (i as f32 * 0.05).sin() * 0.3).collect();
+        let mut duplicator = Duplicator::new(offset).unwrap();
+        let mut sent = vec![];
+        for packet in encoder.encode(&pcm).unwrap() {
+            sent.extend(duplicator.push(&packet))
+        }
+        sent.extend(duplicator.flush());
+        let seq = |framed: &[u8]| crate::opus::Packet::parse_framed(framed).unwrap().0;
+        sent.into_iter().map(|framed| (seq(&framed), framed)).collect()
+    }
+
+    fn receive(offset: usize, received: &[(u64, Vec<u8>)]) -> (Vec<u64>, u64) {
+        let mut deduplicator = Deduplicator::new(offset).unwrap();
+        let mut out = vec![];
+        for (_, framed) in received {
+            out.extend(deduplicator.push(framed).unwrap())
+        }
+        out.extend(deduplicator.flush());
+        let seqs = out.iter().map(|f| crate::opus::Packet::parse_framed(f).unwrap().0).collect();
+        (seqs, deduplicator.duplicates())
+    }
+
+    #[test]
+    fn duplicator() {
+        let sent = sent(2);
+        let seqs: Vec<u64> = sent.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, [0, 1, 2, 0, 3, 1, 4, 2, 5, 3, 6, 4, 7, 5, 8, 6, 9, 7, 8, 9]);
+        assert!(Duplicator::new(0).is_err());
+        assert!(Deduplicator::new(0).is_err());
+    }
+
+    #[test]
+    fn deduplicator() {
+        let all: Vec<u64> = (0..10).collect();
+        let sent = sent(2);
+        assert_eq!(receive(2, &sent), (all.clone(), 10));
+        // A burst of `offset` lost packets is recovered from the copies.
+        let received: Vec<_> = sent
+            .iter()
+            .enumerate()
+            .filter(|(i, (seq, _))| !((*seq == 3 || *seq == 4) && *i < 7))
+            .map(|(_, p)| p.clone())
+            .collect();
+        assert_eq!(receive(2, &received), (all.clone(), 8));
+        // When both copies are lost, the following packets are returned with a gap.
+        let received: Vec<_> = sent.iter().filter(|(seq, _)| *seq != 5).cloned().collect();
+        let expected: Vec<u64> = all.iter().copied().filter(|&seq| seq != 5).collect();
+        assert_eq!(receive(2, &received), (expected, 9));
+    }
+}
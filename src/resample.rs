@@ -0,0 +1,299 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Streaming resampling of interleaved pcm on top of rubato. Rubato processes fixed size chunks
+// and delays its output, both are hidden here: any number of samples can be pushed and the
+// output is aligned with the input. The default polynomial interpolation has a short delay but
+// no anti-aliasing filter, the sinc interpolation filters out the aliasing at the cost of a delay
+// of about half the filter length.
+
+use crate::Result;
+
+pub use rubato::WindowFunction;
+
+/// How the resamplers compute the output samples between the input ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Interpolation {
+    /// Degree 7 polynomial, cheap and with almost no delay.
+    #[default]
+    Polynomial,
+    Sinc(SincParams),
+}
+
+/// Parameters of the windowed sinc filter, see the rubato documentation for details.
+#[derive(Debug, Clone, Copy)]
+pub struct SincParams {
+    /// Filter length in input samples, rounded up to a multiple of 8. The resampler delay is
+    /// about half of it.
+    pub len: usize,
+    /// Cutoff frequency relative to the lowest of the input and output Nyquist frequencies.
+    /// Higher values keep more of the high frequencies but let more aliasing through, unless
+    /// the filter is made longer.
+    pub cutoff: f32,
+    /// Number of precomputed filters between two input samples.
+    pub oversampling_factor: usize,
+    pub interpolation: SincInterpolation,
+    pub window: WindowFunction,
+}
+
+/// Interpolation between the precomputed sinc filters, higher degrees need fewer filters for the
+/// same quality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SincInterpolation {
+    Nearest,
+    Linear,
+    Quadratic,
+    Cubic,
+}
+
+impl SincParams {
+    /// A short filter for realtime paths, about 16 input samples of delay with more aliasing
+    /// than the default.
+    pub fn low_latency() -> Self {
+        Self {
+            len: 32,
+            cutoff: rubato::calculate_cutoff(32, WindowFunction::Blackman2),
+            oversampling_factor: 128,
+            interpolation: SincInterpolation::Cubic,
+            window: WindowFunction::Blackman2,
+        }
+    }
+
+    pub(crate) fn to_rubato(self) -> rubato::SincInterpolationParameters {
+        let interpolation = match self.interpolation {
+            SincInterpolation::Nearest => rubato::SincInterpolationType::Nearest,
+            SincInterpolation::Linear => rubato::SincInterpolationType::Linear,
+            SincInterpolation::Quadratic => rubato::SincInterpolationType::Quadratic,
+            SincInterpolation::Cubic => rubato::SincInterpolationType::Cubic,
+        };
+        rubato::SincInterpolationParameters {
+            sinc_len: self.len,
+            f_cutoff: self.cutoff,
+            oversampling_factor: self.oversampling_factor,
+            interpolation,
+            window: self.window,
+        }
+    }
+}
+
+/// Works on `f32` or `f64` samples.
+pub struct StreamResampler<T: rubato::Sample = f32> {
+    // One resampler per channel, empty when the input and output rates are the same.
+    resamplers: Vec<crate::AudioOutputData_<T>>,
+    input_sample_rate: usize,
+    output_sample_rate: usize,
+    channels: usize,
+    planar: Vec<Vec<T>>,
+    // Output samples per channel still to be dropped to compensate for the filter delay.
+    to_skip: usize,
+    input_len: u64,
+    output_len: u64,
+}
+
+impl<T: rubato::Sample + Into<f64>> StreamResampler<T> {
+    /// Resamples interleaved pcm with `channels` channels from `input_sample_rate` to
+    /// `output_sample_rate`.
+    pub fn new(
+        input_sample_rate: usize,
+        output_sample_rate: usize,
+        channels: usize,
+    ) -> Result<Self> {
+        Self::new_with_interpolation(
+            input_sample_rate,
+            output_sample_rate,
+            channels,
+            Interpolation::Polynomial,
+        )
+    }
+
+    pub fn new_with_interpolation(
+        input_sample_rate: usize,
+        output_sample_rate: usize,
+        channels: usize,
+        interpolation: Interpolation,
+    ) -> Result<Self> {
+        if input_sample_rate == 0 || output_sample_rate == 0 || channels == 0 {
+            crate::bail!(
+                "unsupported resampling {input_sample_rate}Hz to {output_sample_rate}Hz with \
+                 {channels} channels"
+            )
+        }
+        let resamplers = if input_sample_rate == output_sample_rate {
+            vec![]
+        } else {
+            (0..channels)
+                .map(|_| {
+                    crate::AudioOutputData_::new_with_interpolation(
+                        input_sample_rate,
+                        output_sample_rate,
+                        interpolation,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        let to_skip = resamplers.first().map_or(0, |r| r.output_delay());
+        Ok(Self {
+            resamplers,
+            input_sample_rate,
+            output_sample_rate,
+            channels,
+            planar: vec![vec![]; channels],
+            to_skip,
+            input_len: 0,
+            output_len: 0,
+        })
+    }
+
+    pub fn input_sample_rate(&self) -> usize {
+        self.input_sample_rate
+    }
+
+    pub fn output_sample_rate(&self) -> usize {
+        self.output_sample_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Resamples `pcm`, which must hold whole frames. The returned samples may lag behind the
+    /// input by up to a resampler chunk, the remaining samples are returned by `flush`.
+    pub fn process(&mut self, pcm: &[T]) -> Result<Vec<T>> {
+        if !pcm.len().is_multiple_of(self.channels) {
+            crate::bail!("{} samples is not a whole number of frames", pcm.len())
+        }
+        if self.resamplers.is_empty() {
+            return Ok(pcm.to_vec());
+        }
+        self.planar.iter_mut().for_each(|c| c.clear());
+        crate::deinterleave_into(pcm, &mut self.planar);
+        self.input_len += self.planar.first().map_or(0, |c| c.len() as u64);
+        for (resampler, pcm) in self.resamplers.iter_mut().zip(self.planar.iter()) {
+            resampler.push_samples(pcm)?
+        }
+        Ok(self.take_output(u64::MAX))
+    }
+
+    // Takes the resampled data, skipping the delay and without going past `max_len` output
+    // samples in total.
+    fn take_output(&mut self, max_len: u64) -> Vec<T> {
+        let mut planar: Vec<Vec<T>> = self.resamplers.iter_mut().map(|r| r.take_all()).collect();
+        let available = planar.first().map_or(0, |c| c.len());
+        let skip = usize::min(self.to_skip, available);
+        self.to_skip -= skip;
+        let len = usize::min(available - skip, max_len.saturating_sub(self.output_len) as usize);
+        for c in planar.iter_mut() {
+            c.drain(..skip);
+            c.truncate(len);
+        }
+        self.output_len += len as u64;
+        crate::interleave(&planar)
+    }
+
+    /// Pushes silence until all the input has been resampled and returns the remaining output,
+    /// the total output length then matches the input duration.
+    pub fn flush(&mut self) -> Result<Vec<T>> {
+        let expected_len = (self.input_len * self.output_sample_rate as u64)
+            .div_ceil(self.input_sample_rate as u64);
+        let mut pcm = vec![];
+        while self.output_len < expected_len {
+            let chunk_size = self.resamplers.first().map_or(0, |r| r.input_chunk_size());
+            let zeros = vec![T::coerce(0.); chunk_size];
+            for resampler in self.resamplers.iter_mut() {
+                resampler.push_samples(&zeros)?
+            }
+            pcm.extend(self.take_output(expected_len));
+        }
+        Ok(pcm)
+    }
+
+    /// Clears the resampler state so that it can be used for a new stream.
+    pub fn reset(&mut self) {
+        self.resamplers.iter_mut().for_each(|r| r.reset());
+        self.to_skip = self.resamplers.first().map_or(0, |r| r.output_delay());
+        self.input_len = 0;
+        self.output_len = 0;
+    }
+}
+
+/// Resamples a whole mono buffer of `f32` or `f64` samples with the default sinc interpolation,
+/// which low-pass filters the signal when downsampling. The filter delay is compensated and the
+/// tail is flushed, so the output is aligned with the input and has the same duration.
+pub fn resample<T: rubato::Sample + Into<f64>>(
+    pcm: &[T],
+    from_hz: usize,
+    to_hz: usize,
+) -> Result<Vec<T>> {
+    let interpolation = Interpolation::Sinc(SincParams::default());
+    let mut resampler = StreamResampler::new_with_interpolation(from_hz, to_hz, 1, interpolation)?;
+    let mut out = resampler.process(pcm)?;
+    out.extend(resampler.flush()?);
+    Ok(out)
+}
+
+impl Default for SincParams {
+    /// The starting point suggested by rubato, a 256 samples filter.
+    fn default() -> Self {
+        Self {
+            len: 256,
+            cutoff: 0.95,
+            oversampling_factor: 128,
+            interpolation: SincInterpolation::Linear,
+            window: WindowFunction::BlackmanHarris2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(len: usize) -> Vec<f64> {
+        (0..len).map(|i| 0.5 * (i as f64 * 0.05).sin()).collect()
+    }
+
+    #[test]
+    fn f64_samples() {
+        let pcm64 = sine(24_000);
+        let pcm32: Vec<f32> = pcm64.iter().map(|&v| v as f32).collect();
+        let out64 = resample(&pcm64, 24_000, 16_000).unwrap();
+        let out32 = resample(&pcm32, 24_000, 16_000).unwrap();
+        assert_eq!(out64.len(), 16_000);
+        assert_eq!(out32.len(), 16_000);
+        let max_diff = out64.iter().zip(out32.iter()).map(|(a, &b)| (a - b as f64).abs());
+        let max_diff = max_diff.fold(0f64, f64::max);
+        assert!(max_diff < 1e-4, "{max_diff}");
+    }
+
+    #[test]
+    fn impulse_position() {
+        for (from_hz, to_hz) in [(24_000, 48_000), (48_000, 16_000), (16_000, 24_000)] {
+            let mut pcm = vec![0f32; from_hz / 2];
+            let pos = from_hz / 10;
+            pcm[pos] = 1.;
+            let expected = pos * to_hz / from_hz;
+            // The root function is the same as the one of this module.
+            for out in [
+                resample(&pcm, from_hz, to_hz).unwrap(),
+                crate::resample(&pcm, from_hz, to_hz).unwrap(),
+            ] {
+                assert_eq!(out.len(), to_hz / 2);
+                let peak = (0..out.len()).max_by(|&a, &b| out[a].total_cmp(&out[b])).unwrap();
+                assert!(peak.abs_diff(expected) <= 1, "{from_hz} {to_hz} {peak} {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn level() {
+        let mut resampler = crate::AudioOutputData_::<f64>::new(24_000, 48_000).unwrap();
+        assert!(resampler.db10().abs() < 1e-3);
+        resampler.push_samples(&sine(24_000)).unwrap();
+        // The mean square of a sine with an amplitude of 0.5 is 0.125.
+        let expected = 10. + 0.125f32.log10();
+        assert!((resampler.db10() - expected).abs() < 0.05, "{}", resampler.db10());
+        resampler.reset();
+        assert!(resampler.db10().abs() < 1e-3);
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Playback speed changes, either by plain resampling (the pitch changes with the speed) or by
+// time-stretching using WSOLA (waveform similarity overlap-add) which preserves the pitch.
+
+use crate::Result;
+
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 2.0;
+
+// Speed changes are spread over this duration to avoid abrupt pitch or tempo jumps.
+const SPEED_RAMP_MS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedMode {
+    /// Linear interpolation resampling, cheap but the pitch follows the speed.
+    Resample,
+    /// Pitch preserving time-stretching.
+    TimeStretch,
+}
+
+pub struct SpeedChanger {
+    mode: SpeedMode,
+    // The speed being ramped to, and the speed currently applied.
+    speed: f64,
+    current_speed: f64,
+    // Speed increment per input sample during a ramp.
+    speed_step: f64,
+    ramp_len: usize,
+    // Resample mode state: position of the next output sample after `prev`.
+    frac_pos: f64,
+    prev: f32,
+    // Time-stretch mode state, positions are absolute sample indexes in the input stream.
+    hop: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+    buf: Vec<f32>,
+    buf_offset: usize,
+    next_nominal: f64,
+    prev_pos: Option<usize>,
+    overlap: Vec<f32>,
+}
+
+impl SpeedChanger {
+    pub fn new(mode: SpeedMode, sample_rate: usize, speed: f64) -> Result<Self> {
+        // 30ms frames with 50% overlap, the frame positions are adjusted by up to 10ms to
+        // maximize the similarity with the previous frame.
+        let hop = usize::max(sample_rate * 15 / 1000, 1);
+        let window = (0..2 * hop)
+            .map(|i| (0.5 - 0.5 * (std::f64::consts::PI * i as f64 / hop as f64).cos()) as f32)
+            .collect();
+        check_speed(speed)?;
+        let s = Self {
+            mode,
+            speed,
+            current_speed: speed,
+            speed_step: 0.,
+            ramp_len: usize::max(sample_rate * SPEED_RAMP_MS / 1000, 1),
+            frac_pos: 0.,
+            prev: 0.,
+            hop,
+            tolerance: sample_rate / 100,
+            window,
+            buf: vec![],
+            buf_offset: 0,
+            next_nominal: 0.,
+            prev_pos: None,
+            overlap: vec![0f32; hop],
+        };
+        Ok(s)
+    }
+
+    pub fn mode(&self) -> SpeedMode {
+        self.mode
+    }
+
+    /// The speed reached at the end of the current ramp.
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Changes the speed, the next processed samples ramp from the current speed to the new one
+    /// over 50ms.
+    pub fn set_speed(&mut self, speed: f64) -> Result<()> {
+        check_speed(speed)?;
+        self.speed = speed;
+        self.speed_step = (speed - self.current_speed) / self.ramp_len as f64;
+        Ok(())
+    }
+
+    // Moves the current speed towards the target after `samples` input samples.
+    fn advance_speed(&mut self, samples: usize) {
+        if self.current_speed != self.speed {
+            let speed = self.current_speed + self.speed_step * samples as f64;
+            let done = (self.speed_step > 0.) == (speed >= self.speed);
+            self.current_speed = if done { self.speed } else { speed };
+        }
+    }
+
+    pub fn process(&mut self, pcm: &[f32], out: &mut Vec<f32>) {
+        match self.mode {
+            SpeedMode::Resample => self.process_resample(pcm, out),
+            SpeedMode::TimeStretch => self.process_time_stretch(pcm, out),
+        }
+    }
+
+    fn process_resample(&mut self, pcm: &[f32], out: &mut Vec<f32>) {
+        for &next in pcm.iter() {
+            while self.frac_pos < 1. {
+                let pos = self.frac_pos as f32;
+                out.push(self.prev + (next - self.prev) * pos);
+                self.frac_pos += self.current_speed;
+            }
+            self.frac_pos -= 1.;
+            self.prev = next;
+            self.advance_speed(1);
+        }
+    }
+
+    fn process_time_stretch(&mut self, pcm: &[f32], out: &mut Vec<f32>) {
+        let (hop, tol) = (self.hop, self.tolerance);
+        self.buf.extend_from_slice(pcm);
+        let buf_end = self.buf_offset + self.buf.len();
+        loop {
+            let nominal = self.next_nominal.round() as usize;
+            let needed = match self.prev_pos {
+                None => nominal + 2 * hop,
+                Some(prev_pos) => usize::max(nominal + tol + 2 * hop, prev_pos + 2 * hop),
+            };
+            if needed > buf_end {
+                break;
+            }
+            let pos = match self.prev_pos {
+                None => nominal,
+                Some(prev_pos) => {
+                    // The natural continuation of the previous frame.
+                    let target = &self.buf[prev_pos + hop - self.buf_offset..][..hop];
+                    let lo = usize::max(nominal.saturating_sub(tol), self.buf_offset);
+                    let mut best = (nominal, f32::NEG_INFINITY);
+                    for pos in lo..=nominal + tol {
+                        let cand = &self.buf[pos - self.buf_offset..][..hop];
+                        let xcorr: f32 = cand.iter().zip(target.iter()).map(|(a, b)| a * b).sum();
+                        if xcorr > best.1 {
+                            best = (pos, xcorr)
+                        }
+                    }
+                    best.0
+                }
+            };
+            let frame = &self.buf[pos - self.buf_offset..][..2 * hop];
+            for i in 0..hop {
+                out.push(self.overlap[i] + frame[i] * self.window[i]);
+                self.overlap[i] = frame[hop + i] * self.window[hop + i];
+            }
+            self.prev_pos = Some(pos);
+            self.next_nominal += hop as f64 * self.current_speed;
+            self.advance_speed(hop);
+            let keep_from =
+                usize::min((self.next_nominal as usize).saturating_sub(tol + 1), pos + hop);
+            if keep_from > self.buf_offset {
+                self.buf.drain(..keep_from - self.buf_offset);
+                self.buf_offset = keep_from;
+            }
+        }
+    }
+}
+
+fn check_speed(speed: f64) -> Result<()> {
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        crate::bail!("unsupported playback speed {speed}, expected {MIN_SPEED}-{MAX_SPEED}")
+    }
+    Ok(())
+}
@@ -0,0 +1,250 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Insertion of pre-encoded ogg/opus clips (jingles, announcements) into a live ogg/opus stream
+// at page boundaries. The pages of the clip and of the live stream that follow it get their
+// serial, page sequence numbers and granule positions rewritten so that the output remains a
+// single valid logical stream, none of the opus packets are re-encoded.
+
+use crate::ogg_pager::{page_ranges, rewrite_page, PageHeader};
+use crate::parse::{
+    segments, ChannelMapping, OpusHead, Toc, BEGIN_OF_STREAM, END_OF_STREAM, PAGE_HEADER_SIZE,
+};
+use crate::Result;
+
+// The OpusHead packet, which is alone on the first page of the stream, and its channel mapping.
+fn read_head(page: &[u8]) -> Result<(OpusHead, ChannelMapping)> {
+    let packet = &page[PAGE_HEADER_SIZE + page[PAGE_HEADER_SIZE - 1] as usize..];
+    Ok((OpusHead::from_slice(packet)?, OpusHead::channel_mapping(packet)?))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Splicer {
+    serial: Option<u32>,
+    // Added to the live granule positions and page sequence numbers, this accounts for the
+    // inserted clips.
+    granule_offset: u64,
+    sequence_offset: u32,
+    last_granule_position: u64,
+    last_page_sequence: Option<u32>,
+    channel_mapping: Option<ChannelMapping>,
+}
+
+impl Splicer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Total duration of the inserted clips so far, in samples at 48kHz.
+    pub fn inserted_duration(&self) -> u64 {
+        self.granule_offset
+    }
+
+    /// Passes pages of the live stream through, `data` must only contain complete pages, e.g.
+    /// the output of `ogg_opus::Encoder::encode_page`.
+    pub fn process(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut data = data.to_vec();
+        for range in page_ranges(&data)? {
+            let page = &mut data[range];
+            let hdr = PageHeader::read(page);
+            if hdr.is_bos() {
+                self.channel_mapping = Some(read_head(page)?.1);
+            }
+            let serial = *self.serial.get_or_insert(hdr.bitstream_serial);
+            let granule_position = match hdr.granule_position {
+                u64::MAX => u64::MAX,
+                // Header pages
+                0 => 0,
+                g => match g.checked_add(self.granule_offset) {
+                    Some(g) => g,
+                    None => crate::bail!("granule position overflow {g}"),
+                },
+            };
+            let page_sequence = hdr.page_sequence.wrapping_add(self.sequence_offset);
+            if self.granule_offset > 0 || self.sequence_offset > 0 {
+                rewrite_page(page, hdr.header_type, granule_position, serial, page_sequence);
+            }
+            if granule_position != u64::MAX {
+                self.last_granule_position = granule_position
+            }
+            self.last_page_sequence = Some(page_sequence);
+        }
+        Ok(data)
+    }
+
+    /// Returns the audio pages of the complete ogg/opus file `clip`, rewritten to follow the last
+    /// live page processed so far. These bytes have to be sent before the next live pages. The
+    /// clip must have the channel count and mapping of the live stream. Its pre-skip is not
+    /// applied by the live decoders, so the live granule positions advance by the full duration
+    /// of its packets.
+    pub fn insert(&mut self, clip: &[u8]) -> Result<Vec<u8>> {
+        let (Some(last_page_sequence), Some(channel_mapping)) =
+            (self.last_page_sequence, self.channel_mapping.as_ref())
+        else {
+            crate::bail!("clips can only be inserted after the live stream headers")
+        };
+        let ranges = page_ranges(clip)?;
+        let Some(first) = ranges.first() else { crate::bail!("empty clip") };
+        let (_, clip_mapping) = read_head(&clip[first.clone()])?;
+        if &clip_mapping != channel_mapping {
+            crate::bail!(
+                "clip channel mapping {clip_mapping:?} does not match the live stream {channel_mapping:?}"
+            )
+        }
+        let serial = self.serial.unwrap_or(0);
+        let mut out = Vec::with_capacity(clip.len());
+        let mut n_pages = 0u32;
+        let mut clip_duration = 0u64;
+        let mut n_packets = 0;
+        let mut packet = vec![];
+        for range in ranges {
+            let hdr = PageHeader::read(&clip[range.clone()]);
+            // The OpusHead and OpusTags packets come first, the audio starts on the page that
+            // follows the end of the OpusTags packet.
+            let header_page = n_packets < 2;
+            let mut packet_ends = false;
+            for segment in segments(&clip[range.clone()]) {
+                if n_packets >= 2 {
+                    packet.extend_from_slice(segment)
+                }
+                if segment.len() < 255 {
+                    if n_packets >= 2 {
+                        clip_duration += packet_duration(&packet);
+                        packet.clear();
+                    }
+                    n_packets += 1;
+                    packet_ends = true;
+                }
+            }
+            if header_page {
+                continue;
+            }
+            let granule_position = if packet_ends {
+                match self.last_granule_position.checked_add(clip_duration) {
+                    Some(g) => g,
+                    None => crate::bail!("granule position overflow {}", hdr.granule_position),
+                }
+            } else {
+                u64::MAX
+            };
+            n_pages = n_pages.wrapping_add(1);
+            let mut page = clip[range].to_vec();
+            let header_type = hdr.header_type & !(BEGIN_OF_STREAM | END_OF_STREAM);
+            let page_sequence = last_page_sequence.wrapping_add(n_pages);
+            rewrite_page(&mut page, header_type, granule_position, serial, page_sequence);
+            out.extend_from_slice(&page);
+        }
+        self.last_granule_position += clip_duration;
+        self.last_page_sequence = Some(last_page_sequence.wrapping_add(n_pages));
+        self.granule_offset += clip_duration;
+        self.sequence_offset = self.sequence_offset.wrapping_add(n_pages);
+        Ok(out)
+    }
+}
+
+// Duration of a packet in samples at 48kHz, 0 for packets that are skipped by the decoders.
+fn packet_duration(packet: &[u8]) -> u64 {
+    Toc::parse(packet).map_or(0, |toc| toc.duration_us() as u64 * 48 / 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(channels: usize, len: usize) -> Vec<u8> {
+        let mut cfg = crate::ogg_opus::EncoderConfig::new(48_000);
+        cfg.channels = channels;
+        let pcm: Vec<f32> = (0..len * channels).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        crate::ogg_opus::Encoder::new_with_config(cfg).unwrap().encode_file(&pcm).unwrap()
+    }
+
+    fn headers(data: &[u8]) -> Vec<PageHeader> {
+        page_ranges(data).unwrap().into_iter().map(|r| PageHeader::read(&data[r])).collect()
+    }
+
+    #[test]
+    fn insert_clip() {
+        let live = encode(1, 48_000);
+        let ranges = page_ranges(&live).unwrap();
+        let mut splicer = Splicer::new();
+        assert!(splicer.insert(&[]).is_err());
+        // The headers and the first audio pages, then a clip.
+        let mid = ranges[5].end;
+        let mut data = splicer.process(&live[..mid]).unwrap();
+        let clip = encode(1, 9_600);
+        data.extend_from_slice(&splicer.insert(&clip).unwrap());
+        // All the clip packets are played, including the ones covering its pre-skip.
+        let clip_packets = headers(&clip)
+            .iter()
+            .filter(|h| h.granule_position != 0 && h.granule_position != u64::MAX)
+            .count();
+        assert_eq!(splicer.inserted_duration(), clip_packets as u64 * 960);
+        assert!(splicer.insert(&encode(2, 9_600)).is_err());
+        data.extend_from_slice(&splicer.process(&live[mid..]).unwrap());
+
+        let hdrs = headers(&data);
+        assert!(hdrs.iter().all(|h| h.bitstream_serial == hdrs[0].bitstream_serial));
+        assert_eq!(hdrs.iter().filter(|h| h.is_bos()).count(), 1);
+        assert_eq!(hdrs.iter().filter(|h| h.is_eos()).count(), 1);
+        for (i, h) in hdrs.iter().enumerate() {
+            assert_eq!(h.page_sequence, i as u32);
+        }
+        let granules: Vec<u64> =
+            hdrs.iter().map(|h| h.granule_position).filter(|&g| g != u64::MAX).collect();
+        assert!(granules.windows(2).all(|w| w[0] <= w[1]), "{granules:?}");
+        assert_eq!(crate::validate::validate(&data), vec![]);
+        let mut reader = crate::ogg_pager::PageReader::new();
+        reader.set_checksum_policy(crate::ogg_pager::ChecksumPolicy::Reject);
+        reader.append_bytes(&data);
+        while reader.next().unwrap().is_some() {}
+    }
+
+    #[test]
+    fn page_sequence_wraps() {
+        let mut live = encode(1, 9_600);
+        let ranges = page_ranges(&live).unwrap();
+        for (i, range) in ranges.iter().enumerate() {
+            let hdr = PageHeader::read(&live[range.clone()]);
+            let page_sequence = (u32::MAX - 3).wrapping_add(i as u32);
+            let page = &mut live[range.clone()];
+            rewrite_page(page, hdr.header_type, hdr.granule_position, 1, page_sequence);
+        }
+        let mid = ranges[3].end;
+        let mut splicer = Splicer::new();
+        let mut data = splicer.process(&live[..mid]).unwrap();
+        data.extend_from_slice(&splicer.insert(&encode(1, 9_600)).unwrap());
+        data.extend_from_slice(&splicer.process(&live[mid..]).unwrap());
+        let hdrs = headers(&data);
+        for (i, h) in hdrs.iter().enumerate() {
+            assert_eq!(h.page_sequence, (u32::MAX - 3).wrapping_add(i as u32));
+        }
+    }
+
+    #[test]
+    fn clip_with_large_tags() {
+        let mut cfg = crate::ogg_opus::EncoderConfig::new(48_000);
+        cfg.comments.push(("METADATA_BLOCK_PICTURE".to_string(), "A".repeat(100_000)));
+        let pcm: Vec<f32> = (0..9_600).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        let clip = crate::ogg_opus::Encoder::new_with_config(cfg).unwrap().encode_file(&pcm);
+        let clip = clip.unwrap();
+        // The middle pages of the OpusTags packet have no granule position.
+        let clip_pages = headers(&clip);
+        let first_audio_page = clip_pages
+            .iter()
+            .position(|h| h.granule_position != 0 && h.granule_position != u64::MAX)
+            .unwrap();
+        assert!(first_audio_page > 2);
+        let audio_pages = clip_pages.len() - first_audio_page;
+
+        let live = encode(1, 48_000);
+        let mid = page_ranges(&live).unwrap()[5].end;
+        let mut splicer = Splicer::new();
+        let mut data = splicer.process(&live[..mid]).unwrap();
+        let inserted = splicer.insert(&clip).unwrap();
+        assert_eq!(headers(&inserted).len(), audio_pages);
+        data.extend_from_slice(&inserted);
+        data.extend_from_slice(&splicer.process(&live[mid..]).unwrap());
+        assert_eq!(crate::validate::validate(&data), vec![]);
+    }
+}
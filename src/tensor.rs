@@ -0,0 +1,108 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Conversions between the interleaved pcm returned by the decoders and the tensors consumed by
+// ml models. Tensors are laid out as (channels, samples), the layout expected by most audio
+// models, and are converted back to interleaved pcm so that they can be fed to the encoders.
+
+use crate::Result;
+
+fn frames(pcm: &[f32], channels: usize) -> Result<usize> {
+    if channels == 0 {
+        crate::bail!("the number of channels has to be positive")
+    }
+    if !pcm.len().is_multiple_of(channels) {
+        crate::bail!("{} samples is not a whole number of frames of {channels} channels", pcm.len())
+    }
+    Ok(pcm.len() / channels)
+}
+
+/// Copies interleaved pcm to an array of shape (channels, samples).
+#[cfg(feature = "ndarray")]
+pub fn to_array2(pcm: &[f32], channels: usize) -> Result<ndarray::Array2<f32>> {
+    let frames = frames(pcm, channels)?;
+    let array = ndarray::Array2::from_shape_vec((frames, channels), pcm.to_vec())
+        .map_err(crate::Error::wrap)?;
+    Ok(array.reversed_axes().as_standard_layout().into_owned())
+}
+
+/// Interleaves the rows of an array of shape (channels, samples), whatever its memory layout.
+#[cfg(feature = "ndarray")]
+pub fn from_array2(array: ndarray::ArrayView2<f32>) -> Vec<f32> {
+    array.t().iter().copied().collect()
+}
+
+/// Copies interleaved pcm to a f32 tensor of shape (channels, samples) on `device`.
+#[cfg(feature = "candle")]
+pub fn to_tensor(
+    pcm: &[f32],
+    channels: usize,
+    device: &candle_core::Device,
+) -> Result<candle_core::Tensor> {
+    let frames = frames(pcm, channels)?;
+    let tensor = candle_core::Tensor::from_slice(pcm, (frames, channels), device)
+        .and_then(|t| t.t()?.contiguous())
+        .map_err(crate::Error::wrap)?;
+    Ok(tensor)
+}
+
+/// Interleaves a tensor of shape (channels, samples), or (samples,) for mono audio. Tensors of
+/// other float dtypes are converted to f32 and the data is copied back from the device.
+#[cfg(feature = "candle")]
+pub fn from_tensor(tensor: &candle_core::Tensor) -> Result<Vec<f32>> {
+    let convert = || {
+        let tensor = tensor.to_dtype(candle_core::DType::F32)?;
+        match tensor.rank() {
+            1 => tensor.to_vec1::<f32>(),
+            2 => tensor.t()?.flatten_all()?.to_vec1::<f32>(),
+            rank => Err(candle_core::Error::Msg(format!(
+                "expected a tensor of shape (channels, samples), got rank {rank}"
+            ))),
+        }
+    };
+    convert().map_err(crate::Error::wrap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three frames of two channels.
+    const PCM: [f32; 6] = [0., 0.5, 0.1, 0.6, 0.2, 0.7];
+
+    #[test]
+    fn frames_validation() {
+        assert_eq!(frames(&PCM, 2).unwrap(), 3);
+        assert!(frames(&PCM, 0).is_err());
+        assert!(frames(&PCM, 4).is_err());
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn array2_round_trip() -> Result<()> {
+        let array = to_array2(&PCM, 2)?;
+        assert_eq!(array, ndarray::array![[0., 0.1, 0.2], [0.5, 0.6, 0.7]]);
+        assert_eq!(from_array2(array.view()), PCM);
+        // Non standard layouts are interleaved the same way.
+        let transposed = ndarray::array![[0., 0.5], [0.1, 0.6], [0.2, 0.7]];
+        assert_eq!(from_array2(transposed.t()), PCM);
+        Ok(())
+    }
+
+    #[cfg(feature = "candle")]
+    #[test]
+    fn tensor_round_trip() -> Result<()> {
+        let device = candle_core::Device::Cpu;
+        let tensor = to_tensor(&PCM, 2, &device)?;
+        assert_eq!(tensor.dims(), [2, 3]);
+        let rows = tensor.to_vec2::<f32>().map_err(crate::Error::wrap)?;
+        assert_eq!(rows, [[0., 0.1, 0.2], [0.5, 0.6, 0.7]]);
+        assert_eq!(from_tensor(&tensor)?, PCM);
+        let wide = tensor.to_dtype(candle_core::DType::F64).map_err(crate::Error::wrap)?;
+        assert_eq!(from_tensor(&wide)?, PCM);
+        let mono = to_tensor(&PCM, 1, &device)?.squeeze(0).map_err(crate::Error::wrap)?;
+        assert_eq!(from_tensor(&mono)?, PCM);
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Re-encoding of ogg/opus streams, e.g. to create low bandwidth renditions of stored sessions.
+// The packets are decoded and re-encoded one at a time at 48kHz, the OpusTags are copied as is
+// and the granule positions of the input are preserved.
+// Audio files in any format supported by symphonia can also be converted to ogg/opus, with the
+// progress reported on a watch channel.
+
+use crate::ogg_pager::PacketEnd;
+use crate::Result;
+
+// Offset of the pre-skip field in the OpusHead packet.
+const PRE_SKIP_OFFSET: usize = 10;
+
+/// Re-encodes the complete ogg/opus stream `input` at `target_bitrate` bits per second. The
+/// decoder and encoder use the channels of the input OpusHead, streams with a channel mapping
+/// that the encoder would not reproduce, e.g. family 255, are rejected.
+pub fn transcode_bitrate(input: &[u8], target_bitrate: u32) -> Result<Vec<u8>> {
+    let mut pr = crate::ogg_pager::PacketReader::new();
+    // Created with the serial of the first page.
+    let mut pw = None;
+    // Created once the OpusHead has been read, with the encoder lookahead.
+    let mut codec: Option<(crate::opus::Encoder, crate::opus::Decoder, usize)> = None;
+    let mut pcm = vec![];
+    let mut decoded = 0u64;
+    let mut start_offset = None;
+    let mut last_granule_position = 0;
+    for range in crate::ogg_pager::page_ranges(input)? {
+        let page = &input[range];
+        let pw = pw.get_or_insert_with(|| {
+            let serial = crate::ogg_pager::PageHeader::read(page).bitstream_serial;
+            crate::ogg_pager::PageWriter::new(serial)
+        });
+        pr.append_bytes(page);
+        while let Some(packet) = pr.next()? {
+            if packet.starts_with(b"OpusHead") {
+                let mut head = packet;
+                let channel_mapping = crate::ogg_opus::OpusHead::channel_mapping(&head)?;
+                let mut cfg = crate::opus::EncoderConfig::new(48_000);
+                cfg.bitrate = Some(target_bitrate);
+                cfg.channels = channel_mapping.channels();
+                let mut encoder = crate::opus::Encoder::new(&cfg)?;
+                if encoder.channel_mapping() != &channel_mapping {
+                    crate::bail!(
+                        "cannot transcode channel mapping {channel_mapping:?}, the encoder uses {:?}",
+                        encoder.channel_mapping()
+                    )
+                }
+                let decoder = crate::opus::Decoder::new_with_channel_mapping(
+                    48_000,
+                    false,
+                    &channel_mapping,
+                )?;
+                // The encoder delay is added to the pre-skip so that the output stays aligned
+                // with the input timeline.
+                let lookahead = encoder.lookahead()?;
+                let pre_skip =
+                    u16::from_le_bytes([head[PRE_SKIP_OFFSET], head[PRE_SKIP_OFFSET + 1]]);
+                let pre_skip = pre_skip.saturating_add(lookahead as u16);
+                head[PRE_SKIP_OFFSET..PRE_SKIP_OFFSET + 2].copy_from_slice(&pre_skip.to_le_bytes());
+                pw.write_packet(&head, 0, PacketEnd::EndPage);
+                codec = Some((encoder, decoder, lookahead));
+                continue;
+            }
+            if packet.starts_with(b"OpusTags") {
+                pw.write_packet(&packet, 0, PacketEnd::EndPage);
+                continue;
+            }
+            let Some((encoder, decoder, _)) = codec.as_mut() else {
+                crate::bail!("no OpusHead before the audio packets")
+            };
+            pcm.clear();
+            decoded += (decoder.decode(&packet, &mut pcm)? / decoder.channels()) as u64;
+            for packet in encoder.encode(&pcm)? {
+                let granule_position = packet.granule_position + start_offset.unwrap_or(0);
+                pw.write_packet(&packet.data, granule_position, PacketEnd::EndPage);
+            }
+        }
+        let granule_position = pr.last_granule_position();
+        if decoded > 0 && granule_position != u64::MAX {
+            // Streams that do not start at 0, e.g. after some shifting, keep their offset.
+            start_offset.get_or_insert(granule_position.saturating_sub(decoded));
+            last_granule_position = granule_position;
+        }
+    }
+    let Some(mut pw) = pw else { crate::bail!("no ogg page in transcoding input") };
+    let Some((mut encoder, _, lookahead)) = codec else {
+        crate::bail!("no OpusHead in transcoding input")
+    };
+    // Flush the encoder delay and the last partial frame, the end of the stream is trimmed to the
+    // input duration.
+    let mut packets = encoder.encode(&vec![0f32; lookahead * encoder.channels()])?;
+    packets.extend(encoder.flush()?);
+    let end = last_granule_position + lookahead as u64;
+    pw.end_stream_with(
+        packets
+            .iter()
+            .map(|p| (&p.data[..], u64::min(p.granule_position + start_offset.unwrap_or(0), end))),
+    );
+    Ok(pw.take_data())
+}
+
+#[derive(Debug, Clone)]
+pub struct TranscodeConfig {
+    /// The sample rate is replaced by the one of the input file, `resample` is set by default so
+    /// that any rate is accepted. When `channel_map` is set, the channels are selected from the
+    /// input file. Otherwise the first `channels` channels are used.
+    pub encoder: crate::opus::EncoderConfig,
+}
+
+impl TranscodeConfig {
+    pub fn new() -> Self {
+        let mut encoder = crate::opus::EncoderConfig::new(48_000);
+        encoder.resample = true;
+        Self { encoder }
+    }
+}
+
+impl Default for TranscodeConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TranscodeProgress {
+    pub bytes_read: u64,
+    /// Size of the input file.
+    pub total_bytes: u64,
+    /// Duration of the audio converted so far.
+    pub seconds_processed: f64,
+    /// Estimated from the read throughput so far, `None` until some data has been read.
+    pub eta: Option<std::time::Duration>,
+    pub done: bool,
+}
+
+// A file that tracks how far it has been read.
+struct ProgressFile {
+    file: std::fs::File,
+    position: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl std::io::Read for ProgressFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read(buf)?;
+        self.position.fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl std::io::Seek for ProgressFile {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let position = self.file.seek(pos)?;
+        self.position.store(position, std::sync::atomic::Ordering::Relaxed);
+        Ok(position)
+    }
+}
+
+impl symphonia::core::io::MediaSource for ProgressFile {
+    fn is_seekable(&self) -> bool {
+        symphonia::core::io::MediaSource::is_seekable(&self.file)
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        symphonia::core::io::MediaSource::byte_len(&self.file)
+    }
+}
+
+/// Converts the audio file `src` to an ogg/opus file `dst`. The conversion runs on the tokio
+/// blocking thread pool once the returned future is polled, its progress is published on the
+/// returned watch channel. The future resolves to the final progress.
+pub fn transcode_file<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+    src: P,
+    dst: Q,
+    cfg: TranscodeConfig,
+) -> (
+    impl std::future::Future<Output = Result<TranscodeProgress>>,
+    tokio::sync::watch::Receiver<TranscodeProgress>,
+) {
+    let (tx, rx) = tokio::sync::watch::channel(TranscodeProgress::default());
+    let src = src.as_ref().to_path_buf();
+    let dst = dst.as_ref().to_path_buf();
+    let fut = async move {
+        let task =
+            move || transcode_file_blocking(&src, &dst, &cfg, &tx).map_err(|e| e.with_path(&src));
+        tokio::task::spawn_blocking(task).await.map_err(crate::Error::wrap)?
+    };
+    (fut, rx)
+}
+
+fn transcode_file_blocking(
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    cfg: &TranscodeConfig,
+    tx: &tokio::sync::watch::Sender<TranscodeProgress>,
+) -> Result<TranscodeProgress> {
+    use std::io::Write;
+
+    let start_time = std::time::Instant::now();
+    let file = crate::open_audio_file(src)?;
+    let total_bytes = file.metadata()?.len();
+    let position = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let file = ProgressFile { file, position: position.clone() };
+    let (sources, input_channels) = match cfg.encoder.channel_map.as_ref() {
+        Some(map) => (map.sources.clone(), Some(map.input_channels)),
+        None => ((0..cfg.encoder.channels).collect(), None),
+    };
+    let mut out = std::io::BufWriter::new(std::fs::File::create(dst)?);
+    let mut encoder: Option<crate::ogg_opus::Encoder> = None;
+    let mut frames = 0u64;
+    let mut progress = TranscodeProgress { total_bytes, ..Default::default() };
+    crate::decode_source(Box::new(file), &sources, input_channels, |pcm, sample_rate| {
+        let encoder = match encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => {
+                let mut encoder_cfg = cfg.encoder.clone();
+                encoder_cfg.sample_rate = sample_rate as usize;
+                encoder_cfg.channel_map = None;
+                let e = crate::ogg_opus::Encoder::new_with_config(encoder_cfg)?;
+                out.write_all(e.header_data())?;
+                encoder.insert(e)
+            }
+        };
+        out.write_all(&encoder.encode_page(&crate::interleave(pcm))?)?;
+        frames += pcm.first().map_or(0, |c| c.len() as u64);
+        let bytes_read = position.load(std::sync::atomic::Ordering::Relaxed);
+        progress.bytes_read = bytes_read;
+        progress.seconds_processed = frames as f64 / sample_rate.max(1) as f64;
+        if bytes_read > 0 {
+            let remaining = total_bytes.saturating_sub(bytes_read) as f64 / bytes_read as f64;
+            progress.eta = Some(start_time.elapsed().mul_f64(remaining));
+        }
+        tx.send_replace(progress.clone());
+        Ok(())
+    })?;
+    let Some(encoder) = encoder else { crate::bail!("no audio in transcoding input") };
+    out.write_all(&encoder.finish()?)?;
+    out.flush()?;
+    progress.bytes_read = total_bytes;
+    progress.eta = Some(std::time::Duration::ZERO);
+    progress.done = true;
+    tx.send_replace(progress.clone());
+    Ok(progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(channels: usize, len: usize) -> Vec<u8> {
+        let mut cfg = crate::ogg_opus::EncoderConfig::new(48_000);
+        cfg.channels = channels;
+        let pcm: Vec<f32> = (0..len * channels)
+            .map(|i| 0.3 * ((i / channels) as f32 * 0.01 * (1 + i % channels) as f32).sin())
+            .collect();
+        crate::ogg_opus::Encoder::new_with_config(cfg).unwrap().encode_file(&pcm).unwrap()
+    }
+
+    #[test]
+    fn transcode_channels() {
+        for channels in [1, 2, 6] {
+            let input = encode(channels, 48_000);
+            let output = transcode_bitrate(&input, 16_000).unwrap();
+            let info_in = crate::ogg_opus::inspect(&input).unwrap();
+            let info_out = crate::ogg_opus::inspect(&output).unwrap();
+            assert_eq!(info_out.head.channel_count as usize, channels);
+            assert_eq!(info_out.head.mapping_family, info_in.head.mapping_family);
+            assert_eq!(info_out.duration, info_in.duration);
+            assert!(output.len() < input.len(), "{} {}", output.len(), input.len());
+
+            let mut pr = crate::ogg_pager::PacketReader::new();
+            pr.append_bytes(&output);
+            let head = pr.next().unwrap().unwrap();
+            let mapping = crate::ogg_opus::OpusHead::channel_mapping(&head).unwrap();
+            let mut decoder =
+                crate::opus::Decoder::new_with_channel_mapping(48_000, false, &mapping).unwrap();
+            let mut pcm = vec![];
+            pr.next().unwrap().unwrap();
+            while let Some(packet) = pr.next().unwrap() {
+                decoder.decode(&packet, &mut pcm).unwrap();
+            }
+            assert!(pcm.len() >= 48_000 * channels);
+        }
+    }
+
+    #[test]
+    fn unsupported_mapping() {
+        // Two uncoupled streams with mapping family 1, the encoder would use family 0.
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 2, 0, 0, 0x80, 0xbb, 0, 0, 0, 0, 1, 2, 0, 0, 1]);
+        let mut pw = crate::ogg_pager::PageWriter::new(1);
+        pw.write_packet(&head, 0, crate::ogg_pager::PacketEnd::EndStream);
+        let err = transcode_bitrate(&pw.take_data(), 16_000).unwrap_err();
+        assert!(err.to_string().contains("channel mapping"), "{err}");
+    }
+}
@@ -0,0 +1,465 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+//
+// Checks of complete ogg/opus streams against the ogg framing and RFC 7845: header packets and
+// their placement, page sequence numbers, beginning and end of stream flags, granule positions
+// and channel mappings. All the violations are reported rather than stopping at the first one,
+// so that this can run in CI on the produced files and on ingested third-party files.
+
+use crate::ogg_opus::{OpusHead, OpusTags};
+use crate::parse::{BEGIN_OF_STREAM, CONTINUED_PACKET, END_OF_STREAM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// A recommendation of the specifications is not followed, players handle the stream.
+    Warning,
+    /// A requirement of the specifications is not met.
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub severity: Severity,
+    /// Offset of the page in the stream.
+    pub offset: u64,
+    pub serial: Option<u32>,
+    pub page_sequence: Option<u32>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity} at offset {}", self.offset)?;
+        if let Some(serial) = self.serial {
+            write!(f, ", serial {serial}")?;
+        }
+        if let Some(page_sequence) = self.page_sequence {
+            write!(f, ", page {page_sequence}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+// Longest duration of an opus packet, in samples at 48kHz.
+const MAX_PACKET_DURATION: u64 = 5760;
+
+#[derive(Default)]
+struct Stream {
+    serial: u32,
+    // Whether the first packet of the stream is an OpusHead, other streams are only checked at
+    // the ogg level.
+    opus: bool,
+    next_sequence: u32,
+    ended: bool,
+    packets: u64,
+    // Segments of a packet continued on the next page.
+    partial: Vec<u8>,
+    // Number of pages read for the stream.
+    pages: u32,
+    // Index in the stream of the page on which the packet being read started.
+    packet_start_page: Option<u32>,
+    head: Option<OpusHead>,
+    // Granule position of the last page with a completed audio packet.
+    last_granule_position: Option<u64>,
+}
+
+struct Validator {
+    violations: Vec<Violation>,
+    offset: u64,
+    serial: Option<u32>,
+    page_sequence: Option<u32>,
+}
+
+impl Validator {
+    fn report(&mut self, severity: Severity, message: String) {
+        self.violations.push(Violation {
+            severity,
+            offset: self.offset,
+            serial: self.serial,
+            page_sequence: self.page_sequence,
+            message,
+        })
+    }
+
+    fn error(&mut self, message: String) {
+        self.report(Severity::Error, message)
+    }
+}
+
+/// Validates a complete ogg/opus stream held in memory and returns the violations found, an
+/// empty list means that the stream is compliant.
+pub fn validate(data: &[u8]) -> Vec<Violation> {
+    let hdr_size = crate::parse::PAGE_HEADER_SIZE;
+    let mut v = Validator { violations: vec![], offset: 0, serial: None, page_sequence: None };
+    let mut streams: Vec<Stream> = vec![];
+    // Beginning of stream pages have to come before the other pages of their link.
+    let mut data_pages_in_link = false;
+    let mut pos = 0;
+    while pos < data.len() {
+        v.offset = pos as u64;
+        v.serial = None;
+        v.page_sequence = None;
+        if pos + hdr_size > data.len() {
+            v.error(format!("truncated page header, {} bytes left", data.len() - pos));
+            break;
+        }
+        if &data[pos..pos + 4] != b"OggS" {
+            v.error("missing capture pattern".to_string());
+            match data[pos + 1..].windows(4).position(|w| w == b"OggS") {
+                Some(skip) => {
+                    pos += skip + 1;
+                    continue;
+                }
+                None => break,
+            }
+        }
+        let hdr = crate::parse::PageHeader::read(&data[pos..]);
+        let (serial, page_sequence) = (hdr.bitstream_serial, hdr.page_sequence);
+        let (header_type, granule_position, checksum) =
+            (hdr.header_type, hdr.granule_position, hdr.checksum);
+        v.serial = Some(serial);
+        v.page_sequence = Some(page_sequence);
+        let table_end = pos + hdr_size + hdr.page_segments as usize;
+        if table_end > data.len() {
+            v.error("truncated segment table".to_string());
+            break;
+        }
+        let lacing = &data[pos + hdr_size..table_end];
+        let page_end = table_end + lacing.iter().map(|&l| l as usize).sum::<usize>();
+        if page_end > data.len() {
+            v.error(format!("truncated page, {} bytes missing", page_end - data.len()));
+            break;
+        }
+        let page = &data[pos..page_end];
+        pos = page_end;
+        if hdr.version != 0 {
+            v.error(format!("unsupported ogg version {}", hdr.version));
+            continue;
+        }
+        let crc = crate::parse::page_checksum(page);
+        if crc != checksum {
+            v.error(format!("checksum mismatch, expected {crc:08x}, got {checksum:08x}"));
+        }
+
+        let bos = header_type & BEGIN_OF_STREAM != 0;
+        let index = match streams.iter().position(|s| s.serial == serial) {
+            Some(index) => {
+                if bos {
+                    v.error("beginning of stream flag on a page that is not the first".into());
+                }
+                index
+            }
+            None => {
+                if !bos {
+                    v.error("first page of the stream without the beginning of stream flag".into());
+                }
+                let link_ended = streams.iter().all(|s| s.ended);
+                if link_ended {
+                    // A chained stream starts a new link.
+                    data_pages_in_link = false;
+                } else if data_pages_in_link {
+                    v.error("beginning of stream page after the start of the data pages".into());
+                }
+                streams.push(Stream { serial, next_sequence: page_sequence, ..Default::default() });
+                streams.len() - 1
+            }
+        };
+        if !bos {
+            data_pages_in_link = true;
+        }
+        let stream = &mut streams[index];
+        if stream.ended {
+            v.error("page after the end of stream page".into());
+        }
+        if page_sequence != stream.next_sequence {
+            let expected = stream.next_sequence;
+            v.error(format!(
+                "page sequence {page_sequence}, expected {expected}, pages are missing"
+            ));
+        }
+        stream.next_sequence = page_sequence.wrapping_add(1);
+        let eos = header_type & END_OF_STREAM != 0;
+        stream.ended |= eos;
+
+        // Split the page into its packets.
+        let continued = header_type & CONTINUED_PACKET != 0;
+        if continued && stream.partial.is_empty() {
+            v.error("continued packet flag without a packet to continue".into());
+        } else if !continued && !stream.partial.is_empty() {
+            v.error("packet not continued on the following page".into());
+            stream.partial.clear();
+            stream.packet_start_page = None;
+        }
+        let page_index = stream.pages;
+        stream.pages = stream.pages.saturating_add(1);
+        let mut packets = vec![];
+        let mut body = &data[table_end..page_end];
+        for &l in lacing.iter() {
+            let start_page = *stream.packet_start_page.get_or_insert(page_index);
+            stream.partial.extend_from_slice(&body[..l as usize]);
+            body = &body[l as usize..];
+            if l < 255 {
+                packets.push((std::mem::take(&mut stream.partial), start_page));
+                stream.packet_start_page = None;
+            }
+        }
+        if eos && !stream.partial.is_empty() {
+            v.error("incomplete packet on the end of stream page".into());
+        }
+        if packets.is_empty() && granule_position != u64::MAX {
+            v.error(format!("granule position {granule_position} on a page without packet end"));
+        } else if !packets.is_empty() && granule_position == u64::MAX {
+            v.error("granule position of -1 on a page with a packet end".into());
+        }
+        check_packets(&mut v, stream, &packets, granule_position, eos);
+    }
+
+    v.offset = data.len() as u64;
+    v.page_sequence = None;
+    if !streams.iter().any(|s| s.opus) {
+        v.serial = None;
+        v.error("no opus stream found".into());
+    }
+    for stream in streams.iter() {
+        v.serial = Some(stream.serial);
+        if !stream.ended {
+            v.error("the stream does not end with an end of stream page".into());
+        }
+        if stream.opus && stream.packets < 2 {
+            v.error("missing OpusTags header".into());
+        }
+    }
+    v.violations
+}
+
+// Checks the packets completed on a page, with the index of the page on which each started.
+fn check_packets(
+    v: &mut Validator,
+    stream: &mut Stream,
+    packets: &[(Vec<u8>, u32)],
+    granule_position: u64,
+    eos: bool,
+) {
+    let mut page_duration = 0;
+    let mut audio_packets = 0;
+    for (i, (packet, start_page)) in packets.iter().enumerate() {
+        let index = stream.packets;
+        stream.packets += 1;
+        match index {
+            0 => {
+                stream.opus = packet.starts_with(b"OpusHead");
+                if !stream.opus {
+                    return;
+                }
+                check_head(v, stream, packet);
+                if packets.len() != 1 || !stream.partial.is_empty() {
+                    v.error("the OpusHead packet has to be alone on the first page".into());
+                }
+                if granule_position != 0 {
+                    v.error(format!("granule position {granule_position} on the OpusHead page"));
+                }
+            }
+            _ if !stream.opus => {}
+            1 => {
+                if *start_page != 1 {
+                    v.error("the OpusTags packet has to start on the second page".into());
+                }
+                if let Err(err) = OpusTags::from_slice(packet) {
+                    v.error(format!("invalid OpusTags packet: {err}"));
+                }
+                if i + 1 != packets.len() || !stream.partial.is_empty() {
+                    v.error("audio data on the last page of the OpusTags packet".into());
+                }
+                if granule_position != 0 {
+                    v.error(format!("granule position {granule_position} on the OpusTags page"));
+                }
+            }
+            _ => {
+                audio_packets += 1;
+                match crate::opus::Toc::parse(packet) {
+                    Ok(toc) => {
+                        let duration = toc.duration_us() as u64 * 48 / 1000;
+                        if duration > MAX_PACKET_DURATION {
+                            v.error(format!("packet duration {duration} above 120ms"));
+                        }
+                        page_duration += duration;
+                    }
+                    // Zero-length packets only carry timing.
+                    Err(_) if packet.is_empty() => {}
+                    Err(err) => v.error(format!("invalid audio packet {}: {err}", index - 2)),
+                }
+            }
+        }
+    }
+    if audio_packets == 0 || granule_position == u64::MAX {
+        return;
+    }
+    match stream.last_granule_position {
+        None => {
+            // The start of the stream is the granule position minus the page duration, only
+            // the end of stream page can have a granule position below its duration.
+            if granule_position < page_duration && !eos {
+                let msg = format!(
+                    "granule position {granule_position} of the first audio page is below its \
+                     duration {page_duration}"
+                );
+                v.error(msg);
+            }
+            let pre_skip = stream.head.as_ref().map_or(0, |h| h.pre_skip as u64);
+            if eos && granule_position < pre_skip {
+                let msg = format!("granule position {granule_position} below the pre-skip");
+                v.error(msg);
+            }
+        }
+        Some(last) => {
+            let expected = last + page_duration;
+            if granule_position < last {
+                v.error(format!("granule position {granule_position} after {last}"));
+            } else if granule_position < expected && !eos {
+                // Only the end of stream page can trim the end of its last packet.
+                let msg = format!(
+                    "granule position {granule_position} below the end of the page audio \
+                     {expected}"
+                );
+                v.error(msg);
+            } else if granule_position > expected {
+                let msg = format!("gap of {} samples in the audio", granule_position - expected);
+                v.report(Severity::Warning, msg);
+            }
+        }
+    }
+    stream.last_granule_position = Some(granule_position);
+}
+
+fn check_head(v: &mut Validator, stream: &mut Stream, packet: &[u8]) {
+    let head = match OpusHead::from_slice(packet) {
+        Ok(head) => head,
+        Err(err) => {
+            v.error(format!("invalid OpusHead packet: {err}"));
+            return;
+        }
+    };
+    let (version, channels, family) = (head.version, head.channel_count, head.mapping_family);
+    if version & 0xf0 != 0 {
+        v.error(format!("unsupported OpusHead version {version}"));
+    }
+    if channels == 0 {
+        v.error("OpusHead with 0 channels".into());
+    }
+    match family {
+        0 if channels > 2 => v.error(format!("{channels} channels with mapping family 0")),
+        1 if channels > 8 => v.error(format!("{channels} channels with mapping family 1")),
+        2..=254 => v.report(Severity::Warning, format!("undefined mapping family {family}")),
+        _ => {}
+    }
+    if family != 0 {
+        match OpusHead::channel_mapping(packet) {
+            Ok(mapping) => {
+                let (streams, coupled) =
+                    (mapping.streams as usize, mapping.coupled_streams as usize);
+                if streams == 0 || coupled > streams || streams + coupled > 255 {
+                    v.error(format!("invalid stream counts {streams}, {coupled} coupled"));
+                }
+                if let Some(m) =
+                    mapping.mapping.iter().find(|&&m| m != 255 && m as usize >= streams + coupled)
+                {
+                    v.error(format!("channel mapping {m} above the number of decoded channels"));
+                }
+            }
+            Err(err) => v.error(format!("invalid channel mapping table: {err}")),
+        }
+    }
+    stream.head = Some(head);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(comments: Vec<(String, String)>) -> Vec<u8> {
+        let mut cfg = crate::ogg_opus::EncoderConfig::new(48_000);
+        cfg.comments = comments;
+        let pcm: Vec<f32> = (0..48_000).map(|i| 0.3 * (i as f32 * 0.01).sin()).collect();
+        crate::ogg_opus::Encoder::new_with_config(cfg).unwrap().encode_file(&pcm).unwrap()
+    }
+
+    fn pages(data: &[u8]) -> Vec<Vec<u8>> {
+        let ranges = crate::parse::page_ranges(data).unwrap();
+        ranges.into_iter().map(|r| data[r].to_vec()).collect()
+    }
+
+    fn errors(data: &[u8]) -> Vec<String> {
+        let violations = validate(data).into_iter();
+        violations.filter(|v| v.severity == Severity::Error).map(|v| v.message).collect()
+    }
+
+    #[test]
+    fn valid_streams() {
+        assert_eq!(validate(&encode(vec![])), vec![]);
+        // The OpusTags packet spans several pages.
+        let picture = ("METADATA_BLOCK_PICTURE".to_string(), "A".repeat(100_000));
+        let data = encode(vec![picture]);
+        assert!(pages(&data).iter().any(|p| crate::parse::PageHeader::read(p).is_continued()));
+        assert_eq!(validate(&data), vec![]);
+    }
+
+    #[test]
+    fn missing_end_of_stream() {
+        let mut pages = pages(&encode(vec![]));
+        pages.pop();
+        let errors = errors(&pages.concat());
+        assert_eq!(errors, ["the stream does not end with an end of stream page"]);
+    }
+
+    #[test]
+    fn missing_page() {
+        let mut pages = pages(&encode(vec![]));
+        pages.remove(10);
+        let violations = validate(&pages.concat());
+        assert_eq!(violations.len(), 2, "{violations:?}");
+        assert_eq!(violations[0].message, "page sequence 11, expected 10, pages are missing");
+        assert_eq!(violations[0].page_sequence, Some(11));
+        assert_eq!(violations[1].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn tags_not_on_second_page() {
+        let mut pages = pages(&encode(vec![]));
+        // An empty page between the OpusHead and OpusTags pages.
+        let mut empty = pages[1][..crate::parse::PAGE_HEADER_SIZE - 1].to_vec();
+        empty.push(0);
+        pages.insert(1, empty);
+        for (i, page) in pages.iter_mut().enumerate() {
+            let hdr = crate::parse::PageHeader::read(page);
+            let granule_position = if i == 1 { u64::MAX } else { hdr.granule_position };
+            let (header_type, serial) = (hdr.header_type, hdr.bitstream_serial);
+            crate::parse::rewrite_page(page, header_type, granule_position, serial, i as u32);
+        }
+        let errors = errors(&pages.concat());
+        assert_eq!(errors, ["the OpusTags packet has to start on the second page"]);
+    }
+
+    #[test]
+    fn invalid_channel_mapping() {
+        let mut pages = pages(&encode(vec![]));
+        // Two channels with mapping family 1, the second one maps to a missing stream.
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&3840u16.to_le_bytes());
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 1, 1, 0, 0, 5]);
+        let mut page = pages[0][..crate::parse::PAGE_HEADER_SIZE - 1].to_vec();
+        page.extend_from_slice(&[1, head.len() as u8]);
+        page.extend_from_slice(&head);
+        let hdr = crate::parse::PageHeader::read(&page);
+        let (serial, page_sequence) = (hdr.bitstream_serial, hdr.page_sequence);
+        crate::parse::rewrite_page(&mut page, hdr.header_type, 0, serial, page_sequence);
+        pages[0] = page;
+        let errors = errors(&pages.concat());
+        assert_eq!(errors, ["channel mapping 5 above the number of decoded channels"]);
+    }
+}
@@ -2,6 +2,7 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+use crate::Result;
 use std::io::prelude::*;
 
 pub trait Sample {
@@ -32,29 +33,393 @@ pub fn write_pcm_as_wav<W: Write, S: Sample>(
     sample_rate: u32,
     n_channels: u32,
 ) -> std::io::Result<()> {
-    let len = 12u32; // header
-    let len = len + 24u32; // fmt
-    let len = len + samples.len() as u32 * 2 + 8; // data
-    let bytes_per_second = sample_rate * 2 * n_channels;
+    write_wav(w, samples, sample_rate, n_channels, None)
+}
+
+/// Same as `write_pcm_as_wav` with a Broadcast Wave `bext` chunk before the data.
+pub fn write_pcm_as_bwf<W: Write, S: Sample>(
+    w: &mut W,
+    samples: &[S],
+    sample_rate: u32,
+    n_channels: u32,
+    bext: &Bext,
+) -> std::io::Result<()> {
+    write_wav(w, samples, sample_rate, n_channels, Some(bext))
+}
+
+fn write_wav<W: Write, S: Sample>(
+    w: &mut W,
+    samples: &[S],
+    sample_rate: u32,
+    n_channels: u32,
+    bext: Option<&Bext>,
+) -> std::io::Result<()> {
+    let spec = WavSpec::new(sample_rate, n_channels as u16);
+    write_header(w, &spec, samples.len(), bext)?;
+    for sample in samples.iter() {
+        w.write_all(&sample.to_i16().to_le_bytes())?
+    }
+    Ok(())
+}
+
+// Writes everything up to the start of the samples in the data chunk.
+fn write_header<W: Write>(
+    w: &mut W,
+    spec: &WavSpec,
+    n_samples: usize,
+    bext: Option<&Bext>,
+) -> std::io::Result<()> {
+    let bext = bext.map(|b| b.to_bytes());
+    let bytes_per_sample = spec.sample_format.bytes_per_sample() as u32;
+    let data_len = n_samples as u64 * bytes_per_sample as u64;
+    let len = 12u64; // header
+    let len = len + 24; // fmt
+    let len = len + bext.as_ref().map_or(0, |b| b.len() as u64 + 8); // bext
+    let len = len + data_len + 8; // data
+                                  // The RIFF sizes are 32 bits.
+    let (Ok(data_len), Ok(riff_len)) = (u32::try_from(data_len), u32::try_from(len - 8)) else {
+        let msg = format!("{n_samples} samples do not fit in a wav file");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+    };
+    let block_align = bytes_per_sample * spec.channels as u32;
     w.write_all(b"RIFF")?;
-    w.write_all(&(len - 8).to_le_bytes())?; // total length minus 8 bytes
+    w.write_all(&riff_len.to_le_bytes())?; // total length minus 8 bytes
     w.write_all(b"WAVE")?;
 
     // Format block
     w.write_all(b"fmt ")?;
-    w.write_all(&16u32.to_le_bytes())?; // bits per sample
-    w.write_all(&1u16.to_le_bytes())?; // 1: PCM int, 2: PCM float
-    w.write_all(&(n_channels as u16).to_le_bytes())?;
-    w.write_all(&sample_rate.to_le_bytes())?;
-    w.write_all(&bytes_per_second.to_le_bytes())?;
-    w.write_all(&2u16.to_le_bytes())?; // 2 bytes of data per sample
-    w.write_all(&16u16.to_le_bytes())?; // bits per sample
+    w.write_all(&16u32.to_le_bytes())?; // size of the format block
+    w.write_all(&spec.sample_format.format_tag().to_le_bytes())?;
+    w.write_all(&spec.channels.to_le_bytes())?;
+    w.write_all(&spec.sample_rate.to_le_bytes())?;
+    w.write_all(&(spec.sample_rate * block_align).to_le_bytes())?; // bytes per second
+    w.write_all(&(block_align as u16).to_le_bytes())?; // bytes per frame
+    w.write_all(&(bytes_per_sample as u16 * 8).to_le_bytes())?; // bits per sample
+
+    // Broadcast extension block
+    if let Some(bext) = bext {
+        w.write_all(b"bext")?;
+        w.write_all(&(bext.len() as u32).to_le_bytes())?;
+        w.write_all(&bext)?;
+    }
 
     // Data block
     w.write_all(b"data")?;
-    w.write_all(&(samples.len() as u32 * 2).to_le_bytes())?;
-    for sample in samples.iter() {
-        w.write_all(&sample.to_i16().to_le_bytes())?
+    w.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    I16,
+    I24,
+    I32,
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            Self::I16 => 2,
+            Self::I24 => 3,
+            Self::I32 | Self::F32 => 4,
+        }
+    }
+
+    fn format_tag(&self) -> u16 {
+        match self {
+            Self::I16 | Self::I24 | Self::I32 => WAVE_FORMAT_PCM,
+            Self::F32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
     }
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+// The actual format tag is then stored in the first two bytes of the sub-format guid.
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavSpec {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+}
+
+impl WavSpec {
+    /// 16-bit samples, as used by `write_pcm_as_wav`.
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self { sample_rate, channels, sample_format: SampleFormat::I16 }
+    }
+}
+
+/// Writes the interleaved pcm, with values between -1 and 1, as a wav file using the sample
+/// format of `spec`. Values outside of this range are clamped for the integer formats, which use
+/// the scaling of the `pcm` module so that `read` returns the written values.
+pub fn write<W: Write>(w: &mut W, pcm: &[f32], spec: &WavSpec) -> std::io::Result<()> {
+    write_header(w, spec, pcm.len(), None)?;
+    let mut quantizer = crate::pcm::Quantizer::new(crate::pcm::Dither::None);
+    w.write_all(&quantizer.to_bytes(pcm, spec.sample_format))
+}
+
+pub fn write_file<P: AsRef<std::path::Path>>(path: P, pcm: &[f32], spec: &WavSpec) -> Result<()> {
+    let mut w = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write(&mut w, pcm, spec)?;
+    w.flush()?;
     Ok(())
 }
+
+/// Reads a wav file holding 16, 24 or 32 bits integer samples or 32 bits float samples, returns
+/// the interleaved pcm with values between -1 and 1 and the format of the file.
+pub fn read<R: Read>(mut r: R) -> Result<(Vec<f32>, WavSpec)> {
+    let mut data = vec![];
+    r.read_to_end(&mut data)?;
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        crate::bail!("not a wav file")
+    }
+    let mut fmt = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]);
+        let start = pos + 8;
+        // Writers that stream their output set the data size to the maximum, the data then
+        // extends to the end of the file.
+        let end = match size {
+            u32::MAX if id == b"data" => data.len(),
+            size => usize::min(start + size as usize, data.len()),
+        };
+        let chunk = &data[start..end];
+        if id == b"fmt " {
+            fmt = Some(read_fmt(chunk)?);
+        } else if id == b"data" {
+            let Some(spec) = fmt else { crate::bail!("wav data chunk before fmt chunk") };
+            // A truncated last sample is dropped.
+            let len = chunk.len() / spec.sample_format.bytes_per_sample();
+            let chunk = &chunk[..len * spec.sample_format.bytes_per_sample()];
+            let pcm = crate::pcm::from_bytes(chunk, spec.sample_format)?;
+            return Ok((pcm, spec));
+        }
+        // Chunks are padded to an even size.
+        pos = start + size as usize + (size as usize % 2);
+    }
+    crate::bail!("no data chunk in wav file")
+}
+
+pub fn read_file<P: AsRef<std::path::Path>>(path: P) -> Result<(Vec<f32>, WavSpec)> {
+    read(std::fs::File::open(path)?)
+}
+
+fn read_fmt(chunk: &[u8]) -> Result<WavSpec> {
+    if chunk.len() < 16 {
+        crate::bail!("wav fmt chunk is too short {}", chunk.len())
+    }
+    let u16_at = |pos: usize| u16::from_le_bytes([chunk[pos], chunk[pos + 1]]);
+    let mut format_tag = u16_at(0);
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        if chunk.len() < 26 {
+            crate::bail!("wav extensible fmt chunk is too short {}", chunk.len())
+        }
+        format_tag = u16_at(24)
+    }
+    let channels = u16_at(2);
+    let sample_rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+    let bits = u16_at(14);
+    let sample_format = match (format_tag, bits) {
+        (WAVE_FORMAT_PCM, 16) => SampleFormat::I16,
+        (WAVE_FORMAT_PCM, 24) => SampleFormat::I24,
+        (WAVE_FORMAT_PCM, 32) => SampleFormat::I32,
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => SampleFormat::F32,
+        _ => crate::bail!("unsupported wav format {format_tag} with {bits} bits per sample"),
+    };
+    if channels == 0 {
+        crate::bail!("wav file without any channel")
+    }
+    Ok(WavSpec { sample_rate, channels, sample_format })
+}
+
+// Size of the fixed part of the bext chunk, before the coding history.
+const BEXT_FIXED_LEN: usize = 602;
+
+/// Broadcast Wave Format metadata, see EBU Tech 3285. Only the fields below are supported, the
+/// UMID and loudness fields are written as zeros.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bext {
+    /// At most 256 ASCII characters.
+    pub description: String,
+    /// At most 32 ASCII characters.
+    pub originator: String,
+    /// At most 32 ASCII characters.
+    pub originator_reference: String,
+    /// yyyy-mm-dd
+    pub origination_date: String,
+    /// hh:mm:ss
+    pub origination_time: String,
+    /// Position of the first sample since midnight, in samples.
+    pub time_reference: u64,
+    pub coding_history: String,
+}
+
+fn write_fixed(dst: &mut Vec<u8>, s: &str, len: usize) {
+    let bytes = s.as_bytes();
+    let n = usize::min(bytes.len(), len);
+    dst.extend_from_slice(&bytes[..n]);
+    dst.resize(dst.len() + len - n, 0);
+}
+
+fn read_fixed(src: &[u8]) -> String {
+    let end = src.iter().position(|&b| b == 0).unwrap_or(src.len());
+    String::from_utf8_lossy(&src[..end]).to_string()
+}
+
+impl Bext {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(BEXT_FIXED_LEN + self.coding_history.len() + 1);
+        write_fixed(&mut data, &self.description, 256);
+        write_fixed(&mut data, &self.originator, 32);
+        write_fixed(&mut data, &self.originator_reference, 32);
+        write_fixed(&mut data, &self.origination_date, 10);
+        write_fixed(&mut data, &self.origination_time, 8);
+        data.extend_from_slice(&(self.time_reference as u32).to_le_bytes());
+        data.extend_from_slice(&((self.time_reference >> 32) as u32).to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes()); // version
+        data.resize(BEXT_FIXED_LEN, 0); // UMID, loudness and reserved fields
+        data.extend_from_slice(self.coding_history.as_bytes());
+        // Chunks have to be word aligned.
+        if data.len() % 2 == 1 {
+            data.push(0)
+        }
+        data
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < BEXT_FIXED_LEN {
+            crate::bail!("bext chunk is too short {}", data.len())
+        }
+        let low = u32::from_le_bytes([data[338], data[339], data[340], data[341]]) as u64;
+        let high = u32::from_le_bytes([data[342], data[343], data[344], data[345]]) as u64;
+        Ok(Self {
+            description: read_fixed(&data[..256]),
+            originator: read_fixed(&data[256..288]),
+            originator_reference: read_fixed(&data[288..320]),
+            origination_date: read_fixed(&data[320..330]),
+            origination_time: read_fixed(&data[330..338]),
+            time_reference: low | (high << 32),
+            coding_history: read_fixed(&data[BEXT_FIXED_LEN..]),
+        })
+    }
+}
+
+/// Returns the content of the `bext` chunk of a wav file, if any.
+pub fn read_bext(data: &[u8]) -> Result<Option<Bext>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        crate::bail!("not a wav file")
+    }
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]);
+        let start = pos + 8;
+        let end = usize::min(start + size as usize, data.len());
+        if id == b"bext" {
+            return Ok(Some(Bext::from_bytes(&data[start..end])?));
+        }
+        // Chunks are padded to an even size.
+        pos = start + size as usize + (size as usize % 2);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [SampleFormat; 4] =
+        [SampleFormat::I16, SampleFormat::I24, SampleFormat::I32, SampleFormat::F32];
+
+    fn write_vec(pcm: &[f32], spec: &WavSpec) -> Vec<u8> {
+        let mut data = vec![];
+        write(&mut data, pcm, spec).unwrap();
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        // Multiples of 2^-15 are exact in all the formats.
+        let pcm: Vec<f32> = (-32_768..32_768).step_by(7).map(|v| v as f32 / 32_768.).collect();
+        for sample_format in FORMATS {
+            let spec = WavSpec { sample_rate: 24_000, channels: 2, sample_format };
+            let (read_pcm, read_spec) = read(write_vec(&pcm, &spec).as_slice()).unwrap();
+            assert_eq!(read_spec, spec);
+            assert_eq!(read_pcm, pcm, "{sample_format:?}");
+        }
+    }
+
+    #[test]
+    fn integer_formats_clamp() {
+        for sample_format in [SampleFormat::I16, SampleFormat::I24, SampleFormat::I32] {
+            let spec = WavSpec { sample_rate: 24_000, channels: 1, sample_format };
+            let (pcm, _) = read(write_vec(&[-2., -1., 1., 2.], &spec).as_slice()).unwrap();
+            assert_eq!(pcm[..2], [-1., -1.], "{sample_format:?}");
+            // The largest 32 bits value rounds to 1 as a f32.
+            assert!(pcm[2..].iter().all(|&v| v <= 1. && v > 0.999), "{sample_format:?} {pcm:?}");
+        }
+    }
+
+    #[test]
+    fn data_size() {
+        let spec = WavSpec::new(16_000, 1);
+        // An empty data chunk followed by other chunks.
+        let mut data = write_vec(&[], &spec);
+        data.extend_from_slice(b"LIST\x04\x00\x00\x00INFO");
+        let (pcm, _) = read(data.as_slice()).unwrap();
+        assert!(pcm.is_empty());
+        // A streamed file, the data extends to the end of the file.
+        let mut data = write_vec(&[0.5, -0.5], &spec);
+        let data_size = data.len() - 8;
+        data[data_size..data_size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0, 0x40]);
+        let (pcm, _) = read(data.as_slice()).unwrap();
+        assert_eq!(pcm, [0.5, -0.5, 0.5]);
+    }
+
+    #[test]
+    fn too_large() {
+        let spec = WavSpec::new(48_000, 2);
+        let err = write_header(&mut vec![], &spec, 1 << 31, None).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // The largest data chunk that fits, along with the other chunks.
+        let max = (u32::MAX as usize - 36) / 2;
+        assert!(write_header(&mut vec![], &spec, max, None).is_ok());
+        assert!(write_header(&mut vec![], &spec, max + 1, None).is_err());
+        let bext = Bext::default();
+        assert!(write_header(&mut vec![], &spec, max, Some(&bext)).is_err());
+    }
+
+    #[test]
+    fn bwf_round_trip() {
+        let bext = Bext {
+            description: "session".to_string(),
+            originator: "kaudio".to_string(),
+            originator_reference: "ref-1".to_string(),
+            origination_date: "2024-03-01".to_string(),
+            origination_time: "12:34:56".to_string(),
+            // More than 32 bits, the time reference is split in two fields.
+            time_reference: (1 << 33) + 48_000 * 3600,
+            coding_history: "A=PCM,F=48000,W=16,M=mono".to_string(),
+        };
+        let pcm = [0.5f32, -0.25, 0.125];
+        let mut data = vec![];
+        write_pcm_as_bwf(&mut data, &pcm, 48_000, 1, &bext).unwrap();
+        assert_eq!(read_bext(&data).unwrap(), Some(bext));
+        let (read_pcm, spec) = read(data.as_slice()).unwrap();
+        assert_eq!(spec, WavSpec::new(48_000, 1));
+        assert_eq!(read_pcm.len(), pcm.len());
+        let riff_len = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        assert_eq!(riff_len as usize, data.len() - 8);
+
+        let mut data = vec![];
+        write_pcm_as_wav(&mut data, &pcm, 48_000, 1).unwrap();
+        assert_eq!(read_bext(&data).unwrap(), None);
+    }
+}